@@ -0,0 +1,167 @@
+//! Limits tightening when the service slows down
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Scales the limits of a limiter down when the latency of the service rises above a target,
+/// and back up as it recovers, to protect a backend under stress, see
+/// [RateLimiter::with_adaptive_limit](../middleware/struct.RateLimiter.html#method.with_adaptive_limit).
+///
+/// The latency of the responses is averaged over every period, one second by default. At the
+/// end of a period whose average is over the target, the factor applied to the limits is
+/// multiplied by the decrease, 0.9 by default, down to the minimum factor, 0.1 by default.
+/// Otherwise it goes up by the increase, 0.05 by default, up to 1, the configured limits. This
+/// is additive increase, multiplicative decrease, as in TCP congestion control: the limits drop
+/// fast when the service struggles, and come back slowly.
+///
+/// Limits scaled this way change from a period to the next, so the limit and remaining headers
+/// are only a snapshot, and a client can see its remaining requests drop faster than it sends
+/// them. Like stores, it should be created once outside of the `HttpServer` factory and cloned
+/// into every worker, for all of them to follow the same factor.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::adaptive::AdaptiveLimit;
+///
+/// // Aim for responses within 200ms, halving the limits while over it
+/// let adaptive = AdaptiveLimit::new(Duration::from_millis(200))
+///     .with_decrease(0.5)
+///     .with_min_factor(0.2);
+/// ```
+#[derive(Clone)]
+pub struct AdaptiveLimit {
+    target: Duration,
+    decrease: f64,
+    increase: f64,
+    min_factor: f64,
+    period: Duration,
+    inner: Arc<Mutex<State>>,
+}
+
+struct State {
+    factor: f64,
+    // Latency of the responses of the current period
+    total: Duration,
+    responses: u32,
+    since: Instant,
+}
+
+impl AdaptiveLimit {
+    /// Scales the limits down while the average latency of the responses is over `target`
+    pub fn new(target: Duration) -> Self {
+        AdaptiveLimit {
+            target,
+            decrease: 0.9,
+            increase: 0.05,
+            min_factor: 0.1,
+            period: Duration::from_secs(1),
+            inner: Arc::new(Mutex::new(State {
+                factor: 1.0,
+                total: Duration::from_secs(0),
+                responses: 0,
+                since: Instant::now(),
+            })),
+        }
+    }
+
+    /// Factor the limits are multiplied by after a slow period.
+    ///
+    /// # Panics
+    /// If `decrease` is not within `0.0` and `1.0` (both exclusive).
+    pub fn with_decrease(mut self, decrease: f64) -> Self {
+        assert!(
+            decrease > 0.0 && decrease < 1.0,
+            "decrease must be within 0 and 1"
+        );
+        self.decrease = decrease;
+        self
+    }
+
+    /// Share of the configured limits given back after a period within the target.
+    ///
+    /// # Panics
+    /// If `increase` is not within `0.0` (exclusive) and `1.0` (inclusive).
+    pub fn with_increase(mut self, increase: f64) -> Self {
+        assert!(
+            increase > 0.0 && increase <= 1.0,
+            "increase must be within 0 and 1"
+        );
+        self.increase = increase;
+        self
+    }
+
+    /// Lowest factor the limits are scaled by, however slow the service.
+    ///
+    /// # Panics
+    /// If `min_factor` is not within `0.0` (exclusive) and `1.0` (inclusive).
+    pub fn with_min_factor(mut self, min_factor: f64) -> Self {
+        assert!(
+            min_factor > 0.0 && min_factor <= 1.0,
+            "min_factor must be within 0 and 1"
+        );
+        self.min_factor = min_factor;
+        self
+    }
+
+    /// Period over which the latency is averaged, between two adjustments
+    pub fn with_period(mut self, period: Duration) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Factor currently applied to the limits, between the minimum factor and 1
+    pub fn factor(&self) -> f64 {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).factor
+    }
+
+    /// Records the latency of a response, and adjusts the factor at the end of a period
+    pub(crate) fn record(&self, latency: Duration) {
+        let mut state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        state.total += latency;
+        state.responses += 1;
+        if state.since.elapsed() < self.period {
+            return;
+        }
+        let average = state.total / state.responses;
+        state.factor = if average > self.target {
+            (state.factor * self.decrease).max(self.min_factor)
+        } else {
+            (state.factor + self.increase).min(1.0)
+        };
+        state.total = Duration::from_secs(0);
+        state.responses = 0;
+        state.since = Instant::now();
+    }
+
+    /// Scales `max_requests` by the current factor, keeping at least one request
+    pub(crate) fn scale(&self, max_requests: usize) -> usize {
+        let scaled = (max_requests as f64 * self.factor()).floor() as usize;
+        scaled.max(max_requests.min(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let adaptive = AdaptiveLimit::new(Duration::from_millis(100))
+            .with_decrease(0.5)
+            .with_increase(0.25)
+            .with_min_factor(0.2)
+            .with_period(Duration::from_secs(0));
+        adaptive.record(Duration::from_millis(300));
+        assert_eq!(adaptive.scale(10), 5);
+        adaptive.record(Duration::from_millis(300));
+        adaptive.record(Duration::from_millis(300));
+        assert_eq!(adaptive.factor(), 0.2);
+        assert_eq!(adaptive.scale(2), 1);
+        adaptive.record(Duration::from_millis(10));
+        assert!((adaptive.factor() - 0.45).abs() < 1e-9);
+        for _ in 0..3 {
+            adaptive.record(Duration::from_millis(10));
+        }
+        assert_eq!(adaptive.scale(10), 10);
+    }
+}
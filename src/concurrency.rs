@@ -0,0 +1,80 @@
+//! Cap on the number of requests a client can have in flight
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Counts the requests being processed for every client, see
+/// [RateLimiter::with_concurrency_limit](../middleware/struct.RateLimiter.html#method.with_concurrency_limit).
+///
+/// Like stores, it should be created once outside of the `HttpServer` factory and cloned into
+/// every worker, otherwise each worker counts on its own.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    max_concurrent: usize,
+    inner: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ConcurrencyLimit {
+    /// Allows at most `max_concurrent` requests in flight per client
+    pub fn new(max_concurrent: usize) -> Self {
+        ConcurrencyLimit {
+            max_concurrent,
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the configured maximum
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Takes a slot for the client identified by `key`, unless all of them are taken. The slot is
+    /// given back when the returned permit is dropped.
+    pub fn acquire(&self, key: &str) -> Option<Permit> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let in_flight = inner.entry(key.to_string()).or_insert(0);
+        if *in_flight >= self.max_concurrent {
+            return None;
+        }
+        *in_flight += 1;
+        Some(Permit {
+            key: key.to_string(),
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+/// Slot held by a request in flight
+pub struct Permit {
+    key: String,
+    inner: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(in_flight) = inner.get_mut(&self.key) {
+            *in_flight -= 1;
+            if *in_flight == 0 {
+                inner.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let limit = ConcurrencyLimit::new(2);
+        let first = limit.acquire("client").unwrap();
+        let _second = limit.acquire("client").unwrap();
+        assert!(limit.acquire("client").is_none());
+        assert!(limit.acquire("other").is_some());
+        drop(first);
+        assert!(limit.acquire("client").is_some());
+        drop(_second);
+        assert!(limit.inner.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+//! Declarative configuration of a limiter, for applications keeping their limits in one place
+use actix::dev::{Handler, ToEnvelope};
+use actix::prelude::*;
+use std::time::Duration;
+
+use crate::errors::ConfigError;
+use crate::middleware::{Algorithm, FailureMode, Limit, RateLimiter};
+use crate::ActorMessage;
+
+/// Limits of a limiter, which can be deserialized from TOML, JSON or any format supported by
+/// serde with the `config` feature.
+///
+/// The store is not part of the configuration: it has to be created once, outside of the
+/// `HttpServer` factory, and is given to [build](#method.build), which compiles the
+/// configuration into a [RateLimiter](../middleware/struct.RateLimiter.html).
+///
+/// # Example
+/// ```toml
+/// max_requests = 100
+/// interval = 60
+/// algorithm = "sliding_window_counter"
+/// failure_mode = "fail_open"
+///
+/// [[paths]]
+/// prefix = "/login"
+/// max_requests = 5
+/// interval = 60
+///
+/// [[paths]]
+/// prefix = "/reports"
+/// max_requests = 1000
+/// interval = 3600
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize))]
+pub struct RateLimitConfig {
+    /// Maximum number of requests in the window, for the requests matching no path
+    pub max_requests: usize,
+    /// Size of the window in seconds, for the requests matching no path
+    pub interval: u64,
+    /// Defaults to [Algorithm::FixedWindow](../middleware/enum.Algorithm.html)
+    #[cfg_attr(feature = "config", serde(default))]
+    pub algorithm: Option<Algorithm>,
+    /// Defaults to [FailureMode::FailClosed](../middleware/enum.FailureMode.html)
+    #[cfg_attr(feature = "config", serde(default))]
+    pub failure_mode: Option<FailureMode>,
+    /// Limits of their own for path prefixes, see
+    /// [RateLimiter::with_path_limit](../middleware/struct.RateLimiter.html#method.with_path_limit)
+    #[cfg_attr(feature = "config", serde(default))]
+    pub paths: Vec<PathLimitConfig>,
+}
+
+/// Limit of the requests whose path starts with `prefix`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize, serde::Serialize))]
+pub struct PathLimitConfig {
+    /// Path prefix, matching whole path segments
+    pub prefix: String,
+    /// Maximum number of requests in the window
+    pub max_requests: usize,
+    /// Size of the window in seconds
+    pub interval: u64,
+}
+
+impl RateLimitConfig {
+    /// Builds the limiter described by this configuration on `store`. The configuration is
+    /// validated like with [RateLimiterBuilder::build](../middleware/struct.RateLimiterBuilder.html#method.build),
+    /// and each path limit needs at least one request in a window of at least one second.
+    /// Further settings can be applied to the returned limiter with its `with_*` methods.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::config::{PathLimitConfig, RateLimitConfig};
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let config = RateLimitConfig {
+    ///         max_requests: 100,
+    ///         interval: 60,
+    ///         algorithm: None,
+    ///         failure_mode: None,
+    ///         paths: vec![PathLimitConfig {
+    ///             prefix: "/login".to_string(),
+    ///             max_requests: 5,
+    ///             interval: 60,
+    ///         }],
+    ///     };
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = config
+    ///         .build(MemoryStoreActor::from(store).start())
+    ///         .expect("invalid rate limiting configuration");
+    /// }
+    /// ```
+    pub fn build<T>(&self, store: Addr<T>) -> Result<RateLimiter<T>, ConfigError>
+    where
+        T: Handler<ActorMessage> + Send + Sync + 'static,
+        <T as Actor>::Context: ToEnvelope<T, ActorMessage>,
+    {
+        for path in &self.paths {
+            if path.max_requests == 0 || path.interval == 0 {
+                return Err(ConfigError::InvalidValue(
+                    "paths",
+                    format!(
+                        "{} needs at least one request in at least one second",
+                        path.prefix
+                    ),
+                ));
+            }
+        }
+        RateLimiter::builder(store)
+            .with_max_requests(self.max_requests)
+            .with_interval(Duration::from_secs(self.interval))
+            .configure(|mut limiter| {
+                if let Some(algorithm) = self.algorithm {
+                    limiter = limiter.with_algorithm(algorithm);
+                }
+                if let Some(failure_mode) = self.failure_mode {
+                    limiter = limiter.with_failure_mode(failure_mode);
+                }
+                for path in &self.paths {
+                    limiter = limiter.with_path_limit(
+                        path.prefix.as_str(),
+                        Limit {
+                            max_requests: path.max_requests,
+                            interval: Duration::from_secs(path.interval),
+                        },
+                    );
+                }
+                limiter
+            })
+            .build()
+    }
+}
+
+/// Effective configuration of a limiter, see
+/// [RateLimiter::describe](../middleware/struct.RateLimiter.html#method.describe), which can
+/// be serialized with the `config` feature, for instance for a debug endpoint.
+///
+/// It tells what the limiter does rather than how it was set up: closures, such as identifiers
+/// and resolvers, are only reported as enabled, and stores by their type, never by their
+/// address or credentials.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "config", derive(serde::Serialize))]
+pub struct RateLimiterDescription {
+    /// Counting algorithm
+    pub algorithm: Algorithm,
+    /// Maximum number of requests in the window, for the requests matching no path. This is
+    /// the current value of the [shared limit](../middleware/struct.RateLimiter.html#method.with_shared_limit),
+    /// when there is one, before the adaptive limit and the warmup.
+    pub max_requests: usize,
+    /// Size of the window in seconds, for the requests matching no path
+    pub interval: u64,
+    /// Behaviour when the store fails
+    pub failure_mode: FailureMode,
+    /// Limits of their own for path prefixes
+    pub paths: Vec<PathLimitConfig>,
+    /// Whether a [limit resolver](../middleware/struct.RateLimiter.html#method.with_limit_resolver)
+    /// may override the limits for some requests
+    pub dynamic_limits: bool,
+    /// Type of the store
+    pub store: &'static str,
+    /// Number of stores the clients are spread over, one without
+    /// [shards](../middleware/struct.RateLimiter.html#method.with_shards)
+    pub shards: usize,
+    /// Optional settings in use, by the name of their `with_*` method, such as `monitor_only`
+    /// or `token_scale`
+    pub features: Vec<&'static str>,
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use super::*;
+    use crate::{MemoryStore, MemoryStoreActor};
+    use actix_web::{test, web, App, HttpResponse};
+    use log::Level;
+
+    #[actix_rt::test]
+    async fn test_build() {
+        let mut config = RateLimitConfig {
+            max_requests: 100,
+            interval: 60,
+            algorithm: None,
+            failure_mode: None,
+            paths: vec![PathLimitConfig {
+                prefix: "/login".to_string(),
+                max_requests: 2,
+                interval: 60,
+            }],
+        };
+        let limiter = config
+            .build(MemoryStoreActor::from(MemoryStore::new()).start())
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/login", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for (uri, limit) in &[("/", "100"), ("/login", "2")] {
+            let req = test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), limit);
+        }
+
+        config.paths[0].interval = 0;
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let err = config.build(store).err().unwrap();
+        assert!(err.to_string().starts_with("invalid value for paths"));
+    }
+
+    #[actix_rt::test]
+    async fn test_describe() {
+        let config = RateLimitConfig {
+            max_requests: 100,
+            interval: 60,
+            algorithm: Some(Algorithm::SlidingWindowCounter),
+            failure_mode: Some(FailureMode::FailOpen),
+            paths: vec![PathLimitConfig {
+                prefix: "/login".to_string(),
+                max_requests: 2,
+                interval: 60,
+            }],
+        };
+        let description = config
+            .build(MemoryStoreActor::from(MemoryStore::new()).start())
+            .unwrap()
+            .with_token_scale(1000)
+            .describe();
+        assert_eq!(description.max_requests, 100);
+        assert_eq!(description.paths, config.paths);
+        assert_eq!(description.features, vec!["token_scale"]);
+        assert!(description.store.ends_with("MemoryStoreActor"));
+        #[cfg(feature = "config")]
+        {
+            let json = serde_json::to_value(&description).unwrap();
+            assert_eq!(json["algorithm"], "sliding_window_counter");
+            assert_eq!(json["failure_mode"], "fail_open");
+            assert_eq!(json["paths"][0]["prefix"], "/login");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_describe_every_option() {
+        use crate::adaptive::AdaptiveLimit;
+        use crate::concurrency::ConcurrencyLimit;
+        use crate::middleware::{
+            LimiterSwitch, LogKey, Normalization, OversizedKey, Policy, RemainingHeader,
+            SharedLimit, ShedThreshold, TraceSource,
+        };
+        use crate::prefilter::PreFilter;
+        use actix_web::http::{HeaderName, Method};
+
+        let store = MemoryStore::new();
+        let limiter = RateLimiter::memory(&store, 100, Duration::from_secs(60));
+        assert!(limiter.describe().features.is_empty());
+
+        let addr = MemoryStoreActor::from(store).start();
+        let limit = Limit {
+            max_requests: 10,
+            interval: Duration::from_secs(60),
+        };
+        let description = limiter
+            .with_async_identifier(|_| Box::pin(async { Ok("client".to_string()) }))
+            .with_previous_identifier(|_| Ok("client".to_string()))
+            .with_missing_identifier(Policy::Allow)
+            .with_hashed_span_keys(true)
+            .with_key_normalization(Normalization::Lowercase)
+            .with_max_key_length(64)
+            .with_oversized_key(OversizedKey::Reject)
+            .with_monitor_only(true)
+            .with_switch(LimiterSwitch::new(true), false)
+            .with_over_limit_grace(1)
+            .with_recovery_grace(Duration::from_secs(60), 0.5)
+            .with_throttle_delay(Duration::from_millis(100))
+            .with_error_limit(
+                |status| status.is_client_error(),
+                10,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            )
+            .with_distinct_limit(|_| None, 10)
+            .with_backoff_penalty(1, Duration::from_secs(1))
+            .with_adaptive_limit(AdaptiveLimit::new(Duration::from_millis(200)))
+            .with_warmup(Duration::from_secs(60), 0.5)
+            .with_shed_threshold(ShedThreshold::QueueDepth(100))
+            .with_remaining_header(RemainingHeader::Requests { typical_cost: 2 })
+            .with_used_header(true)
+            .with_algorithm_header(true)
+            .with_header_prefix("ratelimit")
+            .with_retry_after_jitter(Duration::from_secs(1))
+            .with_headers_on_errors(false)
+            .with_token_scale(1000)
+            .with_rejection_log(Level::Warn, LogKey::Hashed)
+            .with_rejection_log_path(true)
+            .with_trace_id(TraceSource::Header(HeaderName::from_static("x-request-id")))
+            .with_store_error_handler(|_, _| HttpResponse::ServiceUnavailable().finish())
+            .with_sliding_expiry(true)
+            .with_store_clock(true)
+            .with_prefilter(PreFilter::new(64))
+            .with_boosts(true)
+            .with_idempotency_key(HeaderName::from_static("idempotency-key"))
+            .with_content_length_cost(1)
+            .with_min_cost(2)
+            .with_cache_hit_refund(0.5)
+            .with_store("eu", addr.clone())
+            .with_store_selector(|_| "eu")
+            .with_shard_selector(|_| 0)
+            .with_observer(|_| {})
+            .with_soft_limit(0.8)
+            .with_shared_limit(SharedLimit::new(limit))
+            .with_scope_limit("/admin", limit)
+            .with_peek_methods(vec![Method::HEAD])
+            .with_allowlist(|_| false)
+            .with_content_type_filter(|_| true)
+            .with_should_limit(|_| true)
+            .with_concurrency_limit(ConcurrencyLimit::new(2))
+            .describe();
+        assert_eq!(
+            description.features,
+            vec![
+                "async_identifier",
+                "previous_identifier",
+                "missing_identifier",
+                "hashed_span_keys",
+                "key_normalization",
+                "max_key_length",
+                "oversized_key",
+                "monitor_only",
+                "switch",
+                "over_limit_grace",
+                "recovery_grace",
+                "throttle_delay",
+                "error_limit",
+                "distinct_limit",
+                "backoff_penalty",
+                "adaptive_limit",
+                "warmup",
+                "shed_threshold",
+                "remaining_header",
+                "used_header",
+                "algorithm_header",
+                "header_prefix",
+                "retry_after_jitter",
+                "headers_on_errors",
+                "token_scale",
+                "rejection_log",
+                "rejection_log_path",
+                "trace_id",
+                "store_error_handler",
+                "sliding_expiry",
+                "store_clock",
+                "prefilter",
+                "boosts",
+                "idempotency_key",
+                "content_length_cost",
+                "min_cost",
+                "cache_hit_refund",
+                "store",
+                "store_selector",
+                "shard_selector",
+                "observer",
+                "soft_limit",
+                "shared_limit",
+                "scope_limit",
+                "peek_methods",
+                "allowlist",
+                "content_type_filter",
+                "should_limit",
+                "concurrency_limit",
+            ]
+        );
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_deserialize() {
+        let config: RateLimitConfig = serde_json::from_str(
+            r#"{
+                "max_requests": 100,
+                "interval": 60,
+                "algorithm": { "calendar_window": { "unit": "hour" } },
+                "paths": [{ "prefix": "/login", "max_requests": 5, "interval": 60 }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.algorithm,
+            Some(Algorithm::CalendarWindow {
+                unit: crate::middleware::CalendarUnit::Hour
+            })
+        );
+        assert_eq!(config.failure_mode, None);
+        assert_eq!(config.paths[0].max_requests, 5);
+        let config: RateLimitConfig = serde_json::from_str(
+            r#"{ "max_requests": 1, "interval": 1, "algorithm": "sliding_window_counter" }"#,
+        )
+        .unwrap();
+        assert_eq!(config.algorithm, Some(Algorithm::SlidingWindowCounter));
+        assert!(config.paths.is_empty());
+    }
+}
@@ -1,39 +1,169 @@
 //! Errors that can occur during middleware processing stage
-use actix_web::error::Error as AWError;
-use actix_web::web::HttpResponse;
-use failure::{self, Fail};
+use actix::{prelude::SendError, MailboxError};
+use actix_web::{dev::HttpResponseBuilder, http::StatusCode, HttpResponse, ResponseError};
 use log::*;
+use std::fmt;
 
 /// Custom error type. Useful for logging and debugging different kinds of errors.
-/// This type can be converted to Actix Error, which defaults to
+/// Implements [ResponseError](https://docs.rs/actix-web/3.3.2/actix_web/trait.ResponseError.html),
+/// so it can be returned from handlers and converted to Actix Error, which defaults to
 /// InternalServerError
 ///
-#[derive(Debug, Fail)]
+#[derive(Debug)]
 pub enum ARError {
     /// Store is not connected
-    #[fail(display = "store not connected")]
     NotConnected,
 
     /// Store is disconnected after initial successful connection
-    #[fail(display = "store disconnected")]
     Disconnected,
 
     /// Read/Write error on store
-    #[fail(display = "read/write operatiion failed: {}", _0)]
     ReadWriteError(String),
 
     /// Could be any kind of IO error
-    #[fail(display = "unknown error: {}", _0)]
     UnknownError(std::io::Error),
 
     /// Identifier error
-    #[fail(display = "client identification failed")]
     IdentificationError,
+
+    /// Store could not be reached, for instance because the connection was refused or dropped
+    StoreUnavailable,
+
+    /// Store did not answer in time
+    Timeout,
+
+    /// Store actor's mailbox is full, it can not take more messages for now. Converted from the
+    /// `SendError::Full` of `Addr::try_send`, `Addr::send` waits for room instead
+    MailboxFull,
+
+    /// Store answered with something which could not be understood
+    Protocol(String),
+
+    /// Any other failure of a store. Custom stores can use this for errors which do not fit
+    /// into one of the categories above
+    Other(String),
+}
+
+impl fmt::Display for ARError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ARError::NotConnected => write!(f, "store not connected"),
+            ARError::Disconnected => write!(f, "store disconnected"),
+            ARError::ReadWriteError(e) => write!(f, "read/write operatiion failed: {}", e),
+            ARError::UnknownError(e) => write!(f, "unknown error: {}", e),
+            ARError::IdentificationError => write!(f, "client identification failed"),
+            ARError::StoreUnavailable => write!(f, "store unavailable"),
+            ARError::Timeout => write!(f, "store operation timed out"),
+            ARError::MailboxFull => write!(f, "store mailbox full"),
+            ARError::Protocol(e) => write!(f, "unexpected store response: {}", e),
+            ARError::Other(e) => write!(f, "store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ARError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ARError::UnknownError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ResponseError for ARError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Details are only logged, they are of no use to the client
+        error!("{}", self);
+        HttpResponseBuilder::new(self.status_code()).finish()
+    }
+}
+
+/// Error returned when a [RateLimiterBuilder](../middleware/struct.RateLimiterBuilder.html) is
+/// not configured correctly
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A required field was never set
+    MissingField(&'static str),
+
+    /// A field was set to a value which can not work
+    InvalidValue(&'static str, String),
+
+    /// Two options were set which can not work together
+    Incompatible(&'static str, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingField(field) => write!(f, "missing required field: {}", field),
+            ConfigError::InvalidValue(field, e) => write!(f, "invalid value for {}: {}", field, e),
+            ConfigError::Incompatible(a, b) => write!(f, "{} can not be combined with {}", a, b),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ARError {
+    fn from(err: std::io::Error) -> ARError {
+        ARError::UnknownError(err)
+    }
 }
 
-impl From<ARError> for AWError {
-    fn from(err: ARError) -> AWError {
-        error!("{}", &err);
-        HttpResponse::InternalServerError().into()
+impl From<MailboxError> for ARError {
+    fn from(err: MailboxError) -> ARError {
+        match err {
+            MailboxError::Closed => ARError::Disconnected,
+            MailboxError::Timeout => ARError::Timeout,
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for ARError {
+    fn from(err: SendError<T>) -> ARError {
+        match err {
+            SendError::Full(_) => ARError::MailboxFull,
+            SendError::Closed(_) => ARError::Disconnected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::error::Error as AWError;
+
+    #[test]
+    fn test_response_error() {
+        let err = ARError::Protocol("bad reply".to_string());
+        assert_eq!(err.to_string(), "unexpected store response: bad reply");
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        let err: AWError = err.into();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        let err: Box<dyn std::error::Error> = Box::new(ARError::Timeout);
+        assert_eq!(err.to_string(), "store operation timed out");
+    }
+
+    #[test]
+    fn test_mailbox_error() {
+        assert!(matches!(
+            ARError::from(MailboxError::Timeout),
+            ARError::Timeout
+        ));
+        assert!(matches!(
+            ARError::from(MailboxError::Closed),
+            ARError::Disconnected
+        ));
+        assert!(matches!(
+            ARError::from(SendError::Full(())),
+            ARError::MailboxFull
+        ));
     }
 }
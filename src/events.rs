@@ -0,0 +1,57 @@
+//! Events emitted by the middleware and the stores
+//!
+//! Register an observer with [RateLimiter::with_observer](../middleware/struct.RateLimiter.html#method.with_observer)
+//! to receive them, for instance to feed metrics or alerting.
+use actix_web::http::Method;
+use std::time::Duration;
+
+use crate::errors::ARError;
+use crate::middleware::{DecisionReason, Limit};
+
+/// Represents an event of interest that happened while processing a request. New events may be
+/// added in minor releases, observers should ignore the ones they do not know.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RateLimitEvent<'a> {
+    /// The store failed to answer after previously answering (or before ever answering). Requests
+    /// are now handled according to the configured
+    /// [FailureMode](../middleware/enum.FailureMode.html) until the store recovers.
+    Degraded { error: &'a ARError },
+    /// The store answered again after having been failing for `degraded_for`
+    Recovered { degraded_for: Duration },
+    /// The store was saturated, and the request of the client identified by `key` was shed, see
+    /// [RateLimiter::with_shed_threshold](../middleware/struct.RateLimiter.html#method.with_shed_threshold)
+    Shed { key: &'a str },
+    /// The request of the client identified by `key` to `method` `path` was rejected, for being
+    /// over the limit or the concurrency limit. `trace_id` is the trace the request is part of,
+    /// when [RateLimiter::with_trace_id](../middleware/struct.RateLimiter.html#method.with_trace_id)
+    /// is set and the request has one.
+    Rejected {
+        key: &'a str,
+        method: &'a Method,
+        path: &'a str,
+        trace_id: Option<&'a str>,
+    },
+    /// The request of the client identified by `key` was let through, see
+    /// [DecisionReason](../middleware/enum.DecisionReason.html) for why
+    Allowed {
+        key: &'a str,
+        reason: DecisionReason,
+    },
+    /// The request of the client identified by `key` was counted against `limit` instead of the
+    /// `default` configured with _max_requests_ and _interval_, because of a
+    /// [path limit](../middleware/struct.RateLimiter.html#method.with_path_limit), the
+    /// [limit resolver](../middleware/struct.RateLimiter.html#method.with_limit_resolver) or a
+    /// [shared limit](../middleware/struct.RateLimiter.html#method.with_shared_limit), for
+    /// instance to keep an audit trail of which clients got which limits. It is emitted before
+    /// the request is decided.
+    LimitApplied {
+        key: &'a str,
+        limit: Limit,
+        default: Limit,
+    },
+    /// `key` was evicted from a memory store holding too many keys, see
+    /// [MemoryStore::with_max_keys](../struct.MemoryStore.html#method.with_max_keys). Emitted by
+    /// the store to its own observer, not by the middleware.
+    Evicted { key: &'a str },
+}
@@ -0,0 +1,175 @@
+//! Limiting from handlers and extractors, for applications structured around guards rather than
+//! middleware
+use actix::dev::{Handler, ToEnvelope};
+use actix::prelude::*;
+use actix_web::{error::Error as AWError, web::Data, FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::errors::ARError;
+use crate::limiter::{Decision, Limiter};
+use crate::middleware::{limit_header, Algorithm, Limit, RateLimitInfo};
+use crate::ActorMessage;
+
+type Key = Rc<Box<dyn Fn(&HttpRequest) -> Result<String, ARError>>>;
+
+/// The check of [RateLimiter](../middleware/struct.RateLimiter.html), on its own, to limit
+/// requests from a handler or an extractor instead of a middleware.
+///
+/// actix guards are synchronous and can not wait for the store, the guard is rather registered
+/// as app data and run by the [RateLimited](struct.RateLimited.html) extractor, or called
+/// directly with [check](#method.check). Like the middleware, it uses the store for its
+/// counters, so both can share a store as long as their keys do not overlap. It is a
+/// [Limiter](../limiter/struct.Limiter.html) keyed on requests.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_ratelimit::guard::{RateLimitGuard, RateLimited};
+/// use actix_ratelimit::middleware::Limit;
+/// use actix_ratelimit::{MemoryStore, MemoryStoreActor};
+///
+/// async fn search(_: RateLimited<MemoryStoreActor>) -> HttpResponse {
+///     HttpResponse::Ok().finish()
+/// }
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let guard = RateLimitGuard::new(
+///         MemoryStoreActor::from(store).start(),
+///         |req| {
+///             req.peer_addr()
+///                 .map(|addr| format!("search:{}", addr.ip()))
+///                 .ok_or(actix_ratelimit::errors::ARError::IdentificationError)
+///         },
+///         Limit { max_requests: 10, interval: Duration::from_secs(60) },
+///     );
+///     let app = App::new()
+///         .data(guard)
+///         .route("/search", web::get().to(search));
+/// }
+/// ```
+pub struct RateLimitGuard<T>
+where
+    T: Handler<ActorMessage> + 'static,
+{
+    limiter: Limiter<T>,
+    key: Key,
+}
+
+impl<T> RateLimitGuard<T>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Allows `limit` for each key `key` returns, counted in `store`
+    pub fn new<F>(store: Addr<T>, key: F, limit: Limit) -> Self
+    where
+        F: Fn(&HttpRequest) -> Result<String, ARError> + 'static,
+    {
+        RateLimitGuard {
+            limiter: Limiter::new(store, limit),
+            key: Rc::new(Box::new(key)),
+        }
+    }
+
+    /// Specify how requests are counted. Defaults to
+    /// [Algorithm::FixedWindow](../middleware/enum.Algorithm.html).
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.limiter = self.limiter.with_algorithm(algorithm);
+        self
+    }
+
+    /// Counts `req`, and returns where its client stands. Requests over the limit get an error
+    /// which renders as 429 Too Many Requests with the ratelimit headers, and store failures an
+    /// error which renders as 500 Internal Server Error. The returned info is also stored in the
+    /// request extensions, for the [RateLimitInfo](../middleware/struct.RateLimitInfo.html)
+    /// extractor.
+    pub async fn check(&self, req: &HttpRequest) -> Result<RateLimitInfo, AWError> {
+        let key = (self.key)(req)?;
+        let max_requests = self.limiter.limit().max_requests;
+        match self.limiter.try_acquire(&key).await? {
+            Decision::Allowed { remaining, reset } => {
+                let info = RateLimitInfo {
+                    limit: max_requests,
+                    remaining,
+                    reset,
+                    exceeded: false,
+                };
+                req.extensions_mut().insert(info);
+                Ok(info)
+            }
+            Decision::Rejected { reset } => {
+                let mut response = HttpResponse::TooManyRequests();
+                response.set_header("x-ratelimit-limit", limit_header(max_requests));
+                response.set_header("x-ratelimit-remaining", "0");
+                response.set_header("x-ratelimit-reset", reset.as_secs().to_string());
+                Err(response.into())
+            }
+        }
+    }
+}
+
+/// Extractor running the [RateLimitGuard](struct.RateLimitGuard.html) registered as app data
+/// with the store actor `T`, before the handler. It fails with 500 Internal Server Error when
+/// no such guard is registered.
+pub struct RateLimited<T>(pub RateLimitInfo, PhantomData<T>);
+
+impl<T> FromRequest for RateLimited<T>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    type Error = AWError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        let guard = req.app_data::<Data<RateLimitGuard<T>>>().cloned();
+        Box::pin(async move {
+            let guard = guard.ok_or_else(|| {
+                actix_web::error::ErrorInternalServerError("no rate limit guard is registered")
+            })?;
+            let info = guard.check(&req).await?;
+            Ok(RateLimited(info, PhantomData))
+        })
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use super::*;
+    use crate::{MemoryStore, MemoryStoreActor};
+    use actix_web::{dev::Service, test, web, App};
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_guard() {
+        let guard = RateLimitGuard::new(
+            MemoryStoreActor::from(MemoryStore::new()).start(),
+            |req| Ok(req.path().to_string()),
+            Limit {
+                max_requests: 1,
+                interval: Duration::from_secs(60),
+            },
+        );
+        let mut app = test::init_service(App::new().data(guard).route(
+            "/",
+            web::get().to(|limited: RateLimited<MemoryStoreActor>| {
+                HttpResponse::Ok().body(limited.0.remaining.to_string())
+            }),
+        ))
+        .await;
+        let res = test::call_service(&mut app, test::TestRequest::default().to_request()).await;
+        assert_eq!(test::read_body(res).await, "0");
+        let res = app.call(test::TestRequest::default().to_request()).await;
+        match res {
+            Ok(res) => assert_eq!(res.status(), 429),
+            Err(e) => assert_eq!(e.as_response_error().status_code(), 429),
+        }
+    }
+}
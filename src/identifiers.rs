@@ -0,0 +1,914 @@
+//! Functions to identify the client of a request
+//!
+//! Any of these can be passed to [RateLimiter::with_identifier](../middleware/struct.RateLimiter.html#method.with_identifier),
+//! but [asn](fn.asn.html), which is async, goes to
+//! [RateLimiter::with_async_identifier](../middleware/struct.RateLimiter.html#method.with_async_identifier).
+//!
+//! # Example
+//! ```rust
+//! # use std::time::Duration;
+//! use actix_ratelimit::{identifiers, MemoryStore, MemoryStoreActor, RateLimiter};
+//!
+//! #[actix_rt::main]
+//! async fn main() {
+//!     let store = MemoryStore::new();
+//!     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store).start())
+//!         .with_interval(Duration::from_secs(60))
+//!         .with_max_requests(100)
+//!         .with_identifier(identifiers::real_ip);
+//! }
+//! ```
+//!
+//! # HTTP/2
+//!
+//! An HTTP/2 connection carries many requests, and proxies or CDNs in front of the application
+//! multiplex the requests of many of their clients over the same few connections. Identifiers
+//! built on the connection, such as the default [remote_addr](fn.remote_addr.html), then put all
+//! these clients in one bucket, and the limiter logs a warning the first time it identifies an
+//! HTTP/2 request that way. Prefer an identity carried by the request itself, the user set by an
+//! authentication middleware or a header set by the proxy, and only fall back to the address:
+//! ```rust
+//! # use std::time::Duration;
+//! use actix_web::http::HeaderName;
+//! use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+//!
+//! struct User {
+//!     id: u64,
+//! }
+//!
+//! #[actix_rt::main]
+//! async fn main() {
+//!     let store = MemoryStore::new();
+//!     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+//!         .with_identifier(identifiers::first_of(vec![
+//!             Box::new(identifiers::from_extension(|user: &User| format!("user:{}", user.id))),
+//!             Box::new(identifiers::from_header(HeaderName::from_static("x-client-id"))),
+//!             Box::new(identifiers::real_ip),
+//!         ]));
+//! }
+//! ```
+use actix_web::{
+    dev::ServiceRequest,
+    http::{HeaderName, Version},
+    HttpMessage,
+};
+use futures::future::LocalBoxFuture;
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::errors::ARError;
+
+/// A boxed identifier, one of the parts given to [composite](fn.composite.html).
+pub type Identifier = Box<dyn Fn(&ServiceRequest) -> Result<String, ARError>>;
+
+/// Identifies the client by the address of the socket the request came from. This is the
+/// default identifier.
+///
+/// All the requests of an HTTP/2 connection share the address, even when a proxy multiplexes
+/// several clients over it, see [HTTP/2](index.html#http2).
+pub fn remote_addr(req: &ServiceRequest) -> Result<String, ARError> {
+    let connection_info = req.connection_info();
+    let ip = connection_info
+        .remote_addr()
+        .ok_or(ARError::IdentificationError)?;
+    Ok(String::from(ip))
+}
+
+// Whether the default identifier has seen an HTTP/2 request yet
+static HTTP2_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Default identifier of the limiter, which warns once that it may put the clients multiplexed
+// over an HTTP/2 connection in one bucket
+pub(crate) fn default(req: &ServiceRequest) -> Result<String, ARError> {
+    if req.version() == Version::HTTP_2 && !HTTP2_WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "HTTP/2 requests are identified by the address of their connection, which clients \
+             behind a proxy may share, see RateLimiter::with_identifier"
+        );
+    }
+    remote_addr(req)
+}
+
+/// Identifies the client by the IP address of the socket the request came from, without the
+/// port, so that all the connections of a client share the same limit. Unlike
+/// [real_ip](fn.real_ip.html), it ignores the headers set by proxies and clients.
+pub fn peer_ip(req: &ServiceRequest) -> Result<String, ARError> {
+    req.peer_addr()
+        .map(|socket| socket.ip().to_string())
+        .ok_or(ARError::IdentificationError)
+}
+
+/// Identifies the client by its IP address as resolved by actix-web from the `Forwarded` and
+/// `X-Forwarded-For` headers, falling back to the address of the peer when the headers are
+/// absent.
+///
+/// Use this when running behind a reverse proxy, otherwise every client is identified by the
+/// address of the proxy. Since these headers are set by the client, they can be spoofed unless
+/// the proxy overwrites them.
+///
+/// The port is stripped when the address is taken from the socket, so that all the connections
+/// of a client share the same limit.
+pub fn real_ip(req: &ServiceRequest) -> Result<String, ARError> {
+    let connection_info = req.connection_info();
+    match connection_info.realip_remote_addr() {
+        Some(addr) => match addr.parse::<SocketAddr>() {
+            Ok(socket) => Ok(socket.ip().to_string()),
+            Err(_) => Ok(String::from(addr)),
+        },
+        None => req
+            .peer_addr()
+            .map(|socket| socket.ip().to_string())
+            .ok_or(ARError::IdentificationError),
+    }
+}
+
+/// DER encoded certificate presented by the client of a mutually authenticated TLS connection.
+///
+/// actix-web does not expose the TLS session to middlewares, so it has to be copied from the
+/// connection into the request extensions with `HttpServer::on_connect`, for instance with
+/// rustls:
+/// ```rust,ignore
+/// use actix_ratelimit::identifiers::PeerCertificate;
+/// use actix_tls::rustls::{Session, TlsStream};
+/// use actix_web::rt::net::TcpStream;
+///
+/// HttpServer::new(app)
+///     .on_connect(|connection, extensions| {
+///         if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+///             let (_, session) = tls.get_ref();
+///             if let Some(certificate) = session.get_peer_certificates().and_then(|c| c.into_iter().next()) {
+///                 extensions.insert(PeerCertificate(certificate.0));
+///             }
+///         }
+///     })
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerCertificate(pub Vec<u8>);
+
+/// Identifies the client by the SHA-256 fingerprint of its TLS certificate, see
+/// [PeerCertificate](struct.PeerCertificate.html) to make it available. This is the natural
+/// identity in mutually authenticated service meshes.
+///
+/// Requests without a certificate can not be identified, and are handled by
+/// [RateLimiter::with_missing_identifier](../middleware/struct.RateLimiter.html#method.with_missing_identifier).
+/// To fall back to the address of the client instead:
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(|req| {
+///             identifiers::client_certificate(req).or_else(|_| identifiers::real_ip(req))
+///         });
+/// }
+/// ```
+pub fn client_certificate(req: &ServiceRequest) -> Result<String, ARError> {
+    let extensions = req.extensions();
+    let certificate = extensions
+        .get::<PeerCertificate>()
+        .ok_or(ARError::IdentificationError)?;
+    let fingerprint = Sha256::digest(&certificate.0);
+    let fingerprint: String = fingerprint.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("cert:{}", fingerprint))
+}
+
+/// Identifies the client by the identity an authentication middleware stored in the request
+/// extensions, for instance `|user: &User| format!("user:{}", user.id)`. It fails for requests
+/// the authentication did not identify, which are handled according to
+/// [with_missing_identifier](../middleware/struct.RateLimiter.html#method.with_missing_identifier).
+///
+/// The identity is only there when the limiter runs after the authentication, that is when it
+/// is registered before it with `wrap`, since the last middleware registered runs first. See
+/// the crate documentation for limiting both before and after authentication.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// struct User {
+///     id: u64,
+/// }
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 1000, Duration::from_secs(60))
+///         .with_identifier(identifiers::from_extension(|user: &User| format!("user:{}", user.id)));
+/// }
+/// ```
+pub fn from_extension<E, F>(key: F) -> impl Fn(&ServiceRequest) -> Result<String, ARError>
+where
+    E: 'static,
+    F: Fn(&E) -> String,
+{
+    move |req| {
+        req.extensions()
+            .get::<E>()
+            .map(&key)
+            .ok_or(ARError::IdentificationError)
+    }
+}
+
+/// Identifies the client by the value of the header `name`, for instance a client id set by
+/// the proxy in front of the application, as `{name}:{value}`. It fails for requests without
+/// the header, or with an empty value or one which is not visible ASCII.
+///
+/// Like any header, it can be set by the client unless the proxy overwrites it.
+pub fn from_header(name: HeaderName) -> impl Fn(&ServiceRequest) -> Result<String, ARError> {
+    move |req| {
+        req.headers()
+            .get(&name)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .map(|value| format!("{}:{}", name, value))
+            .ok_or(ARError::IdentificationError)
+    }
+}
+
+/// Identifies the client by the first of `identifiers` which succeeds, in order, for instance
+/// the authenticated user, then the address for anonymous requests. It fails when all of them
+/// fail.
+///
+/// The identifiers should give keys which can not collide, such as the `user:` prefixed keys
+/// of an authentication middleware and addresses.
+pub fn first_of(
+    identifiers: Vec<Identifier>,
+) -> impl Fn(&ServiceRequest) -> Result<String, ARError> {
+    move |req| {
+        identifiers
+            .iter()
+            .find_map(|identifier| (identifier)(req).ok())
+            .ok_or(ARError::IdentificationError)
+    }
+}
+
+/// Identifies the client by the autonomous system its address belongs to, so that a network
+/// rotating its addresses, such as a hosting provider, shares one bucket. The address is the
+/// key of `ip`, for instance [real_ip](fn.real_ip.html), and `lookup` finds its AS number, from
+/// a GeoIP/ASN database for instance. The key is `asn:` followed by the number, or the address
+/// itself when it is not found, so that unknown networks are still limited per address.
+///
+/// The lookup is async, and this is to be given to
+/// [RateLimiter::with_async_identifier](../middleware/struct.RateLimiter.html#method.with_async_identifier).
+/// It runs for every request, before the store, and adds its latency to all of them: a local
+/// database read in memory, such as an mmdb file, is best, and lookups over the network should
+/// be cached by address, with an expiry, in the `lookup` function. A failed lookup should
+/// return `None` rather than wait, the request then being limited by its address.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use std::collections::HashMap;
+/// use std::net::IpAddr;
+/// use std::sync::Arc;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     // Stands for a database loaded at startup
+///     let database: Arc<HashMap<IpAddr, u32>> = Arc::new(HashMap::new());
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 1000, Duration::from_secs(60))
+///         .with_async_identifier(identifiers::asn(identifiers::real_ip, move |ip| {
+///             let asn = database.get(&ip).copied();
+///             async move { asn }
+///         }));
+/// }
+/// ```
+pub fn asn<I, L, F>(
+    ip: I,
+    lookup: L,
+) -> impl Fn(&ServiceRequest) -> LocalBoxFuture<'static, Result<String, ARError>>
+where
+    I: Fn(&ServiceRequest) -> Result<String, ARError>,
+    L: Fn(IpAddr) -> F,
+    F: Future<Output = Option<u32>> + 'static,
+{
+    move |req| {
+        let key = match (ip)(req) {
+            Ok(key) => key,
+            Err(e) => return Box::pin(futures::future::err(e)),
+        };
+        let lookup = key.parse::<IpAddr>().ok().map(&lookup);
+        Box::pin(async move {
+            match lookup {
+                Some(lookup) => Ok(match lookup.await {
+                    Some(asn) => format!("asn:{}", asn),
+                    None => key,
+                }),
+                None => Ok(key),
+            }
+        })
+    }
+}
+
+/// Identifies the device of the client by a hash of the values of the `names` headers, for
+/// instance `User-Agent` and `Accept-Language`, to tell apart clients sharing an address behind
+/// a NAT. The key is `device:` followed by the hex SHA-256 of the values, which only depends on
+/// the values and their order in `names`. A missing header hashes differently from an empty
+/// one, and all the clients without any of the headers share one bucket.
+///
+/// Fingerprints are only a heuristic: clients choose their headers, so a client can change
+/// them to get a new bucket with every request, and unrelated clients with common browsers
+/// share one. Combine them with a limit per address rather than relying on them alone.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_web::http::header;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(identifiers::from_headers_hash(&[
+///             header::USER_AGENT,
+///             header::ACCEPT_LANGUAGE,
+///         ]));
+/// }
+/// ```
+pub fn from_headers_hash(
+    names: &[HeaderName],
+) -> impl Fn(&ServiceRequest) -> Result<String, ARError> {
+    let names = names.to_vec();
+    move |req| {
+        let mut hasher = Sha256::new();
+        for name in &names {
+            let values: Vec<&[u8]> = req
+                .headers()
+                .get_all(name)
+                .map(|value| value.as_bytes())
+                .collect();
+            if values.is_empty() {
+                hasher.update([0]);
+                continue;
+            }
+            // Length prefixed so that values can not run into each other
+            hasher.update([1]);
+            hasher.update((values.len() as u64).to_be_bytes());
+            for value in values {
+                hasher.update((value.len() as u64).to_be_bytes());
+                hasher.update(value);
+            }
+        }
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(format!("device:{}", hash))
+    }
+}
+
+/// Wraps `identifier` so that several credentials share one bucket, for instance all the API
+/// keys of an account. `group` maps what `identifier` returns to the key of its group, and
+/// credentials without a group keep a bucket of their own.
+///
+/// Since all members of a group are counted under the same key, the `x-ratelimit-remaining`
+/// and `x-ratelimit-reset` headers of every member reflect the shared pool. Group keys are
+/// prefixed with `group:` and the credentials without a group with `raw:`, so that a
+/// credential can not collide with a group, even one sent as `group:acme`.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use std::collections::HashMap;
+/// use actix_ratelimit::{errors::ARError, identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let accounts: HashMap<String, String> = vec![
+///         ("key-1".to_string(), "acme".to_string()),
+///         ("key-2".to_string(), "acme".to_string()),
+///     ]
+///     .into_iter()
+///     .collect();
+///     let api_key = |req: &actix_web::dev::ServiceRequest| {
+///         req.headers()
+///             .get("x-api-key")
+///             .and_then(|key| key.to_str().ok())
+///             .map(String::from)
+///             .ok_or(ARError::IdentificationError)
+///     };
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(identifiers::grouped(api_key, move |key| accounts.get(key).cloned()));
+/// }
+/// ```
+pub fn grouped<I, G>(identifier: I, group: G) -> impl Fn(&ServiceRequest) -> Result<String, ARError>
+where
+    I: Fn(&ServiceRequest) -> Result<String, ARError>,
+    G: Fn(&str) -> Option<String>,
+{
+    move |req| {
+        let credential = (identifier)(req)?;
+        match (group)(&credential) {
+            Some(group) => Ok(format!("group:{}", group)),
+            None => Ok(format!("raw:{}", credential)),
+        }
+    }
+}
+
+/// Wraps `identifier` so that the caller gets a bucket for each value of the path parameters
+/// `params`, for instance a limit per caller and target user on `/users/{id}/posts`, against
+/// enumeration.
+///
+/// Path parameters are only known once the request is routed: a limiter wrapping the `App` or
+/// a `Scope` runs before the `{id}` of a resource is matched, and only a limiter wrapping the
+/// resource itself (`web::resource("/users/{id}/posts").wrap(limiter)`) sees it. When a
+/// parameter is not matched, the whole path is used instead, so the caller still gets a
+/// bucket for each resource, just one per distinct path.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let app = App::new().service(
+///         web::resource("/users/{id}/posts")
+///             .wrap(
+///                 RateLimiter::memory(&store, 10, Duration::from_secs(60))
+///                     .with_identifier(identifiers::path_scoped(identifiers::real_ip, &["id"])),
+///             )
+///             .route(web::get().to(HttpResponse::Ok)),
+///     );
+/// }
+/// ```
+pub fn path_scoped<I>(
+    identifier: I,
+    params: &[&str],
+) -> impl Fn(&ServiceRequest) -> Result<String, ARError>
+where
+    I: Fn(&ServiceRequest) -> Result<String, ARError>,
+{
+    let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+    move |req| {
+        let caller = (identifier)(req)?;
+        let mut parts = vec![caller];
+        for param in &params {
+            match req.match_info().get(param) {
+                Some(value) => parts.push(format!("{}={}", param, value)),
+                None => {
+                    parts.truncate(1);
+                    parts.push(format!("path={}", req.path()));
+                    break;
+                }
+            }
+        }
+        Ok(join(&parts, ':'))
+    }
+}
+
+/// Wraps `identifier` so that the caller gets a bucket for each endpoint it calls, the method and
+/// the route pattern of the request, for instance `GET:/users/{id}`. Keying on the pattern
+/// rather than the path keeps a single bucket for `/users/1`, `/users/2` and so on, instead of
+/// one per value of the path parameters. Requests matching no route share the `unmatched`
+/// endpoint, for the same reason.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(identifiers::endpoint(identifiers::peer_ip));
+/// }
+/// ```
+pub fn endpoint<I>(identifier: I) -> impl Fn(&ServiceRequest) -> Result<String, ARError>
+where
+    I: Fn(&ServiceRequest) -> Result<String, ARError>,
+{
+    move |req| {
+        let caller = (identifier)(req)?;
+        let pattern = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        Ok(join(
+            &[caller.as_str(), req.method().as_str(), &pattern],
+            ':',
+        ))
+    }
+}
+
+/// Wraps `identifier` so that the caller gets a bucket for each gRPC method it calls, for
+/// instance `/package.Service/Method`. Requests which are not gRPC calls, see
+/// [predicates::is_grpc](../predicates/fn.is_grpc.html), are keyed on the caller alone.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(identifiers::grpc_method(identifiers::real_ip));
+/// }
+/// ```
+pub fn grpc_method<I>(identifier: I) -> impl Fn(&ServiceRequest) -> Result<String, ARError>
+where
+    I: Fn(&ServiceRequest) -> Result<String, ARError>,
+{
+    move |req| {
+        let caller = (identifier)(req)?;
+        if crate::predicates::is_grpc(req) {
+            Ok(join(&[caller.as_str(), req.path()], ':'))
+        } else {
+            Ok(caller)
+        }
+    }
+}
+
+/// Combines several identifiers into one key, for instance the address of the client and the
+/// value of a header. See [join](fn.join.html) for how the parts are separated. The request
+/// can not be identified when one of the parts fails.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{errors::ARError, identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let tenant = |req: &actix_web::dev::ServiceRequest| {
+///         req.headers()
+///             .get("x-tenant")
+///             .and_then(|tenant| tenant.to_str().ok())
+///             .map(String::from)
+///             .ok_or(ARError::IdentificationError)
+///     };
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(identifiers::composite(
+///             vec![Box::new(identifiers::real_ip), Box::new(tenant)],
+///             ':',
+///         ));
+/// }
+/// ```
+pub fn composite(
+    parts: Vec<Identifier>,
+    separator: char,
+) -> impl Fn(&ServiceRequest) -> Result<String, ARError> {
+    move |req| {
+        let parts = parts
+            .iter()
+            .map(|part| (part)(req))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(join(&parts, separator))
+    }
+}
+
+/// Joins `parts` with `separator` so that different parts never give the same key, even when
+/// they come from the client. `%` and the separator are percent-encoded in each part, so
+/// `["1.2.3.4", "/a:b"]` and `["1.2.3.4:/a", "b"]` give `1.2.3.4:/a%3Ab` and `1.2.3.4%3A/a:b`.
+pub fn join<S: AsRef<str>>(parts: &[S], separator: char) -> String {
+    let mut key = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            key.push(separator);
+        }
+        for c in part.as_ref().chars() {
+            if c == '%' || c == separator {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    key.push_str(&format!("%{:02X}", byte));
+                }
+            } else {
+                key.push(c);
+            }
+        }
+    }
+    key
+}
+
+/// Identifies the client by the claim `claim`, typically `sub`, of the JWT in the header
+/// `header`, with or without the `Bearer` scheme. Requires the `jwt` feature.
+///
+/// **The signature of the token is not verified.** Anyone can craft a token with any claim, and
+/// either spread their requests over many buckets or exhaust the quota of someone else. The
+/// token has to be verified by an authentication middleware running before the limiter, that
+/// is wrapped after it: `App::new().wrap(limiter).wrap(auth)`.
+///
+/// Keys are `{claim}:{value}`. Requests without a token, or with a malformed one, are
+/// identified by [real_ip](fn.real_ip.html) instead.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_identifier(identifiers::from_jwt_claim("authorization", "sub"));
+/// }
+/// ```
+#[cfg(feature = "jwt")]
+pub fn from_jwt_claim(
+    header: &'static str,
+    claim: &'static str,
+) -> impl Fn(&ServiceRequest) -> Result<String, ARError> {
+    move |req| match jwt_claim(req, header, claim) {
+        Some(value) => Ok(format!("{}:{}", claim, value)),
+        None => real_ip(req),
+    }
+}
+
+#[cfg(feature = "jwt")]
+fn jwt_claim(req: &ServiceRequest, header: &str, claim: &str) -> Option<String> {
+    let token = req.headers().get(header)?.to_str().ok()?.trim();
+    let token = match token.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        _ => token,
+    };
+    let payload = token.split('.').nth(1)?;
+    let payload =
+        base64::decode_config(payload.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    match claims.get(claim)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Stable 64 bit FNV-1a hash, so that hashed keys are the same across processes
+pub(crate) fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_real_ip() {
+        let req = TestRequest::default()
+            .header("x-forwarded-for", "10.0.0.1, 10.0.0.2")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(real_ip(&req).unwrap(), "10.0.0.1");
+
+        let req = TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(real_ip(&req).unwrap(), "127.0.0.1");
+
+        let req = TestRequest::default().to_srv_request();
+        assert!(real_ip(&req).is_err());
+    }
+
+    #[test]
+    fn test_grouped() {
+        let identifier = grouped(
+            |req: &ServiceRequest| {
+                req.headers()
+                    .get("x-api-key")
+                    .map(|key| key.to_str().unwrap().to_string())
+                    .ok_or(ARError::IdentificationError)
+            },
+            |key| match key {
+                "key-1" | "key-2" => Some("acme".to_string()),
+                _ => None,
+            },
+        );
+        let request = |key| {
+            TestRequest::default()
+                .header("x-api-key", key)
+                .to_srv_request()
+        };
+        assert_eq!(identifier(&request("key-1")).unwrap(), "group:acme");
+        assert_eq!(identifier(&request("key-2")).unwrap(), "group:acme");
+        assert_eq!(identifier(&request("key-3")).unwrap(), "raw:key-3");
+        // A credential shaped like a group key does not share its bucket
+        assert_eq!(
+            identifier(&request("group:acme")).unwrap(),
+            "raw:group:acme"
+        );
+        assert!(identifier(&TestRequest::default().to_srv_request()).is_err());
+    }
+
+    #[test]
+    fn test_path_scoped() {
+        let identifier = path_scoped(|_: &ServiceRequest| Ok("caller".to_string()), &["id"]);
+        let req = TestRequest::with_uri("/users/42/posts").to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "caller:path=/users/42/posts");
+        let req = TestRequest::with_uri("/users/42/posts")
+            .param("id", "42")
+            .to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "caller:id=42");
+    }
+
+    #[cfg(feature = "jwt")]
+    #[test]
+    fn test_from_jwt_claim() {
+        let identifier = from_jwt_claim("authorization", "sub");
+        let token = |payload: &str| {
+            let payload = base64::encode_config(payload, base64::URL_SAFE_NO_PAD);
+            format!("Bearer eyJhbGciOiJIUzI1NiJ9.{}.signature", payload)
+        };
+        let request = |value: &str| {
+            TestRequest::default()
+                .header("authorization", value)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_srv_request()
+        };
+        let req = request(&token(r#"{"sub":"alice","exp":1}"#));
+        assert_eq!(identifier(&req).unwrap(), "sub:alice");
+        let req = request(&token(r#"{"sub":42}"#));
+        assert_eq!(identifier(&req).unwrap(), "sub:42");
+        for malformed in &[
+            token(r#"{"exp":1}"#),
+            token("not json"),
+            "garbage".to_string(),
+        ] {
+            assert_eq!(identifier(&request(malformed)).unwrap(), "127.0.0.1");
+        }
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(join(&["1.2.3.4", "/a:b"], ':'), "1.2.3.4:/a%3Ab");
+        assert_eq!(join(&["1.2.3.4:/a", "b"], ':'), "1.2.3.4%3A/a:b");
+        // Adversarial parts, all distinct, give distinct keys
+        let cases: Vec<Vec<&str>> = vec![
+            vec!["a", "b:c"],
+            vec!["a:b", "c"],
+            vec!["a:b:c"],
+            vec!["a%3Ab", "c"],
+            vec!["a%", "3Ab:c"],
+            vec!["a", "", "b:c"],
+            vec!["a:", "b:c"],
+        ];
+        let keys: std::collections::HashSet<String> =
+            cases.iter().map(|parts| join(parts, ':')).collect();
+        assert_eq!(keys.len(), cases.len());
+        assert_eq!(join(&["a|b", "c"], '|'), "a%7Cb|c");
+        assert_eq!(join(&["a·b"], '·'), "a%C2%B7b");
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let identifier = endpoint(peer_ip);
+        // Matched patterns are covered by RateLimiter::per_client_per_endpoint
+        let req = TestRequest::with_uri("/users/42")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "127.0.0.1:GET:unmatched");
+    }
+
+    #[test]
+    fn test_grpc_method() {
+        let identifier = grpc_method(|_| Ok("caller".to_string()));
+        let req = TestRequest::with_uri("/helloworld.Greeter/SayHello")
+            .header("content-type", "application/grpc")
+            .to_srv_request();
+        assert_eq!(
+            identifier(&req).unwrap(),
+            "caller:/helloworld.Greeter/SayHello"
+        );
+        let req = TestRequest::with_uri("/helloworld.Greeter/SayHello").to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "caller");
+    }
+
+    #[test]
+    fn test_composite() {
+        let identifier = composite(
+            vec![
+                Box::new(|_: &ServiceRequest| Ok("::1".to_string())),
+                Box::new(|req: &ServiceRequest| Ok(req.path().to_string())),
+            ],
+            ':',
+        );
+        let req = TestRequest::with_uri("/a:b").to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "%3A%3A1:/a%3Ab");
+        let identifier = composite(vec![Box::new(real_ip)], ':');
+        assert!(identifier(&TestRequest::default().to_srv_request()).is_err());
+    }
+
+    #[test]
+    fn test_first_of() {
+        let identifier = first_of(vec![
+            Box::new(from_header(HeaderName::from_static("x-client-id"))),
+            Box::new(real_ip),
+        ]);
+        let req = TestRequest::default()
+            .header("x-client-id", "abc")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "x-client-id:abc");
+        let req = TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(identifier(&req).unwrap(), "127.0.0.1");
+        assert!(identifier(&TestRequest::default().to_srv_request()).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_asn() {
+        let identifier = asn(real_ip, |ip: IpAddr| {
+            let asn = if ip.to_string().starts_with("203.0.113.") {
+                Some(64496)
+            } else {
+                None
+            };
+            async move { asn }
+        });
+        let req = |addr: &str| {
+            TestRequest::default()
+                .peer_addr(addr.parse().unwrap())
+                .to_srv_request()
+        };
+        assert_eq!(
+            identifier(&req("203.0.113.7:80")).await.unwrap(),
+            "asn:64496"
+        );
+        assert_eq!(
+            identifier(&req("203.0.113.9:80")).await.unwrap(),
+            "asn:64496"
+        );
+        assert_eq!(identifier(&req("192.0.2.1:80")).await.unwrap(), "192.0.2.1");
+        assert!(identifier(&TestRequest::default().to_srv_request())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_from_extension() {
+        struct User(u64);
+        let identifier = from_extension(|user: &User| format!("user:{}", user.0));
+        let req = TestRequest::default().to_srv_request();
+        assert!(identifier(&req).is_err());
+        req.extensions_mut().insert(User(42));
+        assert_eq!(identifier(&req).unwrap(), "user:42");
+    }
+
+    #[test]
+    fn test_from_headers_hash() {
+        use actix_web::http::header;
+        let identifier = from_headers_hash(&[header::USER_AGENT, header::ACCEPT_LANGUAGE]);
+        let key = |headers: &[(&str, &str)]| {
+            let mut req = TestRequest::default();
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            identifier(&req.to_srv_request()).unwrap()
+        };
+        let device = key(&[("user-agent", "curl"), ("accept-language", "en")]);
+        assert!(device.starts_with("device:"));
+        assert_eq!(
+            device,
+            key(&[("accept-language", "en"), ("user-agent", "curl")])
+        );
+        let keys: std::collections::HashSet<String> = vec![
+            device,
+            key(&[("user-agent", "curl")]),
+            key(&[("user-agent", "curl"), ("accept-language", "")]),
+            key(&[("accept-language", "curl")]),
+            key(&[("user-agent", "curlen")]),
+            key(&[]),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(keys.len(), 6);
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        let req = TestRequest::default().to_srv_request();
+        assert!(client_certificate(&req).is_err());
+        let req = TestRequest::default().to_srv_request();
+        req.extensions_mut()
+            .insert(PeerCertificate(b"abc".to_vec()));
+        assert_eq!(
+            client_certificate(&req).unwrap(),
+            "cert:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -80,7 +80,7 @@
 //!
 //! ## Supported
 //! - In-memory (based on concurrent [hashmap](https://github.com/xacrimon/dashmap))
-//! - Redis (based on [redis-rs](https://github.com/mitsuhiko/redis-rs))
+//! - Redis (based on [redis-rs](https://github.com/mitsuhiko/redis-rs)), including Redis Cluster and Sentinel
 //!
 //! ## Planned
 //! - Memcached (not yet implemented)
@@ -100,12 +100,13 @@
 //! ```
 //!
 //! * By default, the client's IP address is used as the identifier which can be customized
-//! using [ServiceRequest](https://docs.rs/actix-web/3.3.2/actix_web/dev/struct.ServiceRequest.html) instance.
-//! For example, using api key header to identify client:
+//!   using [ServiceRequest](https://docs.rs/actix-web/3.3.2/actix_web/dev/struct.ServiceRequest.html) instance.
+//!   For example, using api key header to identify client:
 //! ```rust
 //! # #[cfg(feature = "default")] {
 //! # use std::time::Duration;
 //! # use actix_web::{web, App, HttpRequest, HttpServer, Responder};
+//! # use actix_ratelimit::errors::ARError;
 //! # use actix_ratelimit::{RateLimiter, MemoryStore, MemoryStoreActor};
 //! # async fn greet(req: HttpRequest) -> impl Responder{
 //! #     let name = req.match_info().get("name").unwrap_or("World!");
@@ -123,8 +124,10 @@
 //!                     .with_interval(Duration::from_secs(60))
 //!                     .with_max_requests(100)
 //!                     .with_identifier(|req| {
-//!                         let key = req.headers().get("x-api-key").unwrap();
-//!                         let key = key.to_str().unwrap();
+//!                         // Requests without a key are handled by `with_missing_identifier`
+//!                         let key = req.headers().get("x-api-key")
+//!                             .and_then(|key| key.to_str().ok())
+//!                             .ok_or(ARError::IdentificationError)?;
 //!                         Ok(key.to_string())
 //!                     })
 //!             )
@@ -139,8 +142,8 @@
 //! ```
 //!
 //! * It is **important** to initialize store before creating HttpServer instance, or else a store
-//! will be created for each web worker. This may lead to instability and inconsistency! For
-//! example, initializing your app in the following manner would create more than one stores:
+//!   will be created for each web worker. This may lead to instability and inconsistency! For
+//!   example, initializing your app in the following manner would create more than one stores:
 //! ```rust
 //! # #[cfg(feature = "default")] {
 //! # use std::time::Duration;
@@ -171,8 +174,84 @@
 //! ```
 //!
 //! * The exception is redis, where multiple connections will be
-//! created for each worker. Since redis store is based on Multiplexed connection, sharing once
-//! connection across multiple store actors should suffice for most use cases.
+//!   created for each worker. Since redis store is based on Multiplexed connection, sharing once
+//!   connection across multiple store actors should suffice for most use cases.
+//!
+//! * Where the limiter sits relative to authentication matters. Before it, the limiter protects
+//!   the authentication itself, from credential stuffing for instance, but only knows the
+//!   address of the client. After it, the limiter can key on the user, with
+//!   [identifiers::from_extension](identifiers/fn.from_extension.html), but abusive requests
+//!   are authenticated before being rejected. Both can be combined on the same store, with a
+//!   coarse limit per address and a finer one per user. Since the last middleware registered
+//!   with `wrap` runs first, the limiter running after the authentication is registered before
+//!   it. The keys of the two stages should not overlap, which prefixing the user identifier
+//!   ensures:
+//! ```rust
+//! # use std::time::Duration;
+//! use actix_web::{dev::Service, web, App, HttpMessage, HttpResponse};
+//! use actix_ratelimit::{identifiers, middleware::Policy, MemoryStore, RateLimiter};
+//!
+//! struct User {
+//!     id: u64,
+//! }
+//!
+//! #[actix_rt::main]
+//! async fn main() {
+//!     let store = MemoryStore::new();
+//!     let app = App::new()
+//!         // Second, once authenticated: 100 requests per user. Anonymous requests share a bucket
+//!         .wrap(
+//!             RateLimiter::memory(&store, 100, Duration::from_secs(60))
+//!                 .with_identifier(identifiers::from_extension(|user: &User| {
+//!                     format!("user:{}", user.id)
+//!                 }))
+//!                 .with_missing_identifier(Policy::SharedBucket("anonymous".to_string())),
+//!         )
+//!         // Authentication, between the two stages
+//!         .wrap_fn(|req, srv| {
+//!             if req.headers().contains_key("authorization") {
+//!                 req.extensions_mut().insert(User { id: 42 });
+//!             }
+//!             srv.call(req)
+//!         })
+//!         // First, before the authentication: 1000 requests per address
+//!         .wrap(
+//!             RateLimiter::memory(&store, 1000, Duration::from_secs(60))
+//!                 .with_identifier(identifiers::real_ip),
+//!         )
+//!         .route("/", web::get().to(HttpResponse::Ok));
+//! }
+//! ```
+//!   Responses rejected by either stage carry the headers of that stage, and allowed responses
+//!   those of the stage before the authentication, which sets them last.
+//!
+//! * WebSocket endpoints are limited on the handshake: the middleware sees the upgrade request
+//!   like any other `GET`, but not the messages exchanged once the connection is upgraded. See
+//!   [predicates::is_websocket_upgrade](predicates/fn.is_websocket_upgrade.html) to give new
+//!   connections a limit of their own.
+//!
+//! * gRPC calls, detected by their `Content-Type`, see
+//!   [predicates::is_grpc](predicates/fn.is_grpc.html), are limited like any other request, by
+//!   path for their method, but are rejected with the `grpc-status: 8` (`RESOURCE_EXHAUSTED`)
+//!   header that gRPC clients understand.
+//!
+//! * The optional `metrics` feature records the following through the
+//!   [metrics](https://docs.rs/metrics) facade, for whichever recorder the application installs:
+//!   - `ratelimit_requests_total`, a counter labelled with `result`, one of `allowed`,
+//!     `rejected` or `error` (the store failed)
+//!   - `ratelimit_store_errors_total`, a counter of failed store round trips
+//!   - `ratelimit_store_latency`, a histogram of the time spent on the store for each request,
+//!     in seconds
+//!
+//! * The optional `tracing` feature wraps the store round trips of every request in a
+//!   `ratelimit` span, see
+//!   [RateLimiter::with_hashed_span_keys](middleware/struct.RateLimiter.html#method.with_hashed_span_keys).
+//!   Spans are exported by whichever subscriber the application installs, OpenTelemetry
+//!   included through `tracing-opentelemetry`.
+//!
+//! * The optional `config` feature lets
+//!   [config::RateLimitConfig](config/struct.RateLimitConfig.html) be deserialized, so that the
+//!   limits of the application can be kept in a TOML or JSON file.
 //!
 //!
 //! # Status
@@ -184,11 +263,22 @@
 //! # LICENSE
 //! This project is licensed under MIT license.
 
+pub mod adaptive;
+pub mod concurrency;
+pub mod config;
 pub mod errors;
+pub mod events;
+pub mod guard;
+pub mod identifiers;
+pub mod limiter;
 pub mod middleware;
+pub mod predicates;
+pub mod prefilter;
+pub mod stats;
 pub mod stores;
+mod telemetry;
 use errors::ARError;
-pub use middleware::RateLimiter;
+pub use middleware::{RateLimitExt, RateLimiter};
 
 #[cfg(feature = "memory")]
 pub use stores::memory::{MemoryStore, MemoryStoreActor};
@@ -214,12 +304,99 @@ pub enum ActorMessage {
         value: usize,
         expiry: Duration,
     },
-    /// Change the value of count for the client identified by `key` by `value`
+    /// Change the value of count for the client identified by `key` by `value`, without going
+    /// below zero, and return the new count. Fails when the key does not exist.
     Update { key: String, value: usize },
+    /// Like `Update`, and also get the expiration time of the client, in a single round trip for
+    /// remote stores, so that the reset sent with a counted request is the one of the key it was
+    /// counted against. Redis runs the `DECRBY` and the `TTL` in one script.
+    Consume { key: String, value: usize },
     /// Get the expiration time for the client.
     Expire(String),
     /// Remove the client from the store
     Remove(String),
+    /// Atomically rotate the windows of the client identified by `key` for the sliding window
+    /// counter. The count of the current window lives under `key`, where `Get` and `Update`
+    /// work on it, and the count of the previous one under `{key}:previous`. On the first
+    /// rotation with a given `new_window_start`, the current count is moved to the previous one,
+    /// and `key` is set to `value`, both valid for `expiry`. Answers with the count of the
+    /// previous window, if any.
+    Rotate {
+        key: String,
+        new_window_start: u64,
+        value: usize,
+        expiry: Duration,
+    },
+    /// List up to `limit` clients whose key starts with `prefix`, with their remaining count and
+    /// the time until their reset. This walks the keys of the store, which is expensive on large
+    /// stores, so it is meant for admin tools rather than for every request. Stores may not
+    /// support it.
+    List { prefix: String, limit: usize },
+    /// Atomically set the count of the client identified by `key` to `new`, valid for `expiry`,
+    /// if its current count is `expected`, where `None` means that the key must not exist.
+    /// Answers with whether the count was set. This lets algorithms built on top of the store
+    /// read a count, compute the next one and retry when another request changed it in between,
+    /// instead of a racy read-modify-write.
+    ///
+    /// Stores implement it with whatever atomic primitive they have, a lock for the memory
+    /// store, a Lua script for redis, `gets` and `cas` for memcached. With redis, the same can be
+    /// done from a client with `WATCH key`, `GET key`, then `MULTI`, `SET key new EX expiry`,
+    /// `EXEC`, which aborts if the key was changed after the `WATCH`.
+    CompareAndSet {
+        key: String,
+        expected: Option<usize>,
+        new: usize,
+        expiry: Duration,
+    },
+    /// Check that the store is reachable, with a round trip to it for remote stores
+    Ping,
+    /// Current time of the store, since the Unix epoch, for the nodes sharing a store to agree
+    /// on when windows start rather than each go by its own clock. Stores without a clock of
+    /// their own, such as the memory store, answer with the local one.
+    Time,
+    /// Attach `metadata` to the client identified by `key`, valid for `expiry`, or remove it
+    /// when `None`. Metadata is opaque to the limiter, a note or a tag for admin tools, and does
+    /// not change how the client is limited. Stores may not support it.
+    SetMetadata {
+        key: String,
+        metadata: Option<String>,
+        expiry: Duration,
+    },
+    /// Get the metadata attached to the client identified by `key`, if any
+    GetMetadata(String),
+    /// Get the remaining counts of several clients at once, in the order of the keys, as `Get`
+    /// would one by one, for instance to check every tenant of a batch before processing it.
+    /// Remote stores read them in one round trip where they can. The answer is all or nothing:
+    /// when any key can not be read, the whole message fails, and a missing key is not a
+    /// failure but `None`.
+    MultiGet(Vec<String>),
+    /// Get the metadata attached to several clients at once, in the order of the keys, as
+    /// `GetMetadata` would one by one, for admin tools listing clients with their metadata.
+    /// Like `MultiGet`, remote stores read them in one round trip where they can, and the
+    /// answer is all or nothing.
+    MultiGetMetadata(Vec<String>),
+    /// Add `by` to the remaining count of the client identified by `key`, without going above
+    /// `cap`, only if the key exists and has not expired, keeping its expiry, and return the new
+    /// count, or `None` when there was no key. A count already above `cap`, when the limit went
+    /// down, is left as is. This is what refunds use, so that they neither bring back a window
+    /// which ended nor give more requests than the limit. Counts are read and written at once,
+    /// in redis with a script such as
+    /// `local v = redis.call('GET', KEYS[1]) if v then redis.call('INCRBY', KEYS[1], new - v) end`,
+    /// `INCRBY` keeping the expiry of the key.
+    IncrementIfExists { key: String, by: usize, cap: usize },
+    /// Add `member` to the set of the client identified by `key` and return how many distinct
+    /// members the set holds. The set is created empty with an expiry of `expiry` when it does
+    /// not exist or has expired, and adding members does not extend it. Once the set holds
+    /// `cap` members, new ones are not kept and the count stays at `cap`, so that clients sending
+    /// endless distinct members do not grow it further. The count may be an estimate: redis
+    /// keeps a HyperLogLog (`PFADD` and `PFCOUNT`), off by about 1% but of a few kilobytes
+    /// whatever the number of members.
+    AddToSet {
+        key: String,
+        member: String,
+        expiry: Duration,
+        cap: usize,
+    },
 }
 
 impl Message for ActorMessage {
@@ -237,10 +414,34 @@ pub enum ActorResponse {
     Set(Output<()>),
     /// Returned in response to [Messages::Update](enum.Messages.html)
     Update(Output<usize>),
+    /// Returned in response to [Messages::Consume](enum.Messages.html)
+    Consume(Output<(usize, Duration)>),
     /// Returned in response to [Messages::Expire](enum.Messages.html)
     Expire(Output<Duration>),
     /// Returned in response to [Messages::Remove](enum.Messages.html)
     Remove(Output<usize>),
+    /// Returned in response to [Messages::Rotate](enum.Messages.html)
+    Rotate(Output<Option<usize>>),
+    /// Returned in response to [Messages::List](enum.Messages.html)
+    List(Output<Vec<(String, usize, Duration)>>),
+    /// Returned in response to [Messages::CompareAndSet](enum.Messages.html)
+    CompareAndSet(Output<bool>),
+    /// Returned in response to [Messages::Ping](enum.Messages.html)
+    Ping(Output<()>),
+    /// Returned in response to [Messages::Time](enum.Messages.html)
+    Time(Output<Duration>),
+    /// Returned in response to [Messages::SetMetadata](enum.Messages.html)
+    SetMetadata(Output<()>),
+    /// Returned in response to [Messages::GetMetadata](enum.Messages.html)
+    GetMetadata(Output<Option<String>>),
+    /// Returned in response to [Messages::MultiGet](enum.Messages.html)
+    MultiGet(Output<Vec<Option<usize>>>),
+    /// Returned in response to [Messages::MultiGetMetadata](enum.Messages.html)
+    MultiGetMetadata(Output<Vec<Option<String>>>),
+    /// Returned in response to [Messages::IncrementIfExists](enum.Messages.html)
+    IncrementIfExists(Output<Option<usize>>),
+    /// Returned in response to [Messages::AddToSet](enum.Messages.html)
+    AddToSet(Output<usize>),
 }
 
 impl<A, M> MessageResponse<A, M> for ActorResponse
@@ -0,0 +1,158 @@
+//! The limiter on its own, for code outside of actix-web such as queue consumers
+use actix::dev::{Handler, ToEnvelope};
+use actix::prelude::*;
+use std::time::Duration;
+
+use crate::errors::ARError;
+use crate::middleware::{self, decide, Algorithm, Limit};
+use crate::ActorMessage;
+
+/// Counts events against a limit in a store, for instance the messages of every tenant a
+/// background worker pulls from a queue, without HTTP requests. The
+/// [guard](../guard/struct.RateLimitGuard.html) is built on it, and the
+/// [middleware](../middleware/struct.RateLimiter.html) counts in the store in the same way, so
+/// all of them can share a store and even keys.
+///
+/// It only needs the actix system the store actors run in, and cloning it gives another handle
+/// on the same store.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::limiter::{Decision, Limiter};
+/// use actix_ratelimit::middleware::Limit;
+/// use actix_ratelimit::{MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     // Ten messages per second for every tenant
+///     let limiter = Limiter::new(
+///         store,
+///         Limit { max_requests: 10, interval: Duration::from_secs(1) },
+///     );
+///     let queue = vec![("tenant-a", "first"), ("tenant-a", "second")];
+///     for (tenant, message) in queue {
+///         loop {
+///             match limiter.try_acquire(tenant).await {
+///                 Ok(Decision::Allowed { .. }) => break,
+///                 Ok(Decision::Rejected { reset }) => actix_rt::time::delay_for(reset).await,
+///                 Err(e) => panic!("store failed: {}", e),
+///             }
+///         }
+///         println!("processing {}", message);
+///     }
+/// }
+/// ```
+pub struct Limiter<T>
+where
+    T: Handler<ActorMessage> + 'static,
+{
+    store: Addr<T>,
+    limit: Limit,
+    algorithm: Algorithm,
+    store_clock: bool,
+}
+
+/// Outcome of [Limiter::try_acquire](struct.Limiter.html#method.try_acquire)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// The event was counted, with `remaining` more allowed until the window resets in `reset`
+    Allowed { remaining: usize, reset: Duration },
+    /// The key is over its limit until `reset`, the event was not counted
+    Rejected { reset: Duration },
+}
+
+impl<T> Clone for Limiter<T>
+where
+    T: Handler<ActorMessage> + 'static,
+{
+    fn clone(&self) -> Self {
+        Limiter {
+            store: self.store.clone(),
+            limit: self.limit,
+            algorithm: self.algorithm,
+            store_clock: self.store_clock,
+        }
+    }
+}
+
+impl<T> Limiter<T>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Allows `limit` for each key, counted in `store`
+    pub fn new(store: Addr<T>, limit: Limit) -> Self {
+        Limiter {
+            store,
+            limit,
+            algorithm: Algorithm::FixedWindow,
+            store_clock: false,
+        }
+    }
+
+    /// Specify how events are counted. Defaults to
+    /// [Algorithm::FixedWindow](../middleware/enum.Algorithm.html).
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Go by the clock of the store rather than the local one, see
+    /// [RateLimiter::with_store_clock](../middleware/struct.RateLimiter.html#method.with_store_clock).
+    pub fn with_store_clock(mut self, store_clock: bool) -> Self {
+        self.store_clock = store_clock;
+        self
+    }
+
+    /// The limit of every key
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Counts an event for `key` if it is within its limit
+    pub async fn try_acquire(&self, key: &str) -> Result<Decision, ARError> {
+        let decision = decide(
+            &self.store,
+            self.algorithm,
+            false,
+            key,
+            self.limit.max_requests,
+            self.limit.interval,
+            self.store_clock,
+        )
+        .await?;
+        Ok(match decision {
+            middleware::Decision::Allowed {
+                remaining, reset, ..
+            } => Decision::Allowed { remaining, reset },
+            middleware::Decision::Rejected { reset } => Decision::Rejected { reset },
+        })
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use super::*;
+    use crate::{MemoryStore, MemoryStoreActor};
+
+    #[actix_rt::test]
+    async fn test_try_acquire() {
+        let limiter = Limiter::new(
+            MemoryStoreActor::from(MemoryStore::new()).start(),
+            Limit {
+                max_requests: 2,
+                interval: Duration::from_secs(60),
+            },
+        );
+        let remaining = |decision| match decision {
+            Decision::Allowed { remaining, .. } => Some(remaining),
+            Decision::Rejected { .. } => None,
+        };
+        assert_eq!(remaining(limiter.try_acquire("a").await.unwrap()), Some(1));
+        assert_eq!(remaining(limiter.try_acquire("a").await.unwrap()), Some(0));
+        assert_eq!(remaining(limiter.try_acquire("a").await.unwrap()), None);
+        assert_eq!(remaining(limiter.try_acquire("b").await.unwrap()), Some(1));
+    }
+}
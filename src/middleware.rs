@@ -1,24 +1,864 @@
 //! RateLimiter middleware for actix application
 use actix::dev::*;
+use actix_service::ServiceFactory;
 use actix_web::{
-    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    body::MessageBody,
+    dev::{HttpResponseBuilder, Service, ServiceRequest, ServiceResponse, Transform},
     error::Error as AWError,
-    http::{HeaderName, HeaderValue},
-    HttpResponse,
+    http::{
+        header::{InvalidHeaderName, CONTENT_LENGTH, RETRY_AFTER},
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    web::Data,
+    App, FromRequest, HttpMessage, HttpRequest, HttpResponse,
 };
-use futures::future::{ok, Ready};
+use arc_swap::ArcSwap;
+use futures::future::{err, ok, LocalBoxFuture, Ready};
 use log::*;
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     future::Future,
     ops::Fn,
     pin::Pin,
     rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{errors::ARError, ActorMessage, ActorResponse};
+use crate::config::{PathLimitConfig, RateLimiterDescription};
+use crate::{
+    adaptive::AdaptiveLimit,
+    concurrency::ConcurrencyLimit,
+    errors::{ARError, ConfigError},
+    events::RateLimitEvent,
+    identifiers, predicates,
+    prefilter::{PreCheck, PreFilter},
+    stats::StoreStats,
+    stores::{
+        add_to_set, compare_and_set, consume, derived_key, expire, get, increment_if_exists,
+        multi_get, ping, rotate, set, time, update,
+    },
+    telemetry::{self, DecisionSpan, Outcome},
+    ActorMessage,
+};
+#[cfg(feature = "memory")]
+use crate::{MemoryStore, MemoryStoreActor};
+
+type Identifier = Rc<Box<dyn Fn(&ServiceRequest) -> Result<String, ARError>>>;
+type AsyncIdentifier =
+    Rc<Box<dyn Fn(&ServiceRequest) -> LocalBoxFuture<'static, Result<String, ARError>>>>;
+type Observer = Rc<Box<dyn Fn(&RateLimitEvent)>>;
+type Extractor = Rc<Box<dyn Fn(&ServiceRequest) -> Option<String>>>;
+type Predicate = Rc<Box<dyn Fn(&ServiceRequest) -> bool>>;
+type Resolver = Rc<Box<dyn Fn(&ServiceRequest) -> Limit>>;
+type StoreErrorHandler = Rc<Box<dyn Fn(&ServiceRequest, &ARError) -> HttpResponse>>;
+type StoreSelector = Rc<Box<dyn Fn(&ServiceRequest) -> StoreId>>;
+type ShardSelector = Rc<Box<dyn Fn(&str) -> usize>>;
+
+/// Name of a store of a limiter, see
+/// [RateLimiter::with_store](struct.RateLimiter.html#method.with_store).
+pub type StoreId = &'static str;
+
+// Blocks the clients whose responses match `predicate` too often, see
+// RateLimiter::with_error_limit
+#[derive(Clone)]
+struct ErrorLimit {
+    predicate: Rc<Box<dyn Fn(StatusCode) -> bool>>,
+    max_errors: usize,
+    interval: Duration,
+    cooldown: Duration,
+}
+
+// Limits the distinct members a client touches in a window, see
+// RateLimiter::with_distinct_limit
+#[derive(Clone)]
+struct DistinctLimit {
+    extractor: Extractor,
+    max_distinct: usize,
+}
+
+// Names of the headers a limiter sends, see RateLimiter::with_header_prefix
+struct HeaderNames {
+    limit: HeaderName,
+    remaining: HeaderName,
+    reset: HeaderName,
+    used: HeaderName,
+    algorithm: HeaderName,
+    warning: HeaderName,
+    dryrun: HeaderName,
+}
+
+impl HeaderNames {
+    fn new(prefix: &str) -> Result<Self, InvalidHeaderName> {
+        let name = |suffix| HeaderName::from_bytes(format!("{}-{}", prefix, suffix).as_bytes());
+        Ok(HeaderNames {
+            limit: name("limit")?,
+            remaining: name("remaining")?,
+            reset: name("reset")?,
+            used: name("used")?,
+            algorithm: name("algorithm")?,
+            warning: name("warning")?,
+            dryrun: name("dryrun")?,
+        })
+    }
+}
+
+impl Default for HeaderNames {
+    fn default() -> Self {
+        HeaderNames {
+            limit: HeaderName::from_static("x-ratelimit-limit"),
+            remaining: HeaderName::from_static("x-ratelimit-remaining"),
+            reset: HeaderName::from_static("x-ratelimit-reset"),
+            used: HeaderName::from_static("x-ratelimit-used"),
+            algorithm: HeaderName::from_static("x-ratelimit-algorithm"),
+            warning: HeaderName::from_static("x-ratelimit-warning"),
+            dryrun: HeaderName::from_static("x-ratelimit-dryrun"),
+        }
+    }
+}
+
+/// Limit applied to a single request, see
+/// [RateLimiter::with_limit_resolver](struct.RateLimiter.html#method.with_limit_resolver).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    /// Maximum number of requests in the window
+    pub max_requests: usize,
+    /// Size of the window
+    pub interval: Duration,
+}
+
+/// Limit which can be changed while the application runs, for instance from a `SIGHUP` handler
+/// or an admin endpoint, see
+/// [RateLimiter::with_shared_limit](struct.RateLimiter.html#method.with_shared_limit).
+///
+/// Clones share the same limit. It is read atomically by every request, so a change applies to
+/// the next requests of every worker without a redeploy.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::middleware::{Limit, SharedLimit};
+///
+/// let limit = SharedLimit::new(Limit {
+///     max_requests: 100,
+///     interval: Duration::from_secs(60),
+/// });
+/// // Kept by the admin endpoint
+/// let admin = limit.clone();
+/// admin.set(Limit {
+///     max_requests: 50,
+///     interval: Duration::from_secs(60),
+/// });
+/// assert_eq!(limit.get().max_requests, 50);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedLimit(Arc<ArcSwap<Limit>>);
+
+impl SharedLimit {
+    /// Creates a shared limit starting at `limit`
+    pub fn new(limit: Limit) -> Self {
+        SharedLimit(Arc::new(ArcSwap::from_pointee(limit)))
+    }
+
+    /// Returns the current limit
+    pub fn get(&self) -> Limit {
+        **self.0.load()
+    }
+
+    /// Replaces the limit for the following requests. Windows already started keep their
+    /// expiry, as with a [limit resolver](struct.RateLimiter.html#method.with_limit_resolver).
+    pub fn set(&self, limit: Limit) {
+        self.0.store(Arc::new(limit));
+    }
+
+    /// Replaces the limit with the one set in the environment variables `max_requests_var` and
+    /// `interval_var`, the latter in seconds, and returns it. The limit is left as is when a
+    /// variable is missing or invalid.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::middleware::{Limit, SharedLimit};
+    ///
+    /// let limit = SharedLimit::new(Limit {
+    ///     max_requests: 100,
+    ///     interval: Duration::from_secs(60),
+    /// });
+    /// // For instance on SIGHUP
+    /// if let Err(e) = limit.reload_from_env("RATELIMIT_MAX_REQUESTS", "RATELIMIT_INTERVAL") {
+    ///     eprintln!("limit not reloaded: {}", e);
+    /// }
+    /// ```
+    pub fn reload_from_env(
+        &self,
+        max_requests_var: &'static str,
+        interval_var: &'static str,
+    ) -> Result<Limit, ConfigError> {
+        let var = |name: &'static str| -> Result<u64, ConfigError> {
+            let value = std::env::var(name).map_err(|_| ConfigError::MissingField(name))?;
+            value
+                .trim()
+                .parse()
+                .map_err(|e| ConfigError::InvalidValue(name, format!("{}", e)))
+        };
+        let max_requests = var(max_requests_var)? as usize;
+        let interval = var(interval_var)?;
+        if interval == 0 {
+            return Err(ConfigError::InvalidValue(
+                interval_var,
+                "must be at least 1 second".to_string(),
+            ));
+        }
+        let limit = Limit {
+            max_requests,
+            interval: Duration::from_secs(interval),
+        };
+        self.set(limit);
+        Ok(limit)
+    }
+}
+
+/// Switch turning the enforcement of a limiter on and off while the application runs, for
+/// instance during a planned maintenance or an incident, see
+/// [RateLimiter::with_switch](struct.RateLimiter.html#method.with_switch).
+///
+/// Clones share the same state. It is read atomically by every request, so flipping it applies
+/// to the next requests of every worker without a redeploy.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::middleware::LimiterSwitch;
+///
+/// let switch = LimiterSwitch::new(true);
+/// // Kept by the admin endpoint
+/// let admin = switch.clone();
+/// admin.set_enabled(false);
+/// assert!(!switch.is_enabled());
+/// ```
+#[derive(Clone, Debug)]
+pub struct LimiterSwitch(Arc<AtomicBool>);
+
+impl LimiterSwitch {
+    /// Creates a switch, enforcing the limit when `enabled`
+    pub fn new(enabled: bool) -> Self {
+        LimiterSwitch(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    /// Whether the limit is enforced
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Turns the enforcement on or off for the following requests
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Behaviour of the middleware when the store fails to answer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum FailureMode {
+    /// The store error is returned, which results in a 500 Internal Server Error. This is the
+    /// default.
+    FailClosed,
+    /// The request is passed to the service as if no limit was configured, without ratelimit
+    /// headers.
+    FailOpen,
+}
+
+/// What happens to a request when the identifier fails to identify its client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+    /// The identifier's error is returned, which results in a 500 Internal Server Error.
+    Reject,
+    /// The request is passed to the service without being counted, and without ratelimit
+    /// headers.
+    Allow,
+    /// All unidentified requests are counted together under the given key. This is the
+    /// default, with the key `unidentified`.
+    SharedBucket(String),
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::SharedBucket("unidentified".to_string())
+    }
+}
+
+/// Cost of a request, reported by the handler once it knows it, for instance after parsing a
+/// batch of operations from the body.
+///
+/// The middleware runs before the body is read, so it reserves one request up front. When the
+/// response is ready, a cost found in the request extensions is charged minus that
+/// reservation, and the ratelimit headers account for it. Costs lower than one are not
+/// refunded. When the handler fails, with an error or a 5xx response, only the reservation is
+/// charged.
+///
+/// # Example
+/// ```rust
+/// use actix_web::{web, HttpRequest, HttpResponse};
+/// use actix_ratelimit::middleware::ActualCost;
+///
+/// async fn batch(req: HttpRequest, operations: web::Json<Vec<String>>) -> HttpResponse {
+///     req.extensions_mut().insert(ActualCost(operations.len()));
+///     HttpResponse::Ok().finish()
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ActualCost(pub usize);
+
+/// Cost of a request in fractions of a token, reported by the handler like an
+/// [ActualCost](struct.ActualCost.html), which it takes precedence over. It is rounded to the
+/// nearest unit of the [token scale](struct.RateLimiter.html#method.with_token_scale), so it
+/// is only charged in whole tokens without one.
+///
+/// # Example
+/// ```rust
+/// use actix_web::{HttpRequest, HttpResponse};
+/// use actix_ratelimit::middleware::FractionalCost;
+///
+/// async fn complete(req: HttpRequest, prompt: String) -> HttpResponse {
+///     // A token of the limit per thousand words of the prompt
+///     let words = prompt.split_whitespace().count();
+///     req.extensions_mut().insert(FractionalCost(words as f64 / 1000.0));
+///     HttpResponse::Ok().finish()
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FractionalCost(pub f64);
+
+/// Marks a request served from a cache, which the handler found out once it processed it, to be
+/// charged less than a request to the origin, see
+/// [RateLimiter::with_cache_hit_refund](struct.RateLimiter.html#method.with_cache_hit_refund).
+/// It is ignored by limiters without a cache hit refund.
+///
+/// # Example
+/// ```rust
+/// use actix_web::{HttpRequest, HttpResponse};
+/// use actix_ratelimit::middleware::CacheHit;
+///
+/// async fn report(req: HttpRequest) -> HttpResponse {
+///     let cached: Option<String> = None;
+///     match cached {
+///         Some(report) => {
+///             req.extensions_mut().insert(CacheHit);
+///             HttpResponse::Ok().body(report)
+///         }
+///         None => HttpResponse::Ok().body("fresh report"),
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheHit;
+
+/// Marks a request which should not have counted, for instance a request which turned out to be
+/// a no-op, found out by the handler once it processed it.
+///
+/// When the response is ready, the middleware finds it in the request extensions and gives the
+/// request back to the client: its count goes up by one, within the same window, and the
+/// ratelimit headers account for it. This applies whatever the status of the response, and
+/// takes precedence over an [ActualCost](struct.ActualCost.html). Requests too late
+/// for their window, which ended while they were processed, and requests let through by the
+/// [prefilter](struct.RateLimiter.html#method.with_prefilter) are not refunded. The count goes
+/// up with an [increment if exists](../enum.ActorMessage.html#variant.IncrementIfExists), which
+/// the memory, redis and memcached stores support natively;
+/// [adapted](../stores/adapter/index.html) stores retry their compare-and-set unless they
+/// override it, or read and set the count again when they have neither.
+///
+/// # Example
+/// ```rust
+/// use actix_web::{web, HttpRequest, HttpResponse};
+/// use actix_ratelimit::middleware::Refund;
+///
+/// async fn sync(req: HttpRequest, changes: web::Json<Vec<String>>) -> HttpResponse {
+///     if changes.is_empty() {
+///         req.extensions_mut().insert(Refund);
+///     }
+///     HttpResponse::Ok().finish()
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Refund;
+
+/// Outcome of the rate limiting of a request, available to the middlewares and handlers it is
+/// passed to, from the request extensions or as an extractor.
+///
+/// This lets an inner layer degrade gracefully, for instance serve a cached response to a
+/// client close to its limit. Middlewares registered with `wrap` run in the reverse order, so
+/// the limiter has to be wrapped after such a layer to run before it:
+/// `App::new().wrap(cache).wrap(limiter)`. Requests over the limit are rejected before reaching
+/// the layer, unless the limiter is in [monitor only](struct.RateLimiter.html#method.with_monitor_only)
+/// mode, where they reach it with `exceeded` set. A cache wrapped after the limiter instead runs
+/// first, and its responses are neither counted nor limited.
+///
+/// # Example
+/// ```rust
+/// use actix_web::HttpResponse;
+/// use actix_ratelimit::middleware::RateLimitInfo;
+///
+/// async fn index(info: Option<RateLimitInfo>) -> HttpResponse {
+///     match info {
+///         Some(info) if info.remaining < 10 => HttpResponse::Ok().body("cached"),
+///         _ => HttpResponse::Ok().body("fresh"),
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitInfo {
+    /// Maximum number of requests in the window
+    pub limit: usize,
+    /// Requests left in the window, before the cost reported with
+    /// [ActualCost](struct.ActualCost.html). Both counts are in units of the
+    /// [token scale](struct.RateLimiter.html#method.with_token_scale), when set.
+    pub remaining: usize,
+    /// Time until the window resets
+    pub reset: Duration,
+    /// Whether the request is over the limit, which only reaches the service in monitor only
+    /// mode
+    pub exceeded: bool,
+}
+
+impl FromRequest for RateLimitInfo {
+    type Error = AWError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        match req.extensions().get::<RateLimitInfo>() {
+            Some(info) => ok(*info),
+            None => err(actix_web::error::ErrorInternalServerError(
+                "request was not rate limited",
+            )),
+        }
+    }
+}
+
+/// Decision of the limiter on a request, for the middlewares around the limiter, for instance
+/// to put it in an access log. It is in the request extensions, for the inner layers and for
+/// the outer ones in the response of allowed requests. Rejected requests return an error
+/// instead of a response: a middleware running around the limiter, wrapped before it, puts a
+/// [DecisionSlot](struct.DecisionSlot.html) in the request extensions to receive the decision
+/// either way. It is only given when the store decided on the request, not when the limiter
+/// let it through without asking the store, such as when it failed open.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_web::{dev::Service, web, App, HttpMessage, HttpResponse};
+/// use actix_ratelimit::middleware::DecisionSlot;
+/// use actix_ratelimit::{MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let app = App::new()
+///         .wrap(RateLimiter::memory(&store, 100, Duration::from_secs(60)))
+///         .wrap_fn(|req, srv| {
+///             let slot = DecisionSlot::new();
+///             req.extensions_mut().insert(slot.clone());
+///             let path = req.path().to_string();
+///             let res = srv.call(req);
+///             async move {
+///                 let res = res.await;
+///                 if let Some(decision) = slot.get() {
+///                     log::info!("{} {:?}", path, decision);
+///                 }
+///                 res
+///             }
+///         })
+///         .route("/", web::get().to(HttpResponse::Ok));
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitDecision {
+    /// Whether the request was let through, over the limit or not
+    pub allowed: bool,
+    /// Hash of the key of the client, which tells clients apart without logging their
+    /// addresses
+    pub key_hash: u64,
+    /// Requests left in the window, in units of the
+    /// [token scale](struct.RateLimiter.html#method.with_token_scale), when set
+    pub remaining: usize,
+    /// Time until the window resets
+    pub reset: Duration,
+    /// [Name](enum.Algorithm.html#method.name) of the algorithm
+    pub algorithm: &'static str,
+}
+
+/// Receives the [decision](struct.RateLimitDecision.html) of the limiter on a request, for a
+/// middleware around the limiter, when put in the request extensions. Clones share the slot.
+#[derive(Clone, Debug, Default)]
+pub struct DecisionSlot(Rc<Cell<Option<RateLimitDecision>>>);
+
+impl DecisionSlot {
+    /// Creates an empty slot
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The decision of the limiter, once it decided
+    pub fn get(&self) -> Option<RateLimitDecision> {
+        self.0.get()
+    }
+}
+
+/// Why a request was let through, available from the request extensions or as an extractor
+/// to the middlewares and handlers it is passed to, and reported to the
+/// [observer](struct.RateLimiter.html#method.with_observer) with
+/// [RateLimitEvent::Allowed](../events/enum.RateLimitEvent.html), for instance to tell from
+/// real traffic how often clients go over their limit.
+///
+/// # Example
+/// ```rust
+/// use actix_web::HttpResponse;
+/// use actix_ratelimit::middleware::DecisionReason;
+///
+/// async fn index(reason: Option<DecisionReason>) -> HttpResponse {
+///     if reason != Some(DecisionReason::WithinLimit) {
+///         log::info!("request let through as {:?}", reason);
+///     }
+///     HttpResponse::Ok().finish()
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum DecisionReason {
+    /// The client is within its limit
+    WithinLimit,
+    /// The client is over its limit, but the limiter is in
+    /// [monitor only](struct.RateLimiter.html#method.with_monitor_only) mode
+    MonitorOnly,
+    /// The request was not counted, see
+    /// [with_peek_methods](struct.RateLimiter.html#method.with_peek_methods)
+    Peek,
+    /// The store failed and the limiter is configured to
+    /// [fail open](enum.FailureMode.html#variant.FailOpen)
+    FailOpen,
+    /// The client is over its limit, but within the
+    /// [grace](struct.RateLimiter.html#method.with_over_limit_grace) of the window
+    OverLimitGrace,
+    /// The store was [saturated](struct.RateLimiter.html#method.with_shed_threshold) and the
+    /// limiter is configured to fail open
+    Shed,
+    /// The client was over its limit, and the request was
+    /// [delayed](struct.RateLimiter.html#method.with_throttle_delay) until it was within it
+    Throttled,
+    /// The client is over its limit, but the store recovered recently and the limit is enforced
+    /// [leniently](struct.RateLimiter.html#method.with_recovery_grace)
+    RecoveryGrace,
+    /// The request is a retry of one counted in the window, carrying the same
+    /// [idempotency key](struct.RateLimiter.html#method.with_idempotency_key), and was not
+    /// counted again
+    Retry,
+}
+
+impl FromRequest for DecisionReason {
+    type Error = AWError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        match req.extensions().get::<DecisionReason>() {
+            Some(reason) => ok(*reason),
+            None => err(actix_web::error::ErrorInternalServerError(
+                "request was not rate limited",
+            )),
+        }
+    }
+}
+
+/// How the identifier appears in the messages logged for rejected requests, see
+/// [RateLimiter::with_rejection_log](struct.RateLimiter.html#method.with_rejection_log).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogKey {
+    /// The identifier is logged as is. This is the default.
+    Plain,
+    /// A stable hash of the identifier is logged, so that messages about a client can still be
+    /// correlated.
+    Hashed,
+    /// The identifier is left out.
+    Omitted,
+}
+
+/// Where the trace id given with [RateLimitEvent::Rejected](../events/enum.RateLimitEvent.html)
+/// comes from, see
+/// [RateLimiter::with_trace_id](struct.RateLimiter.html#method.with_trace_id).
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceSource {
+    /// The trace id of the W3C `traceparent` header, for instance
+    /// `4bf92f3577b34da6a3ce929d0e0e4736` out of
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`. Malformed headers are ignored.
+    TraceParent,
+    /// The value of a header of its own, for instance `x-request-id` or `x-b3-traceid`, as is
+    Header(HeaderName),
+    /// The id of the current span of the `tracing` crate, in hex. Available with the `tracing`
+    /// feature.
+    #[cfg(feature = "tracing")]
+    CurrentSpan,
+}
+
+impl TraceSource {
+    fn trace_id(&self, req: &ServiceRequest) -> Option<String> {
+        match self {
+            TraceSource::TraceParent => {
+                let value = req.headers().get("traceparent")?.to_str().ok()?;
+                let parts: Vec<&str> = value.trim().split('-').collect();
+                let hex = |part: &str, len| {
+                    part.len() == len && part.bytes().all(|b| b.is_ascii_hexdigit())
+                };
+                match parts.as_slice() {
+                    [version, trace_id, parent_id, flags, ..]
+                        if hex(version, 2)
+                            && *version != "ff"
+                            && hex(trace_id, 32)
+                            && trace_id.bytes().any(|b| b != b'0')
+                            && hex(parent_id, 16)
+                            && hex(flags, 2) =>
+                    {
+                        Some(trace_id.to_ascii_lowercase())
+                    }
+                    _ => None,
+                }
+            }
+            TraceSource::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(String::from),
+            #[cfg(feature = "tracing")]
+            TraceSource::CurrentSpan => tracing::Span::current()
+                .id()
+                .map(|id| format!("{:016x}", id.into_u64())),
+        }
+    }
+}
+
+/// What happens to identifiers longer than
+/// [RateLimiter::with_max_key_length](struct.RateLimiter.html#method.with_max_key_length).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OversizedKey {
+    /// The identifier is replaced with a hash of it. This is the default, and the recommended
+    /// mitigation, since clients keep distinct buckets. The hash is not cryptographic.
+    Hash,
+    /// The identifier is cut to the maximum length, so identifiers sharing a long prefix share
+    /// a bucket.
+    Truncate,
+    /// The request is handled as if the client could not be identified, see
+    /// [with_missing_identifier](struct.RateLimiter.html#method.with_missing_identifier).
+    Reject,
+}
+
+/// How identifiers are normalized before they are looked up in the store, see
+/// [RateLimiter::with_key_normalization](struct.RateLimiter.html#method.with_key_normalization).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    /// The identifier is used as is. This is the default.
+    None,
+    /// The identifier is lowercased, so that `Example.com` and `example.com` share a bucket.
+    Lowercase,
+}
+
+/// Unit of the `x-ratelimit-limit` and `x-ratelimit-remaining` headers, see
+/// [RateLimiter::with_remaining_header](struct.RateLimiter.html#method.with_remaining_header).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemainingHeader {
+    /// The headers count tokens, the unit of [ActualCost](struct.ActualCost.html). This is the
+    /// default.
+    Tokens,
+    /// The headers count requests of `typical_cost` tokens, rounded down. Both headers are
+    /// divided, so that `remaining / limit` is the same fraction of the quota as in tokens,
+    /// and a client can make at least `remaining` requests of the typical cost.
+    Requests { typical_cost: usize },
+}
+
+/// Most requests per window sent in the `x-ratelimit-limit` and `x-ratelimit-remaining`
+/// headers. Larger limits, such as `usize::MAX` used to leave clients unlimited, are sent as
+/// `unlimited` in both headers, since some clients mishandle larger numbers.
+pub const UNLIMITED_THRESHOLD: usize = u32::MAX as usize;
+
+// Value of the `x-ratelimit-limit` header of the guard, which counts requests
+pub(crate) fn limit_header(max_requests: usize) -> String {
+    if max_requests > UNLIMITED_THRESHOLD {
+        String::from("unlimited")
+    } else {
+        max_requests.to_string()
+    }
+}
+
+impl RemainingHeader {
+    // Value of the headers for `units` out of `max_requests`, `scale` of which make a token
+    fn count(self, scale: usize, max_requests: usize, units: usize) -> String {
+        if max_requests / scale.max(1) > UNLIMITED_THRESHOLD {
+            return String::from("unlimited");
+        }
+        match self {
+            RemainingHeader::Tokens => format_tokens(units, scale),
+            RemainingHeader::Requests { typical_cost } => {
+                (units / typical_cost.max(1).saturating_mul(scale)).to_string()
+            }
+        }
+    }
+
+    // Value of the `x-ratelimit-used` header, in the same unit
+    fn used(self, scale: usize, max_requests: usize, remaining: usize) -> String {
+        match self {
+            RemainingHeader::Tokens => format_tokens(max_requests.saturating_sub(remaining), scale),
+            RemainingHeader::Requests { typical_cost } => {
+                let per_request = typical_cost.max(1).saturating_mul(scale);
+                (max_requests / per_request)
+                    .saturating_sub(remaining / per_request)
+                    .to_string()
+            }
+        }
+    }
+}
+
+// Tokens in `units`, with as many decimals as `scale` needs, rounded down and without trailing
+// zeros
+fn format_tokens(units: usize, scale: usize) -> String {
+    let scale = scale.max(1);
+    let (tokens, fraction) = (units / scale, units % scale);
+    if fraction == 0 {
+        return tokens.to_string();
+    }
+    let digits = (scale - 1).to_string().len();
+    let decimals = fraction as u128 * 10u128.pow(digits as u32) / scale as u128;
+    let decimals = format!("{:0width$}", decimals, width = digits);
+    format!("{}.{}", tokens, decimals.trim_end_matches('0'))
+}
+
+/// When the store is considered saturated, see
+/// [RateLimiter::with_shed_threshold](struct.RateLimiter.html#method.with_shed_threshold).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShedThreshold {
+    /// The store took longer than this to decide on a request
+    Latency(Duration),
+    /// This many requests of the worker are already waiting for the store
+    QueueDepth(usize),
+}
+
+/// Algorithm used to count the requests of a client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Algorithm {
+    /// Requests are counted in a window which starts with the first request of the client and
+    /// lasts _interval_. This is the default. A client can send up to twice _max_requests_ in a
+    /// short time by bunching them around the end of one window and the start of the next.
+    FixedWindow,
+    /// Requests are counted in consecutive windows of _interval_ aligned on the UNIX epoch, and
+    /// the limit is checked against the weighted estimate
+    ///
+    /// `previous_window * (1 - elapsed / interval) + current_window`
+    ///
+    /// which approximates a window sliding with every request, using two store entries per
+    /// client. The estimate assumes the requests of the previous window were evenly spread: it
+    /// is exact for even traffic, and is off by at most `max(weight, 1 - weight)` times the
+    /// count of the previous window otherwise, where `weight = 1 - elapsed / interval`.
+    ///
+    /// A request is counted with a compare-and-set against the count it was checked on, so the
+    /// store has to support both `Rotate` and `CompareAndSet`, as the stores of this crate do.
+    SlidingWindowCounter,
+    /// Requests are counted in windows aligned on wall-clock boundaries in UTC, the top of the
+    /// minute, of the hour or midnight, so that the quota of every client resets at the same
+    /// time, for products advertising for instance 1000 requests per calendar hour. The window
+    /// is the `unit`, _interval_ is not used, and the reset header counts down to the next
+    /// boundary.
+    ///
+    /// Since all clients reset together, their retries are synchronized on the boundary, and the
+    /// store and the application see a burst whenever a window starts. Spreading the resets with
+    /// jitter would defeat the purpose of this algorithm, [FixedWindow](#variant.FixedWindow)
+    /// is the better choice when the reset time does not have to be advertised.
+    CalendarWindow { unit: CalendarUnit },
+    /// Requests are counted in an exponentially weighted moving average, which loses half of its
+    /// value every `half_life`, and a request is rejected when it would take the average above
+    /// `threshold`. Rather than a hard count reset at once, the allowance of a client comes back
+    /// smoothly as its past requests fade, for soft throttling.
+    ///
+    /// A client sending a steady `r` requests a second settles at an average of
+    /// `r * half_life / ln 2`, so a threshold of `n` lets about `n * ln 2 / half_life` requests
+    /// through every second, about `0.7 * n` per half-life, once the client reached it. From
+    /// an average of zero, a client can burst up to `n` requests at once. To allow about `m`
+    /// requests per _interval_, take `half_life = interval` and `n = 1.44 * m`.
+    ///
+    /// The average is kept in the store under `{key}:ewma`, as the time in microseconds since
+    /// the Unix epoch when it decays down to one request, which the average at any other time
+    /// follows from. Nodes sharing a store should agree on the time, see
+    /// [with_store_clock](struct.RateLimiter.html#method.with_store_clock). _max_requests_ and _interval_ only go in the headers,
+    /// where _remaining_ is what is left under the threshold and the reset is when the average
+    /// fades back to zero, or to the next request when rejected: set _max_requests_ to the
+    /// threshold for the headers to add up. Every request counts as one, costs reported by the
+    /// handlers, refunds and a [token scale](struct.RateLimiter.html#method.with_token_scale)
+    /// do not apply, and the threshold must be above one, which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks.
+    Ewma { half_life: Duration, threshold: f64 },
+}
+
+impl Algorithm {
+    /// Name of the algorithm, as sent in the `x-ratelimit-algorithm` header, see
+    /// [RateLimiter::with_algorithm_header](struct.RateLimiter.html#method.with_algorithm_header).
+    /// Calendar windows are named after their unit, for instance `calendar-hour`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::FixedWindow => "fixed-window",
+            Algorithm::SlidingWindowCounter => "sliding-window-counter",
+            Algorithm::CalendarWindow {
+                unit: CalendarUnit::Minute,
+            } => "calendar-minute",
+            Algorithm::CalendarWindow {
+                unit: CalendarUnit::Hour,
+            } => "calendar-hour",
+            Algorithm::CalendarWindow {
+                unit: CalendarUnit::Day,
+            } => "calendar-day",
+            Algorithm::Ewma { .. } => "ewma",
+        }
+    }
+}
+
+/// Wall-clock boundary on which the windows of
+/// [Algorithm::CalendarWindow](enum.Algorithm.html#variant.CalendarWindow) reset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CalendarUnit {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl CalendarUnit {
+    fn as_secs(self) -> u64 {
+        match self {
+            CalendarUnit::Minute => 60,
+            CalendarUnit::Hour => 60 * 60,
+            CalendarUnit::Day => 24 * 60 * 60,
+        }
+    }
+
+    // Start of the window at `now`, and the time left until the next one
+    fn window(self, now: Duration) -> (u64, Duration) {
+        let start = now.as_secs() - now.as_secs() % self.as_secs();
+        let next = Duration::from_secs(start + self.as_secs());
+        (start, next - now)
+    }
+}
 
 /// Type that implements the ratelimit middleware.
 ///
@@ -50,7 +890,78 @@ where
     interval: Duration,
     max_requests: usize,
     store: Addr<T>,
-    identifier: Rc<Box<dyn Fn(&ServiceRequest) -> Result<String, ARError>>>,
+    identifier: Identifier,
+    async_identifier: Option<AsyncIdentifier>,
+    previous_identifier: Option<Identifier>,
+    failure_mode: FailureMode,
+    store_error_handler: Option<StoreErrorHandler>,
+    algorithm: Algorithm,
+    observer: Option<Observer>,
+    allowlist: Option<Predicate>,
+    content_type_filter: Option<Predicate>,
+    should_limit: Option<Predicate>,
+    concurrency: Option<ConcurrencyLimit>,
+    resolver: Option<Resolver>,
+    shared_limit: Option<SharedLimit>,
+    soft_limit: Option<f64>,
+    token_scale: usize,
+    missing_identifier: Policy,
+    hash_span_keys: bool,
+    path_limits: Rc<Vec<(String, Limit)>>,
+    stores: Rc<Vec<(StoreId, Addr<T>)>>,
+    store_selector: Option<StoreSelector>,
+    shards: Rc<Vec<Addr<T>>>,
+    shard_selector: Option<ShardSelector>,
+    scope_limits: Rc<Vec<(String, Limit)>>,
+    key_normalization: Normalization,
+    max_key_length: Option<usize>,
+    oversized_key: OversizedKey,
+    monitor_only: bool,
+    switch: Option<(LimiterSwitch, bool)>,
+    over_limit_grace: usize,
+    recovery_grace: Option<(Duration, f64)>,
+    throttle_delay: Option<Duration>,
+    sliding_expiry: bool,
+    store_clock: bool,
+    boosts: bool,
+    idempotency_header: Option<HeaderName>,
+    content_length_cost: Option<usize>,
+    min_cost: usize,
+    cache_hit_refund: Option<f64>,
+    prefilter: Option<PreFilter>,
+    stats: StoreStats,
+    error_limit: Option<ErrorLimit>,
+    distinct_limit: Option<DistinctLimit>,
+    backoff_penalty: Option<(usize, Duration)>,
+    adaptive: Option<AdaptiveLimit>,
+    // Start, length and starting fraction of the warmup
+    warmup: Option<(Instant, Duration, f64)>,
+    remaining_header: RemainingHeader,
+    used_header: bool,
+    algorithm_header: bool,
+    header_names: Rc<HeaderNames>,
+    retry_after_jitter: Option<Duration>,
+    headers_on_errors: bool,
+    shed_threshold: Option<ShedThreshold>,
+    rejection_log: (Level, LogKey),
+    log_path: bool,
+    trace_source: Option<TraceSource>,
+    peek_methods: Rc<Vec<Method>>,
+}
+
+#[cfg(feature = "memory")]
+impl RateLimiter<MemoryStoreActor> {
+    /// Creates a limiter allowing `max_requests` per `interval` for every client, backed by a
+    /// new actor on the provided memory store. Further settings can be applied with the `with_*`
+    /// methods.
+    ///
+    /// The store should be created once, outside of the `HttpServer` factory, so that it is
+    /// shared by all the workers.
+    pub fn memory(store: &MemoryStore, max_requests: usize, interval: Duration) -> Self {
+        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+            .with_interval(interval)
+            .with_max_requests(max_requests)
+    }
 }
 
 impl<T> RateLimiter<T>
@@ -58,20 +969,203 @@ where
     T: Handler<ActorMessage> + Send + Sync + 'static,
     <T as Actor>::Context: ToEnvelope<T, ActorMessage>,
 {
+    /// Creates a limiter allowing `max_requests` per `interval` for every client on every
+    /// endpoint, the most common setup. Clients are identified by their IP address, see
+    /// [identifiers::peer_ip](../identifiers/fn.peer_ip.html), and endpoints by the method and
+    /// route pattern of the request, see [identifiers::endpoint](../identifiers/fn.endpoint.html).
+    ///
+    /// The route pattern, `/users/{id}`, is used rather than the path, `/users/42`, so that a
+    /// client gets one bucket per endpoint instead of one per value of the path parameters,
+    /// which would let it multiply its limit and fill the store. Further settings can be
+    /// applied with the `with_*` methods, for instance
+    /// [with_identifier](#method.with_identifier) with
+    /// `identifiers::endpoint(identifiers::real_ip)` behind a proxy.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::per_client_per_endpoint(
+    ///         MemoryStoreActor::from(store.clone()).start(),
+    ///         100,
+    ///         Duration::from_secs(60),
+    ///     );
+    /// }
+    /// ```
+    pub fn per_client_per_endpoint(
+        store: Addr<T>,
+        max_requests: usize,
+        interval: Duration,
+    ) -> Self {
+        RateLimiter::new(store)
+            .with_interval(interval)
+            .with_max_requests(max_requests)
+            .with_identifier(identifiers::endpoint(identifiers::peer_ip))
+    }
+
     /// Creates a new instance of `RateLimiter` with the provided address of `StoreActor`.
     pub fn new(store: Addr<T>) -> Self {
-        let identifier = |req: &ServiceRequest| {
-            let connection_info = req.connection_info();
-            let ip = connection_info
-                .remote_addr()
-                .ok_or(ARError::IdentificationError)?;
-            Ok(String::from(ip))
-        };
         RateLimiter {
             interval: Duration::from_secs(0),
             max_requests: 0,
-            store: store,
-            identifier: Rc::new(Box::new(identifier)),
+            store,
+            identifier: Rc::new(Box::new(identifiers::default)),
+            async_identifier: None,
+            previous_identifier: None,
+            failure_mode: FailureMode::FailClosed,
+            store_error_handler: None,
+            algorithm: Algorithm::FixedWindow,
+            observer: None,
+            allowlist: None,
+            content_type_filter: None,
+            should_limit: None,
+            concurrency: None,
+            resolver: None,
+            shared_limit: None,
+            soft_limit: None,
+            token_scale: 1,
+            missing_identifier: Policy::default(),
+            hash_span_keys: false,
+            path_limits: Rc::new(Vec::new()),
+            stores: Rc::new(Vec::new()),
+            store_selector: None,
+            shards: Rc::new(Vec::new()),
+            shard_selector: None,
+            scope_limits: Rc::new(Vec::new()),
+            key_normalization: Normalization::None,
+            max_key_length: None,
+            oversized_key: OversizedKey::Hash,
+            monitor_only: false,
+            switch: None,
+            over_limit_grace: 0,
+            recovery_grace: None,
+            throttle_delay: None,
+            sliding_expiry: false,
+            store_clock: false,
+            boosts: false,
+            idempotency_header: None,
+            content_length_cost: None,
+            min_cost: 1,
+            cache_hit_refund: None,
+            prefilter: None,
+            stats: StoreStats::new(),
+            error_limit: None,
+            distinct_limit: None,
+            backoff_penalty: None,
+            adaptive: None,
+            warmup: None,
+            remaining_header: RemainingHeader::Tokens,
+            used_header: false,
+            algorithm_header: false,
+            header_names: Rc::new(HeaderNames::default()),
+            retry_after_jitter: None,
+            headers_on_errors: true,
+            shed_threshold: None,
+            rejection_log: (Level::Info, LogKey::Plain),
+            log_path: false,
+            trace_source: None,
+            peek_methods: Rc::new(Vec::new()),
+        }
+    }
+
+    /// Checks that the store is reachable, so that the application can fail at startup instead
+    /// of answering every request with an error when, for instance, redis is misconfigured.
+    /// Remote stores connect in the background, the check waits for their first connection
+    /// attempt.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60));
+    ///     ratelimiter
+    ///         .validate()
+    ///         .await
+    ///         .expect("rate limiting store is unreachable");
+    /// }
+    /// ```
+    pub async fn validate(&self) -> Result<(), ARError> {
+        ping(&self.store).await?;
+        for (_, store) in self.stores.iter() {
+            ping(store).await?;
+        }
+        for store in self.shards.iter() {
+            ping(store).await?;
+        }
+        Ok(())
+    }
+
+    /// Key under which the requests like `req` are counted in the store, derived from the
+    /// [identifier](#method.with_identifier) exactly as the middleware does, with the
+    /// [normalization](#method.with_key_normalization), the
+    /// [length limit](#method.with_oversized_key), the
+    /// [shared bucket](#method.with_missing_identifier) of unidentified clients and the prefix
+    /// of the [path limit](#method.with_path_limit). This lets admin tools reset or inspect a
+    /// client without copying the key scheme. It fails with the error of the identifier when
+    /// the client can not be identified and is not counted in a shared bucket, and with
+    /// [ARError::IdentificationError](../errors/enum.ARError.html) when the limiter has an
+    /// [async identifier](#method.with_async_identifier), whose key can not be derived here.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{identifiers, stores, MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_web::test::TestRequest;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+    ///     let ratelimiter = RateLimiter::new(store.clone())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_identifier(identifiers::peer_ip);
+    ///     // The request of the client to reset, as the middleware would see it
+    ///     let req = TestRequest::default()
+    ///         .peer_addr("203.0.113.7:443".parse().unwrap())
+    ///         .to_srv_request();
+    ///     let key = ratelimiter.key_for(&req).unwrap();
+    ///     assert_eq!(key, "203.0.113.7");
+    ///     stores::seed(&store, &key, 100, Duration::from_secs(60)).await.unwrap();
+    /// }
+    /// ```
+    pub fn key_for(&self, req: &ServiceRequest) -> Result<String, ARError> {
+        if self.async_identifier.is_some() {
+            warn!("The key of an async identifier can not be derived synchronously");
+            return Err(ARError::IdentificationError);
+        }
+        let identifier = identify(
+            req,
+            &self.identifier,
+            self.key_normalization,
+            self.max_key_length,
+            self.oversized_key,
+        );
+        let identifier = match (identifier, &self.missing_identifier) {
+            (Ok(identifier), _) => identifier,
+            (Err(_), Policy::SharedBucket(key)) => key.clone(),
+            (Err(e), _) => return Err(e),
+        };
+        Ok(match match_path_limit(&self.path_limits, req.path()) {
+            Some((prefix, _)) => format!("{}:{}", prefix, identifier),
+            None => identifier,
+        })
+    }
+
+    /// Creates a [RateLimiterBuilder](struct.RateLimiterBuilder.html), which makes sure the
+    /// limiter is fully configured before it is used.
+    pub fn builder(store: Addr<T>) -> RateLimiterBuilder<T> {
+        RateLimiterBuilder {
+            limiter: RateLimiter::new(store),
+            interval: None,
+            max_requests: None,
         }
     }
 
@@ -87,172 +1181,6945 @@ where
         self
     }
 
-    /// Function to get the identifier for the client request
+    /// Function to get the identifier for the client request. Defaults to
+    /// [identifiers::remote_addr](../identifiers/fn.remote_addr.html), see the
+    /// [identifiers](../identifiers/index.html) module for alternatives.
     pub fn with_identifier<F: Fn(&ServiceRequest) -> Result<String, ARError> + 'static>(
         mut self,
         identifier: F,
     ) -> Self {
         self.identifier = Rc::new(Box::new(identifier));
+        self.async_identifier = None;
         self
     }
-}
 
-impl<T, S, B> Transform<S> for RateLimiter<T>
-where
-    T: Handler<ActorMessage> + Send + Sync + 'static,
-    T::Context: ToEnvelope<T, ActorMessage>,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = S::Error;
-    type InitError = ();
-    type Transform = RateLimitMiddleware<S, T>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+    /// Async function to get the identifier for the client request, in place of
+    /// [with_identifier](#method.with_identifier), for identities which take a lookup to find,
+    /// such as [identifiers::asn](../identifiers/fn.asn.html). Whichever of the two was given
+    /// last applies.
+    ///
+    /// It is awaited for every request before the store is asked, and its latency adds to
+    /// the one of every request: results which take a round trip to find should be cached.
+    /// [key_for](#method.key_for) can not await it, and fails with an async identifier.
+    pub fn with_async_identifier<F>(mut self, identifier: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> LocalBoxFuture<'static, Result<String, ARError>> + 'static,
+    {
+        self.async_identifier = Some(Rc::new(Box::new(identifier)));
+        self
+    }
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        ok(RateLimitMiddleware {
-            service: Rc::new(RefCell::new(service)),
-            store: self.store.clone(),
-            max_requests: self.max_requests,
-            interval: self.interval.as_secs(),
-            identifier: self.identifier.clone(),
-        })
+    /// Function giving the identifier of the client under the key scheme used before the current
+    /// [identifier](#method.with_identifier), to change schemes without resetting the limits of
+    /// every client at deploy time. Clients not yet in the store under their current key carry
+    /// their count and the end of their window over from their previous key, only the current
+    /// key is written from then on.
+    ///
+    /// This is a transition mode: it costs a store round trip for every request, and up to three
+    /// more for the first request of a client under its current key. Remove it once the windows
+    /// which started before the deploy have ended, one _interval_ later. The previous identifier
+    /// gets the [path limit](#method.with_path_limit) prefix the current one gets, but no
+    /// [normalization](#method.with_key_normalization) nor
+    /// [length limit](#method.with_max_key_length), it has to produce the previous keys as they
+    /// were. Only [Algorithm::FixedWindow](enum.Algorithm.html) keys are carried over, which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{identifiers, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Moving from the address with its port to the address alone
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_identifier(identifiers::peer_ip)
+    ///         .with_previous_identifier(identifiers::remote_addr);
+    /// }
+    /// ```
+    pub fn with_previous_identifier<F>(mut self, identifier: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Result<String, ARError> + 'static,
+    {
+        self.previous_identifier = Some(Rc::new(Box::new(identifier)));
+        self
     }
-}
 
-/// Service factory for RateLimiter
-pub struct RateLimitMiddleware<S, T>
-where
-    S: 'static,
-    T: Handler<ActorMessage> + 'static,
-{
-    service: Rc<RefCell<S>>,
-    store: Addr<T>,
-    // Exists here for the sole purpose of knowing the max_requests and interval from RateLimiter
-    max_requests: usize,
-    interval: u64,
-    identifier: Rc<Box<dyn Fn(&ServiceRequest) -> Result<String, ARError> + 'static>>,
-}
+    /// Normalize identifiers before they are looked up in the store, so that one client does
+    /// not get several buckets, for instance when keying on the `Host` header or on API keys
+    /// which clients send in any case. Defaults to [Normalization::None](enum.Normalization.html).
+    ///
+    /// This is only for case-insensitive sources: lowercasing a case-sensitive credential, such
+    /// as a token, merges the buckets of distinct clients. Normalization happens before the
+    /// [maximum length](#method.with_max_key_length) is applied, and before the path limit
+    /// prefix is added.
+    pub fn with_key_normalization(mut self, key_normalization: Normalization) -> Self {
+        self.key_normalization = key_normalization;
+        self
+    }
 
-impl<T, S, B> Service for RateLimitMiddleware<S, T>
-where
-    T: Handler<ActorMessage> + 'static,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
-    S::Future: 'static,
-    B: 'static,
-    T::Context: ToEnvelope<T, ActorMessage>,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
+    /// Limit the length of identifiers, which often come from the request and could otherwise
+    /// be used to fill the store with huge keys. Longer identifiers are hashed, see
+    /// [with_oversized_key](#method.with_oversized_key) for alternatives.
+    pub fn with_max_key_length(mut self, max_key_length: usize) -> Self {
+        self.max_key_length = Some(max_key_length);
+        self
+    }
+
+    /// Specify what happens to identifiers longer than the
+    /// [maximum length](#method.with_max_key_length). Defaults to
+    /// [OversizedKey::Hash](enum.OversizedKey.html).
+    pub fn with_oversized_key(mut self, oversized_key: OversizedKey) -> Self {
+        self.oversized_key = oversized_key;
+        self
+    }
+
+    /// Count requests and send the ratelimit headers without enforcing the limit, for staged
+    /// rollouts. Requests over the limit, or over the
+    /// [concurrency limit](#method.with_concurrency_limit), are passed to the service instead of
+    /// being rejected, and are only logged. Every response gets an `x-ratelimit-dryrun: true`
+    /// header, so clients and tooling can observe the forthcoming limits and see they are not
+    /// enforced yet. The header is never sent in enforcing mode, which is the default.
+    pub fn with_monitor_only(mut self, monitor_only: bool) -> Self {
+        self.monitor_only = monitor_only;
+        self
+    }
+
+    /// Enforce the limit only while `switch` is [enabled](struct.LimiterSwitch.html), so that it
+    /// can be lifted at runtime, for instance during a planned maintenance, with
+    /// [LimiterSwitch::set_enabled](struct.LimiterSwitch.html#method.set_enabled).
+    ///
+    /// While the switch is disabled, requests are passed to the service without touching the
+    /// store and without ratelimit headers, like [allowlisted](#method.with_allowlist) ones.
+    /// With `headers_when_disabled`, they are instead counted and sent the headers as in
+    /// [monitor only](#method.with_monitor_only) mode, so that clients keep seeing their limit,
+    /// at the cost of the store round trips.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::middleware::LimiterSwitch;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let switch = LimiterSwitch::new(true);
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_switch(switch.clone(), false);
+    ///     // Later, from the admin endpoint
+    ///     switch.set_enabled(false);
+    /// }
+    /// ```
+    pub fn with_switch(mut self, switch: LimiterSwitch, headers_when_disabled: bool) -> Self {
+        self.switch = Some((switch, headers_when_disabled));
+        self
+    }
+
+    /// Let the first `grace` requests over the limit of each window through with a
+    /// `x-ratelimit-warning: limit exceeded` header, and only reject the following ones, so that
+    /// clients hitting the limit for the first time are warned before being blocked. Defaults
+    /// to 0, every request over the limit is rejected.
+    ///
+    /// The requests over the limit are counted in the store under `{key}:over`, which expires
+    /// with the window, so each window gets its grace anew. Requests let through this way get
+    /// `x-ratelimit-remaining: 0` and are reported as
+    /// [DecisionReason::OverLimitGrace](enum.DecisionReason.html).
+    pub fn with_over_limit_grace(mut self, grace: usize) -> Self {
+        self.over_limit_grace = grace;
+        self
+    }
+
+    /// Enforce the limit leniently for `period` after the store recovers from a failure: clients
+    /// get up to `factor` times their limit, and only the ones over that are rejected. While the
+    /// store fails, requests are not counted, or counted in another store with a
+    /// [fallback](enum.FailureMode.html), so the usage of the clients during the outage is
+    /// unknown; this avoids rejecting all the clients which resume at once. Defaults to no
+    /// grace, the limit is enforced as soon as the store answers.
+    ///
+    /// Requests over the limit are let through like with
+    /// [with_over_limit_grace](#method.with_over_limit_grace), which they add up with, and are
+    /// reported as [DecisionReason::RecoveryGrace](enum.DecisionReason.html). The recovery is
+    /// tracked by each worker, when its limiter sees the store answer again.
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks that
+    /// `factor` is at least 1.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{middleware::FailureMode, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Up to 300 requests a minute in the five minutes after an outage
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_failure_mode(FailureMode::FailOpen)
+    ///         .with_recovery_grace(Duration::from_secs(300), 3.0);
+    /// }
+    /// ```
+    pub fn with_recovery_grace(mut self, period: Duration, factor: f64) -> Self {
+        self.recovery_grace = Some((period, factor));
+        self
+    }
+
+    /// Delay the requests over the limit until their window resets, instead of rejecting them,
+    /// to smooth the traffic to a fragile service without clients having to back off. A
+    /// request is only delayed when it can be let through within `max_wait`, otherwise it is
+    /// rejected right away; requests which are still over the limit once their window reset,
+    /// because others took the new window, wait again within the same `max_wait`, up to ten
+    /// times. Defaults to no delay.
+    ///
+    /// Delayed requests hold their connection while waiting, `max_wait` should stay well
+    /// below the timeouts of the clients. Requests let through after a delay are reported as
+    /// [DecisionReason::Throttled](enum.DecisionReason.html).
+    pub fn with_throttle_delay(mut self, max_wait: Duration) -> Self {
+        self.throttle_delay = Some(max_wait);
+        self
+    }
+
+    /// Block the clients for `cooldown` after `max_errors` of their responses match `predicate`
+    /// in `interval`, for instance against scanners probing for endpoints with
+    /// `|status| status == StatusCode::NOT_FOUND`. Blocked clients are rejected before their
+    /// requests are counted or reach the service, whatever their limit.
+    ///
+    /// The responses are counted under `{key}:errors`, and the block is kept under
+    /// `{key}:blocked`, which expires after the cooldown; the count starts over once a client is
+    /// blocked. This costs a store round trip for every request, and another one for every
+    /// matching response. In [monitor only](#method.with_monitor_only) mode, blocked clients are
+    /// only logged.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::http::StatusCode;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 20 not found or unauthorized responses in a minute block the client for an hour
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_error_limit(
+    ///             |status| status == StatusCode::NOT_FOUND || status == StatusCode::UNAUTHORIZED,
+    ///             20,
+    ///             Duration::from_secs(60),
+    ///             Duration::from_secs(3600),
+    ///         );
+    /// }
+    /// ```
+    pub fn with_error_limit<F: Fn(StatusCode) -> bool + 'static>(
+        mut self,
+        predicate: F,
+        max_errors: usize,
+        interval: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        self.error_limit = Some(ErrorLimit {
+            predicate: Rc::new(Box::new(predicate)),
+            max_errors,
+            interval,
+            cooldown,
+        });
+        self
+    }
+
+    /// Limit the number of distinct members, as returned by `extractor`, each client touches in
+    /// a window of the limit, besides its number of requests, for instance against scrapers
+    /// walking through resources with the id in the path. Requests for which `extractor` returns
+    /// `None` are only counted against the limit.
+    ///
+    /// The members are added to a set under `{key}:distinct`, created for the interval of the
+    /// limit, and a request is rejected when the set holds more than `max_distinct` members,
+    /// before it is counted. The set keeps one member past the limit at most, and stays full, so
+    /// a client over the limit is rejected, even for the members it already touched, until the
+    /// set expires; the rejections tell the interval as their reset, which the set outlives
+    /// at most. Redis counts the members in a HyperLogLog, off by about 1%, the memory store
+    /// exactly, and memcached does not support it, which
+    /// [build](struct.RateLimiterBuilder.html#method.build) rejects. The extractor only sees the head of the request: the
+    /// member must come from the path, the query or the headers, not the body. Wrapping the
+    /// whole app, the request is not routed yet, and `match_info` is empty.
+    ///
+    /// This costs a store round trip for every request with a member. Failures of the store are
+    /// logged and the request is only counted against the limit. In
+    /// [monitor only](#method.with_monitor_only) mode, clients over the limit are only logged.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // No more than 50 different products in a minute
+    ///     let ratelimiter = RateLimiter::memory(&store, 1000, Duration::from_secs(60))
+    ///         .with_distinct_limit(
+    ///             |req| req.path().strip_prefix("/products/").map(String::from),
+    ///             50,
+    ///         );
+    /// }
+    /// ```
+    pub fn with_distinct_limit<F: Fn(&ServiceRequest) -> Option<String> + 'static>(
+        mut self,
+        extractor: F,
+        max_distinct: usize,
+    ) -> Self {
+        self.distinct_limit = Some(DistinctLimit {
+            extractor: Rc::new(Box::new(extractor)),
+            max_distinct,
+        });
+        self
+    }
+
+    /// Block the clients which ignore the cooldown advertised when they are rejected, for
+    /// `penalty`, and spare the ones backing off correctly. Blocked clients are rejected like with
+    /// the [error limit](#method.with_error_limit), whatever their limit.
+    ///
+    /// The cooldown of a client starts with the first request it gets rejected for, and lasts
+    /// until the `x-ratelimit-reset` sent with it, when its limit resets. A client waiting for
+    /// it is never penalized. Up to `tolerance` more requests within the cooldown are tolerated,
+    /// for instance the ones already sent when the first rejection came back, the next one
+    /// blocks the client. The cooldown is kept in the store under `{key}:cooldown`, and the
+    /// block under `{key}:blocked`. This costs a store round trip for every request, and
+    /// another one for every rejected request. In [monitor only](#method.with_monitor_only)
+    /// mode no request is rejected, so no client is blocked.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Clients sending more than 5 requests before their reset are blocked for 10 minutes
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_backoff_penalty(5, Duration::from_secs(600));
+    /// }
+    /// ```
+    pub fn with_backoff_penalty(mut self, tolerance: usize, penalty: Duration) -> Self {
+        self.backoff_penalty = Some((tolerance, penalty));
+        self
+    }
+
+    /// Scale the limits down while the service answers slower than the target of `adaptive`,
+    /// and back up as it recovers, see [AdaptiveLimit](../adaptive/struct.AdaptiveLimit.html).
+    /// The factor applies to every limit of the limiter, including the
+    /// [boosts](#method.with_boosts), but not to [scope limits](#method.with_scope_limit), and
+    /// only the requests counted within their limit are timed. Limits become dynamic, and their
+    /// headers approximate.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{adaptive::AdaptiveLimit, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Created once, next to the store
+    ///     let adaptive = AdaptiveLimit::new(Duration::from_millis(200));
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_adaptive_limit(adaptive.clone());
+    /// }
+    /// ```
+    pub fn with_adaptive_limit(mut self, adaptive: AdaptiveLimit) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Tighten the limits to `start_fraction` of their value when the limiter is created, and
+    /// relax them linearly back to their full value over `warmup`, to spare cold caches and
+    /// backends while every client of a freshly deployed memory store gets a full quota. The
+    /// fraction applies to every limit of the limiter, like the
+    /// [adaptive limit](#method.with_adaptive_limit), after it.
+    ///
+    /// The warmup is per process, and starts when the limiter is created, usually in the
+    /// `HttpServer` factory as the server starts. It does not know whether the store is shared
+    /// and already warm, so a node restarting behind a redis store is tightened as well.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 20 requests per minute right after the start, up to 100 five minutes later
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_warmup(Duration::from_secs(300), 0.2);
+    /// }
+    /// ```
+    pub fn with_warmup(mut self, warmup: Duration, start_fraction: f64) -> Self {
+        self.warmup = Some((Instant::now(), warmup, start_fraction));
+        self
+    }
+
+    /// Shed requests when the store is saturated, instead of queueing them behind it, so that
+    /// the limiter does not amplify an overload of the store. Shed requests are handled
+    /// according to the [failure mode](#method.with_failure_mode): they are answered with 503
+    /// Service Unavailable when failing closed, or passed to the service without headers when
+    /// failing open. Each of them is reported to the [observer](#method.with_observer) with
+    /// [RateLimitEvent::Shed](../events/enum.RateLimitEvent.html).
+    ///
+    /// With [ShedThreshold::Latency](enum.ShedThreshold.html), the requests whose decision takes
+    /// longer are shed. The store may still count them once it gets to them. With
+    /// [ShedThreshold::QueueDepth](enum.ShedThreshold.html), the requests arriving while as
+    /// many are waiting for the store are shed without reaching it. The depth is counted for
+    /// each worker.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::middleware::{FailureMode, ShedThreshold};
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_failure_mode(FailureMode::FailOpen)
+    ///         .with_shed_threshold(ShedThreshold::Latency(Duration::from_millis(50)));
+    /// }
+    /// ```
+    pub fn with_shed_threshold(mut self, shed_threshold: ShedThreshold) -> Self {
+        self.shed_threshold = Some(shed_threshold);
+        self
+    }
+
+    /// Send an `x-ratelimit-used` header along with the others, for clients mirroring APIs which
+    /// report what was used rather than what is left. It is `x-ratelimit-limit` minus
+    /// `x-ratelimit-remaining`, in the [unit](#method.with_remaining_header) of these, on every
+    /// response which carries them. Defaults to `false`.
+    pub fn with_used_header(mut self, used_header: bool) -> Self {
+        self.used_header = used_header;
+        self
+    }
+
+    /// Send an `x-ratelimit-algorithm` header along with the others, with the
+    /// [name](enum.Algorithm.html#method.name) of the [algorithm](#method.with_algorithm) in
+    /// effect, to check which one counts the requests of a route when several limiters are
+    /// deployed. It tells clients how they are counted, so it is meant for debugging. Defaults
+    /// to `false`.
+    pub fn with_algorithm_header(mut self, algorithm_header: bool) -> Self {
+        self.algorithm_header = algorithm_header;
+        self
+    }
+
+    /// Name the headers of this limiter `{prefix}-limit`, `{prefix}-remaining` and so on, rather
+    /// than `x-ratelimit-limit`, `x-ratelimit-remaining`, for instance to report both limiters
+    /// when a burst limiter is stacked with a sustained one.
+    ///
+    /// Limiters sending the same headers merge them: an outer limiter keeps the values an inner
+    /// limiter already set on the response when they are more restrictive, that is fewer
+    /// requests remaining, or as many and the soonest reset. `limit`, `remaining`, `reset`,
+    /// `used` and `algorithm` are kept or replaced together, so that they always describe the
+    /// same limiter, the one closest to rejecting the client. Remaining counts are compared as
+    /// sent, in the [unit](#method.with_remaining_header) of each limiter. A warning is not
+    /// replaced by the softer one of the [soft limit](#method.with_soft_limit). A rejection
+    /// carries the headers of the limiter which rejected the request only.
+    ///
+    /// # Panics
+    /// If `prefix` does not make valid header names.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::{web, App, HttpResponse};
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let app = App::new()
+    ///         .wrap(
+    ///             RateLimiter::memory(&store, 1000, Duration::from_secs(3600))
+    ///                 .with_header_prefix("x-ratelimit-hour"),
+    ///         )
+    ///         .wrap(RateLimiter::memory(&store, 10, Duration::from_secs(1)))
+    ///         .route("/", web::get().to(HttpResponse::Ok));
+    /// }
+    /// ```
+    pub fn with_header_prefix(mut self, prefix: &str) -> Self {
+        let header_names = HeaderNames::new(prefix).expect("invalid header prefix");
+        self.header_names = Rc::new(header_names);
+        self
+    }
+
+    /// Send a `Retry-After` header with requests rejected for going over the limit, the seconds
+    /// until the reset plus a jitter of up to `max_jitter`, so that the clients rejected in a
+    /// window do not all retry on the second it ends. The jitter of a client comes from a hash of
+    /// its key, so that it advertises the same time to retry on every rejection, while clients
+    /// are spread over the jitter. A `max_jitter` of zero sends the reset as is. Without this
+    /// option, no `Retry-After` header is sent, and clients go by `x-ratelimit-reset`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Rejected clients retry within 10 seconds after the end of the window
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_retry_after_jitter(Duration::from_secs(10));
+    /// }
+    /// ```
+    pub fn with_retry_after_jitter(mut self, max_jitter: Duration) -> Self {
+        self.retry_after_jitter = Some(max_jitter);
+        self
+    }
+
+    /// Whether the ratelimit headers are added to the responses of the service which are not
+    /// successful (2xx), for instance to keep them off the 500s of a failing handler. Defaults
+    /// to `true`. The requests are counted either way, the `x-ratelimit-warning` and
+    /// `x-ratelimit-dryrun` headers are kept, and the responses the limiter sends itself, such
+    /// as 429 Too Many Requests, always carry the headers.
+    pub fn with_headers_on_errors(mut self, headers_on_errors: bool) -> Self {
+        self.headers_on_errors = headers_on_errors;
+        self
+    }
+
+    /// Specify the unit of the `x-ratelimit-limit` and `x-ratelimit-remaining` headers. With
+    /// costs reported through [ActualCost](struct.ActualCost.html) which vary widely, the raw
+    /// token count is hard to interpret for clients, who can instead be sent the number of
+    /// requests of a typical cost they have left. Defaults to
+    /// [RemainingHeader::Tokens](enum.RemainingHeader.html).
+    ///
+    /// Only the headers change, the limit is still enforced in tokens, and the
+    /// [soft limit](#method.with_soft_limit) still applies to them.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{middleware::RemainingHeader, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 1000 tokens, sent as 100 requests of 10 tokens
+    ///     let ratelimiter = RateLimiter::memory(&store, 1000, Duration::from_secs(60))
+    ///         .with_remaining_header(RemainingHeader::Requests { typical_cost: 10 });
+    /// }
+    /// ```
+    pub fn with_remaining_header(mut self, remaining_header: RemainingHeader) -> Self {
+        self.remaining_header = remaining_header;
+        self
+    }
+
+    /// Count in fixed point, with `scale` units per token, so that requests can cost fractions of
+    /// a token with a [FractionalCost](struct.FractionalCost.html), for instance limits
+    /// on the tokens of a language model. Defaults to 1, counts are whole tokens.
+    ///
+    /// Limits are still given in tokens, and multiplied by `scale` for the store, which counts
+    /// units: keep `scale` times the largest limit within `usize`, as limits saturate there. A
+    /// request costs one token unless it reports a cost, and only one unit is reserved up front,
+    /// so that the cheapest requests are not charged a whole token. Requests failing with a 5xx
+    /// are thus charged one unit only. The headers give tokens with the decimals of `scale`,
+    /// rounded down, such as `x-ratelimit-remaining: 1.5`, the other counts, such as
+    /// [RateLimitInfo](struct.RateLimitInfo.html) and the events, give units. The scale should
+    /// be a power of ten for the headers to be exact, and must not be zero, which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 100 tokens a minute, counted in thousandths
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_token_scale(1000);
+    /// }
+    /// ```
+    pub fn with_token_scale(mut self, scale: usize) -> Self {
+        self.token_scale = scale;
+        self
+    }
+
+    /// Specify the level of the messages logged when a request goes over the limit, and how the
+    /// identifier of the client, which can be personal data, appears in them. Defaults to
+    /// `Level::Info` with [LogKey::Plain](enum.LogKey.html).
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{middleware::LogKey, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_rejection_log(log::Level::Debug, LogKey::Hashed);
+    /// }
+    /// ```
+    pub fn with_rejection_log(mut self, level: Level, key: LogKey) -> Self {
+        self.rejection_log = (level, key);
+        self
+    }
+
+    /// Include the method and the path of the request in the messages logged when a request goes
+    /// over the limit, to tell which endpoint a client is hammering. This is off by default,
+    /// since paths can hold personal data as well. The
+    /// [observer](#method.with_observer) always gets them with
+    /// [RateLimitEvent::Rejected](../events/enum.RateLimitEvent.html).
+    pub fn with_rejection_log_path(mut self, log_path: bool) -> Self {
+        self.log_path = log_path;
+        self
+    }
+
+    /// Give the [observer](#method.with_observer) the trace id of rejected requests, with
+    /// [RateLimitEvent::Rejected](../events/enum.RateLimitEvent.html), for instance to attach
+    /// exemplars to a rejection counter and go from a spike of rejections to the traces. Requests
+    /// without a trace id, or when this is not set, which is the default, are reported with
+    /// `trace_id: None`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::events::RateLimitEvent;
+    /// use actix_ratelimit::middleware::TraceSource;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         // Or TraceSource::Header(HeaderName::from_static("x-request-id"))
+    ///         .with_trace_id(TraceSource::TraceParent)
+    ///         .with_observer(|event| {
+    ///             if let RateLimitEvent::Rejected { trace_id: Some(trace_id), .. } = event {
+    ///                 log::info!("rejected, trace_id={}", trace_id);
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    pub fn with_trace_id(mut self, trace_source: TraceSource) -> Self {
+        self.trace_source = Some(trace_source);
+        self
+    }
+
+    /// Specify what happens to a request when the identifier returns an error. Defaults to
+    /// a [Policy::SharedBucket](enum.Policy.html) named `unidentified`.
+    pub fn with_missing_identifier(mut self, policy: Policy) -> Self {
+        self.missing_identifier = policy;
+        self
+    }
+
+    /// With the `tracing` feature, the store round trips of every request are recorded in a
+    /// `ratelimit` span with the `key`, `decision` and `latency_ms` fields, and a child
+    /// `ratelimit.store` span for each operation. Set this to record a stable hash of the key
+    /// instead of the key itself, for identifiers which should not end up in traces.
+    pub fn with_hashed_span_keys(mut self, hash_span_keys: bool) -> Self {
+        self.hash_span_keys = hash_span_keys;
+        self
+    }
+
+    /// Specify what happens to a request when the store fails to answer. Defaults to
+    /// [FailureMode::FailClosed](enum.FailureMode.html).
+    pub fn with_failure_mode(mut self, failure_mode: FailureMode) -> Self {
+        self.failure_mode = failure_mode;
+        self
+    }
+
+    /// Function building the response sent when the store fails and the limiter
+    /// [fails closed](enum.FailureMode.html#variant.FailClosed), for instance to answer with the
+    /// error body of the rest of the API. Without it the error of the store is returned, which
+    /// actix renders as an `500 Internal Server Error`. Rejected requests are not affected.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::HttpResponse;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_store_error_handler(|_req, _error| {
+    ///             HttpResponse::ServiceUnavailable()
+    ///                 .content_type("application/json")
+    ///                 .body(r#"{"error": "try again later"}"#)
+    ///         });
+    /// }
+    /// ```
+    pub fn with_store_error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&ServiceRequest, &ARError) -> HttpResponse + 'static,
+    {
+        self.store_error_handler = Some(Rc::new(Box::new(handler)));
+        self
+    }
+
+    /// Push the end of the window of a client back to a whole _interval_ with each of its
+    /// requests, instead of ending it _interval_ after the first one, for idle-timeout
+    /// semantics: a client gets its quota back once it has been idle for _interval_. Defaults to
+    /// `false`.
+    ///
+    /// This changes the meaning of the window: a client sending requests steadily is never
+    /// reset, so _max_requests_ is what it can send until it pauses, and the
+    /// `x-ratelimit-reset` header always announces a whole _interval_. Rejected requests push
+    /// the window back too, a client over the limit has to stay idle for _interval_ to be let
+    /// in again. Only [Algorithm::FixedWindow](enum.Algorithm.html) supports it, which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks. The
+    /// expiry is refreshed with a compare-and-set, a store round trip for every request.
+    pub fn with_sliding_expiry(mut self, sliding_expiry: bool) -> Self {
+        self.sliding_expiry = sliding_expiry;
+        self
+    }
+
+    /// Go by the clock of the store rather than the local one to place requests in windows,
+    /// so that nodes sharing a store agree on when they start and end whatever the skew of
+    /// their clocks, and send the same reset headers. Defaults to false.
+    ///
+    /// The clock only matters to [Algorithm::SlidingWindowCounter](enum.Algorithm.html) and
+    /// [Algorithm::CalendarWindow](enum.Algorithm.html), which align their windows on it, the
+    /// fixed window goes by the expiry of its key, which the store keeps. It costs them a store
+    /// round trip for every request, `TIME` with redis, the statistics of the first server with
+    /// memcached, while the memory store, which is local, answers with the local clock. The
+    /// [prefilter](#method.with_prefilter) still counts with the local clock.
+    pub fn with_store_clock(mut self, store_clock: bool) -> Self {
+        self.store_clock = store_clock;
+        self
+    }
+
+    /// Lets the clients far from their limit through without asking the store, for the hottest
+    /// paths where a store round trip per request is too much. `prefilter` counts the requests
+    /// of every client approximately, in memory, and only sends the clients past half of their
+    /// limit to the store, see [PreFilter](../prefilter/struct.PreFilter.html) for how.
+    ///
+    /// The approximation only ever errs on the safe side: a client over its limit is never let
+    /// through, but some clients under it pay for the store lookup, and can be rejected a little
+    /// before their limit when their counters collide with others'. Only requests counted
+    /// against _max_requests_ and _interval_ are prefiltered, the ones matching a
+    /// [path limit](#method.with_path_limit), a [scope limit](#method.with_scope_limit), or
+    /// given another limit by the [limit resolver](#method.with_limit_resolver) or the
+    /// [shared limit](#method.with_shared_limit) always go to the store. Only
+    /// [Algorithm::FixedWindow](enum.Algorithm.html) supports it, without
+    /// [sliding expiry](#method.with_sliding_expiry), which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{prefilter::PreFilter, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Created once, next to the store
+    ///     let prefilter = PreFilter::new(1 << 20);
+    ///     let ratelimiter = RateLimiter::memory(&store, 1000, Duration::from_secs(60))
+    ///         .with_prefilter(prefilter.clone());
+    /// }
+    /// ```
+    pub fn with_prefilter(mut self, prefilter: PreFilter) -> Self {
+        self.prefilter = Some(prefilter);
+        self
+    }
+
+    /// Read the boosts granted to clients with [stores::grant_boost](../stores/fn.grant_boost.html),
+    /// and raise their limit by it while the boost lasts. Defaults to false.
+    ///
+    /// A boost stacks on the limit the request gets otherwise, from a
+    /// [path limit](#method.with_path_limit), the [limit resolver](#method.with_limit_resolver)
+    /// or the [shared limit](#method.with_shared_limit), but not on
+    /// [scope limits](#method.with_scope_limit), which are not per client. It is looked up
+    /// under the key of the client, with the prefix of its path limit if any. When a boost ends
+    /// before the window, the remaining requests are capped at the limit without it, as when the
+    /// limit goes down. This costs a store round trip for every request, and a store failure
+    /// reading the boost is logged and leaves the limit as is.
+    pub fn with_boosts(mut self, boosts: bool) -> Self {
+        self.boosts = boosts;
+        self
+    }
+
+    /// Count the requests carrying the idempotency key found in `header` once per window, so
+    /// that clients retrying an operation are not charged for every attempt. Requests
+    /// without the header are counted as usual.
+    ///
+    /// The first request with a key is counted against the limit, and if let through, the key
+    /// is recorded in the store under `{key}:idempotency:{hash}`, hashed along with the method
+    /// and path of the request, until the end of the window. Up to five later requests of the
+    /// client with the same key, method and path are let through without being counted,
+    /// whatever is left of the limit, like [peeks](#method.with_peek_methods), and reported as
+    /// [DecisionReason::Retry](enum.DecisionReason.html); further ones are counted as new
+    /// requests. A request which was not let through is not recorded, so its retry is counted,
+    /// and rejected when the limit is reached. Once the window ends, the key is forgotten with
+    /// it, and a retry counts as a new request. Two attempts sent at once may both be counted,
+    /// and a store failure checking the key counts the request. This costs two store round trips
+    /// for every retry, one for every other request with the header, and another one for each
+    /// new key.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::http::HeaderName;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_idempotency_key(HeaderName::from_static("idempotency-key"));
+    /// }
+    /// ```
+    pub fn with_idempotency_key(mut self, header: HeaderName) -> Self {
+        self.idempotency_header = Some(header);
+        self
+    }
+
+    /// Count the bytes of the request bodies rather than the requests, for quotas of bytes
+    /// uploaded per window: the cost of a request is its `Content-Length`, capped to the limit,
+    /// and `default_cost` without the header. The limit is then a number of bytes.
+    ///
+    /// A request larger than what its client has left is rejected before its body is read, and
+    /// the byte it reserved is given back. With a
+    /// [throttle delay](struct.RateLimiter.html#method.with_throttle_delay), it waits for a new
+    /// window like any other rejected request, and is checked again then. Otherwise one byte is
+    /// reserved up front, and the rest charged once the service answered, like an [ActualCost](struct.ActualCost.html), which the handler
+    /// can still report to take precedence.
+    ///
+    /// Chunked requests have no `Content-Length`, and their size is only known once the body
+    /// is read. They cost `default_cost`, unless the handler counts the bytes it read and
+    /// reports them as an `ActualCost`. Since the body was read by then, a client can upload
+    /// more than it has left with its last chunked request.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 100 MB per hour, chunked uploads count as 1 MB until the handler tells otherwise
+    ///     let ratelimiter = RateLimiter::memory(&store, 100_000_000, Duration::from_secs(3600))
+    ///         .with_content_length_cost(1_000_000);
+    /// }
+    /// ```
+    pub fn with_content_length_cost(mut self, default_cost: usize) -> Self {
+        self.content_length_cost = Some(default_cost);
+        self
+    }
+
+    /// Charge every request at least `min_cost` units of the
+    /// [token scale](#method.with_token_scale), tokens without one, whatever cost it reports.
+    /// Defaults to 1, the unit reserved up front.
+    ///
+    /// This is a safety net for the [ActualCost](struct.ActualCost.html) and
+    /// [FractionalCost](struct.FractionalCost.html) computed by the handlers: one reporting a
+    /// cost of zero by mistake would otherwise make requests almost free. Reported costs under
+    /// the floor are raised to it with a warning, so that the mistake shows in the logs. The
+    /// floor must not be zero, which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 100 tokens a minute, requests cost at least a tenth of a token
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_token_scale(1000)
+    ///         .with_min_cost(100);
+    /// }
+    /// ```
+    pub fn with_min_cost(mut self, min_cost: usize) -> Self {
+        self.min_cost = min_cost;
+        self
+    }
+
+    /// Give back `fraction` of their cost to the requests the handler marks with
+    /// [CacheHit](struct.CacheHit.html), so that requests served from a cache cost less than the
+    /// ones going to the origin. Without this option, which is the default, cache hits cost as
+    /// much as any request.
+    ///
+    /// The reservation taken up front is adjusted once the response is ready, like for an
+    /// [ActualCost](struct.ActualCost.html): a cache hit is charged its cost, reported or not,
+    /// minus `fraction` of it rounded to the nearest unit of the
+    /// [token scale](#method.with_token_scale), and at least the
+    /// [minimum cost](#method.with_min_cost) when one is set. The `x-ratelimit-remaining`
+    /// header is sent once the discounted cost is charged. A cache hit which costs nothing
+    /// after the discount gives back the unit reserved up front, like a
+    /// [Refund](struct.Refund.html), so that with the default scale, where requests cost a
+    /// single unit, a `fraction` of one half or more makes cache hits free, and a smaller one
+    /// leaves them at full price. `fraction` must be within 0 and 1,
+    /// which [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks,
+    /// and is clamped to it otherwise.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Cache hits cost a tenth of a request
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_token_scale(10)
+    ///         .with_cache_hit_refund(0.9);
+    /// }
+    /// ```
+    pub fn with_cache_hit_refund(mut self, fraction: f64) -> Self {
+        self.cache_hit_refund = Some(fraction);
+        self
+    }
+
+    /// Add a store named `id`, which the [store selector](#method.with_store_selector) can pick
+    /// for a request instead of the store the limiter was created with, for instance a durable
+    /// redis store for trusted partners next to a fast memory store for anonymous traffic.
+    /// Stores of different types can be given as [AnyStore](../stores/struct.AnyStore.html),
+    /// the limiter then being created on an `AnyStore` as well.
+    ///
+    /// Each store keeps its own counts, a client counted in one store is not counted in the
+    /// others. Everything else is the same whichever store decides: the limit, the headers, the
+    /// algorithm and the options of the limiter, which are shared, so that a store failure, the
+    /// [recovery grace](#method.with_recovery_grace) or the [statistics](#method.stats) follow
+    /// all the stores together. [validate](#method.validate) checks all the stores.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix::prelude::*;
+    /// use actix_ratelimit::stores::AnyStore;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     // A redis store in production
+    ///     let durable = MemoryStoreActor::from(MemoryStore::new()).start();
+    ///     let local = MemoryStoreActor::from(MemoryStore::new()).start();
+    ///     let ratelimiter = RateLimiter::new(AnyStore::from(durable).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_store("local", AnyStore::from(local).start())
+    ///         .with_store_selector(|req| {
+    ///             if req.headers().contains_key("x-partner-key") {
+    ///                 "durable"
+    ///             } else {
+    ///                 "local"
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    pub fn with_store(mut self, id: StoreId, store: Addr<T>) -> Self {
+        Rc::make_mut(&mut self.stores).push((id, store));
+        self
+    }
+
+    /// Function picking the store of every request among the ones added with
+    /// [with_store](#method.with_store). An id which is not the one of an added store, such as
+    /// `"default"`, picks the store the limiter was created with.
+    pub fn with_store_selector<F: Fn(&ServiceRequest) -> StoreId + 'static>(
+        mut self,
+        selector: F,
+    ) -> Self {
+        self.store_selector = Some(Rc::new(Box::new(selector)));
+        self
+    }
+
+    /// Spread the clients over `shards`, for instance several redis instances for a key space
+    /// too large for one. Every request goes to the shard the
+    /// [shard selector](#method.with_shard_selector) picks for the key of its client, and so
+    /// do the keys derived from it, such as `{key}:blocked`, so that a client is counted in a
+    /// single shard. The store the limiter was created with is not used for counting once
+    /// shards are set, it can be given again as one of them. Shards can not be combined with a
+    /// [store selector](#method.with_store_selector), and [validate](#method.validate) checks
+    /// all of them.
+    ///
+    /// Admin operations go to the shards too: a client is reset on the shard
+    /// [shard_for](#method.shard_for) its [key](#method.key_for), and
+    /// [stores::list_shards](../stores/fn.list_shards.html) lists the clients of all the shards.
+    /// Changing the shards or the selector moves clients to other shards, where they start
+    /// with a full quota.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix::prelude::*;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     // Redis stores in production
+    ///     let shards: Vec<_> = (0..4)
+    ///         .map(|_| MemoryStoreActor::from(MemoryStore::new()).start())
+    ///         .collect();
+    ///     let ratelimiter = RateLimiter::new(shards[0].clone())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_shards(shards);
+    /// }
+    /// ```
+    pub fn with_shards(mut self, shards: Vec<Addr<T>>) -> Self {
+        self.shards = Rc::new(shards);
+        self
+    }
+
+    /// Function giving the index of the [shard](#method.with_shards) of a client from its key,
+    /// taken modulo the number of shards. Defaults to a hash of the key, which spreads the
+    /// clients evenly.
+    pub fn with_shard_selector<F: Fn(&str) -> usize + 'static>(mut self, selector: F) -> Self {
+        self.shard_selector = Some(Rc::new(Box::new(selector)));
+        self
+    }
+
+    /// Shard of the client whose store key is `key`, as given by
+    /// [key_for](#method.key_for), or the store of the limiter without
+    /// [shards](#method.with_shards).
+    pub fn shard_for(&self, key: &str) -> &Addr<T> {
+        pick_shard(&self.shards, &self.shard_selector, key).unwrap_or(&self.store)
+    }
+
+    /// Statistics of the store round trips of this limiter: latency percentiles and error rate,
+    /// for a quick health read without a metrics stack. The returned handle stays valid once
+    /// the limiter is given to `wrap`, keep it to read the statistics later on.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60));
+    ///     let stats = ratelimiter.stats();
+    ///     // ... serve requests, then
+    ///     let snapshot = stats.snapshot();
+    ///     stats.reset();
+    /// }
+    /// ```
+    pub fn stats(&self) -> StoreStats {
+        self.stats.clone()
+    }
+
+    /// Record the statistics of the store round trips in `stats` instead of statistics of this
+    /// limiter's own. Give the same statistics to the limiters of all the workers to read them
+    /// at once, see [StoreStats](../stats/struct.StoreStats.html).
+    pub fn with_stats(mut self, stats: StoreStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Describes the effective configuration of the limiter, to check what is deployed, for
+    /// instance from a debug endpoint, see
+    /// [RateLimiterDescription](../config/struct.RateLimiterDescription.html).
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_monitor_only(true);
+    ///     let description = ratelimiter.describe();
+    ///     assert_eq!(description.algorithm.name(), "fixed-window");
+    ///     assert_eq!(description.features, vec!["monitor_only"]);
+    /// }
+    /// ```
+    pub fn describe(&self) -> RateLimiterDescription {
+        // Bound field by field so that an option added to the limiter cannot be left out of its
+        // description.
+        let RateLimiter {
+            interval,
+            max_requests,
+            // Reported as the type of the store
+            store: _,
+            // Always set, to the peer address by default
+            identifier: _,
+            async_identifier,
+            previous_identifier,
+            failure_mode,
+            store_error_handler,
+            algorithm,
+            observer,
+            allowlist,
+            content_type_filter,
+            should_limit,
+            concurrency,
+            resolver,
+            shared_limit,
+            soft_limit,
+            token_scale,
+            missing_identifier,
+            hash_span_keys,
+            path_limits,
+            stores,
+            store_selector,
+            shards,
+            shard_selector,
+            scope_limits,
+            key_normalization,
+            max_key_length,
+            oversized_key,
+            monitor_only,
+            switch,
+            over_limit_grace,
+            recovery_grace,
+            throttle_delay,
+            sliding_expiry,
+            store_clock,
+            boosts,
+            idempotency_header,
+            content_length_cost,
+            min_cost,
+            cache_hit_refund,
+            prefilter,
+            // Always kept, shared or not
+            stats: _,
+            error_limit,
+            distinct_limit,
+            backoff_penalty,
+            adaptive,
+            warmup,
+            remaining_header,
+            used_header,
+            algorithm_header,
+            header_names,
+            retry_after_jitter,
+            headers_on_errors,
+            shed_threshold,
+            rejection_log,
+            log_path,
+            trace_source,
+            peek_methods,
+        } = self;
+        let limit = match shared_limit {
+            Some(limit) => limit.get(),
+            None => Limit {
+                max_requests: *max_requests,
+                interval: *interval,
+            },
+        };
+        let enabled = [
+            ("async_identifier", async_identifier.is_some()),
+            ("previous_identifier", previous_identifier.is_some()),
+            (
+                "missing_identifier",
+                *missing_identifier != Policy::default(),
+            ),
+            ("hashed_span_keys", *hash_span_keys),
+            (
+                "key_normalization",
+                *key_normalization != Normalization::None,
+            ),
+            ("max_key_length", max_key_length.is_some()),
+            ("oversized_key", *oversized_key != OversizedKey::Hash),
+            ("monitor_only", *monitor_only),
+            ("switch", switch.is_some()),
+            ("over_limit_grace", *over_limit_grace > 0),
+            ("recovery_grace", recovery_grace.is_some()),
+            ("throttle_delay", throttle_delay.is_some()),
+            ("error_limit", error_limit.is_some()),
+            ("distinct_limit", distinct_limit.is_some()),
+            ("backoff_penalty", backoff_penalty.is_some()),
+            ("adaptive_limit", adaptive.is_some()),
+            ("warmup", warmup.is_some()),
+            ("shed_threshold", shed_threshold.is_some()),
+            (
+                "remaining_header",
+                *remaining_header != RemainingHeader::Tokens,
+            ),
+            ("used_header", *used_header),
+            ("algorithm_header", *algorithm_header),
+            ("header_prefix", header_names.limit != "x-ratelimit-limit"),
+            ("retry_after_jitter", retry_after_jitter.is_some()),
+            ("headers_on_errors", !*headers_on_errors),
+            ("token_scale", *token_scale > 1),
+            (
+                "rejection_log",
+                *rejection_log != (Level::Info, LogKey::Plain),
+            ),
+            ("rejection_log_path", *log_path),
+            ("trace_id", trace_source.is_some()),
+            ("store_error_handler", store_error_handler.is_some()),
+            ("sliding_expiry", *sliding_expiry),
+            ("store_clock", *store_clock),
+            ("prefilter", prefilter.is_some()),
+            ("boosts", *boosts),
+            ("idempotency_key", idempotency_header.is_some()),
+            ("content_length_cost", content_length_cost.is_some()),
+            ("min_cost", *min_cost > 1),
+            ("cache_hit_refund", cache_hit_refund.is_some()),
+            ("store", !stores.is_empty()),
+            ("store_selector", store_selector.is_some()),
+            ("shard_selector", shard_selector.is_some()),
+            ("observer", observer.is_some()),
+            ("soft_limit", soft_limit.is_some()),
+            ("shared_limit", shared_limit.is_some()),
+            ("scope_limit", !scope_limits.is_empty()),
+            ("peek_methods", !peek_methods.is_empty()),
+            ("allowlist", allowlist.is_some()),
+            ("content_type_filter", content_type_filter.is_some()),
+            ("should_limit", should_limit.is_some()),
+            ("concurrency_limit", concurrency.is_some()),
+        ];
+        RateLimiterDescription {
+            algorithm: *algorithm,
+            max_requests: limit.max_requests,
+            interval: limit.interval.as_secs(),
+            failure_mode: *failure_mode,
+            paths: path_limits
+                .iter()
+                .map(|(prefix, limit)| PathLimitConfig {
+                    prefix: prefix.clone(),
+                    max_requests: limit.max_requests,
+                    interval: limit.interval.as_secs(),
+                })
+                .collect(),
+            dynamic_limits: resolver.is_some(),
+            store: std::any::type_name::<T>(),
+            shards: shards.len().max(1),
+            features: enabled
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+        }
+    }
+
+    /// Specify how requests are counted. Defaults to
+    /// [Algorithm::FixedWindow](enum.Algorithm.html).
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Function called with every [RateLimitEvent](../events/enum.RateLimitEvent.html) emitted
+    /// by the middleware.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_ratelimit::events::RateLimitEvent;
+    /// use actix_ratelimit::middleware::FailureMode;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_failure_mode(FailureMode::FailOpen)
+    ///         .with_observer(|event| match event {
+    ///             RateLimitEvent::Degraded { error } => eprintln!("store failing: {}", error),
+    ///             RateLimitEvent::Recovered { degraded_for } => {
+    ///                 eprintln!("store recovered after {:?}", degraded_for)
+    ///             }
+    ///             _ => {}
+    ///         });
+    /// }
+    /// ```
+    pub fn with_observer<F: Fn(&RateLimitEvent) + 'static>(mut self, observer: F) -> Self {
+        self.observer = Some(Rc::new(Box::new(observer)));
+        self
+    }
+
+    /// Warn clients approaching their limit. Once a client has used `fraction` of
+    /// _max_requests_ in the current window, allowed responses get an
+    /// `x-ratelimit-warning: approaching limit` header, until requests are rejected at the hard
+    /// limit. Rejected responses never carry the warning. For instance with 100 requests and a
+    /// soft limit of `0.8`, the 80th to 100th requests are warned, and the 101st is rejected.
+    ///
+    /// `fraction` should be within `0.0` (exclusive) and `1.0` (inclusive), which
+    /// [RateLimiterBuilder::build](struct.RateLimiterBuilder.html#method.build) checks.
+    pub fn with_soft_limit(mut self, fraction: f64) -> Self {
+        self.soft_limit = Some(fraction);
+        self
+    }
+
+    /// Function to pick the limit of every request, for instance from the tier of the client.
+    /// The configured _max_requests_ and _interval_ are only used when no resolver is set.
+    ///
+    /// The limit resolved for the first request of a window is stored along with it: its
+    /// interval is the expiry of the window, and the reset header. When a later request of the
+    /// same window resolves to another limit, for instance because the client changed tier, the
+    /// window keeps its expiry, and the remaining requests are capped at the new
+    /// _max_requests_. An upgraded client thus waits for the current window to end before getting
+    /// the higher limit, and a downgraded client can not use the leftovers of the higher one.
+    /// With [Algorithm::SlidingWindowCounter](enum.Algorithm.html), windows are aligned on the
+    /// interval, so a new interval starts counting in windows of its own.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{middleware::Limit, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_limit_resolver(|req| {
+    ///             if req.headers().contains_key("x-paid") {
+    ///                 Limit { max_requests: 10_000, interval: Duration::from_secs(3600) }
+    ///             } else {
+    ///                 Limit { max_requests: 100, interval: Duration::from_secs(60) }
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    ///
+    /// # Tiers from the authentication
+    ///
+    /// The resolver gets the request with its extensions, so it can read the user an
+    /// authentication middleware stored there. The limiter has to run after the authentication,
+    /// that is to be registered before it with `wrap`, since the last middleware registered runs
+    /// first. Anonymous requests, which the authentication let through without a user, get a
+    /// limit of their own.
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::{dev::Service, web, App, HttpMessage, HttpResponse};
+    /// use actix_ratelimit::{identifiers, middleware::Limit, MemoryStore, RateLimiter};
+    ///
+    /// enum Tier {
+    ///     Free,
+    ///     Pro,
+    /// }
+    ///
+    /// struct User {
+    ///     id: u64,
+    ///     tier: Tier,
+    /// }
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let per_minute = |max_requests| Limit { max_requests, interval: Duration::from_secs(60) };
+    ///     let app = App::new()
+    ///         // Runs second
+    ///         .wrap(
+    ///             RateLimiter::memory(&store, 10, Duration::from_secs(60))
+    ///                 .with_identifier(|req| {
+    ///                     identifiers::from_extension(|user: &User| format!("user:{}", user.id))(req)
+    ///                         .or_else(|_| identifiers::peer_ip(req))
+    ///                 })
+    ///                 .with_limit_resolver(move |req| match req.extensions().get::<User>() {
+    ///                     Some(User { tier: Tier::Pro, .. }) => per_minute(1000),
+    ///                     Some(User { tier: Tier::Free, .. }) => per_minute(100),
+    ///                     None => per_minute(10),
+    ///                 }),
+    ///         )
+    ///         // Runs first, the authentication storing the `User`
+    ///         .wrap_fn(|req, srv| srv.call(req))
+    ///         .route("/", web::get().to(HttpResponse::Ok));
+    /// }
+    /// ```
+    pub fn with_limit_resolver<F: Fn(&ServiceRequest) -> Limit + 'static>(
+        mut self,
+        resolver: F,
+    ) -> Self {
+        self.resolver = Some(Rc::new(Box::new(resolver)));
+        self
+    }
+
+    /// Read the limit from `limit` on every request instead of the configured _max_requests_ and
+    /// _interval_, so that it can be changed at runtime with
+    /// [SharedLimit::set](struct.SharedLimit.html#method.set). A
+    /// [limit resolver](#method.with_limit_resolver) and [path limits](#method.with_path_limit)
+    /// take precedence.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::middleware::{Limit, SharedLimit};
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let limit = SharedLimit::new(Limit {
+    ///         max_requests: 100,
+    ///         interval: Duration::from_secs(60),
+    ///     });
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_shared_limit(limit.clone());
+    /// }
+    /// ```
+    pub fn with_shared_limit(mut self, limit: SharedLimit) -> Self {
+        self.shared_limit = Some(limit);
+        self
+    }
+
+    /// Apply `limit` to the requests whose path starts with `prefix`, for instance to give each
+    /// version of an API a quota of its own with `/v1` and `/v2`. A prefix matches whole path
+    /// segments, so `/v1` matches `/v1` and `/v1/users` but not `/v10`, and the longest
+    /// matching prefix wins. Path limits take precedence over the
+    /// [limit resolver](#method.with_limit_resolver).
+    ///
+    /// Requests matching a prefix are counted under a key namespaced by it, so counters do not
+    /// merge across prefixes: a client migrating from `/v1` to `/v2` starts with a fresh bucket,
+    /// and the `/v1` one simply expires. Requests matching no prefix keep the plain identifier.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{middleware::Limit, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_path_limit("/v1", Limit { max_requests: 10, interval: Duration::from_secs(60) })
+    ///         .with_path_limit("/v2", Limit { max_requests: 1000, interval: Duration::from_secs(60) });
+    /// }
+    /// ```
+    pub fn with_path_limit<S: Into<String>>(mut self, prefix: S, limit: Limit) -> Self {
+        let prefix = prefix.into();
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Rc::make_mut(&mut self.path_limits).push((prefix, limit));
+        self
+    }
+
+    /// Apply `limit` to all the requests whose path starts with `prefix` together, whichever
+    /// client sends them, for instance to protect an expensive `/admin` scope. Prefixes match
+    /// like with [with_path_limit](#method.with_path_limit) and the longest one wins.
+    ///
+    /// The scope limit comes on top of the limit of each client, and a request has to be within
+    /// both. The limit of the client is checked first: a client over its own limit is rejected
+    /// without using the scope, so a single client can not use more of the scope than its own
+    /// limit. A request rejected by the scope still counts against its client. The headers
+    /// report the limit of the client, but the reset of the scope when it rejects the request.
+    /// The scope is counted under `scope:{prefix}` and is charged one request each time,
+    /// whatever [cost](struct.ActualCost.html) the service reports.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{middleware::Limit, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 100 requests per client, and 500 for the whole admin subsystem
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_scope_limit("/admin", Limit { max_requests: 500, interval: Duration::from_secs(60) });
+    /// }
+    /// ```
+    pub fn with_scope_limit<S: Into<String>>(mut self, prefix: S, limit: Limit) -> Self {
+        let prefix = prefix.into();
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Rc::make_mut(&mut self.scope_limits).push((prefix, limit));
+        self
+    }
+
+    /// Let clients probe their quota for free with the given methods, typically `HEAD` and
+    /// `OPTIONS`. Requests with these methods are passed to the service with the current
+    /// ratelimit headers, read from the store without being counted, and are never rejected nor
+    /// subject to the [concurrency limit](#method.with_concurrency_limit). Unlike requests
+    /// exempted with an [allowlist](#method.with_allowlist), which get no headers at all, they
+    /// tell the client where it stands.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::http::Method;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_peek_methods(vec![Method::HEAD, Method::OPTIONS]);
+    /// }
+    /// ```
+    pub fn with_peek_methods<I: IntoIterator<Item = Method>>(mut self, methods: I) -> Self {
+        self.peek_methods = Rc::new(methods.into_iter().collect());
+        self
+    }
+
+    /// Function to exempt requests from rate limiting. Requests for which it returns `true` are
+    /// passed to the service without touching the store, and without ratelimit headers.
+    pub fn with_allowlist<F: Fn(&ServiceRequest) -> bool + 'static>(
+        mut self,
+        allowlist: F,
+    ) -> Self {
+        self.allowlist = Some(Rc::new(Box::new(allowlist)));
+        self
+    }
+
+    /// Function choosing the requests the limiter applies to by their payload, typically with
+    /// [predicates::has_content_type](../predicates/fn.has_content_type.html), so that
+    /// several limiters share a route, each for its own content types. Requests for which it
+    /// returns `false` are passed to the service like [allowlisted](#method.with_allowlist)
+    /// ones, without touching the store and without ratelimit headers from this limiter: the
+    /// headers a client sees are the ones of the limiter which counted its request. It applies
+    /// after the allowlist.
+    ///
+    /// # Example
+    /// An API limit for JSON requests, and a bandwidth limit for uploads:
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::{web, App, HttpResponse};
+    /// use actix_ratelimit::{predicates, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let app = App::new()
+    ///         .wrap(
+    ///             RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///                 .with_content_type_filter(predicates::has_content_type("application/json")),
+    ///         )
+    ///         .wrap(
+    ///             RateLimiter::memory(&store, 100_000_000, Duration::from_secs(3600))
+    ///                 .with_content_type_filter(predicates::has_content_type("multipart/form-data"))
+    ///                 .with_content_length_cost(1_000_000)
+    ///                 .with_identifier(|req| {
+    ///                     actix_ratelimit::identifiers::peer_ip(req).map(|ip| format!("upload:{}", ip))
+    ///                 }),
+    ///         )
+    ///         .route("/", web::post().to(HttpResponse::Ok));
+    /// }
+    /// ```
+    pub fn with_content_type_filter<F: Fn(&ServiceRequest) -> bool + 'static>(
+        mut self,
+        filter: F,
+    ) -> Self {
+        self.content_type_filter = Some(Rc::new(Box::new(filter)));
+        self
+    }
+
+    /// Function deciding whether the limiter applies to a request at all, on anything in the
+    /// request, for the cases the narrower filters do not cover, such as a combination of
+    /// headers. Requests for which it returns `false` are passed to the service without
+    /// touching the store and without ratelimit headers, like
+    /// [allowlisted](#method.with_allowlist) ones.
+    ///
+    /// It runs before any store call, after the allowlist and the
+    /// [content type filter](#method.with_content_type_filter): an allowlisted request is
+    /// never limited, whatever this function returns, and a request is only limited when
+    /// every filter agrees. The limiter has no denylist, requests to refuse outright belong
+    /// in a guard or the service. The function runs for every request, and should be cheap.
+    ///
+    /// # Example
+    /// Limit the writes of anonymous clients only:
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::http::{header, Method};
+    /// use actix_ratelimit::{MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::memory(&store, 10, Duration::from_secs(60))
+    ///         .with_should_limit(|req| {
+    ///             req.method() != Method::GET
+    ///                 && !req.headers().contains_key(header::AUTHORIZATION)
+    ///         });
+    /// }
+    /// ```
+    pub fn with_should_limit<F: Fn(&ServiceRequest) -> bool + 'static>(
+        mut self,
+        should_limit: F,
+    ) -> Self {
+        self.should_limit = Some(Rc::new(Box::new(should_limit)));
+        self
+    }
+
+    /// Same as [with_allowlist](#method.with_allowlist), but the function also receives the
+    /// application data of type `K`, registered with `App::data` or `App::app_data(Data::new(..))`.
+    /// Requests are never exempted when no such data is registered.
+    ///
+    /// # Example
+    /// Exempting internal services which sign their requests:
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_web::{web, App};
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// struct SigningKey(Vec<u8>);
+    ///
+    /// impl SigningKey {
+    ///     fn verify(&self, signature: &[u8]) -> bool {
+    ///         // check the HMAC of the request here
+    ///         signature == self.0.as_slice()
+    ///     }
+    /// }
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let app = App::new()
+    ///         .data(SigningKey(b"secret".to_vec()))
+    ///         .wrap(
+    ///             RateLimiter::new(MemoryStoreActor::from(store).start())
+    ///                 .with_interval(Duration::from_secs(60))
+    ///                 .with_max_requests(100)
+    ///                 .with_allowlist_data(|req, key: &web::Data<SigningKey>| {
+    ///                     req.headers()
+    ///                         .get("x-signature")
+    ///                         .map(|signature| key.verify(signature.as_bytes()))
+    ///                         .unwrap_or(false)
+    ///                 }),
+    ///         );
+    /// }
+    /// ```
+    pub fn with_allowlist_data<K, F>(mut self, allowlist: F) -> Self
+    where
+        K: 'static,
+        F: Fn(&ServiceRequest, &Data<K>) -> bool + 'static,
+    {
+        self.allowlist = Some(Rc::new(Box::new(move |req: &ServiceRequest| {
+            match req.app_data::<Data<K>>() {
+                Some(data) => (allowlist)(req, data),
+                None => {
+                    warn!("Allowlist data is not registered in the application");
+                    false
+                }
+            }
+        })));
+        self
+    }
+
+    /// Additionally caps the number of requests a client can have in flight. Requests are first
+    /// checked against the rate limit, then take a slot of the concurrency limit which is held
+    /// until the service returns its response, fails, or the request is dropped. Requests for
+    /// which no slot is left are rejected with 429 Too Many Requests; they still count against
+    /// the rate limit.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{concurrency::ConcurrencyLimit, MemoryStore, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Created once, next to the store
+    ///     let concurrency = ConcurrencyLimit::new(2);
+    ///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+    ///         .with_concurrency_limit(concurrency.clone());
+    /// }
+    /// ```
+    pub fn with_concurrency_limit(mut self, concurrency: ConcurrencyLimit) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+}
+
+/// Builder for [RateLimiter](struct.RateLimiter.html) which validates the configuration.
+///
+/// Unlike the `with_*` methods of `RateLimiter`, which silently keep their defaults when they are
+/// not called, [build](#method.build) fails with a
+/// [ConfigError](../errors/enum.ConfigError.html) naming the field which is missing or invalid.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::builder(MemoryStoreActor::from(store).start())
+///         .with_interval(Duration::from_secs(60))
+///         .with_max_requests(100)
+///         .configure(|limiter| limiter.with_identifier(actix_ratelimit::identifiers::real_ip))
+///         .build()
+///         .expect("invalid ratelimiter configuration");
+/// }
+/// ```
+pub struct RateLimiterBuilder<T>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    limiter: RateLimiter<T>,
+    interval: Option<Duration>,
+    max_requests: Option<usize>,
+}
+
+impl<T> RateLimiterBuilder<T>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Specify the interval, at least one second. Required.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Specify the maximum number of requests allowed in the interval, at least one. Required.
+    pub fn with_max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Applies the optional settings through the `with_*` methods of
+    /// [RateLimiter](struct.RateLimiter.html)
+    pub fn configure<F: FnOnce(RateLimiter<T>) -> RateLimiter<T>>(mut self, f: F) -> Self {
+        self.limiter = (f)(self.limiter);
+        self
+    }
+
+    /// Validates the configuration and returns the limiter.
+    ///
+    /// Besides the values of single options, this rejects the combinations of options which can
+    /// not work together:
+    ///
+    /// | Option | Incompatible with | Why |
+    /// |--------|-------------------|-----|
+    /// | [with_monitor_only](struct.RateLimiter.html#method.with_monitor_only) | [with_over_limit_grace](struct.RateLimiter.html#method.with_over_limit_grace) | no request is rejected, so the grace is never used |
+    /// | [with_monitor_only](struct.RateLimiter.html#method.with_monitor_only) | [with_throttle_delay](struct.RateLimiter.html#method.with_throttle_delay) | no request is over the limit, so none is delayed |
+    /// | [with_sliding_expiry](struct.RateLimiter.html#method.with_sliding_expiry) | [with_algorithm](struct.RateLimiter.html#method.with_algorithm), but for the fixed window | the other algorithms align their windows on the clock |
+    /// | [with_prefilter](struct.RateLimiter.html#method.with_prefilter) | [with_algorithm](struct.RateLimiter.html#method.with_algorithm), but for the fixed window | the prefilter counts in fixed windows |
+    /// | [with_previous_identifier](struct.RateLimiter.html#method.with_previous_identifier) | [with_algorithm](struct.RateLimiter.html#method.with_algorithm), but for the fixed window | the other algorithms keep several keys per client |
+    /// | [with_prefilter](struct.RateLimiter.html#method.with_prefilter) | [with_sliding_expiry](struct.RateLimiter.html#method.with_sliding_expiry) | requests let through by the prefilter do not push the window back |
+    /// | [with_token_scale](struct.RateLimiter.html#method.with_token_scale) | [Algorithm::Ewma](enum.Algorithm.html#variant.Ewma) | the moving average counts whole requests |
+    /// | [with_distinct_limit](struct.RateLimiter.html#method.with_distinct_limit) | a [MemcacheStore](../struct.MemcacheStore.html) | memcached keeps no sets |
+    /// | [with_shards](struct.RateLimiter.html#method.with_shards) | [with_store_selector](struct.RateLimiter.html#method.with_store_selector) | both pick the store of a request, a key would be counted in either |
+    ///
+    /// and the values which would disable the limiter: a
+    /// [throttle delay](struct.RateLimiter.html#method.with_throttle_delay) of zero, which never
+    /// delays a request, a
+    /// [shed threshold](struct.RateLimiter.html#method.with_shed_threshold) of zero, which sheds
+    /// every request, a [maximum key length](struct.RateLimiter.html#method.with_max_key_length)
+    /// of zero, a [typical cost](enum.RemainingHeader.html) of zero, a
+    /// [token scale](struct.RateLimiter.html#method.with_token_scale) of zero, a
+    /// [minimum cost](struct.RateLimiter.html#method.with_min_cost) of zero, a
+    /// [cache hit refund](struct.RateLimiter.html#method.with_cache_hit_refund) outside of 0
+    /// and 1, and a
+    /// [moving average](enum.Algorithm.html#variant.Ewma) without a half-life or with a
+    /// threshold of one or less.
+    pub fn build(self) -> Result<RateLimiter<T>, ConfigError> {
+        let interval = self.interval.ok_or(ConfigError::MissingField("interval"))?;
+        let max_requests = self
+            .max_requests
+            .ok_or(ConfigError::MissingField("max_requests"))?;
+        // Stores keep expiry with a precision of one second
+        if interval.as_secs() == 0 {
+            return Err(ConfigError::InvalidValue(
+                "interval",
+                "must be at least one second".to_string(),
+            ));
+        }
+        if max_requests == 0 {
+            return Err(ConfigError::InvalidValue(
+                "max_requests",
+                "must be at least one".to_string(),
+            ));
+        }
+        if let Some(fraction) = self.limiter.soft_limit {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(ConfigError::InvalidValue(
+                    "soft_limit",
+                    format!("{} is not within (0, 1]", fraction),
+                ));
+            }
+        }
+        if let Some(fraction) = self.limiter.cache_hit_refund {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(ConfigError::InvalidValue(
+                    "cache_hit_refund",
+                    format!("{} is not within [0, 1]", fraction),
+                ));
+            }
+        }
+        if let Some((_, factor)) = self.limiter.recovery_grace {
+            if !(factor >= 1.0 && factor.is_finite()) {
+                return Err(ConfigError::InvalidValue(
+                    "recovery_grace",
+                    format!("factor {} is not at least 1", factor),
+                ));
+            }
+        }
+        if self.limiter.monitor_only && self.limiter.over_limit_grace > 0 {
+            return Err(ConfigError::Incompatible(
+                "monitor_only",
+                "over_limit_grace",
+            ));
+        }
+        if let Some(error_limit) = &self.limiter.error_limit {
+            if error_limit.max_errors == 0
+                || error_limit.interval.as_secs() == 0
+                || error_limit.cooldown.as_secs() == 0
+            {
+                return Err(ConfigError::InvalidValue(
+                    "error_limit",
+                    "needs at least one error in at least one second, and a cooldown of at least one second"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(distinct_limit) = &self.limiter.distinct_limit {
+            if distinct_limit.max_distinct == 0 {
+                return Err(ConfigError::InvalidValue(
+                    "distinct_limit",
+                    "must allow at least one distinct member".to_string(),
+                ));
+            }
+            if !supports_sets::<T>() {
+                return Err(ConfigError::Incompatible("distinct_limit", "memcached"));
+            }
+        }
+        if self.limiter.sliding_expiry && self.limiter.algorithm != Algorithm::FixedWindow {
+            return Err(ConfigError::Incompatible("sliding_expiry", "algorithm"));
+        }
+        if self.limiter.previous_identifier.is_some()
+            && self.limiter.algorithm != Algorithm::FixedWindow
+        {
+            return Err(ConfigError::Incompatible(
+                "previous_identifier",
+                "algorithm",
+            ));
+        }
+        if let Algorithm::Ewma {
+            half_life,
+            threshold,
+        } = self.limiter.algorithm
+        {
+            if half_life < Duration::from_micros(1) || !(threshold > 1.0 && threshold.is_finite()) {
+                return Err(ConfigError::InvalidValue(
+                    "algorithm",
+                    "the moving average needs a half-life and a threshold above one".to_string(),
+                ));
+            }
+            if self.limiter.token_scale > 1 {
+                return Err(ConfigError::Incompatible("token_scale", "algorithm"));
+            }
+        }
+        if !self.limiter.shards.is_empty() && self.limiter.store_selector.is_some() {
+            return Err(ConfigError::Incompatible("shards", "store_selector"));
+        }
+        if self.limiter.prefilter.is_some() {
+            if self.limiter.algorithm != Algorithm::FixedWindow {
+                return Err(ConfigError::Incompatible("prefilter", "algorithm"));
+            }
+            if self.limiter.sliding_expiry {
+                return Err(ConfigError::Incompatible("prefilter", "sliding_expiry"));
+            }
+        }
+        if self.limiter.monitor_only && self.limiter.throttle_delay.is_some() {
+            return Err(ConfigError::Incompatible("monitor_only", "throttle_delay"));
+        }
+        if self.limiter.throttle_delay == Some(Duration::from_secs(0)) {
+            return Err(ConfigError::InvalidValue(
+                "throttle_delay",
+                "maximum wait must not be zero".to_string(),
+            ));
+        }
+        match self.limiter.shed_threshold {
+            Some(ShedThreshold::Latency(latency)) if latency == Duration::from_secs(0) => {
+                return Err(ConfigError::InvalidValue(
+                    "shed_threshold",
+                    "latency must not be zero".to_string(),
+                ));
+            }
+            Some(ShedThreshold::QueueDepth(0)) => {
+                return Err(ConfigError::InvalidValue(
+                    "shed_threshold",
+                    "queue depth must be at least one".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if self.limiter.max_key_length == Some(0) {
+            return Err(ConfigError::InvalidValue(
+                "max_key_length",
+                "must be at least one".to_string(),
+            ));
+        }
+        if let Some((_, _, fraction)) = self.limiter.warmup {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(ConfigError::InvalidValue(
+                    "warmup",
+                    "start fraction must be above 0 and at most 1".to_string(),
+                ));
+            }
+        }
+        if self.limiter.token_scale == 0 {
+            return Err(ConfigError::InvalidValue(
+                "token_scale",
+                "must be at least one".to_string(),
+            ));
+        }
+        if let RemainingHeader::Requests { typical_cost: 0 } = self.limiter.remaining_header {
+            return Err(ConfigError::InvalidValue(
+                "remaining_header",
+                "typical cost must be at least one".to_string(),
+            ));
+        }
+        if self.limiter.min_cost == 0 {
+            return Err(ConfigError::InvalidValue(
+                "min_cost",
+                "must be at least one".to_string(),
+            ));
+        }
+        Ok(self
+            .limiter
+            .with_interval(interval)
+            .with_max_requests(max_requests))
+    }
+}
+
+impl<T, S, B> Transform<S> for RateLimiter<T>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S, T>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            store: self.store.clone(),
+            max_requests: self.max_requests,
+            interval: self.interval.as_secs(),
+            identifier: self.identifier.clone(),
+            async_identifier: self.async_identifier.clone(),
+            previous_identifier: self.previous_identifier.clone(),
+            failure_mode: self.failure_mode,
+            store_error_handler: self.store_error_handler.clone(),
+            algorithm: self.algorithm,
+            observer: self.observer.clone(),
+            allowlist: self.allowlist.clone(),
+            content_type_filter: self.content_type_filter.clone(),
+            should_limit: self.should_limit.clone(),
+            concurrency: self.concurrency.clone(),
+            resolver: self.resolver.clone(),
+            shared_limit: self.shared_limit.clone(),
+            soft_limit: self.soft_limit,
+            token_scale: self.token_scale,
+            missing_identifier: self.missing_identifier.clone(),
+            hash_span_keys: self.hash_span_keys,
+            path_limits: self.path_limits.clone(),
+            stores: self.stores.clone(),
+            store_selector: self.store_selector.clone(),
+            shards: self.shards.clone(),
+            shard_selector: self.shard_selector.clone(),
+            scope_limits: self.scope_limits.clone(),
+            key_normalization: self.key_normalization,
+            max_key_length: self.max_key_length,
+            oversized_key: self.oversized_key,
+            monitor_only: self.monitor_only,
+            switch: self.switch.clone(),
+            over_limit_grace: self.over_limit_grace,
+            recovery_grace: self.recovery_grace,
+            throttle_delay: self.throttle_delay,
+            sliding_expiry: self.sliding_expiry,
+            store_clock: self.store_clock,
+            boosts: self.boosts,
+            idempotency_header: self.idempotency_header.clone(),
+            content_length_cost: self.content_length_cost,
+            min_cost: self.min_cost,
+            cache_hit_refund: self.cache_hit_refund,
+            prefilter: self.prefilter.clone(),
+            stats: self.stats.clone(),
+            error_limit: self.error_limit.clone(),
+            distinct_limit: self.distinct_limit.clone(),
+            backoff_penalty: self.backoff_penalty,
+            adaptive: self.adaptive.clone(),
+            warmup: self.warmup,
+            remaining_header: self.remaining_header,
+            used_header: self.used_header,
+            algorithm_header: self.algorithm_header,
+            header_names: self.header_names.clone(),
+            retry_after_jitter: self.retry_after_jitter,
+            headers_on_errors: self.headers_on_errors,
+            shed_threshold: self.shed_threshold,
+            in_flight: Rc::new(Cell::new(0)),
+            rejection_log: self.rejection_log,
+            log_path: self.log_path,
+            trace_source: self.trace_source.clone(),
+            peek_methods: self.peek_methods.clone(),
+            degraded_since: Rc::new(Cell::new(None)),
+            recovered_at: Rc::new(Cell::new(None)),
+        })
+    }
+}
+
+/// Service factory for RateLimiter
+pub struct RateLimitMiddleware<S, T>
+where
+    S: 'static,
+    T: Handler<ActorMessage> + 'static,
+{
+    service: Rc<RefCell<S>>,
+    store: Addr<T>,
+    // Exists here for the sole purpose of knowing the max_requests and interval from RateLimiter
+    max_requests: usize,
+    interval: u64,
+    identifier: Identifier,
+    async_identifier: Option<AsyncIdentifier>,
+    previous_identifier: Option<Identifier>,
+    failure_mode: FailureMode,
+    store_error_handler: Option<StoreErrorHandler>,
+    algorithm: Algorithm,
+    observer: Option<Observer>,
+    allowlist: Option<Predicate>,
+    content_type_filter: Option<Predicate>,
+    should_limit: Option<Predicate>,
+    concurrency: Option<ConcurrencyLimit>,
+    resolver: Option<Resolver>,
+    shared_limit: Option<SharedLimit>,
+    soft_limit: Option<f64>,
+    token_scale: usize,
+    missing_identifier: Policy,
+    hash_span_keys: bool,
+    path_limits: Rc<Vec<(String, Limit)>>,
+    stores: Rc<Vec<(StoreId, Addr<T>)>>,
+    store_selector: Option<StoreSelector>,
+    shards: Rc<Vec<Addr<T>>>,
+    shard_selector: Option<ShardSelector>,
+    scope_limits: Rc<Vec<(String, Limit)>>,
+    key_normalization: Normalization,
+    max_key_length: Option<usize>,
+    oversized_key: OversizedKey,
+    monitor_only: bool,
+    switch: Option<(LimiterSwitch, bool)>,
+    over_limit_grace: usize,
+    recovery_grace: Option<(Duration, f64)>,
+    throttle_delay: Option<Duration>,
+    sliding_expiry: bool,
+    store_clock: bool,
+    boosts: bool,
+    idempotency_header: Option<HeaderName>,
+    content_length_cost: Option<usize>,
+    min_cost: usize,
+    cache_hit_refund: Option<f64>,
+    prefilter: Option<PreFilter>,
+    stats: StoreStats,
+    error_limit: Option<ErrorLimit>,
+    distinct_limit: Option<DistinctLimit>,
+    backoff_penalty: Option<(usize, Duration)>,
+    adaptive: Option<AdaptiveLimit>,
+    warmup: Option<(Instant, Duration, f64)>,
+    remaining_header: RemainingHeader,
+    used_header: bool,
+    algorithm_header: bool,
+    header_names: Rc<HeaderNames>,
+    retry_after_jitter: Option<Duration>,
+    headers_on_errors: bool,
+    shed_threshold: Option<ShedThreshold>,
+    rejection_log: (Level, LogKey),
+    log_path: bool,
+    trace_source: Option<TraceSource>,
+    peek_methods: Rc<Vec<Method>>,
+    // Set while the store is failing, per worker
+    degraded_since: Rc<Cell<Option<Instant>>>,
+    // Set when the store answers again after failing, per worker
+    recovered_at: Rc<Cell<Option<Instant>>>,
+    // Requests of the worker waiting for the store
+    in_flight: Rc<Cell<usize>>,
+}
+
+fn log_rejection(
+    rejection_log: (Level, LogKey),
+    log_path: bool,
+    reason: &str,
+    identifier: &str,
+    req: &ServiceRequest,
+) {
+    let endpoint = Some((req.method(), req.path())).filter(|_| log_path);
+    log_rejection_on(rejection_log, reason, identifier, endpoint);
+}
+
+// Logs like `log_rejection`, once the request went to the service, with its method and path
+// when they are logged
+fn log_rejection_on(
+    (level, key): (Level, LogKey),
+    reason: &str,
+    identifier: &str,
+    endpoint: Option<(&Method, &str)>,
+) {
+    if log_enabled!(level) {
+        log!(
+            level,
+            "{}",
+            rejection_message(key, reason, identifier, endpoint)
+        );
+    }
+}
+
+// Message logged for a rejection, with the identifier as `key` asks
+fn rejection_message(
+    key: LogKey,
+    reason: &str,
+    identifier: &str,
+    endpoint: Option<(&Method, &str)>,
+) -> String {
+    let endpoint = match endpoint {
+        Some((method, path)) => format!(" on {} {}", method, path),
+        None => String::new(),
+    };
+    match key {
+        LogKey::Plain => format!("{} for client: {}{}", reason, identifier, endpoint),
+        LogKey::Hashed => format!(
+            "{} for client: hash:{:016x}{}",
+            reason,
+            identifiers::fnv1a(identifier),
+            endpoint
+        ),
+        LogKey::Omitted => format!("{}{}", reason, endpoint),
+    }
+}
+
+// Seconds to wait before a retry, the reset rounded up plus the jitter of the client
+fn retry_after(reset: Duration, max_jitter: Duration, identifier: &str) -> u64 {
+    let reset = reset.as_secs() + u64::from(reset.subsec_nanos() > 0);
+    let jitter = identifiers::fnv1a(identifier) % (max_jitter.as_secs() + 1);
+    reset + jitter
+}
+
+// Whether the headers already hold values set by an inner limiter with the same names which are
+// more restrictive than `remaining` requests left until `reset`, see
+// RateLimiter::with_header_prefix
+fn more_restrictive(headers: &HeaderMap, names: &HeaderNames, remaining: &str, reset: u64) -> bool {
+    let count = |value: &str| match value {
+        "unlimited" => f64::INFINITY,
+        value => value.parse().unwrap_or(f64::INFINITY),
+    };
+    let existing = match headers.get(&names.remaining).and_then(|v| v.to_str().ok()) {
+        Some(existing) => count(existing),
+        None => return false,
+    };
+    let existing_reset = headers
+        .get(&names.reset)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        .unwrap_or(u64::MAX);
+    (existing, existing_reset) < (count(remaining), reset)
+}
+
+// Response to a request over the limit, which gRPC clients only understand as a gRPC status
+fn too_many_requests(req: &ServiceRequest) -> HttpResponseBuilder {
+    if predicates::is_grpc(req) {
+        let mut response = HttpResponse::Ok();
+        response
+            .content_type("application/grpc")
+            .set_header("grpc-status", "8")
+            .set_header("grpc-message", "rate limit exceeded");
+        response
+    } else {
+        HttpResponse::TooManyRequests()
+    }
+}
+
+// What the headers of a rejected request are made of
+struct RejectionHeaders<'a> {
+    names: &'a HeaderNames,
+    unit: RemainingHeader,
+    scale: usize,
+    max_requests: usize,
+    retry_after_jitter: Option<Duration>,
+    used: bool,
+    algorithm: Option<Algorithm>,
+}
+
+// Response to a request rejected with `remaining` units left until `reset`, with the same
+// headers whichever limit rejected it
+fn rejected_response(
+    req: &ServiceRequest,
+    headers: &RejectionHeaders,
+    identifier: &str,
+    remaining: usize,
+    reset: Duration,
+) -> HttpResponseBuilder {
+    let (names, unit, scale, max_requests) = (
+        headers.names,
+        headers.unit,
+        headers.scale,
+        headers.max_requests,
+    );
+    let mut response = too_many_requests(req);
+    response.set_header(
+        names.limit.clone(),
+        unit.count(scale, max_requests, max_requests),
+    );
+    response.set_header(
+        names.remaining.clone(),
+        unit.count(scale, max_requests, remaining),
+    );
+    response.set_header(names.reset.clone(), reset.as_secs().to_string());
+    if let Some(max_jitter) = headers.retry_after_jitter {
+        response.set_header(
+            RETRY_AFTER,
+            retry_after(reset, max_jitter, identifier).to_string(),
+        );
+    }
+    if headers.used {
+        response.set_header(
+            names.used.clone(),
+            unit.used(scale, max_requests, remaining),
+        );
+    }
+    if let Some(algorithm) = headers.algorithm {
+        response.set_header(names.algorithm.clone(), algorithm.name());
+    }
+    response
+}
+
+// Reports a rejected request to the observer
+fn reject(
+    observer: &Option<Observer>,
+    trace_source: &Option<TraceSource>,
+    identifier: &str,
+    req: &ServiceRequest,
+) {
+    if let Some(observer) = observer {
+        let trace_id = trace_source
+            .as_ref()
+            .and_then(|source| source.trace_id(req));
+        (observer)(&RateLimitEvent::Rejected {
+            key: identifier,
+            method: req.method(),
+            path: req.path(),
+            trace_id: trace_id.as_deref(),
+        });
+    }
+}
+
+// Shard of the client whose key is `key`, if the limiter has shards
+fn pick_shard<'a, T: Actor>(
+    shards: &'a [Addr<T>],
+    selector: &Option<ShardSelector>,
+    key: &str,
+) -> Option<&'a Addr<T>> {
+    if shards.is_empty() {
+        return None;
+    }
+    let index = match selector {
+        Some(selector) => (selector)(key),
+        None => identifiers::fnv1a(key) as usize,
+    };
+    shards.get(index % shards.len())
+}
+
+// Key of the client of `req`, before the prefix of its path limit
+fn identify(
+    req: &ServiceRequest,
+    identifier: &Identifier,
+    key_normalization: Normalization,
+    max_key_length: Option<usize>,
+    oversized_key: OversizedKey,
+) -> Result<String, ARError> {
+    shape_key(
+        (identifier)(req),
+        key_normalization,
+        max_key_length,
+        oversized_key,
+    )
+}
+
+// Normalizes the key of the client and keeps it within the maximum length
+fn shape_key(
+    identifier: Result<String, ARError>,
+    key_normalization: Normalization,
+    max_key_length: Option<usize>,
+    oversized_key: OversizedKey,
+) -> Result<String, ARError> {
+    let identifier = match key_normalization {
+        Normalization::None => identifier,
+        Normalization::Lowercase => identifier.map(|key| key.to_lowercase()),
+    };
+    match max_key_length {
+        Some(max_key_length) => {
+            identifier.and_then(|key| limit_key_length(key, max_key_length, oversized_key))
+        }
+        None => identifier,
+    }
+}
+
+fn limit_key_length(
+    key: String,
+    max_key_length: usize,
+    action: OversizedKey,
+) -> Result<String, ARError> {
+    if key.len() <= max_key_length {
+        return Ok(key);
+    }
+    warn!(
+        "Identifier of {} bytes exceeds the maximum of {}, applying {:?}",
+        key.len(),
+        max_key_length,
+        action
+    );
+    match action {
+        OversizedKey::Hash => Ok(format!("hash:{:016x}", identifiers::fnv1a(&key))),
+        OversizedKey::Truncate => {
+            let mut end = max_key_length;
+            while !key.is_char_boundary(end) {
+                end -= 1;
+            }
+            Ok(key[..end].to_string())
+        }
+        OversizedKey::Reject => Err(ARError::IdentificationError),
+    }
+}
+
+// Share of `max_requests` allowed `warmup` after the start, at least one request
+fn warm_up(max_requests: usize, (start, warmup, fraction): (Instant, Duration, f64)) -> usize {
+    let elapsed = start.elapsed();
+    if elapsed >= warmup {
+        return max_requests;
+    }
+    let factor = fraction + (1.0 - fraction) * elapsed.as_secs_f64() / warmup.as_secs_f64();
+    let scaled = (max_requests as f64 * factor).floor() as usize;
+    scaled.max(max_requests.min(1))
+}
+
+// Whether stores of type `T` answer `ActorMessage::AddToSet`, which memcached does not
+#[cfg_attr(
+    not(feature = "memcached"),
+    allow(clippy::extra_unused_type_parameters)
+)]
+fn supports_sets<T: 'static>() -> bool {
+    #[cfg(feature = "memcached")]
+    {
+        if std::any::TypeId::of::<T>() == std::any::TypeId::of::<crate::MemcacheStoreActor>() {
+            return false;
+        }
+    }
+    true
+}
+
+// Longest prefix matching whole segments of `path`
+fn match_path_limit<'a>(
+    path_limits: &'a [(String, Limit)],
+    path: &str,
+) -> Option<&'a (String, Limit)> {
+    path_limits
+        .iter()
+        .filter(|(prefix, _)| {
+            path.starts_with(prefix.as_str())
+                && matches!(path.as_bytes().get(prefix.len()), None | Some(b'/'))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+}
+
+/// Outcome of the store round trips for a single request
+pub(crate) enum Decision {
+    // `key` is the store entry which was charged
+    Allowed {
+        remaining: usize,
+        reset: Duration,
+        key: String,
+    },
+    Rejected {
+        reset: Duration,
+    },
+}
+
+// Counts a request over the limit against the grace of the window, and tells whether it is
+// within it. The request is rejected when the store fails.
+async fn within_grace<T>(store: &Addr<T>, identifier: &str, grace: usize, reset: Duration) -> bool
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let key = derived_key(identifier, ":over");
+    let result = match get(store, &key).await {
+        Ok(Some(0)) => return false,
+        Ok(Some(_)) => update(store, &key, 1).await.map(|_| ()),
+        // The grace of the window ends with it
+        Ok(None) => set(store, &key, grace - 1, reset.max(Duration::from_secs(1))).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Could not count the request against the grace: {}", &e);
+            false
+        }
+    }
+}
+
+// Carries the count of a client over from its `previous` key, the first time it is seen under
+// its `current` one
+async fn migrate_key<T>(store: &Addr<T>, previous: &str, current: &str)
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    match get(store, current).await {
+        Ok(None) => {}
+        _ => return,
+    }
+    let count = match get(store, previous).await {
+        Ok(Some(count)) => count,
+        _ => return,
+    };
+    let expiry = match expire(store, previous).await {
+        Ok(expiry) if expiry > Duration::from_secs(0) => expiry,
+        _ => return,
+    };
+    // Unless a concurrent request of the client created the key in the meantime
+    let result = match compare_and_set(store, current, None, count, expiry).await {
+        Err(_) => set(store, current, count, expiry).await,
+        Ok(_) => Ok(()),
+    };
+    if let Err(e) = result {
+        warn!("Could not carry the count of the client over: {}", &e);
+    }
+}
+
+// Gives the request back to the client counted in `key`, within `max_requests`, and returns the
+// new count. The window is left to expire as it would have.
+async fn refund<T>(store: &Addr<T>, key: &str, max_requests: usize) -> Result<usize, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    // Neither brings back a window which ended nor gives more than the limit
+    match increment_if_exists(store, key, 1, max_requests).await? {
+        Some(count) => Ok(count),
+        None => Err(ARError::Other("the window already ended".to_string())),
+    }
+}
+
+// Rejects an allowed request whose body costs more than what the client has left, and gives back
+// the unit it reserved in the store, if it did
+async fn check_body_cost<T>(
+    store: &Addr<T>,
+    decision: Decision,
+    cost: Option<usize>,
+    max_requests: usize,
+    reserved: bool,
+) -> Decision
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    match (cost, decision) {
+        // One unit was already reserved
+        (
+            Some(cost),
+            Decision::Allowed {
+                remaining,
+                reset,
+                key,
+            },
+        ) if cost > remaining + 1 => {
+            if reserved {
+                if let Err(e) = refund(store, &key, max_requests).await {
+                    warn!(
+                        "Could not give back the unit of a request too large: {}",
+                        &e
+                    );
+                }
+            }
+            Decision::Rejected { reset }
+        }
+        (_, decision) => decision,
+    }
+}
+
+// Pushes the end of the window of the client back to a whole interval. The count is left as is,
+// when it changed since the decision another request refreshes the window.
+async fn refresh_expiry<T>(
+    store: &Addr<T>,
+    decision: Decision,
+    identifier: &str,
+    interval: Duration,
+) -> Decision
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let (key, count) = match &decision {
+        Decision::Allowed { remaining, key, .. } => (key.as_str(), *remaining),
+        Decision::Rejected { .. } => (identifier, 0),
+    };
+    match compare_and_set(store, key, Some(count), count, interval).await {
+        Ok(true) => match decision {
+            Decision::Allowed { remaining, key, .. } => Decision::Allowed {
+                remaining,
+                reset: interval,
+                key,
+            },
+            Decision::Rejected { .. } => Decision::Rejected { reset: interval },
+        },
+        Ok(false) => decision,
+        Err(e) => {
+            warn!("Could not refresh the expiry of the client: {}", &e);
+            decision
+        }
+    }
+}
+
+// Counts a response against the error limit of the client, and blocks it past the limit
+async fn count_error<T>(
+    store: &Addr<T>,
+    identifier: &str,
+    error_limit: &ErrorLimit,
+    rejection_log: (Level, LogKey),
+    endpoint: Option<(&Method, &str)>,
+) where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let key = derived_key(identifier, ":errors");
+    let result = match fixed_window(store, &key, error_limit.max_errors, error_limit.interval).await
+    {
+        Ok(Decision::Allowed { remaining, .. }) if remaining > 0 => Ok(()),
+        Ok(_) => {
+            log_rejection_on(
+                rejection_log,
+                "Too many errors, blocking",
+                identifier,
+                endpoint,
+            );
+            let blocked = derived_key(identifier, ":blocked");
+            match set(store, &blocked, 1, error_limit.cooldown).await {
+                // The count starts over once the client is let back in
+                Ok(()) => set(store, &key, error_limit.max_errors, error_limit.interval).await,
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Could not count the error of the client: {}", &e);
+    }
+}
+
+// Counts a rejected request against the cooldown of the client, which starts with its first
+// rejection and lasts until `reset`, and blocks the client past `tolerance` requests in it
+async fn check_backoff<T>(
+    store: &Addr<T>,
+    identifier: &str,
+    reset: Duration,
+    tolerance: usize,
+    penalty: Duration,
+    rejection_log: (Level, LogKey),
+    endpoint: Option<(&Method, &str)>,
+) where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let key = derived_key(identifier, ":cooldown");
+    let result = match get(store, &key).await {
+        Ok(None) => set(store, &key, tolerance, reset.max(Duration::from_secs(1))).await,
+        Ok(Some(0)) => {
+            log_rejection_on(
+                rejection_log,
+                "Cooldown ignored, blocking",
+                identifier,
+                endpoint,
+            );
+            let blocked = derived_key(identifier, ":blocked");
+            set(store, &blocked, 1, penalty).await
+        }
+        Ok(Some(_)) => update(store, &key, 1).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Could not check the backoff of the client: {}", &e);
+    }
+}
+
+// Counts a request out of the store queue, even when it is dropped while waiting
+struct Waiting<'a>(&'a Cell<usize>);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+// Records the decision for the layers around the limiter
+fn record_decision(
+    req: &ServiceRequest,
+    allowed: bool,
+    identifier: &str,
+    remaining: usize,
+    reset: Duration,
+    algorithm: Algorithm,
+) {
+    let decision = RateLimitDecision {
+        allowed,
+        key_hash: identifiers::fnv1a(identifier),
+        remaining,
+        reset,
+        algorithm: algorithm.name(),
+    };
+    if let Some(slot) = req.extensions().get::<DecisionSlot>() {
+        slot.0.set(Some(decision));
+    }
+    req.extensions_mut().insert(decision);
+}
+
+// Records why the request is let through, for the inner layers and the observer
+fn allow(
+    req: &ServiceRequest,
+    observer: &Option<Observer>,
+    identifier: &str,
+    reason: DecisionReason,
+) {
+    req.extensions_mut().insert(reason);
+    if let Some(observer) = observer {
+        (observer)(&RateLimitEvent::Allowed {
+            key: identifier,
+            reason,
+        });
+    }
+}
+
+// Decides for the client, then for the scope of the request if the client is within its limit
+#[allow(clippy::too_many_arguments)]
+async fn decide_scoped<T>(
+    store: &Addr<T>,
+    algorithm: Algorithm,
+    peek: bool,
+    identifier: &str,
+    max_requests: usize,
+    interval: Duration,
+    scope: Option<&(String, Limit)>,
+    sliding_expiry: bool,
+    store_clock: bool,
+) -> Result<Decision, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let decision = decide(
+        store,
+        algorithm,
+        peek,
+        identifier,
+        max_requests,
+        interval,
+        store_clock,
+    )
+    .await?;
+    let decision = if sliding_expiry {
+        refresh_expiry(store, decision, identifier, interval).await
+    } else {
+        decision
+    };
+    match (&decision, scope) {
+        (Decision::Allowed { .. }, Some((prefix, limit))) => {
+            let key = format!("scope:{}", prefix);
+            match decide(
+                store,
+                algorithm,
+                peek,
+                &key,
+                limit.max_requests,
+                limit.interval,
+                store_clock,
+            )
+            .await?
+            {
+                Decision::Rejected { reset } => Ok(Decision::Rejected { reset }),
+                Decision::Allowed { .. } => Ok(decision),
+            }
+        }
+        _ => Ok(decision),
+    }
+}
+
+pub(crate) async fn decide<T>(
+    store: &Addr<T>,
+    algorithm: Algorithm,
+    peek: bool,
+    identifier: &str,
+    max_requests: usize,
+    interval: Duration,
+    store_clock: bool,
+) -> Result<Decision, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    if peek {
+        return peek_window(
+            store,
+            algorithm,
+            identifier,
+            max_requests,
+            interval,
+            store_clock,
+        )
+        .await;
+    }
+    match algorithm {
+        Algorithm::FixedWindow => fixed_window(store, identifier, max_requests, interval).await,
+        Algorithm::SlidingWindowCounter => {
+            let now = clock(store, store_clock).await?;
+            sliding_window_counter(store, identifier, max_requests, interval, now).await
+        }
+        Algorithm::CalendarWindow { unit } => {
+            // Each window has a key of its own, so that it ends on the boundary even with stores
+            // rounding expiries to the second. In the last second of a window, the expiry is
+            // rounded up, which such stores could otherwise round to zero.
+            let (start, until) = unit.window(clock(store, store_clock).await?);
+            let key = format!("{}:{}", identifier, start);
+            let until = until.max(Duration::from_secs(1));
+            fixed_window(store, &key, max_requests, until).await
+        }
+        Algorithm::Ewma {
+            half_life,
+            threshold,
+        } => {
+            let now = clock(store, store_clock).await?;
+            ewma(store, identifier, half_life, threshold, now).await
+        }
+    }
+}
+
+async fn fixed_window<T>(
+    store: &Addr<T>,
+    identifier: &str,
+    max_requests: usize,
+    interval: Duration,
+) -> Result<Decision, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    // The store is the authority on when the window ends, the reset comes with the count
+    let (remaining, reset) = match get(store, identifier).await? {
+        // Existing entry in store
+        Some(0) => {
+            let reset = expire(store, identifier).await?;
+            return Ok(Decision::Rejected { reset });
+        }
+        // The limit went down since the window started, which can not leave more requests
+        Some(remaining) if remaining > max_requests => {
+            consume(store, identifier, remaining - max_requests + 1).await?
+        }
+        Some(_) => consume(store, identifier, 1).await?,
+        None if max_requests == 0 => return Ok(Decision::Rejected { reset: interval }),
+        // New client, create entry in store
+        None => {
+            let current_value = max_requests - 1;
+            set(store, identifier, current_value, interval).await?;
+            // A key just created expires after the interval, which stores keeping whole seconds
+            // round, only then is it worth asking the store
+            let reset = match interval.subsec_nanos() {
+                0 => interval,
+                _ => expire(store, identifier).await?,
+            };
+            (current_value, reset)
+        }
+    };
+    Ok(Decision::Allowed {
+        remaining,
+        reset,
+        key: identifier.to_string(),
+    })
+}
+
+// Time since the Unix epoch, by the clock of the store or the local one
+async fn clock<T>(store: &Addr<T>, store_clock: bool) -> Result<Duration, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    if store_clock {
+        return time(store).await;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| ARError::Other(e.to_string()))
+}
+
+// Reads where the client stands without counting the request, never rejects
+async fn peek_window<T>(
+    store: &Addr<T>,
+    algorithm: Algorithm,
+    identifier: &str,
+    max_requests: usize,
+    interval: Duration,
+    store_clock: bool,
+) -> Result<Decision, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let mut key = identifier.to_string();
+    let (remaining, reset) = match algorithm {
+        Algorithm::FixedWindow => match peek_get(store, identifier).await? {
+            Some(remaining) => (remaining, expire(store, identifier).await?),
+            // The window starts with the first counted request
+            None => (max_requests, interval),
+        },
+        Algorithm::CalendarWindow { unit } => {
+            let (start, until) = unit.window(clock(store, store_clock).await?);
+            key = format!("{}:{}", identifier, start);
+            match peek_get(store, &key).await? {
+                Some(remaining) => (remaining.min(max_requests), until),
+                None => (max_requests, until),
+            }
+        }
+        Algorithm::SlidingWindowCounter => {
+            let now = clock(store, store_clock).await?;
+            let interval_secs = interval.as_secs().max(1);
+            let start = now.as_secs() - now.as_secs() % interval_secs;
+            let elapsed = now - Duration::from_secs(start);
+            let used = |remaining: Option<usize>| {
+                remaining.map_or(0, |remaining| max_requests.saturating_sub(remaining))
+            };
+            let window = peek_get(store, &derived_key(identifier, ":window")).await?;
+            // The windows are only rotated by counted requests, they may be behind
+            let (previous, current) = match window.map(|window| window as u64) {
+                Some(window) if window == start => (
+                    used(peek_get(store, &derived_key(identifier, ":previous")).await?),
+                    used(peek_get(store, identifier).await?),
+                ),
+                Some(window) if window + interval_secs == start => {
+                    (used(peek_get(store, identifier).await?), 0)
+                }
+                _ => (0, 0),
+            };
+            let estimate = sliding_estimate(previous, current, elapsed, interval);
+            let remaining = (max_requests as f64 - estimate).floor().max(0.0) as usize;
+            (remaining, interval.checked_sub(elapsed).unwrap_or_default())
+        }
+        Algorithm::Ewma {
+            half_life,
+            threshold,
+        } => {
+            let now = clock(store, store_clock).await?;
+            let (_, average) = ewma_average(store, identifier, half_life, now, true).await?;
+            key = derived_key(identifier, ":ewma");
+            (
+                (threshold - average).floor().max(0.0) as usize,
+                ewma_decay(average, 1.0, half_life),
+            )
+        }
+    };
+    Ok(Decision::Allowed {
+        remaining,
+        reset,
+        key,
+    })
+}
+
+// Reads a count without changing anything, which stores may serve from a replica, while
+// counting requests reads with `Get` from the primary
+async fn peek_get<T>(store: &Addr<T>, key: &str) -> Result<Option<usize>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    Ok(multi_get(store, &[key]).await?.into_iter().next().flatten())
+}
+
+// Weighted count of the window sliding over `previous` and `current`, `elapsed` into current
+fn sliding_estimate(previous: usize, current: usize, elapsed: Duration, interval: Duration) -> f64 {
+    let weight = 1.0 - elapsed.as_secs_f64() / interval.as_secs_f64();
+    previous as f64 * weight.max(0.0) + current as f64
+}
+
+async fn sliding_window_counter<T>(
+    store: &Addr<T>,
+    identifier: &str,
+    max_requests: usize,
+    interval: Duration,
+    now: Duration,
+) -> Result<Decision, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let interval_secs = interval.as_secs().max(1);
+    let start = now.as_secs() - now.as_secs() % interval_secs;
+    let elapsed = now - Duration::from_secs(start);
+    let reset = interval.checked_sub(elapsed).unwrap_or_default();
+    // Every window stores its remaining requests, like the fixed window does, and lives until
+    // the end of the next one, where it is the previous window
+    let expiry = (interval * 2).checked_sub(elapsed).unwrap_or(interval);
+    let previous = match rotate(store, identifier, start, max_requests, expiry).await? {
+        Some(remaining) => max_requests.saturating_sub(remaining),
+        None => 0,
+    };
+    // The request is only counted against the count it was decided on, every failed swap is
+    // another request of the client counted in between, which the limit bounds
+    for _ in 0..=max_requests {
+        let stored = match get(store, identifier).await? {
+            Some(remaining) => remaining,
+            // Evicted by the store since the rotation
+            None => {
+                return Err(ARError::ReadWriteError(
+                    "current window missing after rotation".to_string(),
+                ))
+            }
+        };
+        let current = max_requests.saturating_sub(stored) + 1;
+        let estimate = sliding_estimate(previous, current, elapsed, interval);
+        if estimate > max_requests as f64 {
+            return Ok(Decision::Rejected { reset });
+        }
+        if compare_and_set(store, identifier, Some(stored), stored - 1, expiry).await? {
+            let remaining = (max_requests as f64 - estimate).floor().max(0.0) as usize;
+            return Ok(Decision::Allowed {
+                remaining,
+                reset,
+                key: identifier.to_string(),
+            });
+        }
+    }
+    Err(ARError::ReadWriteError(
+        "the count kept changing while counting the request".to_string(),
+    ))
+}
+
+// Retries of a request let through with an idempotency key which are not counted, see
+// RateLimiter::with_idempotency_key
+const IDEMPOTENT_RETRIES: usize = 5;
+
+// Times a delayed request waits for a new window before it is rejected, see
+// RateLimiter::with_throttle_delay
+const THROTTLE_ATTEMPTS: usize = 10;
+
+// Averages which decayed under a millionth of a request are dropped from the store
+const EWMA_ZERO: f64 = 1.0 / 1_000_000.0;
+
+// The moving average is stored as the time, in microseconds since the Unix epoch, when it
+// decays down to one request, rather than as the average and the time it was last updated, so
+// that a single compare-and-set swaps both
+fn ewma_encode(average: f64, now: Duration, half_life: Duration) -> usize {
+    let offset = (half_life.as_micros() as f64 * average.log2()).round() as i128;
+    (now.as_micros() as i128 + offset).max(0) as usize
+}
+
+fn ewma_decode(stored: usize, now: Duration, half_life: Duration) -> f64 {
+    let offset = stored as i128 - now.as_micros() as i128;
+    (offset as f64 / half_life.as_micros() as f64).exp2()
+}
+
+// Moving average of the client at `now`, read from the store: the stored value, which the
+// update compares against, and the average it stands for
+async fn ewma_average<T>(
+    store: &Addr<T>,
+    identifier: &str,
+    half_life: Duration,
+    now: Duration,
+    read: bool,
+) -> Result<(Option<usize>, f64), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let key = derived_key(identifier, ":ewma");
+    let stored = if read {
+        peek_get(store, &key).await?
+    } else {
+        get(store, &key).await?
+    };
+    let average = stored.map_or(0.0, |stored| ewma_decode(stored, now, half_life));
+    Ok((stored, average))
+}
+
+// Time for an average to decay down to `target`
+fn ewma_decay(average: f64, target: f64, half_life: Duration) -> Duration {
+    if average <= target {
+        return Duration::from_secs(0);
+    }
+    Duration::from_secs_f64(half_life.as_secs_f64() * (average / target.max(EWMA_ZERO)).log2())
+}
+
+async fn ewma<T>(
+    store: &Addr<T>,
+    identifier: &str,
+    half_life: Duration,
+    threshold: f64,
+    now: Duration,
+) -> Result<Decision, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let key = derived_key(identifier, ":ewma");
+    // Every failed swap is another request of the client counted in between, which takes the
+    // average up by one and the threshold bounds
+    for _ in 0..=threshold.ceil() as usize {
+        let (stored, average) = ewma_average(store, identifier, half_life, now, false).await?;
+        if average + 1.0 > threshold {
+            return Ok(Decision::Rejected {
+                reset: ewma_decay(average, threshold - 1.0, half_life),
+            });
+        }
+        let average = average + 1.0;
+        // The entries are gone once the average decayed under the precision of the store
+        let expiry = ewma_decay(average, EWMA_ZERO, half_life).max(Duration::from_secs(1));
+        let value = ewma_encode(average, now, half_life);
+        if compare_and_set(store, &key, stored, value, expiry).await? {
+            return Ok(Decision::Allowed {
+                remaining: (threshold - average).floor().max(0.0) as usize,
+                reset: ewma_decay(average, 1.0, half_life),
+                key,
+            });
+        }
+    }
+    Err(ARError::ReadWriteError(
+        "the average kept changing while counting the request".to_string(),
+    ))
+}
+
+impl<T, S, B> Service for RateLimitMiddleware<S, T>
+where
+    T: Handler<ActorMessage> + 'static,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
     type Error = S::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.borrow_mut().poll_ready(cx)
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let store = match &self.store_selector {
+            Some(selector) => {
+                let id = (selector)(&req);
+                self.stores
+                    .iter()
+                    .find(|(store_id, _)| *store_id == id)
+                    .map_or_else(|| self.store.clone(), |(_, store)| store.clone())
+            }
+            None => self.store.clone(),
+        };
+        let mut srv = self.service.clone();
+        if let Some(allowlist) = &self.allowlist {
+            if (allowlist)(&req) {
+                return Box::pin(srv.call(req));
+            }
+        }
+        if let Some(filter) = &self.content_type_filter {
+            if !(filter)(&req) {
+                return Box::pin(srv.call(req));
+            }
+        }
+        if let Some(should_limit) = &self.should_limit {
+            if !(should_limit)(&req) {
+                return Box::pin(srv.call(req));
+            }
+        }
+        let disabled = match &self.switch {
+            Some((switch, _)) if switch.is_enabled() => false,
+            Some((_, false)) => return Box::pin(srv.call(req)),
+            Some((_, true)) => true,
+            None => false,
+        };
+        let path_limit = match_path_limit(&self.path_limits, req.path());
+        let namespace = path_limit.map(|(prefix, _)| prefix.clone());
+        let scope = match_path_limit(&self.scope_limits, req.path()).cloned();
+        let default = Limit {
+            max_requests: self.max_requests,
+            interval: Duration::from_secs(self.interval),
+        };
+        let limit = match (path_limit, &self.resolver) {
+            (Some((_, limit)), _) => *limit,
+            (None, Some(resolver)) => (resolver)(&req),
+            (None, None) => match &self.shared_limit {
+                Some(limit) => limit.get(),
+                None => default,
+            },
+        };
+        let Limit {
+            max_requests,
+            interval,
+        } = limit;
+        let max_requests = max_requests.saturating_mul(self.token_scale);
+        let identifier = self.identifier.clone();
+        let async_identifier = self.async_identifier.clone();
+        let previous_identifier = self.previous_identifier.clone();
+        let failure_mode = self.failure_mode;
+        let store_error_handler = self.store_error_handler.clone();
+        let algorithm = self.algorithm;
+        let observer = self.observer.clone();
+        let degraded_since = self.degraded_since.clone();
+        let concurrency = self.concurrency.clone();
+        let soft_limit = self.soft_limit;
+        let token_scale = self.token_scale;
+        let missing_identifier = self.missing_identifier.clone();
+        let hash_span_keys = self.hash_span_keys;
+        let key_normalization = self.key_normalization;
+        let max_key_length = self.max_key_length;
+        let oversized_key = self.oversized_key;
+        let monitor_only = self.monitor_only || disabled;
+        let over_limit_grace = self.over_limit_grace;
+        let recovery_grace = self.recovery_grace;
+        let recovered_at = self.recovered_at.clone();
+        let throttle_delay = self.throttle_delay;
+        let error_limit = self.error_limit.clone();
+        let distinct_limit = self.distinct_limit.clone();
+        let backoff_penalty = self.backoff_penalty;
+        let adaptive = self.adaptive.clone();
+        let warmup = self.warmup;
+        let remaining_header = self.remaining_header;
+        let used_header = self.used_header;
+        let algorithm_header = self.algorithm_header;
+        let header_names = self.header_names.clone();
+        let retry_after_jitter = self.retry_after_jitter;
+        let headers_on_errors = self.headers_on_errors;
+        let shed_threshold = self.shed_threshold;
+        let in_flight = self.in_flight.clone();
+        let stats = self.stats.clone();
+        let rejection_log = self.rejection_log;
+        let log_path = self.log_path;
+        let trace_source = self.trace_source.clone();
+        let peek = self.peek_methods.contains(req.method());
+        let sliding_expiry = self.sliding_expiry && !peek;
+        let store_clock = self.store_clock;
+        let boosts = self.boosts;
+        let idempotency_header = self.idempotency_header.clone();
+        let content_length_cost = self.content_length_cost;
+        let min_cost = self.min_cost;
+        let cache_hit_refund = self.cache_hit_refund;
+        let shards = self.shards.clone();
+        let shard_selector = self.shard_selector.clone();
+        let prefilter = self
+            .prefilter
+            .clone()
+            .filter(|_| limit == default && scope.is_none() && !peek);
+        Box::pin(async move {
+            let identifier = match &async_identifier {
+                Some(async_identifier) => shape_key(
+                    (async_identifier)(&req).await,
+                    key_normalization,
+                    max_key_length,
+                    oversized_key,
+                ),
+                None => identify(
+                    &req,
+                    &identifier,
+                    key_normalization,
+                    max_key_length,
+                    oversized_key,
+                ),
+            };
+            let identifier: String = match identifier {
+                Ok(identifier) => identifier,
+                Err(e) => match missing_identifier {
+                    Policy::Reject => return Err(e.into()),
+                    Policy::Allow => {
+                        debug!("Client not identified, letting request through: {}", &e);
+                        return srv.call(req).await;
+                    }
+                    Policy::SharedBucket(key) => {
+                        debug!("Client not identified, counting in {}: {}", &key, &e);
+                        key
+                    }
+                },
+            };
+            let previous = previous_identifier
+                .and_then(|previous| (previous)(&req).ok())
+                .map(|previous| match &namespace {
+                    Some(prefix) => format!("{}:{}", prefix, previous),
+                    None => previous,
+                });
+            let identifier = match namespace {
+                Some(prefix) => format!("{}:{}", prefix, identifier),
+                None => identifier,
+            };
+            let store = match pick_shard(&shards, &shard_selector, &identifier) {
+                Some(shard) => shard.clone(),
+                None => store,
+            };
+            if let Some(previous) = previous.filter(|previous| *previous != identifier) {
+                if !peek {
+                    migrate_key(&store, &previous, &identifier).await;
+                }
+            }
+            let max_requests = if boosts {
+                match get(&store, &derived_key(&identifier, ":boost")).await {
+                    Ok(extra) => {
+                        max_requests.saturating_add(extra.unwrap_or(0).saturating_mul(token_scale))
+                    }
+                    Err(e) => {
+                        warn!("Could not read the boost of the client: {}", &e);
+                        max_requests
+                    }
+                }
+            } else {
+                max_requests
+            };
+            let max_requests = match &adaptive {
+                Some(adaptive) => adaptive.scale(max_requests),
+                None => max_requests,
+            };
+            let max_requests = match warmup {
+                Some(warmup) => warm_up(max_requests, warmup),
+                None => max_requests,
+            };
+            let rejection = RejectionHeaders {
+                names: &header_names,
+                unit: remaining_header,
+                scale: token_scale,
+                max_requests,
+                retry_after_jitter,
+                used: used_header,
+                algorithm: Some(algorithm).filter(|_| algorithm_header),
+            };
+            // Size of the body, up front, in units of the scale
+            let body_cost = content_length_cost.map(|default_cost| {
+                req.headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+                    .unwrap_or(default_cost)
+                    .saturating_mul(token_scale)
+                    .min(max_requests)
+            });
+            let idempotency_key = idempotency_header
+                .as_ref()
+                .filter(|_| !peek)
+                .and_then(|header| req.headers().get(header))
+                .map(|value| {
+                    // A key only stands for the operation it was first sent with
+                    let value = String::from_utf8_lossy(value.as_bytes());
+                    let operation = format!("{} {} {}", req.method(), req.path(), value);
+                    let hash = identifiers::fnv1a(&operation);
+                    format!("{}:idempotency:{:016x}", identifier, hash)
+                });
+            // The key holds the retries it has left
+            let retry = match &idempotency_key {
+                Some(key) => match get(&store, key).await {
+                    Ok(Some(left)) if left > 0 => update(&store, key, 1).await.is_ok(),
+                    Ok(_) => false,
+                    Err(e) => {
+                        warn!("Could not check the idempotency key of the request: {}", &e);
+                        false
+                    }
+                },
+                None => false,
+            };
+            // Retries are not counted again
+            let peek = peek || retry;
+            let sliding_expiry = sliding_expiry && !retry;
+            let prefilter = prefilter.filter(|_| !retry);
+            if limit != default {
+                if let Some(observer) = &observer {
+                    (observer)(&RateLimitEvent::LimitApplied {
+                        key: &identifier,
+                        limit,
+                        default,
+                    });
+                }
+            }
+            if error_limit.is_some() || backoff_penalty.is_some() {
+                let key = derived_key(&identifier, ":blocked");
+                let blocked = match get(&store, &key).await {
+                    Ok(Some(_)) => expire(&store, &key).await.ok(),
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("Could not check whether the client is blocked: {}", &e);
+                        None
+                    }
+                };
+                match blocked {
+                    Some(_) if monitor_only => log_rejection(
+                        rejection_log,
+                        log_path,
+                        "Client blocked, not enforced",
+                        &identifier,
+                        &req,
+                    ),
+                    Some(reset) => {
+                        log_rejection(rejection_log, log_path, "Client blocked", &identifier, &req);
+                        reject(&observer, &trace_source, &identifier, &req);
+                        telemetry::request(Outcome::Rejected);
+                        let response = rejected_response(&req, &rejection, &identifier, 0, reset);
+                        return Err(response.into());
+                    }
+                    None => {}
+                }
+            }
+            let member = distinct_limit
+                .as_ref()
+                .filter(|_| !peek)
+                .and_then(|distinct_limit| (distinct_limit.extractor)(&req));
+            if let (Some(distinct_limit), Some(member)) = (&distinct_limit, member) {
+                let key = derived_key(&identifier, ":distinct");
+                // One past the limit tells that it is exceeded, the set needs no more members
+                let cap = distinct_limit.max_distinct + 1;
+                match add_to_set(&store, &key, &member, interval, cap).await {
+                    Ok(count) if count <= distinct_limit.max_distinct => {}
+                    Ok(_) if monitor_only => log_rejection(
+                        rejection_log,
+                        log_path,
+                        "Too many distinct members, not enforced",
+                        &identifier,
+                        &req,
+                    ),
+                    Ok(_) => {
+                        log_rejection(
+                            rejection_log,
+                            log_path,
+                            "Too many distinct members",
+                            &identifier,
+                            &req,
+                        );
+                        reject(&observer, &trace_source, &identifier, &req);
+                        telemetry::request(Outcome::Rejected);
+                        // The set lasts an interval at most
+                        let response =
+                            rejected_response(&req, &rejection, &identifier, 0, interval);
+                        return Err(response.into());
+                    }
+                    Err(e) => warn!("Could not count the distinct members of the client: {}", &e),
+                }
+            }
+            let pre_check = prefilter
+                .as_ref()
+                .map(|prefilter| prefilter.check(&identifier, max_requests, interval));
+            // What is left of the limit in the store, once the prefilter let some requests in
+            let store_max = match pre_check {
+                Some(PreCheck::Store { max_requests }) => max_requests,
+                _ => max_requests,
+            };
+            let decision = match pre_check {
+                Some(PreCheck::Skip { remaining, reset }) => Decision::Allowed {
+                    remaining,
+                    reset,
+                    key: identifier.clone(),
+                },
+                _ => {
+                    let span = DecisionSpan::new(&identifier, hash_span_keys);
+                    let decision = match shed_threshold {
+                        Some(ShedThreshold::QueueDepth(depth)) if in_flight.get() >= depth => None,
+                        _ => {
+                            let started = Instant::now();
+                            let fut = span.run(decide_scoped(
+                                &store,
+                                algorithm,
+                                peek,
+                                &identifier,
+                                store_max,
+                                interval,
+                                scope.as_ref(),
+                                sliding_expiry,
+                                store_clock,
+                            ));
+                            in_flight.set(in_flight.get() + 1);
+                            let _waiting = Waiting(&in_flight);
+                            let decision = match shed_threshold {
+                                Some(ShedThreshold::Latency(latency)) => {
+                                    actix_web::rt::time::timeout(latency, fut).await.ok()
+                                }
+                                _ => Some(fut.await),
+                            };
+                            decision.map(|decision| (decision, started.elapsed()))
+                        }
+                    };
+                    let (decision, latency) = match decision {
+                        Some(decision) => decision,
+                        None => {
+                            warn!("Store saturated, shedding request");
+                            if let Some(observer) = &observer {
+                                (observer)(&RateLimitEvent::Shed { key: &identifier });
+                            }
+                            return match failure_mode {
+                                FailureMode::FailClosed => {
+                                    Err(HttpResponse::ServiceUnavailable().into())
+                                }
+                                FailureMode::FailOpen => {
+                                    allow(&req, &observer, &identifier, DecisionReason::Shed);
+                                    srv.call(req).await
+                                }
+                            };
+                        }
+                    };
+                    telemetry::store_latency(latency);
+                    stats.record(latency, decision.is_err());
+                    span.record(
+                        match &decision {
+                            Ok(Decision::Allowed { .. }) => Outcome::Allowed,
+                            Ok(Decision::Rejected { .. }) => Outcome::Rejected,
+                            Err(_) => Outcome::Error,
+                        },
+                        latency,
+                    );
+                    match decision {
+                        Ok(decision) => {
+                            if let Some(since) = degraded_since.take() {
+                                info!("Store recovered");
+                                recovered_at.set(Some(Instant::now()));
+                                if let Some(observer) = &observer {
+                                    (observer)(&RateLimitEvent::Recovered {
+                                        degraded_for: since.elapsed(),
+                                    });
+                                }
+                            }
+                            decision
+                        }
+                        Err(e) => {
+                            telemetry::store_error();
+                            telemetry::request(Outcome::Error);
+                            if degraded_since.get().is_none() {
+                                degraded_since.set(Some(Instant::now()));
+                                if let Some(observer) = &observer {
+                                    (observer)(&RateLimitEvent::Degraded { error: &e });
+                                }
+                            }
+                            return match failure_mode {
+                                FailureMode::FailClosed => match &store_error_handler {
+                                    Some(handler) => Err((handler)(&req, &e).into()),
+                                    None => Err(e.into()),
+                                },
+                                FailureMode::FailOpen => {
+                                    warn!("Store failed, letting request through: {}", &e);
+                                    allow(&req, &observer, &identifier, DecisionReason::FailOpen);
+                                    srv.call(req).await
+                                }
+                            };
+                        }
+                    }
+                }
+            };
+            // The moving average does not refund, every request counts as one there
+            let refunds = !matches!(algorithm, Algorithm::Ewma { .. });
+            let body_cost_check = body_cost.filter(|_| !peek);
+            let reserved = refunds && !matches!(pre_check, Some(PreCheck::Skip { .. }));
+            let mut decision =
+                check_body_cost(&store, decision, body_cost_check, store_max, reserved).await;
+            let mut throttled = false;
+            if let Some(max_wait) = throttle_delay.filter(|_| !monitor_only) {
+                let deadline = Instant::now() + max_wait;
+                let mut attempts = 0;
+                while let Decision::Rejected { reset } = decision {
+                    let now = Instant::now();
+                    if attempts == THROTTLE_ATTEMPTS || now + reset > deadline {
+                        break;
+                    }
+                    attempts += 1;
+                    // Stores keeping whole seconds report the last one of a window as none
+                    // left, wait it out instead of asking again right away
+                    let reset = if reset == Duration::from_secs(0) {
+                        Duration::from_secs(1).min(deadline - now)
+                    } else {
+                        reset
+                    };
+                    debug!("Limit exceeded, delaying request for {:?}", reset);
+                    // Past the reset, so that the store has expired the window by then
+                    actix_web::rt::time::delay_for(reset + Duration::from_millis(10)).await;
+                    match decide_scoped(
+                        &store,
+                        algorithm,
+                        peek,
+                        &identifier,
+                        store_max,
+                        interval,
+                        scope.as_ref(),
+                        sliding_expiry,
+                        store_clock,
+                    )
+                    .await
+                    {
+                        Ok(next) => {
+                            decision =
+                                check_body_cost(&store, next, body_cost_check, store_max, refunds)
+                                    .await
+                        }
+                        Err(e) => {
+                            warn!("Store failed while delaying request: {}", &e);
+                            break;
+                        }
+                    }
+                    throttled = true;
+                }
+            }
+            // Extra requests over the limit while the store is recovering
+            let recovering = match (recovery_grace, recovered_at.get()) {
+                (Some((period, factor)), Some(at)) if at.elapsed() < period => {
+                    (max_requests as f64 * (factor - 1.0)).ceil() as usize
+                }
+                _ => 0,
+            };
+            let grace = match decision {
+                Decision::Rejected { reset }
+                    if !monitor_only && over_limit_grace + recovering > 0 =>
+                {
+                    within_grace(&store, &identifier, over_limit_grace + recovering, reset).await
+                }
+                _ => false,
+            };
+            match decision {
+                Decision::Rejected { reset } if monitor_only || grace => {
+                    let reason = if monitor_only {
+                        log_rejection(
+                            rejection_log,
+                            log_path,
+                            "Limit exceeded, not enforced",
+                            &identifier,
+                            &req,
+                        );
+                        telemetry::request(Outcome::Rejected);
+                        DecisionReason::MonitorOnly
+                    } else {
+                        log_rejection(
+                            rejection_log,
+                            log_path,
+                            "Limit exceeded, within grace",
+                            &identifier,
+                            &req,
+                        );
+                        telemetry::request(Outcome::Allowed);
+                        if recovering > 0 {
+                            DecisionReason::RecoveryGrace
+                        } else {
+                            DecisionReason::OverLimitGrace
+                        }
+                    };
+                    req.extensions_mut().insert(RateLimitInfo {
+                        limit: max_requests,
+                        remaining: 0,
+                        reset,
+                        exceeded: true,
+                    });
+                    record_decision(&req, true, &identifier, 0, reset, algorithm);
+                    allow(&req, &observer, &identifier, reason);
+                    let mut res = srv.call(req).await?;
+                    let rate_headers = headers_on_errors || res.status().is_success();
+                    let headers = res.headers_mut();
+                    if rate_headers
+                        && !more_restrictive(headers, &header_names, "0", reset.as_secs())
+                    {
+                        headers.insert(
+                            header_names.limit.clone(),
+                            HeaderValue::from_str(&remaining_header.count(
+                                token_scale,
+                                max_requests,
+                                max_requests,
+                            ))
+                            .unwrap(),
+                        );
+                        headers.insert(
+                            header_names.remaining.clone(),
+                            HeaderValue::from_static("0"),
+                        );
+                        headers.insert(
+                            header_names.reset.clone(),
+                            HeaderValue::from_str(reset.as_secs().to_string().as_str()).unwrap(),
+                        );
+                        if used_header {
+                            headers.insert(
+                                header_names.used.clone(),
+                                HeaderValue::from_str(&remaining_header.used(
+                                    token_scale,
+                                    max_requests,
+                                    0,
+                                ))
+                                .unwrap(),
+                            );
+                        }
+                        if algorithm_header {
+                            headers.insert(
+                                header_names.algorithm.clone(),
+                                HeaderValue::from_static(algorithm.name()),
+                            );
+                        }
+                    }
+                    if monitor_only {
+                        headers.insert(
+                            header_names.dryrun.clone(),
+                            HeaderValue::from_static("true"),
+                        );
+                    } else {
+                        headers.insert(
+                            header_names.warning.clone(),
+                            HeaderValue::from_static("limit exceeded"),
+                        );
+                    }
+                    Ok(res)
+                }
+                Decision::Rejected { reset } => {
+                    if let Some((tolerance, penalty)) = backoff_penalty {
+                        let endpoint = Some((req.method(), req.path())).filter(|_| log_path);
+                        check_backoff(
+                            &store,
+                            &identifier,
+                            reset,
+                            tolerance,
+                            penalty,
+                            rejection_log,
+                            endpoint,
+                        )
+                        .await;
+                    }
+                    log_rejection(rejection_log, log_path, "Limit exceeded", &identifier, &req);
+                    reject(&observer, &trace_source, &identifier, &req);
+                    telemetry::request(Outcome::Rejected);
+                    record_decision(&req, false, &identifier, 0, reset, algorithm);
+                    let response = rejected_response(&req, &rejection, &identifier, 0, reset);
+                    Err(response.into())
+                }
+                Decision::Allowed {
+                    remaining,
+                    reset,
+                    key,
+                } => {
+                    // Held until the service is done with the request
+                    let _permit = match &concurrency {
+                        Some(_) if peek => None,
+                        Some(concurrency) => match concurrency.acquire(&identifier) {
+                            Some(permit) => Some(permit),
+                            None if monitor_only => {
+                                log_rejection(
+                                    rejection_log,
+                                    log_path,
+                                    "Concurrency limit exceeded, not enforced",
+                                    &identifier,
+                                    &req,
+                                );
+                                None
+                            }
+                            None => {
+                                log_rejection(
+                                    rejection_log,
+                                    log_path,
+                                    "Concurrency limit exceeded",
+                                    &identifier,
+                                    &req,
+                                );
+                                reject(&observer, &trace_source, &identifier, &req);
+                                telemetry::request(Outcome::Rejected);
+                                record_decision(
+                                    &req,
+                                    false,
+                                    &identifier,
+                                    remaining,
+                                    reset,
+                                    algorithm,
+                                );
+                                let response = rejected_response(
+                                    &req,
+                                    &rejection,
+                                    &identifier,
+                                    remaining,
+                                    reset,
+                                );
+                                return Err(response.into());
+                            }
+                        },
+                        None => None,
+                    };
+                    if let Some(key) = idempotency_key.as_ref().filter(|_| !peek) {
+                        let expiry = reset.max(Duration::from_secs(1));
+                        if let Err(e) = set(&store, key, IDEMPOTENT_RETRIES, expiry).await {
+                            warn!(
+                                "Could not record the idempotency key of the request: {}",
+                                &e
+                            );
+                        }
+                    }
+                    telemetry::request(Outcome::Allowed);
+                    req.extensions_mut().insert(RateLimitInfo {
+                        limit: max_requests,
+                        remaining,
+                        reset,
+                        exceeded: false,
+                    });
+                    record_decision(&req, true, &identifier, remaining, reset, algorithm);
+                    let reason = if retry {
+                        DecisionReason::Retry
+                    } else if peek {
+                        DecisionReason::Peek
+                    } else if throttled {
+                        DecisionReason::Throttled
+                    } else {
+                        DecisionReason::WithinLimit
+                    };
+                    allow(&req, &observer, &identifier, reason);
+                    // Execute the request
+                    // Kept for the log when the client is blocked for its errors
+                    let endpoint = match &error_limit {
+                        Some(_) if log_path => Some((req.method().clone(), req.path().to_string())),
+                        _ => None,
+                    };
+                    let started = Instant::now();
+                    let fut = srv.call(req);
+                    let res = fut.await;
+                    if let Some(adaptive) = &adaptive {
+                        adaptive.record(started.elapsed());
+                    }
+                    if let Some(error_limit) = &error_limit {
+                        let status = match &res {
+                            Ok(res) => res.status(),
+                            Err(e) => e.as_response_error().status_code(),
+                        };
+                        if (error_limit.predicate)(status) {
+                            let endpoint = endpoint.as_ref().map(|(m, p)| (m, p.as_str()));
+                            count_error(&store, &identifier, error_limit, rejection_log, endpoint)
+                                .await;
+                        }
+                    }
+                    let mut res = res?;
+                    // One request was reserved up front, charge the rest of the reported cost
+                    let reported = {
+                        let extensions = res.request().extensions();
+                        match (
+                            extensions.get::<FractionalCost>(),
+                            extensions.get::<ActualCost>(),
+                        ) {
+                            (Some(cost), _) => {
+                                Some((cost.0.max(0.0) * token_scale as f64).round() as usize)
+                            }
+                            (None, Some(cost)) => Some(cost.0.saturating_mul(token_scale)),
+                            (None, None) => None,
+                        }
+                    };
+                    if let Some(cost) = reported.filter(|cost| *cost < min_cost) {
+                        warn!(
+                            "Request to {} reported a cost of {} units, charging the minimum of {}",
+                            res.request().path(),
+                            cost,
+                            min_cost
+                        );
+                    }
+                    let cost = match reported {
+                        Some(cost) => Some(cost),
+                        None if body_cost.is_some() => body_cost,
+                        None if token_scale > 1 => Some(token_scale),
+                        None => None,
+                    };
+                    let cache_hit = cache_hit_refund
+                        .filter(|_| res.request().extensions().get::<CacheHit>().is_some());
+                    let cost = match (cost, cache_hit) {
+                        // Otherwise the request costs the unit reserved up front
+                        (cost, Some(fraction)) => {
+                            let cost = cost.unwrap_or(1);
+                            let fraction = fraction.clamp(0.0, 1.0);
+                            let refund = (cost as f64 * fraction).round() as usize;
+                            Some(cost.saturating_sub(refund))
+                        }
+                        (cost, _) => cost,
+                    };
+                    let cost = match cost {
+                        // Unless a minimum is set, a cache hit may cost nothing
+                        Some(cost) if cache_hit.is_some() && min_cost <= 1 => Some(cost),
+                        Some(cost) => Some(cost.max(min_cost)),
+                        None if min_cost > 1 => Some(min_cost),
+                        None => None,
+                    };
+                    // A request which costs nothing gives back the unit it reserved
+                    let refunded = (res.request().extensions().get::<Refund>().is_some()
+                        || cost == Some(0))
+                        && !peek
+                        && !matches!(pre_check, Some(PreCheck::Skip { .. }))
+                        && !matches!(algorithm, Algorithm::Ewma { .. });
+                    let remaining = match cost {
+                        _ if refunded => match refund(&store, &key, store_max).await {
+                            Ok(_) if algorithm == Algorithm::SlidingWindowCounter => {
+                                (remaining + 1).min(max_requests)
+                            }
+                            Ok(left) => left,
+                            Err(e) => {
+                                warn!("Could not refund the request: {}", &e);
+                                remaining
+                            }
+                        },
+                        // The moving average counts every request as one
+                        Some(_) if matches!(algorithm, Algorithm::Ewma { .. }) => remaining,
+                        Some(cost) if cost > 1 && !peek && !res.status().is_server_error() => {
+                            if let Some(PreCheck::Skip { .. }) = pre_check {
+                                if let Some(prefilter) = &prefilter {
+                                    prefilter.charge(&identifier, cost - 1);
+                                }
+                                remaining.saturating_sub(cost - 1)
+                            } else {
+                                // Never take more than is left, the count can not go below zero
+                                match update(&store, &key, (cost - 1).min(remaining)).await {
+                                    Ok(_) if algorithm == Algorithm::SlidingWindowCounter => {
+                                        remaining.saturating_sub(cost - 1)
+                                    }
+                                    Ok(left) => left,
+                                    Err(e) => {
+                                        warn!("Could not charge the cost of the request: {}", &e);
+                                        remaining
+                                    }
+                                }
+                            }
+                        }
+                        _ => remaining,
+                    };
+                    let rate_headers = headers_on_errors || res.status().is_success();
+                    let headers = res.headers_mut();
+                    let remaining_value =
+                        remaining_header.count(token_scale, max_requests, remaining);
+                    if rate_headers
+                        && !more_restrictive(
+                            headers,
+                            &header_names,
+                            &remaining_value,
+                            reset.as_secs(),
+                        )
+                    {
+                        // Safe unwraps, since usize is always convertible to string
+                        headers.insert(
+                            header_names.limit.clone(),
+                            HeaderValue::from_str(&remaining_header.count(
+                                token_scale,
+                                max_requests,
+                                max_requests,
+                            ))
+                            .unwrap(),
+                        );
+                        headers.insert(
+                            header_names.remaining.clone(),
+                            HeaderValue::from_str(&remaining_value).unwrap(),
+                        );
+                        headers.insert(
+                            header_names.reset.clone(),
+                            HeaderValue::from_str(reset.as_secs().to_string().as_str()).unwrap(),
+                        );
+                        if used_header {
+                            headers.insert(
+                                header_names.used.clone(),
+                                HeaderValue::from_str(&remaining_header.used(
+                                    token_scale,
+                                    max_requests,
+                                    remaining,
+                                ))
+                                .unwrap(),
+                            );
+                        }
+                        if algorithm_header {
+                            headers.insert(
+                                header_names.algorithm.clone(),
+                                HeaderValue::from_static(algorithm.name()),
+                            );
+                        }
+                    }
+                    if let Some(fraction) = soft_limit {
+                        let used = max_requests.saturating_sub(remaining);
+                        if used as f64 >= fraction * max_requests as f64
+                            && !headers.contains_key(&header_names.warning)
+                        {
+                            headers.insert(
+                                header_names.warning.clone(),
+                                HeaderValue::from_static("approaching limit"),
+                            );
+                        }
+                    }
+                    if monitor_only {
+                        headers.insert(
+                            header_names.dryrun.clone(),
+                            HeaderValue::from_static("true"),
+                        );
+                    }
+                    Ok(res)
+                }
+            }
+        })
+    }
+}
+
+/// Extension trait to mount a [RateLimiter](struct.RateLimiter.html) on an `App`.
+///
+/// This is equivalent to `App::wrap`, and composes with other `wrap` calls the same way.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_ratelimit::{MemoryStore, RateLimitExt, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let app = App::new()
+///         .with_rate_limit(RateLimiter::memory(&store, 100, Duration::from_secs(60)))
+///         .route("/", web::get().to(HttpResponse::Ok));
+/// }
+/// ```
+pub trait RateLimitExt<B: MessageBody> {
+    /// Wraps the application with `limiter`
+    fn with_rate_limit<A>(
+        self,
+        limiter: RateLimiter<A>,
+    ) -> App<
+        impl ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = AWError,
+            InitError = (),
+        >,
+        B,
+    >
+    where
+        A: Handler<ActorMessage> + Send + Sync + 'static,
+        A::Context: ToEnvelope<A, ActorMessage>;
+}
+
+impl<T, B> RateLimitExt<B> for App<T, B>
+where
+    B: MessageBody + 'static,
+    T: ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = AWError,
+        InitError = (),
+    >,
+    T::Service: 'static,
+    <T::Service as Service>::Future: 'static,
+{
+    fn with_rate_limit<A>(
+        self,
+        limiter: RateLimiter<A>,
+    ) -> App<
+        impl ServiceFactory<
+            Config = (),
+            Request = ServiceRequest,
+            Response = ServiceResponse<B>,
+            Error = AWError,
+            InitError = (),
+        >,
+        B,
+    >
+    where
+        A: Handler<ActorMessage> + Send + Sync + 'static,
+        A::Context: ToEnvelope<A, ActorMessage>,
+    {
+        self.wrap(limiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActorResponse;
+    use actix_web::{test, web, App};
+    use futures::future;
+
+    /// Store which fails every operation
+    struct FailingStore;
+
+    impl Actor for FailingStore {
+        type Context = actix::Context<Self>;
+    }
+
+    impl Handler<ActorMessage> for FailingStore {
+        type Result = ActorResponse;
+        fn handle(&mut self, _: ActorMessage, _: &mut Self::Context) -> Self::Result {
+            ActorResponse::Set(Box::pin(future::ready(Err(ARError::Disconnected))))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_validate() {
+        let limiter = RateLimiter::new(FailingStore.start());
+        assert!(limiter.validate().await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_store_error_handler() {
+        let limiter = RateLimiter::new(FailingStore.start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .with_store_error_handler(|req, error| {
+                HttpResponse::ServiceUnavailable().body(format!("{}: {}", req.path(), error))
+            });
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let err = app.call(req).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(
+            res.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        let body = test::read_body(ServiceResponse::new(
+            test::TestRequest::default().to_http_request(),
+            res,
+        ))
+        .await;
+        assert_eq!(body, format!("/: {}", ARError::Disconnected).as_bytes());
+    }
+
+    #[actix_rt::test]
+    async fn test_builder() {
+        let store = FailingStore.start();
+        let err = RateLimiter::builder(store.clone())
+            .with_max_requests(10)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "missing required field: interval");
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "missing required field: max_requests");
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_millis(500))
+            .with_max_requests(10)
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("invalid value for interval"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_soft_limit(1.5))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("invalid value for soft_limit"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_monitor_only(true).with_over_limit_grace(1))
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "monitor_only can not be combined with over_limit_grace"
+        );
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| {
+                limiter
+                    .with_monitor_only(true)
+                    .with_throttle_delay(Duration::from_secs(1))
+            })
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "monitor_only can not be combined with throttle_delay"
+        );
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| {
+                limiter
+                    .with_sliding_expiry(true)
+                    .with_algorithm(Algorithm::SlidingWindowCounter)
+            })
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "sliding_expiry can not be combined with algorithm"
+        );
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| {
+                limiter
+                    .with_prefilter(PreFilter::new(64))
+                    .with_algorithm(Algorithm::SlidingWindowCounter)
+            })
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "prefilter can not be combined with algorithm"
+        );
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_recovery_grace(Duration::from_secs(60), 0.5))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value for recovery_grace"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_shed_threshold(ShedThreshold::QueueDepth(0)))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value for shed_threshold"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_token_scale(0))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("invalid value for token_scale"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_min_cost(0))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("invalid value for min_cost"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_cache_hit_refund(1.5))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value for cache_hit_refund"));
+        let err = RateLimiter::builder(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .configure(|limiter| limiter.with_distinct_limit(|_| None, 0))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value for distinct_limit"));
+        assert!(RateLimiter::builder(store)
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .build()
+            .is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_fail_open() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let limiter = RateLimiter::new(FailingStore.start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .with_failure_mode(FailureMode::FailOpen)
+            .with_observer(move |event| recorded.borrow_mut().push(format!("{:?}", event)));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            assert!(res.headers().get("x-ratelimit-limit").is_none());
+        }
+        // Degradation is reported once, not for every failing request
+        let events = events.borrow();
+        assert_eq!(events.len(), 3);
+        assert!(events[0].starts_with("Degraded"));
+        assert_eq!(
+            events.iter().filter(|e| e.ends_with("FailOpen }")).count(),
+            2
+        );
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_with_rate_limit() {
+        use crate::MemoryStore;
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .with_rate_limit(RateLimiter::memory(&store, 1, Duration::from_secs(60)))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let err = app.call(req).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_allowlist_data() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        struct Key(&'static str);
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_allowlist_data(|req, key: &Data<Key>| {
+                req.headers()
+                    .get("x-key")
+                    .map(|value| value == key.0)
+                    .unwrap_or(false)
+            });
+        let mut app = test::init_service(
+            App::new()
+                .data(Key("secret"))
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..3 {
+            let req = test::TestRequest::default()
+                .header("x-key", "secret")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            assert!(res.headers().get("x-ratelimit-limit").is_none());
+        }
+        let req = test::TestRequest::default()
+            .header("x-key", "wrong")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_sliding_estimate() {
+        let interval = Duration::from_secs(60);
+        // A quarter into the window, three quarters of the previous one still count
+        let estimate = sliding_estimate(8, 2, Duration::from_secs(15), interval);
+        assert!((estimate - 8.0).abs() < f64::EPSILON);
+        let estimate = sliding_estimate(8, 2, interval, interval);
+        assert!((estimate - 2.0).abs() < f64::EPSILON);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_sliding_window_counter() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStore::new();
+        let limiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_algorithm(Algorithm::SlidingWindowCounter);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+        }
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = app.call(req).await;
+        let res = res.err().unwrap();
+        assert_eq!(
+            res.as_response_error().status_code(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_sliding_window_counter_concurrent() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let interval = Duration::from_secs(60);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // Requests decided at once do not all go by the same count
+        let decisions = futures::future::join_all(
+            (0..20).map(|_| sliding_window_counter(&store, "client", 5, interval, now)),
+        )
+        .await;
+        let allowed = decisions
+            .iter()
+            .filter(|decision| matches!(decision, Ok(Decision::Allowed { .. })))
+            .count();
+        assert_eq!(allowed, 5);
+        assert!(decisions.iter().all(Result::is_ok));
+        assert_eq!(get(&store, "client").await.unwrap(), Some(0));
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_ewma() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let half_life = Duration::from_secs(10);
+        // The peek goes by the local clock
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for expected in (0..3).rev() {
+            let decision = ewma(&store, "client", half_life, 3.0, now).await.unwrap();
+            assert!(
+                matches!(decision, Decision::Allowed { remaining, .. } if remaining == expected)
+            );
+        }
+        // Back under the threshold once the average decayed from 3 to 2
+        let decision = ewma(&store, "client", half_life, 3.0, now).await.unwrap();
+        assert!(matches!(decision, Decision::Rejected { reset } if reset.as_millis() == 5849));
+        // Half of it is gone after a half-life
+        let later = now + half_life;
+        let decision = ewma(&store, "client", half_life, 3.0, later).await.unwrap();
+        assert!(matches!(decision, Decision::Allowed { remaining: 0, .. }));
+        assert!(matches!(
+            ewma(&store, "client", half_life, 3.0, later).await.unwrap(),
+            Decision::Rejected { .. }
+        ));
+        let decision = peek_window(
+            &store,
+            Algorithm::Ewma {
+                half_life,
+                threshold: 3.0,
+            },
+            "client",
+            3,
+            half_life,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(decision, Decision::Allowed { remaining: 0, .. }));
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_ewma_concurrent() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let half_life = Duration::from_secs(10);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let decisions =
+            futures::future::join_all((0..10).map(|_| ewma(&addr, "client", half_life, 3.0, now)))
+                .await;
+        let allowed = decisions
+            .iter()
+            .filter(|decision| matches!(decision, Ok(Decision::Allowed { .. })))
+            .count();
+        assert_eq!(allowed, 3);
+        assert!(decisions.iter().all(Result::is_ok));
+        // The average and the time it is at are a single entry
+        let stored = get(&addr, "client:ewma").await.unwrap().unwrap();
+        assert!((ewma_decode(stored, now, half_life) - 3.0).abs() < 1e-6);
+        assert!((ewma_decode(stored, now + half_life, half_life) - 1.5).abs() < 1e-6);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_limit_resolver() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_limit_resolver(|req| match req.headers().get("x-tier") {
+                Some(tier) if tier == "paid" => Limit {
+                    max_requests: 5,
+                    interval: Duration::from_secs(3600),
+                },
+                _ => Limit {
+                    max_requests: 1,
+                    interval: Duration::from_secs(60),
+                },
+            });
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .header("x-tier", "paid")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "5");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "4");
+        let reset: u64 = res
+            .headers()
+            .get("x-ratelimit-reset")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(reset > 60);
+        // Downgraded mid-window, the leftovers of the paid tier are capped
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        assert!(app.call(req).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_soft_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(4)
+            .with_soft_limit(0.5);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut warned = Vec::new();
+        for _ in 0..4 {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            warned.push(res.headers().contains_key("x-ratelimit-warning"));
+        }
+        assert_eq!(warned, vec![false, true, true, true]);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_missing_identifier() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStore::new();
+        let app = |policy| {
+            let limiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(1)
+                .with_identifier(|_| Err(ARError::IdentificationError))
+                .with_missing_identifier(policy);
+            test::init_service(
+                App::new()
+                    .wrap(limiter)
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            )
+        };
+        let mut shared = app(Policy::default()).await;
+        let res = test::call_service(&mut shared, test::TestRequest::default().to_request()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        assert!(shared
+            .call(test::TestRequest::default().to_request())
+            .await
+            .is_err());
+        let mut allow = app(Policy::Allow).await;
+        let res = test::call_service(&mut allow, test::TestRequest::default().to_request()).await;
+        assert!(res.status().is_success());
+        assert!(res.headers().get("x-ratelimit-limit").is_none());
+        let mut reject = app(Policy::Reject).await;
+        assert!(reject
+            .call(test::TestRequest::default().to_request())
+            .await
+            .is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_actual_cost() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use actix_web::HttpRequest;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5);
+        let mut app = test::init_service(App::new().wrap(limiter).route(
+            "/{cost}",
+            web::get().to(|req: HttpRequest, cost: web::Path<usize>| {
+                req.extensions_mut().insert(ActualCost(cost.into_inner()));
+                if req.headers().contains_key("x-fail") {
+                    HttpResponse::InternalServerError()
+                } else {
+                    HttpResponse::Ok()
+                }
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::with_uri("/3")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "2");
+        // Failed requests only pay for the reservation
+        let req = test::TestRequest::with_uri("/3")
+            .header("x-fail", "1")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_cost_above_remaining() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use actix_web::HttpRequest;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5);
+        let mut app = test::init_service(App::new().wrap(limiter).route(
+            "/",
+            web::get().to(|req: HttpRequest| {
+                req.extensions_mut().insert(ActualCost(20));
+                HttpResponse::Ok()
+            }),
+        ))
+        .await;
+        let get = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, get()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        let err = app.call(get()).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_min_cost() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use actix_web::HttpRequest;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5)
+            .with_min_cost(2);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route(
+                    "/free",
+                    web::get().to(|req: HttpRequest| {
+                        req.extensions_mut().insert(ActualCost(0));
+                        HttpResponse::Ok()
+                    }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for (uri, remaining) in &[("/free", "3"), ("/", "1")] {
+            let req = test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_cache_hit_refund() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use actix_web::HttpRequest;
+        let limiter = |fraction| {
+            RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(10)
+                .with_token_scale(10)
+                .with_cache_hit_refund(fraction)
+        };
+        let batch = || {
+            web::get().to(|req: HttpRequest| {
+                req.extensions_mut().insert(ActualCost(4));
+                req.extensions_mut().insert(CacheHit);
+                HttpResponse::Ok()
+            })
+        };
+        let get = |uri| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter(0.75))
+                .route(
+                    "/cached",
+                    web::get().to(|req: HttpRequest| {
+                        req.extensions_mut().insert(CacheHit);
+                        HttpResponse::Ok()
+                    }),
+                )
+                .route("/batch", batch())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        // A quarter of a token, rounded to the scale, then a quarter of four tokens
+        for (uri, remaining) in &[("/cached", "9.8"), ("/batch", "8.8"), ("/", "7.8")] {
+            let res = test::call_service(&mut app, get(uri)).await;
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+        // Out of range without the builder, clamped to a full refund
+        let mut app =
+            test::init_service(App::new().wrap(limiter(1.5)).route("/batch", batch())).await;
+        let res = test::call_service(&mut app, get("/batch")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "10");
+        // Down to the minimum cost, when one is set
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter(1.0).with_min_cost(3))
+                .route("/batch", batch()),
+        )
+        .await;
+        let res = test::call_service(&mut app, get("/batch")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "9.7");
+        // With the default scale, the unit reserved up front is given back
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(10)
+                        .with_cache_hit_refund(0.5),
+                )
+                .route(
+                    "/cached",
+                    web::get().to(|req: HttpRequest| {
+                        req.extensions_mut().insert(CacheHit);
+                        HttpResponse::Ok()
+                    }),
+                ),
+        )
+        .await;
+        for _ in 0..3 {
+            let res = test::call_service(&mut app, get("/cached")).await;
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "10");
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_token_scale() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use actix_web::HttpRequest;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_token_scale(1000)
+            .with_used_header(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route(
+                    "/{cost}",
+                    web::get().to(|req: HttpRequest, cost: web::Path<f64>| {
+                        req.extensions_mut()
+                            .insert(FractionalCost(cost.into_inner()));
+                        HttpResponse::Ok()
+                    }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |uri: &str| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call("/0.25")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1.75");
+        assert_eq!(res.headers().get("x-ratelimit-used").unwrap(), "0.25");
+        // Requests without a cost take a whole token
+        let res = test::call_service(&mut app, call("/")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0.75");
+        let res = test::call_service(&mut app, call("/0.75")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        assert!(app.call(call("/0.25")).await.is_err());
+        assert_eq!(format_tokens(1_005, 1000), "1.005");
+        assert_eq!(format_tokens(2, 3), "0.6");
+        assert_eq!(format_tokens(7, 1), "7");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_per_client_per_endpoint() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::per_client_per_endpoint(
+            MemoryStoreActor::from(MemoryStore::new()).start(),
+            2,
+            Duration::from_secs(60),
+        );
+        let mut app = test::init_service(
+            App::new().wrap(limiter).service(
+                web::resource("/users/{id}")
+                    .route(web::get().to(HttpResponse::Ok))
+                    .route(web::post().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let call = |method, uri, port| {
+            test::TestRequest::with_uri(uri)
+                .method(method)
+                .peer_addr(format!("127.0.0.1:{}", port).parse().unwrap())
+                .to_request()
+        };
+        // One bucket for the endpoint and the address, whatever the id and the port
+        for (uri, port, remaining) in &[("/users/1", 8080, "1"), ("/users/2", 8081, "0")] {
+            let res = test::call_service(&mut app, call(Method::GET, uri, port)).await;
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+        let res = test::call_service(&mut app, call(Method::POST, "/users/1", &8080)).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_stats() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1);
+        let stats = limiter.stats();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        test::call_service(&mut app, call()).await;
+        // Rejected requests are a successful round trip too
+        assert!(app.call(call()).await.is_err());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.calls, 2);
+        assert_eq!(snapshot.errors, 0);
+        assert!(snapshot.p50 > Duration::from_secs(0));
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_previous_identifier() {
+        use crate::stores::{get, seed};
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        seed(&store, "127.0.0.1:8080", 2, Duration::from_secs(30))
+            .await
+            .unwrap();
+        let limiter = RateLimiter::new(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5)
+            .with_identifier(identifiers::peer_ip)
+            .with_previous_identifier(identifiers::remote_addr);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-reset").unwrap(), "29");
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        // The previous key is left to expire
+        assert_eq!(get(&store, "127.0.0.1:8080").await.unwrap(), Some(2));
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_key_normalization() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_identifier(|req| Ok(req.connection_info().host().to_string()))
+            .with_key_normalization(Normalization::Lowercase);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for (host, remaining) in &[("Example.com", "1"), ("example.com", "0")] {
+            let req = test::TestRequest::default()
+                .header("host", *host)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_refund() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use actix_web::HttpRequest;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/noop",
+                    web::get().to(|req: HttpRequest| {
+                        req.extensions_mut().insert(Refund);
+                        HttpResponse::Ok()
+                    }),
+                ),
+        )
+        .await;
+        let call = |uri| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        for _ in 0..3 {
+            let res = test::call_service(&mut app, call("/noop")).await;
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "2");
+        }
+        let res = test::call_service(&mut app, call("/")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_trace_id() {
+        let request = |name, value| {
+            test::TestRequest::default()
+                .header(name, value)
+                .to_srv_request()
+        };
+        let trace_id = |req: &ServiceRequest| TraceSource::TraceParent.trace_id(req);
+        let req = request(
+            "traceparent",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        );
+        assert_eq!(trace_id(&req).unwrap(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        for malformed in &[
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736",
+        ] {
+            assert_eq!(trace_id(&request("traceparent", malformed)), None);
+        }
+        let source = TraceSource::Header(HeaderName::from_static("x-request-id"));
+        assert_eq!(
+            source.trace_id(&request("x-request-id", "abc")).unwrap(),
+            "abc"
+        );
+        assert_eq!(source.trace_id(&request("x-other", "abc")), None);
+    }
+
+    #[test]
+    fn test_match_path_limit() {
+        let limit = |max_requests| Limit {
+            max_requests,
+            interval: Duration::from_secs(60),
+        };
+        let path_limits = vec![
+            ("/v1".to_string(), limit(1)),
+            ("/v1/admin".to_string(), limit(2)),
+        ];
+        let matched = |path| match_path_limit(&path_limits, path).map(|(_, l)| l.max_requests);
+        assert_eq!(matched("/v1"), Some(1));
+        assert_eq!(matched("/v1/users"), Some(1));
+        assert_eq!(matched("/v1/admin/users"), Some(2));
+        assert_eq!(matched("/v10"), None);
+        assert_eq!(matched("/v2"), None);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_path_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5)
+            .with_path_limit(
+                "/v1",
+                Limit {
+                    max_requests: 1,
+                    interval: Duration::from_secs(60),
+                },
+            )
+            .with_path_limit(
+                "/v2/",
+                Limit {
+                    max_requests: 2,
+                    interval: Duration::from_secs(60),
+                },
+            );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/{version}/items", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |uri| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call("/v1/items")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        assert!(app.call(call("/v1/items")).await.is_err());
+        // A fresh bucket for the other version
+        let res = test::call_service(&mut app, call("/v2/items")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        let res = test::call_service(&mut app, call("/v3/items")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "5");
+    }
+
+    #[test]
+    fn test_limit_key_length() {
+        let key = || "é".repeat(4);
+        assert_eq!(
+            limit_key_length(key(), 8, OversizedKey::Reject).unwrap(),
+            key()
+        );
+        assert_eq!(
+            limit_key_length(key(), 5, OversizedKey::Truncate).unwrap(),
+            "éé"
+        );
+        let hashed = limit_key_length(key(), 5, OversizedKey::Hash).unwrap();
+        assert!(hashed.starts_with("hash:"));
+        assert_eq!(
+            hashed,
+            limit_key_length(key(), 5, OversizedKey::Hash).unwrap()
+        );
+        assert!(limit_key_length(key(), 5, OversizedKey::Reject).is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_monitor_only() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_monitor_only(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+            assert_eq!(res.headers().get("x-ratelimit-dryrun").unwrap(), "true");
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_switch() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let switch = LimiterSwitch::new(false);
+        let limiter = |headers_when_disabled| {
+            RateLimiter::new(store.clone())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(1)
+                .with_switch(switch.clone(), headers_when_disabled)
+        };
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::resource("/quiet")
+                        .wrap(limiter(false))
+                        .to(HttpResponse::Ok),
+                )
+                .service(web::resource("/").wrap(limiter(true)).to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |path| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, get("/quiet")).await;
+        assert!(res.headers().get("x-ratelimit-remaining").is_none());
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, get("/")).await;
+            assert!(res.status().is_success());
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+            assert_eq!(res.headers().get("x-ratelimit-dryrun").unwrap(), "true");
+        }
+        switch.set_enabled(true);
+        assert!(app.call(get("/")).await.is_err());
+        assert!(app.call(get("/quiet")).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_rate_limit_info() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2);
+        let mut app = test::init_service(App::new().wrap(limiter).route(
+            "/",
+            web::get().to(|info: RateLimitInfo| {
+                HttpResponse::Ok().body(format!("{}/{}", info.remaining, info.limit))
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(body, "1/2");
     }
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let store = self.store.clone();
-        let mut srv = self.service.clone();
-        let max_requests = self.max_requests;
-        let interval = Duration::from_secs(self.interval);
-        let identifier = self.identifier.clone();
-        Box::pin(async move {
-            let identifier: String = (identifier)(&req)?;
-            let remaining: ActorResponse = store
-                .send(ActorMessage::Get(String::from(&identifier)))
-                .await?;
-            match remaining {
-                ActorResponse::Get(opt) => {
-                    let opt = opt.await?;
-                    if let Some(c) = opt {
-                        // Existing entry in store
-                        let expiry = store
-                            .send(ActorMessage::Expire(String::from(&identifier)))
-                            .await?;
-                        let reset: Duration = match expiry {
-                            ActorResponse::Expire(dur) => dur.await?,
-                            _ => unreachable!(),
-                        };
-                        if c == 0 {
-                            info!("Limit exceeded for client: {}", &identifier);
-                            let mut response = HttpResponse::TooManyRequests();
-                            // let mut response = (error_callback)(&mut response);
-                            response.set_header("x-ratelimit-limit", max_requests.to_string());
-                            response.set_header("x-ratelimit-remaining", c.to_string());
-                            response.set_header("x-ratelimit-reset", reset.as_secs().to_string());
-                            Err(response.into())
-                        } else {
-                            // Decrement value
-                            let res: ActorResponse = store
-                                .send(ActorMessage::Update {
-                                    key: identifier,
-                                    value: 1,
-                                })
-                                .await?;
-                            let updated_value: usize = match res {
-                                ActorResponse::Update(c) => c.await?,
-                                _ => unreachable!(),
-                            };
-                            // Execute the request
-                            let fut = srv.call(req);
-                            let mut res = fut.await?;
-                            let headers = res.headers_mut();
-                            // Safe unwraps, since usize is always convertible to string
-                            headers.insert(
-                                HeaderName::from_static("x-ratelimit-limit"),
-                                HeaderValue::from_str(max_requests.to_string().as_str())?,
-                            );
-                            headers.insert(
-                                HeaderName::from_static("x-ratelimit-remaining"),
-                                HeaderValue::from_str(updated_value.to_string().as_str())?,
-                            );
-                            headers.insert(
-                                HeaderName::from_static("x-ratelimit-reset"),
-                                HeaderValue::from_str(reset.as_secs().to_string().as_str())?,
-                            );
-                            Ok(res)
-                        }
-                    } else {
-                        // New client, create entry in store
-                        let current_value = max_requests - 1;
-                        let res = store
-                            .send(ActorMessage::Set {
-                                key: String::from(&identifier),
-                                value: current_value,
-                                expiry: interval,
-                            })
-                            .await?;
-                        match res {
-                            ActorResponse::Set(c) => c.await?,
-                            _ => unreachable!(),
-                        }
-                        let fut = srv.call(req);
-                        let mut res = fut.await?;
-                        let headers = res.headers_mut();
-                        // Safe unwraps, since usize is always convertible to string
-                        headers.insert(
-                            HeaderName::from_static("x-ratelimit-limit"),
-                            HeaderValue::from_str(max_requests.to_string().as_str()).unwrap(),
-                        );
-                        headers.insert(
-                            HeaderName::from_static("x-ratelimit-remaining"),
-                            HeaderValue::from_str(current_value.to_string().as_str()).unwrap(),
-                        );
-                        headers.insert(
-                            HeaderName::from_static("x-ratelimit-reset"),
-                            HeaderValue::from_str(interval.as_secs().to_string().as_str()).unwrap(),
-                        );
-                        Ok(res)
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_peek_methods() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        for algorithm in &[Algorithm::FixedWindow, Algorithm::SlidingWindowCounter] {
+            let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(2)
+                .with_algorithm(*algorithm)
+                .with_peek_methods(vec![Method::HEAD]);
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(limiter)
+                    .route("/", web::to(HttpResponse::Ok)),
+            )
+            .await;
+            let call = |method| {
+                test::TestRequest::default()
+                    .method(method)
+                    .peer_addr("127.0.0.1:8080".parse().unwrap())
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, call(Method::HEAD)).await;
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "2");
+            for remaining in &["1", "0"] {
+                let res = test::call_service(&mut app, call(Method::GET)).await;
+                assert_eq!(
+                    res.headers().get("x-ratelimit-remaining").unwrap(),
+                    remaining
+                );
+            }
+            let res = test::call_service(&mut app, call(Method::HEAD)).await;
+            assert!(res.status().is_success());
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_calendar_window() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let unit = CalendarUnit::Day;
+        let algorithm = Algorithm::CalendarWindow { unit };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // Too close to midnight, the requests could fall in another day
+        let (_, until) = unit.window(now);
+        if until < Duration::from_secs(2) {
+            actix_rt::time::delay_for(until + Duration::from_millis(100)).await;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (start, until) = unit.window(now);
+        assert_eq!(start % 86400, 0);
+        assert!(until <= Duration::from_secs(86400));
+        let interval = Duration::from_secs(1);
+        for remaining in 0..2 {
+            match decide(&store, algorithm, false, "client", 2, interval, false)
+                .await
+                .unwrap()
+            {
+                Decision::Allowed {
+                    remaining: r,
+                    reset,
+                    key,
+                } => {
+                    assert_eq!(r, 1 - remaining);
+                    assert!(reset > interval && reset <= until);
+                    assert_eq!(key, format!("client:{}", start));
+                }
+                Decision::Rejected { .. } => panic!("Should be allowed"),
+            }
+        }
+        match decide(&store, algorithm, false, "client", 2, interval, false)
+            .await
+            .unwrap()
+        {
+            Decision::Rejected { reset } => assert!(reset > interval),
+            Decision::Allowed { .. } => panic!("Should be rejected"),
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_decision_reason() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_monitor_only(true)
+            .with_observer(move |event| {
+                if let RateLimitEvent::Allowed { reason, .. } = event {
+                    recorded.borrow_mut().push(*reason);
+                }
+            });
+        let mut app = test::init_service(
+            App::new().wrap(limiter).route(
+                "/",
+                web::get()
+                    .to(|reason: DecisionReason| HttpResponse::Ok().body(format!("{:?}", reason))),
+            ),
+        )
+        .await;
+        for expected in &["WithinLimit", "MonitorOnly"] {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let body = test::read_response(&mut app, req).await;
+            assert_eq!(body, *expected);
+        }
+        assert_eq!(
+            *events.borrow(),
+            vec![DecisionReason::WithinLimit, DecisionReason::MonitorOnly]
+        );
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_over_limit_grace() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_over_limit_grace(2);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert!(res.headers().get("x-ratelimit-warning").is_none());
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, call()).await;
+            assert!(res.status().is_success());
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+            assert_eq!(
+                res.headers().get("x-ratelimit-warning").unwrap(),
+                "limit exceeded"
+            );
+        }
+        let res = app.call(call()).await;
+        assert!(res.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_throttle_delay() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        for max_wait in &[Duration::from_millis(100), Duration::from_secs(3)] {
+            let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                .with_interval(Duration::from_secs(1))
+                .with_max_requests(1)
+                .with_throttle_delay(*max_wait);
+            let mut app =
+                test::init_service(App::new().wrap(limiter).route(
+                    "/",
+                    web::get().to(|reason: DecisionReason| {
+                        HttpResponse::Ok().body(format!("{:?}", reason))
+                    }),
+                ))
+                .await;
+            let call = || {
+                test::TestRequest::default()
+                    .peer_addr("127.0.0.1:8080".parse().unwrap())
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, call()).await;
+            assert_eq!(test::read_body(res).await, "WithinLimit");
+            let started = Instant::now();
+            match app.call(call()).await {
+                Ok(res) => {
+                    assert_eq!(*max_wait, Duration::from_secs(3));
+                    assert_eq!(test::read_body(res).await, "Throttled");
+                    assert!(started.elapsed() < *max_wait);
+                }
+                Err(_) => {
+                    assert_eq!(*max_wait, Duration::from_millis(100));
+                    assert!(started.elapsed() < *max_wait);
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_throttle_delay_whole_seconds() {
+        use crate::stores::{async_trait, RateLimitStore, StoreActor};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Store whose window always has less than a second left, reported as none
+        #[derive(Default)]
+        struct EndingStore(AtomicUsize);
+
+        #[async_trait]
+        impl RateLimitStore for EndingStore {
+            async fn get(&self, _: &str) -> Result<Option<usize>, ARError> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(0))
+            }
+            async fn set(&self, _: &str, _: usize, _: Duration) -> Result<(), ARError> {
+                Ok(())
+            }
+            async fn update(&self, _: &str, _: usize) -> Result<usize, ARError> {
+                Ok(0)
+            }
+            async fn expire(&self, _: &str) -> Result<Duration, ARError> {
+                Ok(Duration::from_secs(0))
+            }
+            async fn remove(&self, _: &str) -> Result<usize, ARError> {
+                Ok(0)
+            }
+        }
+
+        let store = Arc::new(EndingStore::default());
+        let limiter = RateLimiter::new(StoreActor::new(store.clone()).start())
+            .with_interval(Duration::from_secs(1))
+            .with_max_requests(1)
+            .with_throttle_delay(Duration::from_millis(1500));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        assert!(app.call(req).await.is_err());
+        // Waited a second, then the rest of the delay, instead of polling
+        assert_eq!(store.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_concurrency_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        async fn slow() -> HttpResponse {
+            actix_web::rt::time::delay_for(Duration::from_millis(200)).await;
+            HttpResponse::Ok().finish()
+        }
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .with_concurrency_limit(ConcurrencyLimit::new(1));
+        let mut app =
+            test::init_service(App::new().wrap(limiter).route("/", web::get().to(slow))).await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "9");
+        // The second request comes while the first one holds the only slot
+        let (first, second) = future::join(app.call(call()), app.call(call())).await;
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        let res = second.unwrap_err().as_response_error().error_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // The slot is given back with the response, the rejected request was counted
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "6");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_scope_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_scope_limit(
+                "/admin/",
+                Limit {
+                    max_requests: 3,
+                    interval: Duration::from_secs(60),
+                },
+            );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/admin", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |uri, addr: &str| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr(addr.parse().unwrap())
+                .to_request()
+        };
+        // Over its own limit, the first client does not use the scope
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, call("/admin", "127.0.0.1:8080")).await;
+            assert!(res.status().is_success());
+        }
+        assert!(app.call(call("/admin", "127.0.0.1:8080")).await.is_err());
+        let res = test::call_service(&mut app, call("/admin", "127.0.0.2:8080")).await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+        // The scope is used up, other paths are not affected
+        assert!(app.call(call("/admin", "127.0.0.3:8080")).await.is_err());
+        let res = test::call_service(&mut app, call("/", "127.0.0.3:8080")).await;
+        assert!(res.status().is_success());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_limit_applied_event() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .with_limit_resolver(|req| Limit {
+                max_requests: if req.path() == "/premium" { 100 } else { 10 },
+                interval: Duration::from_secs(60),
+            })
+            .with_observer(move |event| {
+                if let RateLimitEvent::LimitApplied {
+                    key,
+                    limit,
+                    default,
+                } = event
+                {
+                    recorded.borrow_mut().push((
+                        key.to_string(),
+                        limit.max_requests,
+                        default.max_requests,
+                    ));
+                }
+            });
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/premium", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for uri in &["/", "/premium"] {
+            let req = test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+        assert_eq!(
+            *events.borrow(),
+            vec![("127.0.0.1:8080".to_string(), 100, 10)]
+        );
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_grpc() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .with_path_limit(
+                "/helloworld.Greeter",
+                Limit {
+                    max_requests: 1,
+                    interval: Duration::from_secs(60),
+                },
+            );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/{service}/{method}", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |method| {
+            test::TestRequest::post()
+                .uri(&format!("/helloworld.Greeter/{}", method))
+                .header("content-type", "application/grpc")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call("SayHello")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        let err = app.call(call("SayGoodbye")).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("grpc-status").unwrap(), "8");
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "application/grpc"
+        );
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_error_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .with_retry_after_jitter(Duration::from_secs(0))
+            .with_error_limit(
+                |status| status == StatusCode::NOT_FOUND,
+                2,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/missing", web::get().to(HttpResponse::NotFound)),
+        )
+        .await;
+        let call = |uri| {
+            test::TestRequest::with_uri(uri)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call("/")).await;
+        assert!(res.status().is_success());
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, call("/missing")).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        let err = app.call(call("/")).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let reset: u64 = res
+            .headers()
+            .get("x-ratelimit-reset")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(reset > 0 && reset <= 60);
+        // Like any other rejection
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "100");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        // Rounded up rather than down
+        let retry_after: u64 = res
+            .headers()
+            .get("retry-after")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after == reset || retry_after == reset + 1);
+        // Other clients are not blocked
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.2:8080".parse().unwrap())
+            .to_request();
+        assert!(test::call_service(&mut app, req)
+            .await
+            .status()
+            .is_success());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_around_authentication() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        struct User(u64);
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(store.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_identifier(identifiers::from_extension(|user: &User| {
+                            format!("user:{}", user.0)
+                        })),
+                )
+                .wrap_fn(|req, srv| {
+                    if req.headers().contains_key("authorization") {
+                        req.extensions_mut().insert(User(42));
                     }
+                    srv.call(req)
+                })
+                .wrap(
+                    RateLimiter::new(store)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(3),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |authorized| {
+            let req = test::TestRequest::default().peer_addr("127.0.0.1:8080".parse().unwrap());
+            if authorized {
+                req.header("authorization", "user 42").to_request()
+            } else {
+                req.to_request()
+            }
+        };
+        let res = test::call_service(&mut app, call(true)).await;
+        // The inner limiter has less left, its headers are kept
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        let err = app.call(call(true)).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        // Anonymous requests share a bucket after the authentication
+        let res = test::call_service(&mut app, call(false)).await;
+        assert!(res.status().is_success());
+        let err = app.call(call(false)).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_boosts() {
+        use crate::stores::grant_boost;
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        grant_boost(&store, "127.0.0.1:8080", 2, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let limiter = RateLimiter::new(store)
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_boosts(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |addr: &str| {
+            test::TestRequest::default()
+                .peer_addr(addr.parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call("127.0.0.1:8080")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "2");
+        let res = test::call_service(&mut app, call("127.0.0.1:8081")).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_store_selector() {
+        use crate::stores::{get, AnyStore};
+        use crate::{MemoryStore, MemoryStoreActor};
+        let durable = MemoryStoreActor::from(MemoryStore::new()).start();
+        let local = MemoryStoreActor::from(MemoryStore::new()).start();
+        let limiter = RateLimiter::new(AnyStore::from(durable.clone()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5)
+            .with_store("local", AnyStore::from(local.clone()).start())
+            .with_store_selector(|req| {
+                if req.headers().contains_key("x-partner") {
+                    "default"
+                } else {
+                    "local"
                 }
-                _ => {
-                    unreachable!();
+            });
+        assert!(limiter.validate().await.is_ok());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .header("x-partner", "1")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "4");
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8081".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "4");
+        assert_eq!(get(&durable, "127.0.0.1:8080").await.unwrap(), Some(4));
+        assert_eq!(get(&durable, "127.0.0.1:8081").await.unwrap(), None);
+        assert_eq!(get(&local, "127.0.0.1:8081").await.unwrap(), Some(4));
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_idempotency_key() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_idempotency_key(HeaderName::from_static("idempotency-key"));
+        let mut app = test::init_service(
+            App::new().wrap(limiter).route(
+                "/",
+                web::post()
+                    .to(|reason: DecisionReason| HttpResponse::Ok().body(format!("{:?}", reason))),
+            ),
+        )
+        .await;
+        let call = |key: &str| {
+            test::TestRequest::post()
+                .header("idempotency-key", key)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call("a")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        let res = test::call_service(&mut app, call("b")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        // Let through once the limit is reached, since already counted
+        let res = test::call_service(&mut app, call("a")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        assert_eq!(test::read_body(res).await, "Retry");
+        // Never let through, so rejected again
+        assert!(app.call(call("c")).await.is_err());
+        assert!(app.call(call("c")).await.is_err());
+        // The key only stands for the operation it was sent with
+        let other = test::TestRequest::post()
+            .uri("/other")
+            .header("idempotency-key", "a")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        assert!(app.call(other).await.is_err());
+        // Up to a few retries
+        for _ in 1..IDEMPOTENT_RETRIES {
+            let res = test::call_service(&mut app, call("a")).await;
+            assert_eq!(test::read_body(res).await, "Retry");
+        }
+        assert!(app.call(call("a")).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_backoff_penalty() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_backoff_penalty(1, Duration::from_secs(600));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        test::call_service(&mut app, call()).await;
+        let reset = |err: AWError| {
+            let res = err.as_response_error().error_response();
+            let reset = res.headers().get("x-ratelimit-reset").unwrap();
+            reset.to_str().unwrap().parse::<u64>().unwrap()
+        };
+        // The first rejection starts the cooldown, one more request is tolerated
+        assert!(reset(app.call(call()).await.err().unwrap()) <= 60);
+        app.call(call()).await.err().unwrap();
+        app.call(call()).await.err().unwrap();
+        // Blocked for the penalty rather than until the reset
+        assert!(reset(app.call(call()).await.err().unwrap()) > 60);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_adaptive_limit() {
+        use crate::adaptive::AdaptiveLimit;
+        use crate::{MemoryStore, MemoryStoreActor};
+        // Every response is over the target, and adjusts the limit
+        let adaptive = AdaptiveLimit::new(Duration::from_secs(0))
+            .with_decrease(0.5)
+            .with_period(Duration::from_secs(0));
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .with_adaptive_limit(adaptive.clone());
+        let mut app = test::init_service(App::new().wrap(limiter).route(
+            "/",
+            web::get().to(|| {
+                std::thread::sleep(Duration::from_millis(1));
+                HttpResponse::Ok()
+            }),
+        ))
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "10");
+        assert_eq!(adaptive.factor(), 0.5);
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "5");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "4");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_content_type_filter() {
+        use crate::predicates::has_content_type;
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_content_type_filter(has_content_type("application/json"));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let post = |content_type: &str| {
+            test::TestRequest::post()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("content-type", content_type)
+                .to_request()
+        };
+        let res = test::call_service(&mut app, post("application/json")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        // Not counted, and left without headers
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, post("multipart/form-data")).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+            assert!(res.headers().get("x-ratelimit-remaining").is_none());
+        }
+        assert!(app.call(post("application/json")).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_should_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_allowlist(|req| req.headers().contains_key("x-internal"))
+            .with_should_limit(|req| !req.headers().contains_key("authorization"));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |header: Option<&str>| {
+            let req = test::TestRequest::default().peer_addr("127.0.0.1:8080".parse().unwrap());
+            match header {
+                Some(header) => req.header(header, "1"),
+                None => req,
+            }
+            .to_request()
+        };
+        let res = test::call_service(&mut app, get(None)).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        for header in &["authorization", "x-internal"] {
+            let res = test::call_service(&mut app, get(Some(header))).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+            assert!(res.headers().get("x-ratelimit-remaining").is_none());
+        }
+        assert!(app.call(get(None)).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_stacked_headers() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = |max_requests, interval| {
+            RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                .with_interval(Duration::from_secs(interval))
+                .with_max_requests(max_requests)
+        };
+        let get = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        // The most restrictive limiter is reported, whichever is outside
+        for (outer, inner) in &[((2, 60), (5, 60)), ((5, 60), (2, 60)), ((2, 10), (2, 60))] {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(limiter(inner.0, inner.1))
+                    .wrap(limiter(outer.0, outer.1))
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            )
+            .await;
+            let res = test::call_service(&mut app, get()).await;
+            let headers = res.headers();
+            assert_eq!(headers.get("x-ratelimit-limit").unwrap(), "2");
+            assert_eq!(headers.get("x-ratelimit-remaining").unwrap(), "1");
+            let reset = headers.get("x-ratelimit-reset").unwrap().to_str().unwrap();
+            assert!(reset.parse::<u64>().unwrap() <= outer.1.min(inner.1));
+        }
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter(2, 60))
+                .wrap(limiter(5, 3600).with_header_prefix("x-ratelimit-hour"))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let res = test::call_service(&mut app, get()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        assert_eq!(
+            res.headers().get("x-ratelimit-hour-remaining").unwrap(),
+            "4"
+        );
+        assert_eq!(res.headers().get("x-ratelimit-hour-limit").unwrap(), "5");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_distinct_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .with_distinct_limit(
+                |req| req.path().strip_prefix("/items/").map(String::from),
+                2,
+            );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/items/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |path: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        for path in &["/items/1", "/items/2", "/items/1", "/"] {
+            let res = test::call_service(&mut app, get(path)).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        }
+        let err = app.call(get("/items/3")).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "100");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        assert_eq!(res.headers().get("x-ratelimit-reset").unwrap(), "60");
+        // Rejected until the window ends, even for members already seen
+        assert!(app.call(get("/items/1")).await.is_err());
+        let res = test::call_service(&mut app, get("/")).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_supports_sets() {
+        assert!(supports_sets::<FailingStore>());
+        #[cfg(feature = "memcached")]
+        assert!(!supports_sets::<crate::MemcacheStoreActor>());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_content_length_cost() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .with_content_length_cost(10);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let upload = |length: Option<usize>| {
+            let req = test::TestRequest::post().peer_addr("127.0.0.1:8080".parse().unwrap());
+            match length {
+                Some(length) => req.header("content-length", length.to_string()),
+                None => req,
+            }
+            .to_request()
+        };
+        let res = test::call_service(&mut app, upload(Some(60))).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "40");
+        // Chunked, without a length
+        let res = test::call_service(&mut app, upload(None)).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "30");
+        // Larger than what is left, rejected for a byte
+        let err = app.call(upload(Some(50))).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        // Which gave back the unit it reserved
+        let res = test::call_service(&mut app, upload(Some(30))).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_content_length_cost_throttled() {
+        use crate::stores::{async_trait, RateLimitStore, StoreActor};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        /// Store which always leaves 3 requests, and counts the refunds
+        #[derive(Default)]
+        struct LowStore {
+            refunds: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl RateLimitStore for LowStore {
+            async fn get(&self, _: &str) -> Result<Option<usize>, ARError> {
+                Ok(Some(3))
+            }
+            async fn set(&self, _: &str, value: usize, _: Duration) -> Result<(), ARError> {
+                assert_eq!(value, 4);
+                self.refunds.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+            async fn update(&self, _: &str, _: usize) -> Result<usize, ARError> {
+                Ok(2)
+            }
+            async fn expire(&self, _: &str) -> Result<Duration, ARError> {
+                Ok(Duration::from_secs(1))
+            }
+            async fn remove(&self, _: &str) -> Result<usize, ARError> {
+                Ok(0)
+            }
+        }
+
+        let store = Arc::new(LowStore::default());
+        let limiter = RateLimiter::new(StoreActor::new(store.clone()).start())
+            .with_interval(Duration::from_secs(1))
+            .with_max_requests(10)
+            .with_content_length_cost(1)
+            .with_throttle_delay(Duration::from_millis(1500));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .header("content-length", "10")
+            .to_request();
+        // Still too large once delayed, and both reserved units are given back
+        let err = app.call(req).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(store.refunds.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_shards() {
+        use crate::stores::{get, list_shards};
+        use crate::{MemoryStore, MemoryStoreActor};
+        let shards: Vec<_> = (0..2)
+            .map(|_| MemoryStoreActor::from(MemoryStore::new()).start())
+            .collect();
+        let limiter = RateLimiter::new(shards[0].clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5)
+            .with_identifier(identifiers::peer_ip)
+            .with_shards(shards.clone())
+            .with_shard_selector(|key| if key.ends_with(".1") { 0 } else { 1 });
+        assert!(std::ptr::eq(
+            limiter.shard_for("10.0.0.2"),
+            &limiter.shards[1]
+        ));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for peer in &["10.0.0.1:8080", "10.0.0.2:8080"] {
+            let req = test::TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+        assert_eq!(get(&shards[0], "10.0.0.1").await.unwrap(), Some(4));
+        assert_eq!(get(&shards[0], "10.0.0.2").await.unwrap(), None);
+        assert_eq!(get(&shards[1], "10.0.0.2").await.unwrap(), Some(4));
+        let mut clients = list_shards(&shards, "10.", 10).await.unwrap();
+        clients.sort();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[1].0, "10.0.0.2");
+        assert_eq!(list_shards(&shards, "", 1).await.unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_unlimited_headers() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(usize::MAX)
+            .with_used_header(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "unlimited");
+        assert_eq!(
+            res.headers().get("x-ratelimit-remaining").unwrap(),
+            "unlimited"
+        );
+        assert_eq!(res.headers().get("x-ratelimit-used").unwrap(), "1");
+        assert_eq!(
+            RemainingHeader::Tokens.count(1, UNLIMITED_THRESHOLD, 7),
+            "7"
+        );
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let reset = Duration::from_millis(30_500);
+        assert_eq!(retry_after(reset, Duration::from_secs(0), "client"), 31);
+        let jittered = retry_after(reset, Duration::from_secs(10), "client");
+        assert!((31..=41).contains(&jittered));
+        // Stable for a client, spread over clients
+        assert_eq!(
+            retry_after(reset, Duration::from_secs(10), "client"),
+            jittered
+        );
+        let spread: std::collections::HashSet<u64> = (0..100)
+            .map(|i| retry_after(reset, Duration::from_secs(10), &format!("client{}", i)))
+            .collect();
+        assert!(spread.len() > 5);
+    }
+
+    #[test]
+    fn test_rejected_response() {
+        let names = HeaderNames::default();
+        let mut rejection = RejectionHeaders {
+            names: &names,
+            unit: RemainingHeader::Tokens,
+            scale: 1,
+            max_requests: 10,
+            retry_after_jitter: Some(Duration::from_secs(0)),
+            used: true,
+            algorithm: Some(Algorithm::FixedWindow),
+        };
+        let req = test::TestRequest::default().to_srv_request();
+        let res =
+            rejected_response(&req, &rejection, "client", 2, Duration::from_millis(1500)).finish();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let header = |res: &HttpResponse, name| res.headers().get(name).unwrap().clone();
+        assert_eq!(header(&res, "x-ratelimit-limit"), "10");
+        assert_eq!(header(&res, "x-ratelimit-remaining"), "2");
+        assert_eq!(header(&res, "x-ratelimit-reset"), "1");
+        assert_eq!(header(&res, "retry-after"), "2");
+        assert_eq!(header(&res, "x-ratelimit-used"), "8");
+        assert_eq!(header(&res, "x-ratelimit-algorithm"), "fixed-window");
+        // Only the headers which were asked for
+        rejection.retry_after_jitter = None;
+        rejection.used = false;
+        rejection.algorithm = None;
+        let res = rejected_response(&req, &rejection, "client", 0, Duration::from_secs(1)).finish();
+        assert!(res.headers().get("retry-after").is_none());
+        assert!(res.headers().get("x-ratelimit-used").is_none());
+        assert!(res.headers().get("x-ratelimit-algorithm").is_none());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_retry_after_jitter() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_retry_after_jitter(Duration::from_secs(10));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert!(res.headers().get("retry-after").is_none());
+        let err = app.call(call()).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        let retry_after: u64 = res
+            .headers()
+            .get("retry-after")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((59..=70).contains(&retry_after), "{}", retry_after);
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_algorithm_header() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let limiter = RateLimiter::new(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_algorithm(Algorithm::SlidingWindowCounter)
+            .with_algorithm_header(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(
+            res.headers().get("x-ratelimit-algorithm").unwrap(),
+            "sliding-window-counter"
+        );
+        let err = app.call(call()).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(
+            res.headers().get("x-ratelimit-algorithm").unwrap(),
+            "sliding-window-counter"
+        );
+        // Off by default
+        let limiter = RateLimiter::new(store)
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.2:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert!(res.headers().get("x-ratelimit-algorithm").is_none());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_decision_extension() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use std::cell::RefCell;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_identifier(identifiers::peer_ip);
+        let decisions = Rc::new(RefCell::new(Vec::new()));
+        let seen = decisions.clone();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .wrap_fn(move |req, srv| {
+                    let slot = DecisionSlot::new();
+                    req.extensions_mut().insert(slot.clone());
+                    let seen = seen.clone();
+                    let res = srv.call(req);
+                    async move {
+                        let res = res.await;
+                        seen.borrow_mut().push(slot.get().unwrap());
+                        res
+                    }
+                })
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let _ = app.call(req).await;
+        }
+        let decisions = decisions.borrow();
+        assert!(decisions[0].allowed && !decisions[1].allowed);
+        assert_eq!(decisions[0].key_hash, identifiers::fnv1a("127.0.0.1"));
+        assert_eq!(decisions[0].remaining, 0);
+        assert_eq!(decisions[1].algorithm, "fixed-window");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_async_identifier() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        use std::net::IpAddr;
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_async_identifier(identifiers::asn(identifiers::peer_ip, |ip: IpAddr| {
+                let asn = Some(64496).filter(|_| ip.to_string().starts_with("203.0.113."));
+                async move { asn }
+            }));
+        assert!(matches!(
+            limiter.key_for(&test::TestRequest::default().to_srv_request()),
+            Err(ARError::IdentificationError)
+        ));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |addr: &str| {
+            test::TestRequest::default()
+                .peer_addr(addr.parse().unwrap())
+                .to_request()
+        };
+        // Two addresses of the same network share a bucket
+        let res = test::call_service(&mut app, call("203.0.113.7:80")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        let res = test::call_service(&mut app, call("203.0.113.8:80")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        assert!(app.call(call("203.0.113.9:80")).await.is_err());
+        let res = test::call_service(&mut app, call("192.0.2.1:80")).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_key_for() {
+        use crate::stores::get;
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let api_key = |req: &ServiceRequest| {
+            req.headers()
+                .get("x-api-key")
+                .and_then(|key| key.to_str().ok())
+                .map(String::from)
+                .ok_or(ARError::IdentificationError)
+        };
+        let limiter = RateLimiter::new(store.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(10)
+            .with_identifier(api_key)
+            .with_key_normalization(Normalization::Lowercase)
+            .with_missing_identifier(Policy::SharedBucket("anonymous".to_string()))
+            .with_path_limit(
+                "/api",
+                Limit {
+                    max_requests: 5,
+                    interval: Duration::from_secs(60),
+                },
+            );
+        let req = |key: Option<&str>| {
+            let req = test::TestRequest::with_uri("/api/items");
+            match key {
+                Some(key) => req.header("x-api-key", key),
+                None => req,
+            }
+        };
+        let key = limiter.key_for(&req(Some("ABC")).to_srv_request()).unwrap();
+        let anonymous = limiter.key_for(&req(None).to_srv_request()).unwrap();
+        assert_eq!(anonymous, "/api:anonymous");
+        let rejecting = RateLimiter::new(store.clone())
+            .with_identifier(api_key)
+            .with_missing_identifier(Policy::Reject);
+        assert!(matches!(
+            rejecting.key_for(&req(None).to_srv_request()),
+            Err(ARError::IdentificationError)
+        ));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/api/items", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        test::call_service(&mut app, req(Some("ABC")).to_request()).await;
+        test::call_service(&mut app, req(None).to_request()).await;
+        assert_eq!(get(&store, &key).await.unwrap(), Some(4));
+        assert_eq!(get(&store, &anonymous).await.unwrap(), Some(4));
+    }
+
+    #[actix_rt::test]
+    async fn test_warm_up() {
+        let warmup = Duration::from_secs(100);
+        assert_eq!(warm_up(100, (Instant::now(), warmup, 0.2)), 20);
+        let start = Instant::now() - Duration::from_secs(50);
+        assert_eq!(warm_up(100, (start, warmup, 0.2)), 60);
+        let start = Instant::now() - Duration::from_secs(200);
+        assert_eq!(warm_up(100, (start, warmup, 0.2)), 100);
+        assert_eq!(warm_up(2, (Instant::now(), warmup, 0.2)), 1);
+        let err = RateLimiter::builder(FailingStore.start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .configure(|limiter| limiter.with_warmup(warmup, 0.0))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("invalid value for warmup"));
+    }
+
+    #[actix_rt::test]
+    async fn test_store_clock() {
+        use crate::stores::{async_trait, RateLimitStore, StoreActor};
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        /// Store whose clock is 10 seconds into a day
+        #[derive(Default)]
+        struct SkewedStore(Mutex<HashMap<String, usize>>);
+
+        #[async_trait]
+        impl RateLimitStore for SkewedStore {
+            async fn get(&self, key: &str) -> Result<Option<usize>, ARError> {
+                Ok(self.0.lock().unwrap().get(key).copied())
+            }
+            async fn set(&self, key: &str, value: usize, _: Duration) -> Result<(), ARError> {
+                self.0.lock().unwrap().insert(key.to_string(), value);
+                Ok(())
+            }
+            async fn update(&self, key: &str, value: usize) -> Result<usize, ARError> {
+                let mut inner = self.0.lock().unwrap();
+                let count = inner.entry(key.to_string()).or_insert(0);
+                *count = count.saturating_sub(value);
+                Ok(*count)
+            }
+            async fn expire(&self, _: &str) -> Result<Duration, ARError> {
+                Ok(Duration::from_secs(60))
+            }
+            async fn remove(&self, key: &str) -> Result<usize, ARError> {
+                Ok(self.0.lock().unwrap().remove(key).unwrap_or(0))
+            }
+            async fn time(&self) -> Result<Duration, ARError> {
+                Ok(Duration::from_secs(100 * 86400 + 10))
+            }
+        }
+
+        let store = Arc::new(SkewedStore::default());
+        let limiter = RateLimiter::new(StoreActor::new(store.clone()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_algorithm(Algorithm::CalendarWindow {
+                unit: CalendarUnit::Day,
+            })
+            .with_store_clock(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        // Counted in the day of the store
+        let key = format!("127.0.0.1:8080:{}", 100 * 86400);
+        assert_eq!(store.0.lock().unwrap().get(&key), Some(&1));
+    }
+
+    #[actix_rt::test]
+    async fn test_new_client_reset() {
+        use crate::stores::{async_trait, RateLimitStore, StoreActor};
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        /// Store which counts how often it is asked for an expiry, and keeps whole seconds
+        #[derive(Default)]
+        struct SecondsStore {
+            counts: Mutex<HashMap<String, usize>>,
+            expires: AtomicUsize,
+            consumes: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl RateLimitStore for SecondsStore {
+            async fn get(&self, key: &str) -> Result<Option<usize>, ARError> {
+                Ok(self.counts.lock().unwrap().get(key).copied())
+            }
+            async fn set(&self, key: &str, value: usize, _: Duration) -> Result<(), ARError> {
+                self.counts.lock().unwrap().insert(key.to_string(), value);
+                Ok(())
+            }
+            async fn update(&self, key: &str, value: usize) -> Result<usize, ARError> {
+                let mut counts = self.counts.lock().unwrap();
+                let count = counts.entry(key.to_string()).or_insert(0);
+                *count = count.saturating_sub(value);
+                Ok(*count)
+            }
+            async fn consume(&self, key: &str, value: usize) -> Result<(usize, Duration), ARError> {
+                self.consumes.fetch_add(1, Ordering::SeqCst);
+                Ok((self.update(key, value).await?, Duration::from_secs(42)))
+            }
+            async fn expire(&self, _: &str) -> Result<Duration, ARError> {
+                self.expires.fetch_add(1, Ordering::SeqCst);
+                Ok(Duration::from_secs(1))
+            }
+            async fn remove(&self, key: &str) -> Result<usize, ARError> {
+                Ok(self.counts.lock().unwrap().remove(key).unwrap_or(0))
+            }
+        }
+
+        let store = Arc::new(SecondsStore::default());
+        let addr = StoreActor::new(store.clone()).start();
+        // A whole interval is what the store keeps for a new client, no need to ask
+        let decision = fixed_window(&addr, "whole", 5, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(matches!(decision, Decision::Allowed { reset, .. } if reset.as_secs() == 60));
+        assert_eq!(store.expires.load(Ordering::SeqCst), 0);
+        // The store rounds a fractional one
+        let decision = fixed_window(&addr, "fraction", 5, Duration::from_millis(1500))
+            .await
+            .unwrap();
+        assert!(matches!(decision, Decision::Allowed { reset, .. } if reset.as_secs() == 1));
+        assert_eq!(store.expires.load(Ordering::SeqCst), 1);
+        // Existing clients get it with their count, in the same round trip
+        let decision = fixed_window(&addr, "whole", 5, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(matches!(
+            decision,
+            Decision::Allowed { remaining: 3, reset, .. } if reset.as_secs() == 42
+        ));
+        assert_eq!(store.consumes.load(Ordering::SeqCst), 1);
+        assert_eq!(store.expires.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_recovery_grace() {
+        use crate::stores::{async_trait, RateLimitStore, StoreActor};
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        /// Store which fails while `failing` is set
+        #[derive(Default)]
+        struct FlakyStore {
+            failing: AtomicBool,
+            inner: Mutex<HashMap<String, usize>>,
+        }
+
+        impl FlakyStore {
+            fn check(&self) -> Result<(), ARError> {
+                match self.failing.load(Ordering::SeqCst) {
+                    true => Err(ARError::Disconnected),
+                    false => Ok(()),
                 }
             }
-        })
+        }
+
+        #[async_trait]
+        impl RateLimitStore for FlakyStore {
+            async fn get(&self, key: &str) -> Result<Option<usize>, ARError> {
+                self.check()?;
+                Ok(self.inner.lock().unwrap().get(key).copied())
+            }
+            async fn set(&self, key: &str, value: usize, _: Duration) -> Result<(), ARError> {
+                self.check()?;
+                self.inner.lock().unwrap().insert(key.to_string(), value);
+                Ok(())
+            }
+            async fn update(&self, key: &str, value: usize) -> Result<usize, ARError> {
+                self.check()?;
+                let mut inner = self.inner.lock().unwrap();
+                let count = inner.entry(key.to_string()).or_insert(0);
+                *count = count.saturating_sub(value);
+                Ok(*count)
+            }
+            async fn expire(&self, _: &str) -> Result<Duration, ARError> {
+                self.check()?;
+                Ok(Duration::from_secs(60))
+            }
+            async fn remove(&self, key: &str) -> Result<usize, ARError> {
+                self.check()?;
+                Ok(self.inner.lock().unwrap().remove(key).unwrap_or(0))
+            }
+        }
+
+        let store = Arc::new(FlakyStore::default());
+        let limiter = RateLimiter::new(StoreActor::new(store.clone()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_failure_mode(FailureMode::FailOpen)
+            .with_recovery_grace(Duration::from_secs(60), 2.0);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        for _ in 0..2 {
+            test::call_service(&mut app, call()).await;
+        }
+        // Over the limit, and no grace before an outage
+        assert!(app.call(call()).await.is_err());
+        store.failing.store(true, Ordering::SeqCst);
+        test::call_service(&mut app, call()).await;
+        store.failing.store(false, Ordering::SeqCst);
+        store.inner.lock().unwrap().clear();
+        // Twice the limit, then rejected
+        for _ in 0..4 {
+            let res = test::call_service(&mut app, call()).await;
+            assert!(res.status().is_success());
+        }
+        assert!(app.call(call()).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_tier_from_authentication() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        enum Tier {
+            Free,
+            Pro,
+        }
+        struct User(u64, Tier);
+        let per_minute = |max_requests| Limit {
+            max_requests,
+            interval: Duration::from_secs(60),
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_identifier(|req| {
+                            identifiers::from_extension(|user: &User| format!("user:{}", user.0))(
+                                req,
+                            )
+                            .or_else(|_| identifiers::peer_ip(req))
+                        })
+                        .with_limit_resolver(move |req| match req.extensions().get::<User>() {
+                            Some(User(_, Tier::Pro)) => per_minute(100),
+                            Some(User(_, Tier::Free)) => per_minute(10),
+                            None => per_minute(1),
+                        }),
+                )
+                .wrap_fn(|req, srv| {
+                    match req.headers().get("authorization").map(|v| v.as_bytes()) {
+                        Some(b"pro") => req.extensions_mut().insert(User(1, Tier::Pro)),
+                        Some(b"free") => req.extensions_mut().insert(User(2, Tier::Free)),
+                        _ => {}
+                    }
+                    srv.call(req)
+                })
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for (authorization, limit) in &[(Some("pro"), "100"), (Some("free"), "10"), (None, "1")] {
+            let req = test::TestRequest::default().peer_addr("127.0.0.1:8080".parse().unwrap());
+            let req = match authorization {
+                Some(authorization) => req.header("authorization", *authorization),
+                None => req,
+            };
+            let res = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), limit);
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_sliding_expiry() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(2))
+            .with_max_requests(2)
+            .with_sliding_expiry(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        test::call_service(&mut app, call()).await;
+        actix_rt::time::delay_for(Duration::from_millis(1200)).await;
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-reset").unwrap(), "2");
+        // Past the end of the first window, which the second request pushed back
+        actix_rt::time::delay_for(Duration::from_millis(1200)).await;
+        assert!(app.call(call()).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_prefilter() {
+        use crate::stores::get;
+        use crate::{MemoryStore, MemoryStoreActor};
+        let store = MemoryStoreActor::from(MemoryStore::new()).start();
+        let limiter = RateLimiter::new(store.clone())
+            .with_interval(Duration::from_secs(86400))
+            .with_max_requests(4)
+            .with_prefilter(PreFilter::new(64));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        for remaining in &["3", "2"] {
+            let res = test::call_service(&mut app, call()).await;
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+        // Let through by the prefilter alone
+        assert_eq!(get(&store, "127.0.0.1:8080").await.unwrap(), None);
+        for remaining in &["1", "0"] {
+            let res = test::call_service(&mut app, call()).await;
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+        assert!(app.call(call()).await.is_err());
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_used_header() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(20)
+            .with_remaining_header(RemainingHeader::Requests { typical_cost: 10 })
+            .with_used_header(true);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        // 19 tokens left, a single request of 10
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-used").unwrap(), "1");
+        for _ in 0..19 {
+            test::call_service(&mut app, call()).await;
+        }
+        let err = app.call(call()).await.err().unwrap();
+        let res = err.as_response_error().error_response();
+        assert_eq!(res.headers().get("x-ratelimit-used").unwrap(), "2");
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_headers_on_errors() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        for headers_on_errors in &[true, false] {
+            let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(10)
+                .with_monitor_only(true)
+                .with_headers_on_errors(*headers_on_errors);
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(limiter)
+                    .route("/", web::get().to(HttpResponse::Ok))
+                    .route("/failing", web::get().to(HttpResponse::InternalServerError)),
+            )
+            .await;
+            let call = |uri| {
+                test::TestRequest::with_uri(uri)
+                    .peer_addr("127.0.0.1:8080".parse().unwrap())
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, call("/failing")).await;
+            assert_eq!(
+                res.headers().contains_key("x-ratelimit-remaining"),
+                *headers_on_errors
+            );
+            assert_eq!(res.headers().get("x-ratelimit-dryrun").unwrap(), "true");
+            // Failed requests are counted all the same
+            let res = test::call_service(&mut app, call("/")).await;
+            assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "8");
+        }
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_shared_limit() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limit = SharedLimit::new(Limit {
+            max_requests: 5,
+            interval: Duration::from_secs(60),
+        });
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .with_shared_limit(limit.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "5");
+        limit.set(Limit {
+            max_requests: 2,
+            interval: Duration::from_secs(60),
+        });
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+
+        std::env::set_var("TEST_SHARED_LIMIT_MAX", "7");
+        std::env::set_var("TEST_SHARED_LIMIT_INTERVAL", "nope");
+        let err = limit
+            .reload_from_env("TEST_SHARED_LIMIT_MAX", "TEST_SHARED_LIMIT_INTERVAL")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value for TEST_SHARED_LIMIT_INTERVAL"));
+        assert_eq!(limit.get().max_requests, 2);
+        std::env::set_var("TEST_SHARED_LIMIT_INTERVAL", "30");
+        let reloaded = limit
+            .reload_from_env("TEST_SHARED_LIMIT_MAX", "TEST_SHARED_LIMIT_INTERVAL")
+            .unwrap();
+        assert_eq!(reloaded.max_requests, 7);
+        assert_eq!(limit.get().interval, Duration::from_secs(30));
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_remaining_header() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100)
+            .with_remaining_header(RemainingHeader::Requests { typical_cost: 10 });
+        let mut app = test::init_service(App::new().wrap(limiter).route(
+            "/",
+            web::get().to(|req: HttpRequest| {
+                req.extensions_mut().insert(ActualCost(15));
+                HttpResponse::Ok()
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "10");
+        // 85 tokens left
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "8");
+    }
+
+    /// Store which answers every operation after a while
+    struct SlowStore;
+
+    impl Actor for SlowStore {
+        type Context = actix::Context<Self>;
+    }
+
+    impl Handler<ActorMessage> for SlowStore {
+        type Result = ActorResponse;
+        fn handle(&mut self, _: ActorMessage, _: &mut Self::Context) -> Self::Result {
+            ActorResponse::Get(Box::pin(async {
+                actix_rt::time::delay_for(Duration::from_millis(200)).await;
+                Ok(None)
+            }))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_shed_threshold() {
+        for failure_mode in &[FailureMode::FailClosed, FailureMode::FailOpen] {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let recorded = events.clone();
+            let limiter = RateLimiter::new(SlowStore.start())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(10)
+                .with_failure_mode(*failure_mode)
+                .with_shed_threshold(ShedThreshold::Latency(Duration::from_millis(20)))
+                .with_observer(move |event| recorded.borrow_mut().push(format!("{:?}", event)));
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(limiter)
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            )
+            .await;
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            match app.call(req).await {
+                Ok(res) => {
+                    assert_eq!(*failure_mode, FailureMode::FailOpen);
+                    assert!(res.status().is_success());
+                }
+                Err(e) => {
+                    assert_eq!(*failure_mode, FailureMode::FailClosed);
+                    let res = e.as_response_error().error_response();
+                    assert_eq!(
+                        res.status(),
+                        actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+                    );
+                }
+            }
+            assert!(events.borrow()[0].starts_with("Shed"));
+        }
+    }
+
+    #[test]
+    fn test_rejection_message() {
+        let endpoint = Some((&Method::GET, "/posts"));
+        assert_eq!(
+            rejection_message(LogKey::Plain, "Limit exceeded", "203.0.113.7", None),
+            "Limit exceeded for client: 203.0.113.7"
+        );
+        assert_eq!(
+            rejection_message(LogKey::Plain, "Client blocked", "203.0.113.7", endpoint),
+            "Client blocked for client: 203.0.113.7 on GET /posts"
+        );
+        let hashed = rejection_message(LogKey::Hashed, "Limit exceeded", "203.0.113.7", None);
+        assert_eq!(
+            hashed,
+            format!(
+                "Limit exceeded for client: hash:{:016x}",
+                identifiers::fnv1a("203.0.113.7")
+            )
+        );
+        assert!(!hashed.contains("203.0.113.7"));
+        assert_eq!(
+            rejection_message(LogKey::Omitted, "Limit exceeded", "203.0.113.7", endpoint),
+            "Limit exceeded on GET /posts"
+        );
+    }
+
+    #[cfg(feature = "memory")]
+    #[actix_rt::test]
+    async fn test_rejected_event() {
+        use crate::{MemoryStore, MemoryStoreActor};
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let limiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(1)
+            .with_rejection_log_path(true)
+            .with_observer(move |event| {
+                if let RateLimitEvent::Rejected {
+                    key, method, path, ..
+                } = event
+                {
+                    recorded
+                        .borrow_mut()
+                        .push(format!("{} {} {}", key, method, path));
+                }
+            });
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/posts", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::with_uri("/posts")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let _ = app.call(req).await;
+        }
+        assert_eq!(
+            *events.borrow(),
+            vec!["127.0.0.1:8080 GET /posts".to_string()]
+        );
     }
 }
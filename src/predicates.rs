@@ -0,0 +1,130 @@
+//! Predicates on requests
+//!
+//! These can be combined with [RateLimiter::with_allowlist](../middleware/struct.RateLimiter.html#method.with_allowlist)
+//! to give some kinds of requests a limit of their own, with
+//! [RateLimiter::with_content_type_filter](../middleware/struct.RateLimiter.html#method.with_content_type_filter),
+//! or composed in [RateLimiter::with_should_limit](../middleware/struct.RateLimiter.html#method.with_should_limit).
+use actix_web::{dev::ServiceRequest, http::header};
+
+/// Returns `true` when the request is a WebSocket handshake, ie. it carries an
+/// `Upgrade: websocket` header.
+///
+/// The middleware only ever sees this handshake: once the connection is upgraded, the frames
+/// exchanged over it do not go through the middleware stack. Limiting a WebSocket endpoint
+/// therefore limits how often a client can open a connection, not how many messages it sends.
+///
+/// # Example
+/// Allowing 5 new WebSocket connections per minute, separately from the limit of the other
+/// requests:
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_ratelimit::{predicates, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let app = App::new()
+///         .wrap(
+///             RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///                 .with_allowlist(predicates::is_websocket_upgrade),
+///         )
+///         .wrap(
+///             RateLimiter::memory(&store, 5, Duration::from_secs(60))
+///                 .with_allowlist(|req| !predicates::is_websocket_upgrade(req))
+///                 .with_identifier(|req| {
+///                     actix_ratelimit::identifiers::remote_addr(req).map(|ip| format!("ws:{}", ip))
+///                 }),
+///         )
+///         .route("/", web::get().to(HttpResponse::Ok));
+/// }
+/// ```
+pub fn is_websocket_upgrade(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+/// Returns `true` when the request is a gRPC call, ie. its `Content-Type` is `application/grpc`
+/// or one of its variants such as `application/grpc+proto`. The method of a gRPC call is its
+/// path, `/package.Service/Method`, so [path limits](../middleware/struct.RateLimiter.html#method.with_path_limit)
+/// on `/package.Service` apply to all the methods of a service.
+///
+/// gRPC calls over the limit are answered with `200 OK` and the `grpc-status: 8`
+/// (`RESOURCE_EXHAUSTED`) header instead of `429 Too Many Requests`, which gRPC clients would
+/// only report as an unknown error.
+pub fn is_grpc(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value == "application/grpc"
+                || value.starts_with("application/grpc+")
+                || value.starts_with("application/grpc;")
+        })
+        .unwrap_or(false)
+}
+
+/// Returns a predicate which is `true` when the media type of the request is `essence`, for
+/// instance `application/json`, whatever its parameters such as `charset`, and regardless of
+/// case. Requests without a `Content-Type` do not match.
+///
+/// Meant for [RateLimiter::with_content_type_filter](../middleware/struct.RateLimiter.html#method.with_content_type_filter).
+pub fn has_content_type(essence: &'static str) -> impl Fn(&ServiceRequest) -> bool {
+    move |req| {
+        req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().eq_ignore_ascii_case(essence))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let req = TestRequest::default()
+            .header("upgrade", "WebSocket")
+            .header("connection", "Upgrade")
+            .to_srv_request();
+        assert!(is_websocket_upgrade(&req));
+        let req = TestRequest::default().to_srv_request();
+        assert!(!is_websocket_upgrade(&req));
+    }
+
+    #[test]
+    fn test_is_grpc() {
+        for content_type in &["application/grpc", "application/grpc+proto"] {
+            let req = TestRequest::default()
+                .header("content-type", *content_type)
+                .to_srv_request();
+            assert!(is_grpc(&req));
+        }
+        let req = TestRequest::default()
+            .header("content-type", "application/grpc-web")
+            .to_srv_request();
+        assert!(!is_grpc(&req));
+        assert!(!is_grpc(&TestRequest::default().to_srv_request()));
+    }
+
+    #[test]
+    fn test_has_content_type() {
+        let is_json = has_content_type("application/json");
+        let req = TestRequest::default()
+            .header("content-type", "Application/JSON; charset=utf-8")
+            .to_srv_request();
+        assert!(is_json(&req));
+        let req = TestRequest::default()
+            .header("content-type", "multipart/form-data; boundary=x")
+            .to_srv_request();
+        assert!(!is_json(&req));
+        assert!(!is_json(&TestRequest::default().to_srv_request()));
+    }
+}
@@ -0,0 +1,188 @@
+//! Approximate counting in front of the store, for the hottest paths
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::identifiers;
+
+// Rows of the sketch, and hashes of the filter
+const DEPTH: usize = 4;
+
+/// Counts the requests of every client in a small, fixed amount of memory, to let the clients
+/// far from their limit through without asking the store, see
+/// [RateLimiter::with_prefilter](../middleware/struct.RateLimiter.html#method.with_prefilter).
+///
+/// Requests are counted in a count-min sketch for each window of the limit, aligned on the clock.
+/// The sketch never undercounts: clients sharing counters with others are counted higher than
+/// they are, never lower. A client whose count stays under half of its limit is let through by
+/// the sketch alone. Past that, it is marked in a bloom filter, and all its requests go to the
+/// store for the rest of the window and the next one, counted against the other half of the
+/// limit. A client over its limit is thus never let through for an approximation, at worst
+/// clients whose counters collide go to the store early, and get fewer requests than their limit.
+///
+/// Like stores, it should be created once outside of the `HttpServer` factory and cloned into
+/// every worker. Each prefilter lets a client through on its own, so with several nodes a client
+/// can get half its limit from the prefilter of each node: it is meant for single node
+/// deployments, or limits lowered to account for it.
+#[derive(Clone)]
+pub struct PreFilter {
+    width: usize,
+    inner: Arc<Mutex<State>>,
+}
+
+struct State {
+    // Index of the window since the Unix epoch
+    window: u64,
+    // `DEPTH` rows of `width` counters
+    counts: Vec<u32>,
+    // Clients sent to the store in the current and the previous window
+    escalated: [Vec<u64>; 2],
+}
+
+/// Outcome of the prefilter for a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PreCheck {
+    /// Let through without asking the store
+    Skip { remaining: usize, reset: Duration },
+    /// Decide in the store, with `max_requests` left of the limit
+    Store { max_requests: usize },
+}
+
+impl PreFilter {
+    /// Creates a prefilter with `width` counters per row of the sketch, for a total of
+    /// `4 * width` counters of 4 bytes and a bloom filter of `64 * width` bits. The more clients
+    /// in a window, the wider it should be for them not to collide, about ten times the number
+    /// of clients is a good start.
+    ///
+    /// # Panics
+    /// If `width` is zero.
+    pub fn new(width: usize) -> Self {
+        assert!(width > 0, "width must not be zero");
+        PreFilter {
+            width,
+            inner: Arc::new(Mutex::new(State {
+                window: 0,
+                counts: vec![0; DEPTH * width],
+                escalated: [vec![0; width], vec![0; width]],
+            })),
+        }
+    }
+
+    /// Counts a request of the client identified by `key` against `max_requests` in `interval`,
+    /// unless it has to go to the store
+    pub(crate) fn check(&self, key: &str, max_requests: usize, interval: Duration) -> PreCheck {
+        let threshold = max_requests / 2;
+        let interval = interval.as_secs().max(1);
+        if threshold == 0 {
+            return PreCheck::Store { max_requests };
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let window = now.as_secs() / interval;
+        let hash = hash(key);
+        let index = |i: usize, len: usize| slot(hash, i, len);
+        let mut state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if state.window != window {
+            let current = std::mem::replace(&mut state.escalated[0], vec![0; self.width]);
+            state.escalated[1] = if state.window + 1 == window {
+                current
+            } else {
+                vec![0; self.width]
+            };
+            state.counts.iter_mut().for_each(|count| *count = 0);
+            state.window = window;
+        }
+        let bits = 64 * self.width;
+        let escalated = state.escalated.iter().any(|filter| {
+            (0..DEPTH).all(|i| {
+                let bit = index(i, bits);
+                filter[bit / 64] & (1 << (bit % 64)) != 0
+            })
+        });
+        let store = PreCheck::Store {
+            max_requests: max_requests - threshold,
+        };
+        if escalated {
+            return store;
+        }
+        let width = self.width;
+        let estimate = (0..DEPTH)
+            .map(|i| state.counts[i * width + index(i, width)])
+            .min()
+            .unwrap_or(0) as usize;
+        if estimate < threshold {
+            for i in 0..DEPTH {
+                let count = &mut state.counts[i * width + index(i, width)];
+                *count = count.saturating_add(1);
+            }
+            let reset = Duration::from_secs((window + 1) * interval).checked_sub(now);
+            return PreCheck::Skip {
+                remaining: max_requests - estimate - 1,
+                reset: reset.unwrap_or_default(),
+            };
+        }
+        for i in 0..DEPTH {
+            let bit = index(i, bits);
+            state.escalated[0][bit / 64] |= 1 << (bit % 64);
+        }
+        store
+    }
+
+    /// Counts `cost` more requests of the client identified by `key`, which the prefilter let
+    /// through
+    pub(crate) fn charge(&self, key: &str, cost: usize) {
+        let hash = hash(key);
+        let width = self.width;
+        let mut state = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        for i in 0..DEPTH {
+            let count = &mut state.counts[i * width + slot(hash, i, width)];
+            *count = count.saturating_add(cost as u32);
+        }
+    }
+}
+
+// Hash of `key` and the step between its slots, odd so that the slots of a row are distinct
+fn hash(key: &str) -> (u64, u64) {
+    let hash = identifiers::fnv1a(key);
+    (hash, hash.rotate_left(32) | 1)
+}
+
+// `i`-th slot of a key among `len`, by double hashing
+fn slot((hash, step): (u64, u64), i: usize, len: usize) -> usize {
+    (hash.wrapping_add((i as u64).wrapping_mul(step)) % len as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let prefilter = PreFilter::new(64);
+        let interval = Duration::from_secs(86400);
+        let check = || prefilter.check("client", 5, interval);
+        assert!(matches!(check(), PreCheck::Skip { remaining: 4, .. }));
+        assert!(matches!(check(), PreCheck::Skip { remaining: 3, .. }));
+        // Sent to the store for the rest of the window, even once counted lower
+        assert_eq!(check(), PreCheck::Store { max_requests: 3 });
+        prefilter
+            .inner
+            .lock()
+            .unwrap()
+            .counts
+            .iter_mut()
+            .for_each(|c| *c = 0);
+        assert_eq!(check(), PreCheck::Store { max_requests: 3 });
+        assert!(matches!(
+            prefilter.check("other", 5, interval),
+            PreCheck::Skip { remaining: 4, .. }
+        ));
+    }
+
+    #[test]
+    fn test_check_small_limit() {
+        let prefilter = PreFilter::new(64);
+        assert_eq!(
+            prefilter.check("client", 1, Duration::from_secs(60)),
+            PreCheck::Store { max_requests: 1 }
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Latency and errors of the store, kept by the middleware for a quick health read
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Four buckets per power of two of microseconds, up to more than an hour
+const BUCKETS: usize = 128;
+
+/// Histogram of the latency of the store round trips deciding on requests, and count of the
+/// ones which failed, see [RateLimiter::stats](../middleware/struct.RateLimiter.html#method.stats).
+///
+/// It is updated with atomic operations, and cloning it gives another handle on the same
+/// statistics. Each limiter keeps its own, so each worker by default. To read the statistics
+/// of all the workers at once, create one outside of the `HttpServer` factory and give it to
+/// every limiter with
+/// [RateLimiter::with_stats](../middleware/struct.RateLimiter.html#method.with_stats).
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stats::StoreStats, MemoryStore, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let stats = StoreStats::new();
+///     let ratelimiter = RateLimiter::memory(&store, 100, Duration::from_secs(60))
+///         .with_stats(stats.clone());
+///     // Later, for instance from an admin endpoint
+///     let snapshot = stats.snapshot();
+///     println!("p99: {:?}, errors: {:.1}%", snapshot.p99, snapshot.error_rate() * 100.0);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct StoreStats {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    buckets: Vec<AtomicU64>,
+    errors: AtomicU64,
+}
+
+/// Statistics of the store since they were created or last reset. Percentiles are the upper
+/// bound of the bucket they fall in, at most a quarter above the actual latency, and zero when
+/// there was no call.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatsSnapshot {
+    /// Number of round trips, successful or not
+    pub calls: u64,
+    /// Number of round trips which failed
+    pub errors: u64,
+    /// Median latency
+    pub p50: Duration,
+    /// 95th percentile of the latency
+    pub p95: Duration,
+    /// 99th percentile of the latency
+    pub p99: Duration,
+}
+
+impl StatsSnapshot {
+    /// Share of the round trips which failed, between 0 and 1
+    pub fn error_rate(&self) -> f64 {
+        if self.calls == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.calls as f64
+        }
+    }
+}
+
+impl StoreStats {
+    /// Creates empty statistics
+    pub fn new() -> Self {
+        StoreStats {
+            inner: Arc::new(Inner {
+                buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+                errors: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Records a round trip which took `latency`, and whether it failed
+    pub(crate) fn record(&self, latency: Duration, failed: bool) {
+        let micros = latency.as_micros().min(u128::from(u64::MAX)) as u64;
+        self.inner.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.inner.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads the statistics. Round trips recorded while reading may be partly accounted for.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let counts: Vec<u64> = self
+            .inner
+            .buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let calls = counts.iter().sum();
+        let percentile = |fraction: f64| {
+            if calls == 0 {
+                return Duration::from_secs(0);
+            }
+            let rank = ((calls as f64 * fraction).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (index, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    return Duration::from_micros(upper_bound(index));
+                }
+            }
+            Duration::from_micros(upper_bound(BUCKETS - 1))
+        };
+        StatsSnapshot {
+            calls,
+            errors: self.inner.errors.load(Ordering::Relaxed),
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+        }
+    }
+
+    /// Forgets everything recorded so far
+    pub fn reset(&self) {
+        for count in &self.inner.buckets {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.inner.errors.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for StoreStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Values under 8 have a bucket each, then each power of two is split in four
+fn bucket(micros: u64) -> usize {
+    if micros < 8 {
+        return micros as usize;
+    }
+    let msb = 63 - micros.leading_zeros() as usize;
+    let sub = ((micros >> (msb - 2)) & 3) as usize;
+    ((msb - 1) * 4 + sub).min(BUCKETS - 1)
+}
+
+// Smallest value above the bucket at `index`
+fn upper_bound(index: usize) -> u64 {
+    if index < 8 {
+        return index as u64 + 1;
+    }
+    let msb = index / 4 + 1;
+    let sub = (index % 4) as u64;
+    (5 + sub) << (msb - 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        for micros in &[0, 7, 8, 9, 10, 1000, 123_456, 10_000_000] {
+            let index = bucket(*micros);
+            assert!(*micros < upper_bound(index));
+            assert!(index == 0 || *micros >= upper_bound(index - 1));
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let stats = StoreStats::new();
+        assert_eq!(stats.snapshot().p50, Duration::from_secs(0));
+        for millis in 1..=100 {
+            stats.record(Duration::from_millis(millis), millis > 90);
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.calls, 100);
+        assert!((snapshot.error_rate() - 0.1).abs() < 1e-9);
+        let within = |actual: Duration, expected: u64| {
+            let expected = Duration::from_millis(expected);
+            actual >= expected && actual <= expected + expected / 4
+        };
+        assert!(within(snapshot.p50, 50), "{:?}", snapshot.p50);
+        assert!(within(snapshot.p95, 95), "{:?}", snapshot.p95);
+        assert!(within(snapshot.p99, 99), "{:?}", snapshot.p99);
+        stats.reset();
+        assert_eq!(stats.snapshot().calls, 0);
+    }
+}
@@ -0,0 +1,410 @@
+//! Store backed by async methods instead of an actor
+use actix::prelude::*;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::ARError;
+use crate::{ActorMessage, ActorResponse};
+
+/// Backend of a store, for instance an existing async database pool, which
+/// [StoreActor](struct.StoreActor.html) runs as a store actor. Each method answers the
+/// [ActorMessage](../../enum.ActorMessage.html) of the same name, see it for what they do.
+///
+/// This is the short way to a custom store. Implementing `Handler<ActorMessage>` on an actor,
+/// as shown in the [module documentation](../index.html), gives full control over how messages
+/// are processed, for instance to batch them.
+///
+/// # Example
+/// ```rust
+/// use std::collections::HashMap;
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+/// use actix::prelude::*;
+/// use actix_ratelimit::errors::ARError;
+/// use actix_ratelimit::stores::{async_trait, RateLimitStore, StoreActor};
+/// use actix_ratelimit::RateLimiter;
+///
+/// #[derive(Default)]
+/// struct MyStore(Mutex<HashMap<String, usize>>);
+///
+/// #[async_trait]
+/// impl RateLimitStore for MyStore {
+///     async fn get(&self, key: &str) -> Result<Option<usize>, ARError> {
+///         Ok(self.0.lock().unwrap().get(key).copied())
+///     }
+///     async fn set(&self, key: &str, value: usize, _expiry: Duration) -> Result<(), ARError> {
+///         self.0.lock().unwrap().insert(key.to_string(), value);
+///         Ok(())
+///     }
+///     async fn update(&self, key: &str, value: usize) -> Result<usize, ARError> {
+///         let mut inner = self.0.lock().unwrap();
+///         let count = inner.get_mut(key).ok_or(ARError::Other("no such key".to_string()))?;
+///         *count = count.saturating_sub(value);
+///         Ok(*count)
+///     }
+///     async fn expire(&self, _key: &str) -> Result<Duration, ARError> {
+///         // dummy value, you need to implement expiration strategy
+///         Ok(Duration::from_secs(10))
+///     }
+///     async fn remove(&self, key: &str) -> Result<usize, ARError> {
+///         Ok(self.0.lock().unwrap().remove(key).unwrap_or(0))
+///     }
+/// }
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     // Shared by all the workers, like the stores of this crate
+///     let store = Arc::new(MyStore::default());
+///     let ratelimiter = RateLimiter::new(StoreActor::new(store.clone()).start())
+///         .with_interval(Duration::from_secs(60))
+///         .with_max_requests(100);
+/// }
+/// ```
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Remaining count of the client identified by `key`, if any
+    async fn get(&self, key: &str) -> Result<Option<usize>, ARError>;
+
+    /// Sets the remaining count of `key` to `value`, valid for `expiry`
+    async fn set(&self, key: &str, value: usize, expiry: Duration) -> Result<(), ARError>;
+
+    /// Takes `value` from the remaining count of `key`, and returns what is left
+    async fn update(&self, key: &str, value: usize) -> Result<usize, ARError>;
+
+    /// Takes `value` from the remaining count of `key`, and returns what is left with the time
+    /// until `key` expires. By default `update` then `expire`, backends which can do both at once
+    /// should.
+    async fn consume(&self, key: &str, value: usize) -> Result<(usize, Duration), ARError> {
+        let remaining = self.update(key, value).await?;
+        Ok((remaining, self.expire(key).await?))
+    }
+
+    /// Time until `key` expires
+    async fn expire(&self, key: &str) -> Result<Duration, ARError>;
+
+    /// Removes `key`, and returns its remaining count
+    async fn remove(&self, key: &str) -> Result<usize, ARError>;
+
+    /// Rotates the windows of the sliding window counter, see
+    /// [ActorMessage::Rotate](../../enum.ActorMessage.html#variant.Rotate). Not supported by
+    /// default, which only matters with
+    /// [Algorithm::SlidingWindowCounter](../../middleware/enum.Algorithm.html).
+    async fn rotate(
+        &self,
+        _key: &str,
+        _new_window_start: u64,
+        _value: usize,
+        _expiry: Duration,
+    ) -> Result<Option<usize>, ARError> {
+        Err(ARError::Other(
+            "rotating windows is not supported".to_string(),
+        ))
+    }
+
+    /// Lists the clients whose key starts with `prefix`. Not supported by default.
+    async fn list(
+        &self,
+        _prefix: &str,
+        _limit: usize,
+    ) -> Result<Vec<(String, usize, Duration)>, ARError> {
+        Err(ARError::Other("listing keys is not supported".to_string()))
+    }
+
+    /// Sets `key` to `new` if its count is `expected`, atomically. Not supported by default, which
+    /// [RateLimiter::with_sliding_expiry](../../middleware/struct.RateLimiter.html#method.with_sliding_expiry)
+    /// and [Algorithm::Ewma](../../middleware/enum.Algorithm.html#variant.Ewma) need, and without
+    /// which the default [increment_if_exists](#method.increment_if_exists) is not atomic.
+    async fn compare_and_set(
+        &self,
+        _key: &str,
+        _expected: Option<usize>,
+        _new: usize,
+        _expiry: Duration,
+    ) -> Result<bool, ARError> {
+        Err(ARError::Other(
+            "compare-and-set is not supported".to_string(),
+        ))
+    }
+
+    /// Checks that the backend is reachable. Succeeds by default.
+    async fn ping(&self) -> Result<(), ARError> {
+        Ok(())
+    }
+
+    /// Current time of the backend, since the Unix epoch. The local clock by default.
+    async fn time(&self) -> Result<Duration, ARError> {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| ARError::Other(e.to_string()))
+    }
+
+    /// Attaches `metadata` to `key`, or removes it when `None`. Not supported by default.
+    async fn set_metadata(
+        &self,
+        _key: &str,
+        _metadata: Option<&str>,
+        _expiry: Duration,
+    ) -> Result<(), ARError> {
+        Err(ARError::Other("metadata is not supported".to_string()))
+    }
+
+    /// Metadata attached to `key`, if any. Not supported by default.
+    async fn metadata(&self, _key: &str) -> Result<Option<String>, ARError> {
+        Err(ARError::Other("metadata is not supported".to_string()))
+    }
+
+    /// Metadata attached to each of `keys`, in order. Calls [metadata](#method.metadata) for
+    /// each key by default, backends which can read several keys at once should override it.
+    async fn multi_metadata(&self, keys: &[String]) -> Result<Vec<Option<String>>, ARError> {
+        let mut metadata = Vec::with_capacity(keys.len());
+        for key in keys {
+            metadata.push(self.metadata(key).await?);
+        }
+        Ok(metadata)
+    }
+
+    /// Adds `member` to the set at `key` and returns its size, see
+    /// [ActorMessage::AddToSet](../../enum.ActorMessage.html#variant.AddToSet). Not supported by
+    /// default.
+    async fn add_to_set(
+        &self,
+        _key: &str,
+        _member: &str,
+        _expiry: Duration,
+        _cap: usize,
+    ) -> Result<usize, ARError> {
+        Err(ARError::Other("sets are not supported".to_string()))
+    }
+
+    /// Remaining counts of `keys`, in order. Calls [get](#tymethod.get) for each key by default,
+    /// backends which can read several keys at once should override it.
+    async fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<usize>>, ARError> {
+        let mut counts = Vec::with_capacity(keys.len());
+        for key in keys {
+            counts.push(self.get(key).await?);
+        }
+        Ok(counts)
+    }
+
+    /// Adds `by` to the count of `key` up to `cap`, if it exists, see
+    /// [ActorMessage::IncrementIfExists](../../enum.ActorMessage.html#variant.IncrementIfExists).
+    /// Retries [compare_and_set](#method.compare_and_set) by default, or reads the count and sets
+    /// it again when it is not supported, which may lose a concurrent request of the client.
+    /// Backends which can increment atomically should override it.
+    async fn increment_if_exists(
+        &self,
+        key: &str,
+        by: usize,
+        cap: usize,
+    ) -> Result<Option<usize>, ARError> {
+        // Other requests of the client may change the count in between, try again a few times
+        for _ in 0..3 {
+            let count = match self.get(key).await? {
+                Some(count) => count,
+                None => return Ok(None),
+            };
+            let new = count.saturating_add(by).min(cap.max(count));
+            if new == count {
+                return Ok(Some(count));
+            }
+            let expiry = self.expire(key).await?;
+            if expiry == Duration::from_secs(0) {
+                return Ok(None);
+            }
+            match self.compare_and_set(key, Some(count), new, expiry).await {
+                Ok(true) => return Ok(Some(new)),
+                Ok(false) => {}
+                Err(_) => {
+                    self.set(key, new, expiry).await?;
+                    return Ok(Some(new));
+                }
+            }
+        }
+        Err(ARError::Other(
+            "the count kept changing while incrementing".to_string(),
+        ))
+    }
+}
+
+/// Actor running a [RateLimitStore](trait.RateLimitStore.html), to be given to
+/// [RateLimiter::new](../../middleware/struct.RateLimiter.html#method.new).
+pub struct StoreActor<S: RateLimitStore> {
+    store: Arc<S>,
+}
+
+impl<S: RateLimitStore> StoreActor<S> {
+    /// Creates an actor on `store`, which should be created once outside of the `HttpServer`
+    /// factory and shared by the actors of all the workers.
+    pub fn new(store: Arc<S>) -> Self {
+        StoreActor { store }
+    }
+}
+
+impl<S: RateLimitStore> From<Arc<S>> for StoreActor<S> {
+    fn from(store: Arc<S>) -> Self {
+        StoreActor::new(store)
+    }
+}
+
+impl<S: RateLimitStore> Actor for StoreActor<S> {
+    type Context = Context<Self>;
+}
+
+impl<S: RateLimitStore> Supervised for StoreActor<S> {}
+
+impl<S: RateLimitStore> Handler<ActorMessage> for StoreActor<S> {
+    type Result = ActorResponse;
+    fn handle(&mut self, msg: ActorMessage, _: &mut Self::Context) -> Self::Result {
+        let store = self.store.clone();
+        match msg {
+            ActorMessage::Get(key) => {
+                ActorResponse::Get(Box::pin(async move { store.get(&key).await }))
+            }
+            ActorMessage::Set { key, value, expiry } => {
+                ActorResponse::Set(Box::pin(
+                    async move { store.set(&key, value, expiry).await },
+                ))
+            }
+            ActorMessage::Update { key, value } => {
+                ActorResponse::Update(Box::pin(async move { store.update(&key, value).await }))
+            }
+            ActorMessage::Consume { key, value } => {
+                ActorResponse::Consume(Box::pin(async move { store.consume(&key, value).await }))
+            }
+            ActorMessage::Expire(key) => {
+                ActorResponse::Expire(Box::pin(async move { store.expire(&key).await }))
+            }
+            ActorMessage::Remove(key) => {
+                ActorResponse::Remove(Box::pin(async move { store.remove(&key).await }))
+            }
+            ActorMessage::Rotate {
+                key,
+                new_window_start,
+                value,
+                expiry,
+            } => ActorResponse::Rotate(Box::pin(async move {
+                store.rotate(&key, new_window_start, value, expiry).await
+            })),
+            ActorMessage::List { prefix, limit } => {
+                ActorResponse::List(Box::pin(async move { store.list(&prefix, limit).await }))
+            }
+            ActorMessage::CompareAndSet {
+                key,
+                expected,
+                new,
+                expiry,
+            } => ActorResponse::CompareAndSet(Box::pin(async move {
+                store.compare_and_set(&key, expected, new, expiry).await
+            })),
+            ActorMessage::Ping => ActorResponse::Ping(Box::pin(async move { store.ping().await })),
+            ActorMessage::Time => ActorResponse::Time(Box::pin(async move { store.time().await })),
+            ActorMessage::SetMetadata {
+                key,
+                metadata,
+                expiry,
+            } => ActorResponse::SetMetadata(Box::pin(async move {
+                store.set_metadata(&key, metadata.as_deref(), expiry).await
+            })),
+            ActorMessage::GetMetadata(key) => {
+                ActorResponse::GetMetadata(Box::pin(async move { store.metadata(&key).await }))
+            }
+            ActorMessage::MultiGet(keys) => {
+                ActorResponse::MultiGet(Box::pin(async move { store.multi_get(&keys).await }))
+            }
+            ActorMessage::MultiGetMetadata(keys) => {
+                ActorResponse::MultiGetMetadata(Box::pin(async move {
+                    store.multi_metadata(&keys).await
+                }))
+            }
+            ActorMessage::IncrementIfExists { key, by, cap } => {
+                ActorResponse::IncrementIfExists(Box::pin(async move {
+                    store.increment_if_exists(&key, by, cap).await
+                }))
+            }
+            ActorMessage::AddToSet {
+                key,
+                member,
+                expiry,
+                cap,
+            } => ActorResponse::AddToSet(Box::pin(async move {
+                store.add_to_set(&key, &member, expiry, cap).await
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter;
+    use actix_web::{dev::Service, test, web, App, HttpResponse};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MapStore(Mutex<HashMap<String, usize>>);
+
+    #[async_trait]
+    impl RateLimitStore for MapStore {
+        async fn get(&self, key: &str) -> Result<Option<usize>, ARError> {
+            Ok(self.0.lock().unwrap().get(key).copied())
+        }
+        async fn set(&self, key: &str, value: usize, _: Duration) -> Result<(), ARError> {
+            self.0.lock().unwrap().insert(key.to_string(), value);
+            Ok(())
+        }
+        async fn update(&self, key: &str, value: usize) -> Result<usize, ARError> {
+            let mut inner = self.0.lock().unwrap();
+            let count = inner
+                .get_mut(key)
+                .ok_or_else(|| ARError::Other("no such key".to_string()))?;
+            *count = count.saturating_sub(value);
+            Ok(*count)
+        }
+        async fn expire(&self, _: &str) -> Result<Duration, ARError> {
+            Ok(Duration::from_secs(60))
+        }
+        async fn remove(&self, key: &str) -> Result<usize, ARError> {
+            Ok(self.0.lock().unwrap().remove(key).unwrap_or(0))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_store_actor() {
+        let store = Arc::new(MapStore::default());
+        let limiter = RateLimiter::new(StoreActor::new(store.clone()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2);
+        assert!(limiter.validate().await.is_ok());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&mut app, call()).await;
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        test::call_service(&mut app, call()).await;
+        assert!(app.call(call()).await.is_err());
+        assert_eq!(store.0.lock().unwrap().get("127.0.0.1:8080"), Some(&0));
+    }
+
+    #[actix_rt::test]
+    async fn test_increment_without_compare_and_set() {
+        // Only the required methods, refunds work all the same
+        let store = Arc::new(MapStore::default());
+        let addr = StoreActor::new(store.clone()).start();
+        let increment = |key, by, cap| crate::stores::increment_if_exists(&addr, key, by, cap);
+        assert_eq!(increment("a", 1, 10).await.unwrap(), None);
+        store.0.lock().unwrap().insert("a".to_string(), 5);
+        assert_eq!(increment("a", 2, 10).await.unwrap(), Some(7));
+        assert_eq!(increment("a", 5, 10).await.unwrap(), Some(10));
+        assert_eq!(store.0.lock().unwrap().get("a"), Some(&10));
+    }
+}
@@ -0,0 +1,103 @@
+//! Store forwarding to a store actor of any type
+use actix::dev::*;
+
+use crate::stores::unexpected;
+use crate::{ActorMessage, ActorResponse};
+
+/// Store erasing the type of another store actor, which it forwards every message to, so that
+/// stores of different types can be given to a limiter as the same type, see
+/// [RateLimiter::with_store](../../middleware/struct.RateLimiter.html#method.with_store).
+/// Forwarding costs a hop through the mailbox of this actor on top of the one of the store.
+///
+/// # Example
+/// ```rust
+/// use actix::prelude::*;
+/// use actix_ratelimit::stores::AnyStore;
+/// use actix_ratelimit::{MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let memory = MemoryStoreActor::from(MemoryStore::new()).start();
+///     let store = AnyStore::from(memory).start();
+/// }
+/// ```
+pub struct AnyStore {
+    inner: Recipient<ActorMessage>,
+}
+
+impl<A> From<Addr<A>> for AnyStore
+where
+    A: Handler<ActorMessage>,
+    A::Context: ToEnvelope<A, ActorMessage>,
+{
+    fn from(store: Addr<A>) -> Self {
+        AnyStore {
+            inner: store.recipient(),
+        }
+    }
+}
+
+impl Actor for AnyStore {
+    type Context = Context<Self>;
+}
+
+impl Supervised for AnyStore {}
+
+// Answers with the response of the store, which has to be of the same variant as the message
+macro_rules! forward {
+    ($request:expr, $variant:ident) => {{
+        let request = $request;
+        ActorResponse::$variant(Box::pin(async move {
+            match request.await? {
+                ActorResponse::$variant(c) => c.await,
+                other => Err(unexpected(other).await),
+            }
+        }))
+    }};
+}
+
+impl Handler<ActorMessage> for AnyStore {
+    type Result = ActorResponse;
+    fn handle(&mut self, msg: ActorMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            ActorMessage::Get(_) => forward!(self.inner.send(msg), Get),
+            ActorMessage::Set { .. } => forward!(self.inner.send(msg), Set),
+            ActorMessage::Update { .. } => forward!(self.inner.send(msg), Update),
+            ActorMessage::Consume { .. } => forward!(self.inner.send(msg), Consume),
+            ActorMessage::Expire(_) => forward!(self.inner.send(msg), Expire),
+            ActorMessage::Remove(_) => forward!(self.inner.send(msg), Remove),
+            ActorMessage::Rotate { .. } => forward!(self.inner.send(msg), Rotate),
+            ActorMessage::List { .. } => forward!(self.inner.send(msg), List),
+            ActorMessage::CompareAndSet { .. } => forward!(self.inner.send(msg), CompareAndSet),
+            ActorMessage::Ping => forward!(self.inner.send(msg), Ping),
+            ActorMessage::Time => forward!(self.inner.send(msg), Time),
+            ActorMessage::SetMetadata { .. } => forward!(self.inner.send(msg), SetMetadata),
+            ActorMessage::GetMetadata(_) => forward!(self.inner.send(msg), GetMetadata),
+            ActorMessage::MultiGet(_) => forward!(self.inner.send(msg), MultiGet),
+            ActorMessage::MultiGetMetadata(_) => forward!(self.inner.send(msg), MultiGetMetadata),
+            ActorMessage::IncrementIfExists { .. } => {
+                forward!(self.inner.send(msg), IncrementIfExists)
+            }
+            ActorMessage::AddToSet { .. } => forward!(self.inner.send(msg), AddToSet),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use super::*;
+    use crate::stores::{get, seed};
+    use crate::{MemoryStore, MemoryStoreActor};
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_forward() {
+        let memory = MemoryStoreActor::from(MemoryStore::new()).start();
+        let store = AnyStore::from(memory.clone()).start();
+        seed(&store, "client", 3, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(get(&memory, "client").await.unwrap(), Some(3));
+        assert_eq!(get(&store, "client").await.unwrap(), Some(3));
+    }
+}
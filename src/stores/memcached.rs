@@ -1,5 +1,6 @@
 //! Memcached store for rate limiting
 use crate::errors::ARError;
+use crate::stores::derived_key;
 use crate::{ActorMessage, ActorResponse};
 use actix::prelude::*;
 use backoff::backoff::Backoff;
@@ -7,6 +8,7 @@ use backoff::ExponentialBackoff;
 use log::*;
 use r2d2_memcache::r2d2::Pool;
 use r2d2_memcache::MemcacheConnectionManager;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -18,6 +20,7 @@ impl Message for GetAddr {
 /// Type used to connect to a running memecached store
 pub struct MemcacheStore {
     addr: String,
+    pool_size: u32,
     backoff: ExponentialBackoff,
     client: Option<Pool<MemcacheConnectionManager>>,
 }
@@ -35,13 +38,33 @@ impl MemcacheStore {
     /// }
     /// ```
     pub fn connect<S: Into<String>>(addr: S) -> Addr<Self> {
+        Self::connect_with_pool_size(addr, 15)
+    }
+
+    /// Like [connect](#method.connect), with a pool of at most `pool_size` connections instead
+    /// of 15. Each store operation checks a connection out of the pool, so this is how many
+    /// operations can run at the same time.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::MemcacheStore;
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = MemcacheStore::connect_with_pool_size("memcache://127.0.0.1:11211", 32);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_pool_size<S: Into<String>>(addr: S, pool_size: u32) -> Addr<Self> {
         let addr = addr.into();
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = None;
+        let backoff = ExponentialBackoff {
+            max_elapsed_time: None,
+            ..Default::default()
+        };
         let manager = MemcacheConnectionManager::new(addr.clone());
-        let pool = Pool::builder().max_size(15).build(manager).unwrap();
-        Supervisor::start(|_| MemcacheStore {
+        let pool = Pool::builder().max_size(pool_size).build(manager).unwrap();
+        Supervisor::start(move |_| MemcacheStore {
             addr,
+            pool_size,
             backoff,
             client: Some(pool),
         })
@@ -55,7 +78,7 @@ impl Actor for MemcacheStore {
         info!("Started memcached store");
         let addr = self.addr.clone();
         let manager = MemcacheConnectionManager::new(addr);
-        let pool = Pool::builder().max_size(15).build(manager);
+        let pool = Pool::builder().max_size(self.pool_size).build(manager);
         async move { pool }
             .into_actor(self)
             .map(|con, act, context| {
@@ -136,8 +159,10 @@ impl Actor for MemcacheStoreActor {
 
 impl From<Addr<MemcacheStore>> for MemcacheStoreActor {
     fn from(addr: Addr<MemcacheStore>) -> Self {
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_interval = Duration::from_secs(3);
+        let backoff = ExponentialBackoff {
+            max_interval: Duration::from_secs(3),
+            ..Default::default()
+        };
         MemcacheStoreActor {
             addr,
             backoff,
@@ -161,6 +186,9 @@ impl Supervised for MemcacheStoreActor {
     }
 }
 
+// Value, flags and cas token, as answered by `gets`
+type Versioned = (Vec<u8>, u32, Option<u64>);
+
 impl Handler<ActorMessage> for MemcacheStoreActor {
     type Result = ActorResponse;
     fn handle(&mut self, msg: ActorMessage, ctx: &mut Self::Context) -> Self::Result {
@@ -173,15 +201,17 @@ impl Handler<ActorMessage> for MemcacheStoreActor {
                             let ex_key = format!("{}:expire", key);
                             let now = SystemTime::now();
                             let now = now.duration_since(UNIX_EPOCH).unwrap();
+                            // An expiry of zero never expires in memcached
+                            let seconds: u32 = expiry.as_secs().max(1).try_into().unwrap();
                             let result = client.set(
                                 &key,
                                 value as u64,
-                                expiry.as_secs().try_into().unwrap(),
+                                seconds,
                             );
                             let val = now + expiry;
-                            let val: u64 = val.as_secs().try_into().unwrap();
+                            let val: u64 = val.as_secs();
                             client
-                                .set(&ex_key, val, expiry.as_secs().try_into().unwrap())
+                                .set(&ex_key, val, seconds)
                                 .unwrap();
                             match result {
                                 Ok(_) => Ok(()),
@@ -198,6 +228,29 @@ impl Handler<ActorMessage> for MemcacheStoreActor {
                             }
                         }))
                     }
+                    ActorMessage::Consume { key, value } => {
+                        ActorResponse::Consume(Box::pin(async move {
+                            // Memcached has no scripts, the expiry is read right after
+                            let result = client.decrement(&key, value as u64);
+                            let remaining = match result {
+                                Ok(c) => c as usize,
+                                Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            };
+                            let result: Result<Option<u64>, _> =
+                                client.get(&format!("{}:expire", &key));
+                            match result {
+                                Ok(Some(d)) => {
+                                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                                    let res = d.saturating_sub(now.as_secs());
+                                    Ok((remaining, Duration::from_secs(res)))
+                                }
+                                Ok(None) => Err(ARError::ReadWriteError(
+                                    "error: expiration data not found".to_owned(),
+                                )),
+                                Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            }
+                        }))
+                    }
                     ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
                         let result: Result<Option<u64>, _> = client.get(&key);
                         match result {
@@ -215,8 +268,8 @@ impl Handler<ActorMessage> for MemcacheStoreActor {
                             Ok(c) => {
                                 if let Some(d) = c {
                                     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                                    let now = now.as_secs().try_into().unwrap();
-                                    let res = d.checked_sub(now).unwrap_or_else(|| 0);
+                                    let now = now.as_secs();
+                                    let res = d.saturating_sub(now);
                                     Ok(Duration::from_secs(res))
                                 } else {
                                     Err(ARError::ReadWriteError(
@@ -235,6 +288,162 @@ impl Handler<ActorMessage> for MemcacheStoreActor {
                             Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
                         }
                     })),
+                    ActorMessage::List { .. } => ActorResponse::List(Box::pin(async move {
+                        Err(ARError::Other("listing keys is not supported by memcached".to_owned()))
+                    })),
+                    ActorMessage::AddToSet { .. } => ActorResponse::AddToSet(Box::pin(async move {
+                        Err(ARError::Other("sets are not supported by memcached".to_owned()))
+                    })),
+                    ActorMessage::Rotate { key, new_window_start, value, expiry } => {
+                        ActorResponse::Rotate(Box::pin(async move {
+                            let expiry = expiry.as_secs().max(1) as u32;
+                            let previous_key = derived_key(&key, ":previous");
+                            // Only the first request of a window manages to add its marker, and
+                            // moves the counts
+                            let marker = format!("{}:window:{}", &key, new_window_start);
+                            if client.add(&marker, 1u64, expiry).is_err() {
+                                let result: Result<Option<u64>, _> = client.get(&previous_key);
+                                return match result {
+                                    Ok(c) => Ok(c.map(|v| v as usize)),
+                                    Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                                };
+                            }
+                            let result: Result<Option<u64>, _> = client.get(&key);
+                            let current = match result {
+                                Ok(c) => c,
+                                Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            };
+                            let result = match current {
+                                Some(c) => client.set(&previous_key, c, expiry),
+                                None => client.delete(&previous_key).map(|_| ()),
+                            };
+                            if let Err(e) = result.and_then(|_| client.set(&key, value as u64, expiry)) {
+                                return Err(ARError::ReadWriteError(format!("{:?}", &e)));
+                            }
+                            Ok(current.map(|v| v as usize))
+                        }))
+                    }
+                    ActorMessage::Ping => ActorResponse::Ping(Box::pin(async move {
+                        match client.version() {
+                            Ok(_) => Ok(()),
+                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        }
+                    })),
+                    ActorMessage::Time => ActorResponse::Time(Box::pin(async move {
+                        let stats = match client.stats() {
+                            Ok(stats) => stats,
+                            Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        };
+                        // Seconds since the Unix epoch, of the first server
+                        let time = stats.first().and_then(|(_, stats)| stats.get("time")?.parse::<u64>().ok());
+                        match time {
+                            Some(time) => Ok(Duration::from_secs(time)),
+                            None => Err(ARError::Protocol("memcached stats without the time".to_string())),
+                        }
+                    })),
+                    ActorMessage::SetMetadata { key, metadata, expiry } => ActorResponse::SetMetadata(Box::pin(async move {
+                        let meta_key = derived_key(&key, ":metadata");
+                        let result = match metadata {
+                            Some(metadata) => client.set(&meta_key, metadata.as_str(), expiry.as_secs().max(1) as u32),
+                            None => client.delete(&meta_key).map(|_| ()),
+                        };
+                        result.map_err(|e| ARError::ReadWriteError(format!("{:?}", &e)))
+                    })),
+                    ActorMessage::MultiGet(keys) => ActorResponse::MultiGet(Box::pin(async move {
+                        if keys.is_empty() {
+                            return Ok(Vec::new());
+                        }
+                        let names: Vec<&str> = keys.iter().map(String::as_str).collect();
+                        let result: Result<HashMap<String, u64>, _> = client.gets(&names);
+                        match result {
+                            Ok(found) => Ok(keys.iter().map(|key| found.get(key).map(|v| *v as usize)).collect()),
+                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        }
+                    })),
+                    ActorMessage::GetMetadata(key) => ActorResponse::GetMetadata(Box::pin(async move {
+                        let result: Result<Option<String>, _> = client.get(&derived_key(&key, ":metadata"));
+                        result.map_err(|e| ARError::ReadWriteError(format!("{:?}", &e)))
+                    })),
+                    ActorMessage::MultiGetMetadata(keys) => ActorResponse::MultiGetMetadata(Box::pin(async move {
+                        if keys.is_empty() {
+                            return Ok(Vec::new());
+                        }
+                        let meta_keys: Vec<String> = keys.iter().map(|key| derived_key(key, ":metadata")).collect();
+                        let names: Vec<&str> = meta_keys.iter().map(String::as_str).collect();
+                        let result: Result<HashMap<String, String>, _> = client.gets(&names);
+                        match result {
+                            Ok(mut found) => Ok(meta_keys.iter().map(|key| found.remove(key)).collect()),
+                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        }
+                    })),
+                    ActorMessage::CompareAndSet { key, expected, new, expiry } => {
+                        ActorResponse::CompareAndSet(Box::pin(async move {
+                            let ex_key = format!("{}:expire", key);
+                            let reset = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + expiry;
+                            let expiry = expiry.as_secs().max(1) as u32;
+                            let swapped = match expected {
+                                // Fails when the key exists
+                                None => client.add(&key, new as u64, expiry).is_ok(),
+                                Some(expected) => {
+                                    let result: Result<HashMap<String, Versioned>, _> = client.gets(&[&key]);
+                                    let current = match result {
+                                        Ok(c) => c.get(&key).cloned(),
+                                        Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                                    };
+                                    let current = current.and_then(|(value, _, cas)| {
+                                        let value = String::from_utf8(value).ok()?.parse::<usize>().ok()?;
+                                        Some((value, cas?))
+                                    });
+                                    match current {
+                                        Some((value, cas)) if value == expected => {
+                                            match client.cas(&key, new as u64, expiry, cas) {
+                                                Ok(swapped) => swapped,
+                                                Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                                            }
+                                        }
+                                        _ => false,
+                                    }
+                                }
+                            };
+                            if swapped {
+                                let _ = client.set(&ex_key, reset.as_secs(), expiry);
+                            }
+                            Ok(swapped)
+                        }))
+                    }
+                    ActorMessage::IncrementIfExists { key, by, cap } => {
+                        ActorResponse::IncrementIfExists(Box::pin(async move {
+                            let result: Result<HashMap<String, Versioned>, _> = client.gets(&[&key]);
+                            let current = match result {
+                                Ok(c) => c.get(&key).cloned(),
+                                Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            };
+                            let (count, cas) = match current.and_then(|(value, _, cas)| {
+                                let value = String::from_utf8(value).ok()?.parse::<usize>().ok()?;
+                                Some((value, cas?))
+                            }) {
+                                Some(current) => current,
+                                None => return Ok(None),
+                            };
+                            let new = count.saturating_add(by).min(cap.max(count));
+                            if new == count {
+                                return Ok(Some(count));
+                            }
+                            // The key keeps its expiry, which memcached can not tell
+                            let expire: Result<Option<u64>, _> = client.get(&format!("{}:expire", key));
+                            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                            let expiry = match expire {
+                                Ok(Some(reset)) if reset > now => (reset - now) as u32,
+                                Ok(_) => return Ok(None),
+                                Err(e) => return Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            };
+                            match client.cas(&key, new as u64, expiry, cas) {
+                                Ok(true) => Ok(Some(new)),
+                                Ok(false) => Err(ARError::ReadWriteError("the count changed while incrementing".to_string())),
+                                Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            }
+                        }))
+                    }
                 }
             } else {
                 ctx.stop();
@@ -291,7 +500,7 @@ mod tests {
             .send(ActorMessage::Set {
                 key: "hello".to_string(),
                 value: 30usize,
-                expiry: expiry,
+                expiry,
             })
             .await;
         let res = res.expect("Failed to send msg");
@@ -326,7 +535,7 @@ mod tests {
             .send(ActorMessage::Set {
                 key: "hello_test".to_string(),
                 value: 30usize,
-                expiry: expiry,
+                expiry,
             })
             .await;
         let res = res.expect("Failed to send msg");
@@ -337,7 +546,7 @@ mod tests {
             },
             _ => panic!("Shouldn't happen!"),
         }
-        assert_eq!(addr.connected(), true);
+        assert!(addr.connected());
 
         let res3 = addr
             .send(ActorMessage::Expire("hello_test".to_string()))
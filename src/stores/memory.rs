@@ -1,18 +1,38 @@
 //! In memory store for rate limiting
 use actix::prelude::*;
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures::future::{self};
 use log::*;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+#[cfg(feature = "persistence")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "persistence")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::errors::ARError;
+use crate::events::RateLimitEvent;
+use crate::stores::derived_key;
 use crate::{ActorMessage, ActorResponse};
 
+type Observer = Arc<dyn Fn(&RateLimitEvent) + Send + Sync>;
+
 /// Type used to create a concurrent hashmap store
 #[derive(Clone)]
 pub struct MemoryStore {
     inner: Arc<DashMap<String, (usize, Duration)>>,
+    // Metadata of the clients, with when it expires, apart from the counters
+    metadata: Arc<DashMap<String, (String, Duration)>>,
+    // Sets of members of distinct limits, with when they expire
+    sets: Arc<DashMap<String, (HashSet<String>, Duration)>>,
+    // Serializes rotations across the actors of the store
+    rotation: Arc<Mutex<()>>,
+    lru: Option<Arc<Lru>>,
+    observer: Option<Observer>,
+    mailbox_capacity: Option<usize>,
+    #[cfg(feature = "persistence")]
+    persistence: Option<Arc<Persistence>>,
 }
 
 impl MemoryStore {
@@ -28,6 +48,14 @@ impl MemoryStore {
         debug!("Creating new MemoryStore");
         MemoryStore {
             inner: Arc::new(DashMap::<String, (usize, Duration)>::new()),
+            metadata: Arc::new(DashMap::new()),
+            sets: Arc::new(DashMap::new()),
+            rotation: Arc::new(Mutex::new(())),
+            lru: None,
+            observer: None,
+            mailbox_capacity: None,
+            #[cfg(feature = "persistence")]
+            persistence: None,
         }
     }
 
@@ -38,18 +66,500 @@ impl MemoryStore {
             inner: Arc::new(DashMap::<String, (usize, Duration)>::with_capacity(
                 capacity,
             )),
+            metadata: Arc::new(DashMap::new()),
+            sets: Arc::new(DashMap::new()),
+            rotation: Arc::new(Mutex::new(())),
+            lru: None,
+            observer: None,
+            mailbox_capacity: None,
+            #[cfg(feature = "persistence")]
+            persistence: None,
+        }
+    }
+
+    /// Saves the counters of the store to the file at `path` every `interval`, and loads the
+    /// ones saved there by a previous run, so that clients do not get a fresh quota when a single
+    /// node deployment restarts. Requires the `persistence` feature.
+    ///
+    /// The store is saved by one of its actors, and once more when that actor stops. Counts
+    /// changed since the last save are lost if the process crashes. Entries which expired while
+    /// the application was down are dropped on load, and a missing, truncated or otherwise
+    /// corrupt file is logged and ignored, the store then starts empty.
+    ///
+    /// The file starts with the 4 bytes `ARL1` followed by the
+    /// [bincode](https://docs.rs/bincode/1) encoding of a `Vec<(String, usize, Duration)>`, the
+    /// key, its remaining count and its expiry since the Unix epoch. It is written to a temporary
+    /// file next to `path` and then renamed, so that a crash while saving leaves the previous
+    /// file intact.
+    ///
+    /// # Panics
+    /// If `interval` is zero.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let path = std::env::temp_dir().join("ratelimit.bin");
+    ///     let store = MemoryStore::new().with_persistence(path, Duration::from_secs(10));
+    ///     let addr = MemoryStoreActor::from(store).start();
+    /// }
+    /// ```
+    #[cfg(feature = "persistence")]
+    pub fn with_persistence<P: Into<PathBuf>>(mut self, path: P, interval: Duration) -> Self {
+        assert!(
+            interval > Duration::from_secs(0),
+            "interval must not be zero"
+        );
+        let path = path.into();
+        for (key, value) in load(&path) {
+            if let Some(lru) = &self.lru {
+                for evicted in lru.touch(&key) {
+                    self.inner.remove(&evicted);
+                }
+            }
+            self.inner.insert(key, value);
+        }
+        self.persistence = Some(Arc::new(Persistence {
+            path,
+            interval,
+            flusher: AtomicBool::new(false),
+            lock: Mutex::new(()),
+        }));
+        self
+    }
+
+    /// Caps the number of keys the store holds to `max_keys`, evicting the least recently
+    /// written one when a new key would go over it. This protects the node from clients creating
+    /// keys until it runs out of memory, when they control their identifier, for instance with
+    /// [identifiers::from_headers_hash](../../identifiers/fn.from_headers_hash.html).
+    ///
+    /// An evicted client starts over with a full window on its next request, so the cap should
+    /// be well above the number of clients expected in a window: a client creating keys faster
+    /// than the others use theirs can otherwise evict them, and reset its own limit along the
+    /// way. The keys the sliding window counter adds next to each client key, and the sets of
+    /// the [distinct limit](../../middleware/struct.RateLimiter.html#method.with_distinct_limit),
+    /// count towards the cap. Evictions are logged, and reported to the
+    /// [observer](#method.with_observer) as `RateLimitEvent::Evicted`.
+    ///
+    /// # Panics
+    /// If `max_keys` is zero.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::MemoryStore;
+    ///
+    /// let store = MemoryStore::new()
+    ///     .with_max_keys(1_000_000)
+    ///     .with_observer(|event| log::warn!("{:?}", event));
+    /// ```
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        assert!(max_keys > 0, "max_keys must not be zero");
+        let lru = Lru {
+            max_keys,
+            state: Mutex::new(LruState::default()),
+        };
+        let keys: Vec<String> = self.inner.iter().map(|entry| entry.key().clone()).collect();
+        for key in keys {
+            for evicted in lru.touch(&key) {
+                self.inner.remove(&evicted);
+            }
+        }
+        self.lru = Some(Arc::new(lru));
+        self
+    }
+
+    /// Calls `observer` with the events of the store, currently the evictions of
+    /// [with_max_keys](#method.with_max_keys).
+    pub fn with_observer<F: Fn(&RateLimitEvent) + Send + Sync + 'static>(
+        mut self,
+        observer: F,
+    ) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Number of messages each actor of the store queues, instead of the default of 16 of actix.
+    /// Zero means no limit. Past it, the requests of the middleware, which sends with
+    /// `Addr::send`, wait for room in the mailbox, and `Addr::try_send` fails with a
+    /// `SendError::Full`, which converts to
+    /// [ARError::MailboxFull](../../errors/enum.ARError.html#variant.MailboxFull).
+    ///
+    /// Under bursts, a single node deployment whose workers share one actor can fill its mailbox
+    /// faster than the actor answers, and leave requests waiting on it until they time out. A
+    /// larger mailbox absorbs the burst rather than stalling it, at the cost of the memory of
+    /// the queued messages, a key and a reply channel each, and of the latency of the requests
+    /// waiting behind them. A mailbox without limit lets a sustained overload grow the queue,
+    /// and the memory, until the process runs out of it.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new().with_mailbox_capacity(1024);
+    ///     let addr = MemoryStoreActor::from(store).start();
+    /// }
+    /// ```
+    pub fn with_mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.mailbox_capacity = Some(capacity);
+        self
+    }
+}
+
+// Order in which the keys were last written, shared by the actors of the store
+struct Lru {
+    max_keys: usize,
+    state: Mutex<LruState>,
+}
+
+#[derive(Default)]
+struct LruState {
+    tick: u64,
+    order: BTreeMap<u64, String>,
+    ticks: HashMap<String, u64>,
+}
+
+impl Lru {
+    // Marks `key` as the most recently written, and returns the keys to evict
+    fn touch(&self, key: &str) -> Vec<String> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(previous) = state.ticks.insert(key.to_string(), tick) {
+            state.order.remove(&previous);
+        }
+        state.order.insert(tick, key.to_string());
+        let mut evicted = Vec::new();
+        while state.ticks.len() > self.max_keys {
+            let oldest = match state.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = state.order.remove(&oldest) {
+                state.ticks.remove(&key);
+                evicted.push(key);
+            }
+        }
+        evicted
+    }
+
+    fn forget(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tick) = state.ticks.remove(key) {
+            state.order.remove(&tick);
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+struct Persistence {
+    path: PathBuf,
+    interval: Duration,
+    // Set while an actor of the store is saving it
+    flusher: AtomicBool,
+    // Serializes the writes to the file
+    lock: Mutex<()>,
+}
+
+#[cfg(feature = "persistence")]
+const SNAPSHOT_HEADER: &[u8; 4] = b"ARL1";
+
+#[cfg(feature = "persistence")]
+type Snapshot = Vec<(String, usize, Duration)>;
+
+#[cfg(feature = "persistence")]
+fn load(path: &Path) -> Vec<(String, (usize, Duration))> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            warn!("memory store: failed to read {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    if !bytes.starts_with(SNAPSHOT_HEADER) {
+        warn!(
+            "memory store: {} is not a snapshot, ignoring it",
+            path.display()
+        );
+        return Vec::new();
+    }
+    let snapshot: Snapshot = match bincode::deserialize(&bytes[SNAPSHOT_HEADER.len()..]) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!(
+                "memory store: {} is corrupt, ignoring it: {}",
+                path.display(),
+                e
+            );
+            return Vec::new();
         }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    snapshot
+        .into_iter()
+        .filter(|(_, _, expiry)| *expiry > now)
+        .map(|(key, remaining, expiry)| (key, (remaining, expiry)))
+        .collect()
+}
+
+#[cfg(feature = "persistence")]
+fn save(
+    path: &Path,
+    inner: &DashMap<String, (usize, Duration)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let snapshot: Snapshot = inner
+        .iter()
+        .filter(|entry| entry.value().1 > now)
+        .map(|entry| (entry.key().clone(), entry.value().0, entry.value().1))
+        .collect();
+    let mut bytes = SNAPSHOT_HEADER.to_vec();
+    bincode::serialize_into(&mut bytes, &snapshot)?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, &bytes)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// Actor for memory store
 pub struct MemoryStoreActor {
     inner: Arc<DashMap<String, (usize, Duration)>>,
+    metadata: Arc<DashMap<String, (String, Duration)>>,
+    sets: Arc<DashMap<String, (HashSet<String>, Duration)>>,
+    rotation: Arc<Mutex<()>>,
+    lru: Option<Arc<Lru>>,
+    observer: Option<Observer>,
+    mailbox_capacity: Option<usize>,
+    #[cfg(feature = "persistence")]
+    persistence: Option<Arc<Persistence>>,
+    // Whether this actor is the one saving the store
+    #[cfg(feature = "persistence")]
+    flusher: bool,
 }
 
 impl From<MemoryStore> for MemoryStoreActor {
     fn from(store: MemoryStore) -> Self {
-        MemoryStoreActor { inner: store.inner }
+        MemoryStoreActor {
+            inner: store.inner,
+            metadata: store.metadata,
+            sets: store.sets,
+            rotation: store.rotation,
+            lru: store.lru,
+            observer: store.observer,
+            mailbox_capacity: store.mailbox_capacity,
+            #[cfg(feature = "persistence")]
+            persistence: store.persistence,
+            #[cfg(feature = "persistence")]
+            flusher: false,
+        }
+    }
+}
+
+// Removes a key once its expiry has passed, unlike `ActorMessage::Remove` it leaves keys which
+// were set again in the meantime
+struct Purge(String);
+
+impl Message for Purge {
+    type Result = ();
+}
+
+impl Handler<Purge> for MemoryStoreActor {
+    type Result = ();
+    fn handle(&mut self, Purge(key): Purge, _: &mut Self::Context) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        if self
+            .inner
+            .remove_if(&key, |_, value| value.1 <= now)
+            .is_some()
+        {
+            if let Some(lru) = &self.lru {
+                lru.forget(&key);
+            }
+        }
+    }
+}
+
+// Removes a set once its expiry has passed
+struct PurgeSet(String);
+
+impl Message for PurgeSet {
+    type Result = ();
+}
+
+impl Handler<PurgeSet> for MemoryStoreActor {
+    type Result = ();
+    fn handle(&mut self, PurgeSet(key): PurgeSet, _: &mut Self::Context) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        if self
+            .sets
+            .remove_if(&key, |_, (_, until)| *until <= now)
+            .is_some()
+        {
+            if let Some(lru) = &self.lru {
+                lru.forget(&key);
+            }
+        }
+    }
+}
+
+// Removes the metadata of a key once its expiry has passed
+struct PurgeMetadata(String);
+
+impl Message for PurgeMetadata {
+    type Result = ();
+}
+
+impl Handler<PurgeMetadata> for MemoryStoreActor {
+    type Result = ();
+    fn handle(&mut self, PurgeMetadata(key): PurgeMetadata, _: &mut Self::Context) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.metadata.remove_if(&key, |_, (_, until)| *until <= now);
+    }
+}
+
+impl MemoryStoreActor {
+    // Records a write to `key`, evicting the least recently written keys over the cap
+    fn track(&self, key: &str) {
+        let lru = match &self.lru {
+            Some(lru) => lru,
+            None => return,
+        };
+        for evicted in lru.touch(key) {
+            self.inner.remove(&evicted);
+            self.sets.remove(&evicted);
+            warn!("memory store: too many keys, evicted {}", &evicted);
+            if let Some(observer) = &self.observer {
+                (observer)(&RateLimitEvent::Evicted { key: &evicted });
+            }
+        }
+    }
+
+    // Returns the previous count, and whether the windows moved
+    fn rotate(
+        &self,
+        key: String,
+        new_window_start: u64,
+        value: usize,
+        expiry: Duration,
+    ) -> (Option<usize>, bool) {
+        let _rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let live = |key: &str| {
+            self.inner
+                .get(key)
+                .map(|entry| *entry.value())
+                .filter(|entry| entry.1 > now)
+                .map(|entry| entry.0)
+        };
+        let previous_key = derived_key(&key, ":previous");
+        let window_key = derived_key(&key, ":window");
+        if live(&window_key) == Some(new_window_start as usize) {
+            return (live(&previous_key), false);
+        }
+        let current = live(&key);
+        match current {
+            Some(current) => {
+                self.inner.insert(previous_key, (current, now + expiry));
+            }
+            None => {
+                self.inner.remove(&previous_key);
+            }
+        }
+        self.inner.insert(key, (value, now + expiry));
+        self.inner
+            .insert(window_key, (new_window_start as usize, now + expiry));
+        (current, true)
+    }
+
+    fn compare_and_set(
+        &self,
+        key: String,
+        expected: Option<usize>,
+        new: usize,
+        expiry: Duration,
+    ) -> bool {
+        let _rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // The entry locks the shard, so that no update of another actor comes in between
+        match self.inner.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (count, until) = *entry.get();
+                let current = Some(count).filter(|_| until > now);
+                if current != expected {
+                    return false;
+                }
+                entry.insert((new, now + expiry));
+            }
+            Entry::Vacant(entry) => {
+                if expected.is_some() {
+                    return false;
+                }
+                entry.insert((new, now + expiry));
+            }
+        }
+        true
+    }
+
+    // Takes `value` from the count of `key`, without going below zero
+    fn update(&self, key: &str, value: usize) -> Option<usize> {
+        self.inner.get_mut(key).map(|mut c| {
+            let val_mut: &mut (usize, Duration) = c.value_mut();
+            if val_mut.0 > value {
+                val_mut.0 -= value;
+            } else {
+                val_mut.0 = 0;
+            }
+            val_mut.0
+        })
+    }
+
+    fn increment_if_exists(&self, key: &str, by: usize, cap: usize) -> Option<usize> {
+        let _rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut entry = self
+            .inner
+            .get_mut(key)
+            .filter(|entry| entry.value().1 > now)?;
+        let (count, _) = entry.value_mut();
+        *count = count.saturating_add(by).min(cap.max(*count));
+        Some(*count)
+    }
+
+    // Adds `member` to the set at `key`, and returns its size and whether the set was created
+    fn add_to_set(
+        &self,
+        key: String,
+        member: String,
+        expiry: Duration,
+        cap: usize,
+    ) -> (usize, bool) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut entry = self
+            .sets
+            .entry(key)
+            .or_insert_with(|| (HashSet::new(), now));
+        let (members, until) = entry.value_mut();
+        let created = *until <= now;
+        if created {
+            members.clear();
+            *until = now + expiry;
+        }
+        if members.len() < cap {
+            members.insert(member);
+        }
+        (members.len().min(cap), created)
     }
 }
 
@@ -61,8 +571,70 @@ impl MemoryStoreActor {
     }
 }
 
+#[cfg(feature = "persistence")]
+impl MemoryStoreActor {
+    fn flush(&self) {
+        if let Some(persistence) = &self.persistence {
+            let _lock = persistence.lock.lock().unwrap_or_else(|e| e.into_inner());
+            debug!("Saving memory store to {}", persistence.path.display());
+            if let Err(e) = save(&persistence.path, &self.inner) {
+                error!(
+                    "memory store: failed to save {}: {}",
+                    persistence.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
 impl Actor for MemoryStoreActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(capacity) = self.mailbox_capacity {
+            ctx.set_mailbox_capacity(capacity);
+        }
+        #[cfg(feature = "persistence")]
+        self.start_flusher(ctx);
+    }
+
+    #[cfg(feature = "persistence")]
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if self.flusher {
+            self.flush();
+            self.flusher = false;
+            if let Some(persistence) = &self.persistence {
+                persistence.flusher.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl MemoryStoreActor {
+    // Makes this actor the one saving the store, unless another one already is
+    fn start_flusher(&mut self, ctx: &mut Context<Self>) {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence.clone(),
+            None => return,
+        };
+        if persistence
+            .flusher
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+        self.flusher = true;
+        // Keys loaded from disk were not set through this actor, nothing removes them yet
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for entry in self.inner.iter() {
+            let expiry = entry.value().1.checked_sub(now).unwrap_or_default();
+            ctx.notify_later(Purge(entry.key().clone()), expiry);
+        }
+        ctx.run_interval(persistence.interval, |act, _| act.flush());
+    }
 }
 
 impl Supervised for MemoryStoreActor {
@@ -81,26 +653,23 @@ impl Handler<ActorMessage> for MemoryStoreActor {
                 let now = SystemTime::now();
                 let now = now.duration_since(UNIX_EPOCH).unwrap();
                 self.inner.insert(key, (value, now + expiry));
-                ctx.notify_later(ActorMessage::Remove(future_key), expiry);
+                self.track(&future_key);
+                // Only purged once expired, the key may have been set again since
+                ctx.notify_later(Purge(future_key), expiry);
                 ActorResponse::Set(Box::pin(future::ready(Ok(()))))
             }
-            ActorMessage::Update { key, value } => match self.inner.get_mut(&key) {
-                Some(mut c) => {
-                    let val_mut: &mut (usize, Duration) = c.value_mut();
-                    if val_mut.0 > value {
-                        val_mut.0 -= value;
-                    } else {
-                        val_mut.0 = 0;
+            ActorMessage::Update { key, value } => {
+                let updated = self.update(&key, value);
+                match updated {
+                    Some(new_val) => {
+                        self.track(&key);
+                        ActorResponse::Update(Box::pin(future::ready(Ok(new_val))))
                     }
-                    let new_val = val_mut.0;
-                    ActorResponse::Update(Box::pin(future::ready(Ok(new_val))))
-                }
-                None => {
-                    return ActorResponse::Update(Box::pin(future::ready(Err(
+                    None => ActorResponse::Update(Box::pin(future::ready(Err(
                         ARError::ReadWriteError("memory store: read failed!".to_string()),
-                    ))))
+                    )))),
                 }
-            },
+            }
             ActorMessage::Get(key) => {
                 if self.inner.contains_key(&key) {
                     let val = match self.inner.get(&key) {
@@ -111,12 +680,33 @@ impl Handler<ActorMessage> for MemoryStoreActor {
                             ))))
                         }
                     };
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    // Expired, but not purged yet
+                    if val.value().1 <= now {
+                        return ActorResponse::Get(Box::pin(future::ready(Ok(None))));
+                    }
                     let val = val.value().0;
                     ActorResponse::Get(Box::pin(future::ready(Ok(Some(val)))))
                 } else {
                     ActorResponse::Get(Box::pin(future::ready(Ok(None))))
                 }
             }
+            ActorMessage::Consume { key, value } => {
+                let consumed = self.update(&key, value).and_then(|new_val| {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    let until = self.inner.get(&key)?.value().1;
+                    Some((new_val, until.checked_sub(now).unwrap_or_default()))
+                });
+                match consumed {
+                    Some(consumed) => {
+                        self.track(&key);
+                        ActorResponse::Consume(Box::pin(future::ready(Ok(consumed))))
+                    }
+                    None => ActorResponse::Consume(Box::pin(future::ready(Err(
+                        ARError::ReadWriteError("memory store: read failed!".to_string()),
+                    )))),
+                }
+            }
             ActorMessage::Expire(key) => {
                 let c = match self.inner.get(&key) {
                     Some(d) => d,
@@ -131,8 +721,129 @@ impl Handler<ActorMessage> for MemoryStoreActor {
                 let res = dur.checked_sub(now).unwrap_or_else(|| Duration::new(0, 0));
                 ActorResponse::Expire(Box::pin(future::ready(Ok(res))))
             }
+            ActorMessage::Rotate {
+                key,
+                new_window_start,
+                value,
+                expiry,
+            } => {
+                let (previous, rotated) = self.rotate(key.clone(), new_window_start, value, expiry);
+                if rotated {
+                    for suffix in &["", ":previous", ":window"] {
+                        let key = format!("{}{}", &key, suffix);
+                        self.track(&key);
+                        ctx.notify_later(Purge(key), expiry);
+                    }
+                }
+                ActorResponse::Rotate(Box::pin(future::ready(Ok(previous))))
+            }
+            ActorMessage::CompareAndSet {
+                key,
+                expected,
+                new,
+                expiry,
+            } => {
+                let swapped = self.compare_and_set(key.clone(), expected, new, expiry);
+                if swapped {
+                    self.track(&key);
+                    ctx.notify_later(Purge(key), expiry);
+                }
+                ActorResponse::CompareAndSet(Box::pin(future::ready(Ok(swapped))))
+            }
+            ActorMessage::Ping => ActorResponse::Ping(Box::pin(future::ready(Ok(())))),
+            ActorMessage::Time => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                ActorResponse::Time(Box::pin(future::ready(Ok(now))))
+            }
+            ActorMessage::SetMetadata {
+                key,
+                metadata,
+                expiry,
+            } => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                match metadata {
+                    Some(metadata) => {
+                        self.metadata.insert(key.clone(), (metadata, now + expiry));
+                        // Expired metadata is ignored when read, and dropped once expired
+                        ctx.notify_later(PurgeMetadata(key), expiry);
+                    }
+                    None => {
+                        self.metadata.remove(&key);
+                    }
+                }
+                ActorResponse::SetMetadata(Box::pin(future::ready(Ok(()))))
+            }
+            ActorMessage::MultiGet(keys) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let counts = keys
+                    .iter()
+                    .map(|key| {
+                        self.inner
+                            .get(key)
+                            .filter(|entry| entry.value().1 > now)
+                            .map(|entry| entry.value().0)
+                    })
+                    .collect();
+                ActorResponse::MultiGet(Box::pin(future::ready(Ok(counts))))
+            }
+            ActorMessage::IncrementIfExists { key, by, cap } => {
+                let count = self.increment_if_exists(&key, by, cap);
+                ActorResponse::IncrementIfExists(Box::pin(future::ready(Ok(count))))
+            }
+            ActorMessage::AddToSet {
+                key,
+                member,
+                expiry,
+                cap,
+            } => {
+                let (count, created) = self.add_to_set(key.clone(), member, expiry, cap);
+                self.track(&key);
+                if created {
+                    ctx.notify_later(PurgeSet(key), expiry);
+                }
+                ActorResponse::AddToSet(Box::pin(future::ready(Ok(count))))
+            }
+            ActorMessage::GetMetadata(key) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let metadata = self
+                    .metadata
+                    .get(&key)
+                    .filter(|entry| entry.1 > now)
+                    .map(|entry| entry.0.clone());
+                ActorResponse::GetMetadata(Box::pin(future::ready(Ok(metadata))))
+            }
+            ActorMessage::MultiGetMetadata(keys) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let metadata = keys
+                    .iter()
+                    .map(|key| {
+                        self.metadata
+                            .get(key)
+                            .filter(|entry| entry.1 > now)
+                            .map(|entry| entry.0.clone())
+                    })
+                    .collect();
+                ActorResponse::MultiGetMetadata(Box::pin(future::ready(Ok(metadata))))
+            }
+            ActorMessage::List { prefix, limit } => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                let keys = self
+                    .inner
+                    .iter()
+                    .filter(|entry| entry.key().starts_with(&prefix) && entry.value().1 > now)
+                    .take(limit)
+                    .map(|entry| {
+                        let (remaining, expiry) = *entry.value();
+                        (entry.key().clone(), remaining, expiry - now)
+                    })
+                    .collect();
+                ActorResponse::List(Box::pin(future::ready(Ok(keys))))
+            }
             ActorMessage::Remove(key) => {
                 debug!("Removing key: {}", &key);
+                if let Some(lru) = &self.lru {
+                    lru.forget(&key);
+                }
                 let val = match self.inner.remove::<String>(&key) {
                     Some(c) => c,
                     None => {
@@ -173,6 +884,68 @@ mod tests {
         }
     }
 
+    #[actix_rt::test]
+    async fn test_set_again() {
+        use crate::stores::{get, set};
+        let addr = MemoryStoreActor::from(MemoryStore::new()).start();
+        let expiry = Duration::from_millis(1500);
+        set(&addr, "hello", 30, expiry).await.unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(1000)).await;
+        set(&addr, "hello", 20, expiry).await.unwrap();
+        // Past the first expiry, the key was set again since
+        actix_rt::time::delay_for(Duration::from_millis(1000)).await;
+        assert_eq!(get(&addr, "hello").await.unwrap(), Some(20));
+    }
+
+    #[actix_rt::test]
+    async fn test_mailbox_capacity() {
+        let burst = |addr: Addr<MemoryStoreActor>| {
+            (0..32)
+                .map(|i| addr.try_send(ActorMessage::Get(i.to_string())))
+                .try_for_each(|sent| sent.map_err(ARError::from))
+        };
+        let addr = MemoryStoreActor::from(MemoryStore::new()).start();
+        addr.send(ActorMessage::Ping).await.unwrap();
+        assert!(matches!(burst(addr), Err(ARError::MailboxFull)));
+        let store = MemoryStore::new().with_mailbox_capacity(64);
+        let addr = MemoryStoreActor::from(store).start();
+        addr.send(ActorMessage::Ping).await.unwrap();
+        assert!(burst(addr).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_max_keys() {
+        use crate::stores::{add_to_set, get, set, update};
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let events = evicted.clone();
+        let store = MemoryStore::new()
+            .with_max_keys(2)
+            .with_observer(move |event| {
+                if let RateLimitEvent::Evicted { key } = event {
+                    events.lock().unwrap().push(key.to_string());
+                }
+            });
+        let addr = MemoryStoreActor::from(store).start();
+        let expiry = Duration::from_secs(5);
+        set(&addr, "a", 10, expiry).await.unwrap();
+        set(&addr, "b", 10, expiry).await.unwrap();
+        // Written after b, which is now the least recently written
+        update(&addr, "a", 1).await.unwrap();
+        set(&addr, "c", 10, expiry).await.unwrap();
+        assert_eq!(get(&addr, "a").await.unwrap(), Some(9));
+        assert_eq!(get(&addr, "b").await.unwrap(), None);
+        assert_eq!(get(&addr, "c").await.unwrap(), Some(10));
+        assert_eq!(*evicted.lock().unwrap(), vec!["b".to_string()]);
+        // Sets count towards the cap, and are evicted like counts
+        assert_eq!(add_to_set(&addr, "s", "1", expiry, 10).await.unwrap(), 1);
+        assert_eq!(add_to_set(&addr, "s", "2", expiry, 10).await.unwrap(), 2);
+        assert_eq!(get(&addr, "a").await.unwrap(), None);
+        set(&addr, "d", 10, expiry).await.unwrap();
+        set(&addr, "e", 10, expiry).await.unwrap();
+        assert_eq!(add_to_set(&addr, "s", "3", expiry, 10).await.unwrap(), 1);
+        assert_eq!(*evicted.lock().unwrap(), vec!["b", "a", "c", "s", "d"]);
+    }
+
     #[actix_rt::test]
     async fn test_get() {
         let store = MemoryStore::new();
@@ -182,7 +955,7 @@ mod tests {
             .send(ActorMessage::Set {
                 key: "hello".to_string(),
                 value: 30usize,
-                expiry: expiry,
+                expiry,
             })
             .await;
         let res = res.expect("Failed to send msg");
@@ -216,7 +989,7 @@ mod tests {
             .send(ActorMessage::Set {
                 key: "hello".to_string(),
                 value: 30usize,
-                expiry: expiry,
+                expiry,
             })
             .await;
         let res = res.expect("Failed to send msg");
@@ -227,7 +1000,7 @@ mod tests {
             },
             _ => panic!("Shouldn't happen!"),
         }
-        assert_eq!(addr.connected(), true);
+        assert!(addr.connected());
 
         let res3 = addr.send(ActorMessage::Expire("hello".to_string())).await;
         let res3 = res3.expect("Failed to send msg");
@@ -237,8 +1010,6 @@ mod tests {
                     let now = Duration::from_secs(3);
                     if dur > now {
                         panic!("Expiry is invalid!");
-                    } else if dur > now + Duration::from_secs(4) {
-                        panic!("Expiry is invalid!");
                     }
                 }
                 Err(e) => {
@@ -248,4 +1019,279 @@ mod tests {
             _ => panic!("Shouldn't happen!"),
         };
     }
+
+    #[actix_rt::test]
+    async fn test_seed() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        crate::stores::seed(&addr, "seeded", 12, Duration::from_secs(5))
+            .await
+            .expect("Failed to seed");
+        let res = addr.send(ActorMessage::Get("seeded".to_string())).await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(12)),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_rotate() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let rotate = |new_window_start| ActorMessage::Rotate {
+            key: "rotate".to_string(),
+            new_window_start,
+            value: 10,
+            expiry: Duration::from_secs(5),
+        };
+        let previous = |res| match res {
+            ActorResponse::Rotate(c) => c,
+            _ => panic!("Shouldn't happen!"),
+        };
+        let res = addr.send(rotate(60)).await.expect("Failed to send msg");
+        assert_eq!(previous(res).await.unwrap(), None);
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "rotate".to_string(),
+                value: 3,
+            })
+            .await
+            .expect("Failed to send msg");
+        match res {
+            ActorResponse::Update(c) => assert_eq!(c.await.unwrap(), 7),
+            _ => panic!("Shouldn't happen!"),
+        }
+        // Same window, nothing moves
+        let res = addr.send(rotate(60)).await.expect("Failed to send msg");
+        assert_eq!(previous(res).await.unwrap(), None);
+        let res = addr.send(rotate(120)).await.expect("Failed to send msg");
+        assert_eq!(previous(res).await.unwrap(), Some(7));
+        let res = addr
+            .send(ActorMessage::Get("rotate".to_string()))
+            .await
+            .expect("Failed to send msg");
+        match res {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(10)),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_list() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        for key in &["user:1", "user:2", "other"] {
+            crate::stores::seed(&addr, key, 5, Duration::from_secs(5))
+                .await
+                .unwrap();
+        }
+        let mut keys = crate::stores::list(&addr, "user:", 10).await.unwrap();
+        keys.sort();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].0, "user:1");
+        assert_eq!(keys[0].1, 5);
+        assert!(keys[0].2 <= Duration::from_secs(5));
+        assert_eq!(crate::stores::list(&addr, "", 1).await.unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_multi_get() {
+        let addr = MemoryStoreActor::from(MemoryStore::new()).start();
+        crate::stores::seed(&addr, "a", 5, Duration::from_secs(5))
+            .await
+            .unwrap();
+        crate::stores::seed(&addr, "c", 0, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let counts = crate::stores::multi_get(&addr, &["a", "b", "c"])
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![Some(5), None, Some(0)]);
+        assert!(crate::stores::multi_get(&addr, &[])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_compare_and_set_concurrent_update() {
+        let store = MemoryStore::new();
+        let expiry = Duration::from_secs(60);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        store
+            .inner
+            .insert("a".to_string(), (1_000_000, now + expiry));
+        let swapper = MemoryStoreActor::from(store.clone());
+        let updater = MemoryStoreActor::from(store.clone());
+        let start = Arc::new(std::sync::Barrier::new(2));
+        let both = start.clone();
+        let swaps = std::thread::spawn(move || {
+            both.wait();
+            for _ in 0..100_000 {
+                loop {
+                    let current = swapper.inner.get("a").map(|entry| entry.0);
+                    if swapper.compare_and_set(
+                        "a".to_string(),
+                        current,
+                        current.unwrap() + 2,
+                        expiry,
+                    ) {
+                        break;
+                    }
+                }
+            }
+        });
+        let updates = std::thread::spawn(move || {
+            start.wait();
+            for _ in 0..100_000 {
+                updater.update("a", 1);
+            }
+        });
+        swaps.join().unwrap();
+        updates.join().unwrap();
+        // No update is lost in between the compare and the set
+        assert_eq!(
+            store.inner.get("a").unwrap().0,
+            1_000_000 + 200_000 - 100_000
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_increment_if_exists() {
+        let addr = MemoryStoreActor::from(MemoryStore::new()).start();
+        let increment = |key, by, cap| crate::stores::increment_if_exists(&addr, key, by, cap);
+        assert_eq!(increment("a", 1, 10).await.unwrap(), None);
+        crate::stores::seed(&addr, "a", 5, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(increment("a", 2, 10).await.unwrap(), Some(7));
+        assert_eq!(increment("a", 5, 10).await.unwrap(), Some(10));
+        // Already above a lower cap
+        assert_eq!(increment("a", 1, 8).await.unwrap(), Some(10));
+        // The expiry is kept
+        let expiry = crate::stores::expire(&addr, "a").await.unwrap();
+        assert!(expiry <= Duration::from_secs(5) && expiry > Duration::from_secs(3));
+    }
+
+    #[actix_rt::test]
+    async fn test_consume() {
+        let addr = MemoryStoreActor::from(MemoryStore::new()).start();
+        assert!(crate::stores::consume(&addr, "a", 1).await.is_err());
+        crate::stores::seed(&addr, "a", 5, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let (remaining, expiry) = crate::stores::consume(&addr, "a", 2).await.unwrap();
+        assert_eq!(remaining, 3);
+        assert!(expiry <= Duration::from_secs(5) && expiry > Duration::from_secs(3));
+        // Not below zero
+        let (remaining, _) = crate::stores::consume(&addr, "a", 5).await.unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_add_to_set() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let expiry = Duration::from_millis(200);
+        let add = |member| crate::stores::add_to_set(&addr, "ids", member, expiry, 3);
+        assert_eq!(add("1").await.unwrap(), 1);
+        assert_eq!(add("2").await.unwrap(), 2);
+        assert_eq!(add("1").await.unwrap(), 2);
+        // Not kept past the cap
+        for member in &["3", "4", "5"] {
+            assert_eq!(add(member).await.unwrap(), 3);
+        }
+        assert_eq!(store.sets.get("ids").unwrap().0.len(), 3);
+        // Starts over once expired
+        actix_rt::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(add("3").await.unwrap(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_metadata() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let expiry = Duration::from_secs(5);
+        crate::stores::seed(&addr, "user:1", 5, expiry)
+            .await
+            .unwrap();
+        crate::stores::seed(&addr, "user:3", 7, expiry)
+            .await
+            .unwrap();
+        crate::stores::set_metadata(&addr, "user:1", Some("flagged"), expiry)
+            .await
+            .unwrap();
+        let mut keys = crate::stores::list_with_metadata(&addr, "user:", 10)
+            .await
+            .unwrap();
+        keys.sort();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].1, 5);
+        assert_eq!(keys[0].3.as_deref(), Some("flagged"));
+        assert_eq!((keys[1].1, keys[1].3.as_deref()), (7, None));
+        let long = "x".repeat(crate::stores::MAX_METADATA + 1);
+        assert!(
+            crate::stores::set_metadata(&addr, "user:1", Some(&long), expiry)
+                .await
+                .is_err()
+        );
+        crate::stores::set_metadata(&addr, "user:1", None, expiry)
+            .await
+            .unwrap();
+        let metadata = crate::stores::metadata(&addr, "user:1").await.unwrap();
+        assert_eq!(metadata, None);
+        crate::stores::set_metadata(&addr, "user:2", Some("short"), Duration::from_secs(0))
+            .await
+            .unwrap();
+        let metadata = crate::stores::metadata(&addr, "user:2").await.unwrap();
+        assert_eq!(metadata, None);
+        // And dropped once expired, rather than on every write
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        assert!(store.metadata.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_ping() {
+        let addr = MemoryStoreActor::from(MemoryStore::new()).start();
+        crate::stores::ping(&addr).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_compare_and_set() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let expiry = Duration::from_secs(5);
+        let cas =
+            |expected, new| crate::stores::compare_and_set(&addr, "cas", expected, new, expiry);
+        assert!(cas(None, 10).await.unwrap());
+        assert!(!cas(None, 10).await.unwrap());
+        assert!(!cas(Some(9), 8).await.unwrap());
+        assert!(cas(Some(10), 9).await.unwrap());
+        assert_eq!(store.inner.get("cas").unwrap().0, 9);
+    }
+
+    #[cfg(feature = "persistence")]
+    #[actix_rt::test]
+    async fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("ratelimit-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = MemoryStore::new().with_persistence(&path, Duration::from_secs(1));
+        let addr = MemoryStoreActor::from(store).start();
+        crate::stores::seed(&addr, "saved", 7, Duration::from_secs(60))
+            .await
+            .unwrap();
+        crate::stores::seed(&addr, "expired", 7, Duration::from_millis(1))
+            .await
+            .unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(1500)).await;
+
+        let store = MemoryStore::new().with_persistence(&path, Duration::from_secs(1));
+        assert_eq!(store.inner.get("saved").unwrap().0, 7);
+        assert!(store.inner.get("expired").is_none());
+
+        std::fs::write(&path, b"ARL1garbage").unwrap();
+        let store = MemoryStore::new().with_persistence(&path, Duration::from_secs(1));
+        assert!(store.inner.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
 }
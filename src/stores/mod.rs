@@ -16,9 +16,9 @@
 //! # Implementing a custom store:
 //! ```rust
 //! use std::collections::HashMap;
-//! use std::time::Duration;
+//! use std::time::{Duration, SystemTime, UNIX_EPOCH};
 //! use actix::prelude::*;
-//! use actix_ratelimit::{ActorMessage, ActorResponse};
+//! use actix_ratelimit::{errors::ARError, ActorMessage, ActorResponse};
 //! use futures::future::{ok, err};
 //!
 //! struct MyStore(HashMap<String, usize>);
@@ -55,6 +55,12 @@
 //!                 let new_val = *val;
 //!                 ActorResponse::Update(Box::pin(ok(new_val)))
 //!             },
+//!             // Handle Consume message, an Update which also answers with the expiry
+//!             ActorMessage::Consume {key, value} => {
+//!                 let val = self.inner.get_mut(&key).unwrap();
+//!                 *val = val.saturating_sub(value);
+//!                 ActorResponse::Consume(Box::pin(ok((*val, Duration::from_secs(10)))))
+//!             },
 //!             // Handle get message
 //!             ActorMessage::Get(key) => {
 //!                 let val = *self.inner.get(&key).unwrap();
@@ -70,6 +76,63 @@
 //!                 let val = self.inner.remove(&key).unwrap();
 //!                 ActorResponse::Remove(Box::pin(ok(val)))
 //!             },
+//!             // Handle Rotate message, only used by the sliding window counter
+//!             ActorMessage::Rotate {key, value, ..} => {
+//!                 let previous = self.inner.insert(key, value);
+//!                 ActorResponse::Rotate(Box::pin(ok(previous)))
+//!             },
+//!             // Handle List message
+//!             ActorMessage::List {prefix, limit} => {
+//!                 let keys = self.inner.iter()
+//!                     .filter(|(key, _)| key.starts_with(&prefix))
+//!                     .take(limit)
+//!                     .map(|(key, value)| (key.clone(), *value, Duration::from_secs(10)))
+//!                     .collect();
+//!                 ActorResponse::List(Box::pin(ok(keys)))
+//!             },
+//!             // Handle CompareAndSet message
+//!             ActorMessage::CompareAndSet {key, expected, new, ..} => {
+//!                 let swapped = self.inner.get(&key).copied() == expected;
+//!                 if swapped {
+//!                     self.inner.insert(key, new);
+//!                 }
+//!                 ActorResponse::CompareAndSet(Box::pin(ok(swapped)))
+//!             },
+//!             // Handle Ping message
+//!             ActorMessage::Ping => ActorResponse::Ping(Box::pin(ok(()))),
+//!             // Handle Time message, with the local clock
+//!             ActorMessage::Time => {
+//!                 let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+//!                 ActorResponse::Time(Box::pin(ok(now)))
+//!             },
+//!             // Metadata is optional, stores which do not keep it answer with an error
+//!             ActorMessage::SetMetadata {..} => {
+//!                 ActorResponse::SetMetadata(Box::pin(err(ARError::Other("unsupported".into()))))
+//!             },
+//!             ActorMessage::GetMetadata(_) => {
+//!                 ActorResponse::GetMetadata(Box::pin(err(ARError::Other("unsupported".into()))))
+//!             },
+//!             ActorMessage::MultiGetMetadata(_) => {
+//!                 let unsupported = ARError::Other("unsupported".into());
+//!                 ActorResponse::MultiGetMetadata(Box::pin(err(unsupported)))
+//!             },
+//!             // Handle MultiGet message
+//!             ActorMessage::MultiGet(keys) => {
+//!                 let counts = keys.iter().map(|key| self.inner.get(key).copied()).collect();
+//!                 ActorResponse::MultiGet(Box::pin(ok(counts)))
+//!             },
+//!             // Handle IncrementIfExists message
+//!             ActorMessage::IncrementIfExists {key, by, cap} => {
+//!                 let count = self.inner.get_mut(&key).map(|count| {
+//!                     *count = (*count + by).min(cap.max(*count));
+//!                     *count
+//!                 });
+//!                 ActorResponse::IncrementIfExists(Box::pin(ok(count)))
+//!             },
+//!             // Sets are optional as well
+//!             ActorMessage::AddToSet {..} => {
+//!                 ActorResponse::AddToSet(Box::pin(err(ARError::Other("unsupported".into()))))
+//!             },
 //!
 //!             }
 //!         }
@@ -78,6 +141,23 @@
 //! # Note
 //!
 //! The above example is not thread-safe and does not implement key expiration! It's just for demonstration purposes.
+//!
+//! Backends which are async already, such as a database pool, can rather implement
+//! [RateLimitStore](trait.RateLimitStore.html) and be run by a
+//! [StoreActor](struct.StoreActor.html), which handles the messages.
+
+use actix::dev::*;
+use std::time::Duration;
+
+use crate::errors::ARError;
+use crate::{telemetry, ActorMessage, ActorResponse};
+
+pub mod adapter;
+pub use adapter::{RateLimitStore, StoreActor};
+pub mod any;
+pub use any::AnyStore;
+/// Attribute to implement [RateLimitStore](trait.RateLimitStore.html) with async methods
+pub use async_trait::async_trait;
 
 #[cfg(feature = "memory")]
 pub mod memory;
@@ -87,3 +167,602 @@ pub mod redis;
 
 #[cfg(feature = "memcached")]
 pub mod memcached;
+
+/// Sets the remaining count of the client identified by `key` to `value`, valid for `expiry`.
+///
+/// This is useful to carry over the usage of clients migrating from another system, or to put
+/// the store in a known state in tests. The key expires like any key created by the middleware,
+/// after which the client gets a full window again.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     // The client already used 40 of its 100 requests in the current window
+///     stores::seed(&store, "203.0.113.7", 60, Duration::from_secs(30))
+///         .await
+///         .expect("failed to seed the store");
+/// }
+/// ```
+pub async fn seed<T>(
+    store: &Addr<T>,
+    key: &str,
+    value: usize,
+    expiry: Duration,
+) -> Result<(), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    set(store, key, value, expiry).await
+}
+
+/// Raises the limit of the client identified by `key` by `extra` requests for `duration`, for
+/// instance to give a customer headroom for a few hours after an incident. Only limiters
+/// [reading boosts](../middleware/struct.RateLimiter.html#method.with_boosts) apply it.
+///
+/// The boost is kept in the store under `{key}:boost`, and ends when that key expires. Granting
+/// a boost again replaces the previous one, with its duration.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     // 500 more requests per window for the next 6 hours
+///     stores::grant_boost(&store, "203.0.113.7", 500, Duration::from_secs(6 * 3600))
+///         .await
+///         .expect("failed to grant the boost");
+/// }
+/// ```
+pub async fn grant_boost<T>(
+    store: &Addr<T>,
+    key: &str,
+    extra: usize,
+    duration: Duration,
+) -> Result<(), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    set(store, &derived_key(key, ":boost"), extra, duration).await
+}
+
+/// Suffixes of the keys derived from the key of a client, by the middleware and the stores.
+/// Redis clusters keep them in the slot of the client key.
+pub(crate) const DERIVED_SUFFIXES: &[&str] = &[
+    ":window",
+    ":previous",
+    ":ewma",
+    ":metadata",
+    ":over",
+    ":errors",
+    ":blocked",
+    ":cooldown",
+    ":distinct",
+    ":boost",
+];
+
+/// Key derived from the key of a client, `suffix` being one of the
+/// [DERIVED_SUFFIXES](constant.DERIVED_SUFFIXES.html)
+pub(crate) fn derived_key(key: &str, suffix: &str) -> String {
+    debug_assert!(DERIVED_SUFFIXES.contains(&suffix));
+    format!("{}{}", key, suffix)
+}
+
+/// Extracts the error out of a response of the wrong kind. Stores which lost their connection
+/// answer every message with a failed `ActorResponse::Set`.
+pub(crate) async fn unexpected(response: ActorResponse) -> ARError {
+    match response {
+        ActorResponse::Set(fut) => match fut.await {
+            Err(e) => e,
+            Ok(_) => ARError::Protocol("response does not match the message".to_string()),
+        },
+        _ => ARError::Protocol("response does not match the message".to_string()),
+    }
+}
+
+/// Most entries [list](fn.list.html) returns, whatever the requested limit
+pub const MAX_LIST: usize = 1000;
+
+/// Lists up to `limit` (capped to [MAX_LIST](constant.MAX_LIST.html)) clients of a store whose
+/// key starts with `prefix`, as `(key, remaining, reset)`, for instance to show the top
+/// consumers on an admin dashboard. Keys derived from a client key by the store, such as
+/// `{key}:previous`, are listed as well.
+///
+/// This walks the keys of the store, and can be expensive on large stores. The memory and the
+/// redis (not in cluster mode) stores support it, others answer with an error.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     let mut clients = stores::list(&store, "", 100).await.unwrap();
+///     // Top consumers first
+///     clients.sort_by_key(|(_, remaining, _)| *remaining);
+/// }
+/// ```
+pub async fn list<T>(
+    store: &Addr<T>,
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<(String, usize, Duration)>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::List {
+        prefix: String::from(prefix),
+        limit: limit.min(MAX_LIST),
+    };
+    telemetry::store_call("list", async move {
+        match store.send(msg).await? {
+            ActorResponse::List(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Like [list](fn.list.html), over all the shards of a limiter, see
+/// [RateLimiter::with_shards](../middleware/struct.RateLimiter.html#method.with_shards). The
+/// shards are asked one after the other, and the clients returned in the order of the shards,
+/// up to `limit` (capped to [MAX_LIST](constant.MAX_LIST.html)) in total. It fails if any
+/// shard fails, rather than list the clients of some of them.
+pub async fn list_shards<T>(
+    shards: &[Addr<T>],
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<(String, usize, Duration)>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let limit = limit.min(MAX_LIST);
+    let mut clients = Vec::new();
+    for shard in shards {
+        if clients.len() >= limit {
+            break;
+        }
+        clients.extend(list(shard, prefix, limit - clients.len()).await?);
+    }
+    Ok(clients)
+}
+
+/// Longest metadata, in bytes, [set_metadata](fn.set_metadata.html) accepts
+pub const MAX_METADATA: usize = 256;
+
+/// Attaches `metadata` to the client identified by `key` for `expiry`, or removes it when
+/// `None`, for instance to tag a client as flagged for review from an abuse management tool.
+/// Metadata longer than [MAX_METADATA](constant.MAX_METADATA.html) bytes is rejected.
+///
+/// Metadata is opaque to the limiter and never changes how the client is limited. It expires
+/// on its own, independently of the count of the client. The memory, redis and memcached stores
+/// support it, custom stores may not.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     let week = Duration::from_secs(7 * 86400);
+///     stores::set_metadata(&store, "203.0.113.7", Some("flagged for review"), week)
+///         .await
+///         .expect("failed to set the metadata");
+///     let metadata = stores::metadata(&store, "203.0.113.7").await.unwrap();
+///     assert_eq!(metadata.as_deref(), Some("flagged for review"));
+/// }
+/// ```
+pub async fn set_metadata<T>(
+    store: &Addr<T>,
+    key: &str,
+    metadata: Option<&str>,
+    expiry: Duration,
+) -> Result<(), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    if let Some(metadata) = metadata {
+        if metadata.len() > MAX_METADATA {
+            return Err(ARError::Other(format!(
+                "metadata is longer than {} bytes",
+                MAX_METADATA
+            )));
+        }
+    }
+    let msg = ActorMessage::SetMetadata {
+        key: String::from(key),
+        metadata: metadata.map(String::from),
+        expiry,
+    };
+    telemetry::store_call("set_metadata", async move {
+        match store.send(msg).await? {
+            ActorResponse::SetMetadata(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Metadata attached to the client identified by `key` with
+/// [set_metadata](fn.set_metadata.html), if any
+pub async fn metadata<T>(store: &Addr<T>, key: &str) -> Result<Option<String>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    telemetry::store_call("metadata", async move {
+        match store
+            .send(ActorMessage::GetMetadata(String::from(key)))
+            .await?
+        {
+            ActorResponse::GetMetadata(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Like [list](fn.list.html), with the metadata of each client, as
+/// `(key, remaining, reset, metadata)`. The metadata of all the listed clients is read at once
+/// after listing them, see
+/// [ActorMessage::MultiGetMetadata](../enum.ActorMessage.html#variant.MultiGetMetadata).
+pub async fn list_with_metadata<T>(
+    store: &Addr<T>,
+    prefix: &str,
+    limit: usize,
+) -> Result<Vec<(String, usize, Duration, Option<String>)>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let clients = list(store, prefix, limit).await?;
+    let keys = clients.iter().map(|(key, _, _)| key.clone()).collect();
+    let metadata = telemetry::store_call("multi_get_metadata", async move {
+        match store.send(ActorMessage::MultiGetMetadata(keys)).await? {
+            ActorResponse::MultiGetMetadata(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await?;
+    Ok(clients
+        .into_iter()
+        .zip(metadata)
+        .map(|((key, remaining, reset), metadata)| (key, remaining, reset, metadata))
+        .collect())
+}
+
+/// Remaining counts of the clients identified by `keys`, in the same order, read in one round
+/// trip where the store can, see
+/// [ActorMessage::MultiGet](../enum.ActorMessage.html#variant.MultiGet). A client without a
+/// count has its whole limit left, and the call fails as a whole when any key can not be read.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     stores::seed(&store, "tenant:b", 0, Duration::from_secs(60)).await.unwrap();
+///     // Reject the whole batch when any of its tenants is out of requests
+///     let counts = stores::multi_get(&store, &["tenant:a", "tenant:b"]).await.unwrap();
+///     assert_eq!(counts, vec![None, Some(0)]);
+///     let over = counts.iter().any(|count| *count == Some(0));
+///     assert!(over);
+/// }
+/// ```
+pub async fn multi_get<T>(store: &Addr<T>, keys: &[&str]) -> Result<Vec<Option<usize>>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let keys = keys.iter().map(|key| String::from(*key)).collect();
+    telemetry::store_call("multi_get", async move {
+        match store.send(ActorMessage::MultiGet(keys)).await? {
+            ActorResponse::MultiGet(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Sets the count of the client identified by `key` to `new`, valid for `expiry`, if it is
+/// currently `expected` (`None` for a missing key), and returns whether it did, see
+/// [ActorMessage::CompareAndSet](../enum.ActorMessage.html#variant.CompareAndSet).
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     let expiry = Duration::from_secs(60);
+///     // Only one of the requests racing to create the key succeeds
+///     let created = stores::compare_and_set(&store, "client", None, 99, expiry).await.unwrap();
+///     assert!(created);
+///     let created = stores::compare_and_set(&store, "client", None, 99, expiry).await.unwrap();
+///     assert!(!created);
+///     let updated = stores::compare_and_set(&store, "client", Some(99), 98, expiry).await;
+///     assert!(updated.unwrap());
+/// }
+/// ```
+pub async fn compare_and_set<T>(
+    store: &Addr<T>,
+    key: &str,
+    expected: Option<usize>,
+    new: usize,
+    expiry: Duration,
+) -> Result<bool, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::CompareAndSet {
+        key: String::from(key),
+        expected,
+        new,
+        expiry,
+    };
+    telemetry::store_call("compare_and_set", async move {
+        match store.send(msg).await? {
+            ActorResponse::CompareAndSet(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Adds `by` to the count of the client identified by `key`, up to `cap`, if its window has not
+/// ended, and returns the new count, see
+/// [ActorMessage::IncrementIfExists](../enum.ActorMessage.html#variant.IncrementIfExists).
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     assert_eq!(stores::increment_if_exists(&store, "client", 1, 100).await.unwrap(), None);
+///     stores::seed(&store, "client", 99, Duration::from_secs(60)).await.unwrap();
+///     // Refunding two requests gives one back, the limit is 100
+///     let count = stores::increment_if_exists(&store, "client", 2, 100).await.unwrap();
+///     assert_eq!(count, Some(100));
+/// }
+/// ```
+pub async fn increment_if_exists<T>(
+    store: &Addr<T>,
+    key: &str,
+    by: usize,
+    cap: usize,
+) -> Result<Option<usize>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::IncrementIfExists {
+        key: String::from(key),
+        by,
+        cap,
+    };
+    telemetry::store_call("increment_if_exists", async move {
+        match store.send(msg).await? {
+            ActorResponse::IncrementIfExists(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Adds `member` to the set of the client identified by `key`, created with an expiry of
+/// `expiry`, and returns how many distinct members it holds, up to `cap`, see
+/// [ActorMessage::AddToSet](../enum.ActorMessage.html#variant.AddToSet). The memory and the
+/// redis stores support it, others answer with an error.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{stores, MemoryStore, MemoryStoreActor};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStoreActor::from(MemoryStore::new()).start();
+///     let expiry = Duration::from_secs(60);
+///     stores::add_to_set(&store, "client:ids", "1", expiry, 10).await.unwrap();
+///     stores::add_to_set(&store, "client:ids", "2", expiry, 10).await.unwrap();
+///     let count = stores::add_to_set(&store, "client:ids", "1", expiry, 10).await.unwrap();
+///     assert_eq!(count, 2);
+/// }
+/// ```
+pub async fn add_to_set<T>(
+    store: &Addr<T>,
+    key: &str,
+    member: &str,
+    expiry: Duration,
+    cap: usize,
+) -> Result<usize, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::AddToSet {
+        key: String::from(key),
+        member: String::from(member),
+        expiry,
+        cap,
+    };
+    telemetry::store_call("add_to_set", async move {
+        match store.send(msg).await? {
+            ActorResponse::AddToSet(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+/// Checks that the store is reachable, see
+/// [RateLimiter::validate](../middleware/struct.RateLimiter.html#method.validate).
+pub async fn ping<T>(store: &Addr<T>) -> Result<(), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    telemetry::store_call("ping", async move {
+        match store.send(ActorMessage::Ping).await? {
+            ActorResponse::Ping(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn time<T>(store: &Addr<T>) -> Result<Duration, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    telemetry::store_call("time", async move {
+        match store.send(ActorMessage::Time).await? {
+            ActorResponse::Time(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn get<T>(store: &Addr<T>, key: &str) -> Result<Option<usize>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    telemetry::store_call("get", async move {
+        match store.send(ActorMessage::Get(String::from(key))).await? {
+            ActorResponse::Get(opt) => opt.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn set<T>(
+    store: &Addr<T>,
+    key: &str,
+    value: usize,
+    expiry: Duration,
+) -> Result<(), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::Set {
+        key: String::from(key),
+        value,
+        expiry,
+    };
+    telemetry::store_call("set", async move {
+        match store.send(msg).await? {
+            ActorResponse::Set(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn update<T>(store: &Addr<T>, key: &str, value: usize) -> Result<usize, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::Update {
+        key: String::from(key),
+        value,
+    };
+    telemetry::store_call("update", async move {
+        match store.send(msg).await? {
+            ActorResponse::Update(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn consume<T>(
+    store: &Addr<T>,
+    key: &str,
+    value: usize,
+) -> Result<(usize, Duration), ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::Consume {
+        key: String::from(key),
+        value,
+    };
+    telemetry::store_call("consume", async move {
+        match store.send(msg).await? {
+            ActorResponse::Consume(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn expire<T>(store: &Addr<T>, key: &str) -> Result<Duration, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    telemetry::store_call("expire", async move {
+        match store.send(ActorMessage::Expire(String::from(key))).await? {
+            ActorResponse::Expire(dur) => dur.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
+
+pub(crate) async fn rotate<T>(
+    store: &Addr<T>,
+    key: &str,
+    new_window_start: u64,
+    value: usize,
+    expiry: Duration,
+) -> Result<Option<usize>, ARError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    let msg = ActorMessage::Rotate {
+        key: String::from(key),
+        new_window_start,
+        value,
+        expiry,
+    };
+    telemetry::store_call("rotate", async move {
+        match store.send(msg).await? {
+            ActorResponse::Rotate(c) => c.await,
+            other => Err(unexpected(other).await),
+        }
+    })
+    .await
+}
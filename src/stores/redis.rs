@@ -1,24 +1,225 @@
 //! Redis store for rate limiting
+//!
+//! Besides a single redis instance, the store can be pointed at a [Redis
+//! Cluster](https://redis.io/topics/cluster-spec) or at a master monitored by [Redis
+//! Sentinel](https://redis.io/topics/sentinel), see [RedisStore](struct.RedisStore.html).
 use actix::prelude::*;
+use actix_web::error::BlockingError;
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
+use futures::future::LocalBoxFuture;
 use log::*;
-use redis_rs::{self as redis, aio::MultiplexedConnection};
+use redis_rs::{
+    self as redis,
+    aio::MultiplexedConnection,
+    cluster::{ClusterClient, ClusterConnection},
+    FromRedisValue, RedisResult,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::errors::ARError;
+use crate::stores::{derived_key, DERIVED_SUFFIXES};
 use crate::{ActorMessage, ActorResponse};
 
 struct GetAddr;
 impl Message for GetAddr {
-    type Result = Result<MultiplexedConnection, ARError>;
+    // Connections to the primary and to the replica, if any
+    type Result = Result<(Connection, Option<Connection>), ARError>;
+}
+
+/// Describes how the redis deployment is reached
+#[derive(Clone)]
+enum Topology {
+    Single(String),
+    Cluster(Vec<String>),
+    Sentinel {
+        master: String,
+        sentinels: Vec<String>,
+    },
+}
+
+impl Topology {
+    /// Opens `pool_size` connections according to the topology. The returned futures drive the
+    /// multiplexed connections and have to be spawned by the caller.
+    async fn connect(
+        self,
+        pool_size: usize,
+    ) -> RedisResult<(Connection, Vec<LocalBoxFuture<'static, ()>>)> {
+        let mut connections = Vec::with_capacity(pool_size);
+        let mut futures = Vec::new();
+        for _ in 0..pool_size.max(1) {
+            let (con, fut) = self.clone().connect_one().await?;
+            connections.push(con);
+            futures.extend(fut);
+        }
+        let con = if connections.len() == 1 {
+            connections.remove(0)
+        } else {
+            Connection::Pool(Arc::new(connections), Arc::new(AtomicUsize::new(0)))
+        };
+        Ok((con, futures))
+    }
+
+    async fn connect_one(self) -> RedisResult<(Connection, Option<LocalBoxFuture<'static, ()>>)> {
+        match self {
+            Topology::Single(addr) => {
+                let client = redis::Client::open(addr.as_ref())?;
+                let (con, fut) = client.get_multiplexed_async_connection().await?;
+                Ok((Connection::Single(con), Some(Box::pin(fut))))
+            }
+            Topology::Cluster(nodes) => {
+                let con = blocking(move || ClusterClient::open(nodes)?.get_connection()).await?;
+                Ok((Connection::Cluster(Arc::new(Mutex::new(con))), None))
+            }
+            Topology::Sentinel { master, sentinels } => {
+                let addr = Self::resolve_master(&master, &sentinels).await?;
+                info!("Sentinel resolved master {} to {}", &master, &addr);
+                Box::pin(Topology::Single(addr).connect_one()).await
+            }
+        }
+    }
+
+    /// Asks the sentinels, in order, for the address of the current master
+    async fn resolve_master(master: &str, sentinels: &[String]) -> RedisResult<String> {
+        let mut last_error = None;
+        for sentinel in sentinels {
+            let client = redis::Client::open(sentinel.as_ref())?;
+            let result = match client.get_async_connection().await {
+                Ok(mut con) => {
+                    redis::cmd("SENTINEL")
+                        .arg("get-master-addr-by-name")
+                        .arg(master)
+                        .query_async::<_, Option<(String, u16)>>(&mut con)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(Some((host, port))) => return Ok(format!("redis://{}:{}/", host, port)),
+                Ok(None) => warn!("Sentinel {} does not know master {}", sentinel, master),
+                Err(e) => {
+                    warn!("Error querying sentinel {}: {}", sentinel, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            redis::RedisError::from((
+                redis::ErrorKind::ResponseError,
+                "no sentinel knows the requested master",
+            ))
+        }))
+    }
+}
+
+// Runs a synchronous redis operation on the blocking thread pool
+async fn blocking<T, F>(f: F) -> RedisResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> RedisResult<T> + Send + 'static,
+{
+    match actix_web::web::block(f).await {
+        Ok(result) => Ok(result),
+        Err(BlockingError::Error(e)) => Err(e),
+        Err(BlockingError::Canceled) => Err(redis::RedisError::from((
+            redis::ErrorKind::ClientError,
+            "cluster operation canceled",
+        ))),
+    }
+}
+
+/// Connection shared by the store actors
+#[derive(Clone)]
+enum Connection {
+    Single(MultiplexedConnection),
+    // Cluster connections are synchronous, their operations run on the blocking thread pool of
+    // actix-web so that a slow node does not stall the arbiter.
+    Cluster(Arc<Mutex<ClusterConnection>>),
+    // Connections of the same kind, which operations take in turn
+    Pool(Arc<Vec<Connection>>, Arc<AtomicUsize>),
+}
+
+impl Connection {
+    async fn query<T: FromRedisValue + Send + 'static>(
+        &mut self,
+        cmd: redis::Cmd,
+    ) -> RedisResult<T> {
+        match self.checkout() {
+            Connection::Single(mut con) => cmd.query_async(&mut con).await,
+            Connection::Cluster(con) => blocking(move || Self::query_cluster(&con, cmd)).await,
+            Connection::Pool(..) => unreachable!("pools are not nested"),
+        }
+    }
+
+    /// Picks the connection of the pool for the next operation
+    fn checkout(&self) -> Connection {
+        match self {
+            Connection::Pool(connections, next) => {
+                let i = next.fetch_add(1, Ordering::Relaxed) % connections.len();
+                connections[i].clone()
+            }
+            con => con.clone(),
+        }
+    }
+
+    fn is_cluster(&self) -> bool {
+        match self {
+            Connection::Cluster(_) => true,
+            Connection::Pool(connections, _) => connections[0].is_cluster(),
+            Connection::Single(_) => false,
+        }
+    }
+
+    fn query_cluster<T: FromRedisValue>(
+        con: &Mutex<ClusterConnection>,
+        cmd: redis::Cmd,
+    ) -> RedisResult<T> {
+        match con.lock() {
+            Ok(mut con) => cmd.query(&mut *con),
+            Err(_) => Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "cluster connection poisoned",
+            ))),
+        }
+    }
+
+    /// Keys are hash tagged in cluster mode, see `hash_tag`
+    fn key(&self, key: String) -> String {
+        if self.is_cluster() {
+            hash_tag(&key)
+        } else {
+            key
+        }
+    }
+}
+
+/// Wraps the client part of `key` in a hash tag, leaving out the suffixes of the keys derived
+/// from it, so that `{key}` and every `{key}:suffix` hash to the same slot. Keys which already
+/// carry a hash tag are left as they are.
+fn hash_tag(key: &str) -> String {
+    if key.starts_with('{') {
+        return key.to_string();
+    }
+    let mut client = key;
+    while let Some(stripped) = DERIVED_SUFFIXES
+        .iter()
+        .find_map(|suffix| client.strip_suffix(suffix))
+    {
+        client = stripped;
+    }
+    format!("{{{}}}{}", client, &key[client.len()..])
 }
 
 /// Type used to connect to a running redis instance
 pub struct RedisStore {
-    addr: String,
+    topology: Topology,
+    replica: Option<Topology>,
+    pool_size: usize,
     backoff: ExponentialBackoff,
-    client: Option<MultiplexedConnection>,
+    client: Option<Connection>,
+    replica_client: Option<Connection>,
 }
 
 impl RedisStore {
@@ -35,13 +236,163 @@ impl RedisStore {
     /// }
     /// ```
     pub fn connect<S: Into<String>>(addr: S) -> Addr<Self> {
-        let addr = addr.into();
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = None;
-        Supervisor::start(|_| RedisStore {
-            addr,
+        Self::start(Topology::Single(addr.into()), 1)
+    }
+
+    /// Like [connect](#method.connect), but opens `pool_size` connections which the store
+    /// operations take in turn.
+    ///
+    /// Operations of concurrent requests are already pipelined on the multiplexed connection
+    /// opened by [connect](#method.connect), but through a single socket, and a single reader
+    /// on the redis side. Under heavy load, several connections let them proceed in parallel.
+    /// A handful is usually enough, more only add connections to redis.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::RedisStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = RedisStore::connect_with_pool_size("redis://127.0.0.1", 4);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_pool_size<S: Into<String>>(addr: S, pool_size: usize) -> Addr<Self> {
+        Self::start(Topology::Single(addr.into()), pool_size)
+    }
+
+    /// Connects to a redis cluster using the provided seed nodes. The remaining nodes and the
+    /// slot layout are discovered from the seeds.
+    ///
+    /// Keys are hash-tagged (`{key}`) so that all the data of a client lives in a single slot.
+    /// Note that commands which scan the keyspace (eg. clearing keys by pattern) only see the
+    /// keys of the node they are sent to, and must be repeated for every master of the cluster.
+    ///
+    /// The redis client only has synchronous cluster connections: connecting and every
+    /// operation run on the blocking thread pool of actix-web, so that a slow node does not
+    /// stall the other requests of the worker.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::RedisStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = RedisStore::connect_cluster(vec![
+    ///         "redis://127.0.0.1:7000",
+    ///         "redis://127.0.0.1:7001",
+    ///     ]);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_cluster<S: Into<String>>(nodes: Vec<S>) -> Addr<Self> {
+        Self::connect_cluster_with_pool_size(nodes, 1)
+    }
+
+    /// Like [connect_cluster](#method.connect_cluster), but opens `pool_size` connections to
+    /// the cluster. Cluster connections are synchronous and take one operation at a time, which
+    /// waits on the blocking thread pool, so the pool sets how many operations of the store
+    /// reach the cluster at once: with one connection, they all wait in turn.
+    pub fn connect_cluster_with_pool_size<S: Into<String>>(
+        nodes: Vec<S>,
+        pool_size: usize,
+    ) -> Addr<Self> {
+        Self::start(
+            Topology::Cluster(nodes.into_iter().map(Into::into).collect()),
+            pool_size,
+        )
+    }
+
+    /// Connects to the master named `master`, whose address is resolved by querying the
+    /// provided sentinels in order. The address is resolved again whenever the store restarts,
+    /// so a failover is picked up on reconnection.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::RedisStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = RedisStore::connect_sentinel("mymaster", vec!["redis://127.0.0.1:26379"]);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_sentinel<M: Into<String>, S: Into<String>>(
+        master: M,
+        sentinels: Vec<S>,
+    ) -> Addr<Self> {
+        Self::connect_sentinel_with_pool_size(master, sentinels, 1)
+    }
+
+    /// Like [connect_sentinel](#method.connect_sentinel), but opens `pool_size` connections
+    /// to the master, see [connect_with_pool_size](#method.connect_with_pool_size).
+    pub fn connect_sentinel_with_pool_size<M: Into<String>, S: Into<String>>(
+        master: M,
+        sentinels: Vec<S>,
+        pool_size: usize,
+    ) -> Addr<Self> {
+        Self::start(
+            Topology::Sentinel {
+                master: master.into(),
+                sentinels: sentinels.into_iter().map(Into::into).collect(),
+            },
+            pool_size,
+        )
+    }
+
+    /// Like [connect_with_pool_size](#method.connect_with_pool_size), but also connects to the
+    /// replica at `replica`, which serves the reads that change nothing and that the middleware
+    /// makes off its counting path: [peeks](../../middleware/struct.RateLimiter.html#method.with_peek_methods),
+    /// `MultiGet`, `List`, `GetMetadata` and `MultiGetMetadata`. Counting requests, and every other operation, still
+    /// go to the primary, so that a lagging replica can not reset the window of a client.
+    ///
+    /// Replicas lag behind the primary, by a few milliseconds usually and more under load or
+    /// after a network partition. Reads from the replica may then miss the latest requests of
+    /// a client, and report more requests remaining than the primary would, until it catches
+    /// up. Peeks are never rejected, so this only affects their headers, and admin tools.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::RedisStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = RedisStore::connect_with_replica("redis://10.0.0.1", "redis://10.0.0.2", 4);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_replica<S: Into<String>, R: Into<String>>(
+        primary: S,
+        replica: R,
+        pool_size: usize,
+    ) -> Addr<Self> {
+        Self::start_with_replica(
+            Topology::Single(primary.into()),
+            Some(Topology::Single(replica.into())),
+            pool_size,
+        )
+    }
+
+    fn start(topology: Topology, pool_size: usize) -> Addr<Self> {
+        Self::start_with_replica(topology, None, pool_size)
+    }
+
+    fn start_with_replica(
+        topology: Topology,
+        replica: Option<Topology>,
+        pool_size: usize,
+    ) -> Addr<Self> {
+        let backoff = ExponentialBackoff {
+            max_elapsed_time: None,
+            ..Default::default()
+        };
+        Supervisor::start(move |_| RedisStore {
+            topology,
+            replica,
+            pool_size,
             backoff,
             client: None,
+            replica_client: None,
         })
     }
 }
@@ -51,18 +402,30 @@ impl Actor for RedisStore {
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         info!("Started main redis store");
-        let addr = self.addr.clone();
+        let topology = self.topology.clone();
+        let replica = self.replica.clone();
+        let pool_size = self.pool_size;
         async move {
-            let client = redis::Client::open(addr.as_ref()).unwrap();
-            client.get_multiplexed_async_connection().await
+            let (con, mut futures) = topology.connect(pool_size).await?;
+            let replica = match replica {
+                Some(replica) => {
+                    let (replica, replica_futures) = replica.connect(pool_size).await?;
+                    futures.extend(replica_futures);
+                    Some(replica)
+                }
+                None => None,
+            };
+            Ok((con, replica, futures))
         }
         .into_actor(self)
-        .map(|con, act, context| {
+        .map(|con: RedisResult<_>, act, context| {
             match con {
-                Ok(c) => {
-                    act.client = Some(c.0);
-                    let fut = c.1;
-                    fut.into_actor(act).spawn(context);
+                Ok((con, replica, futures)) => {
+                    act.client = Some(con);
+                    act.replica_client = replica;
+                    for fut in futures {
+                        fut.into_actor(act).spawn(context);
+                    }
                 }
                 Err(e) => {
                     error!("Error connecting to redis: {}", &e);
@@ -82,14 +445,15 @@ impl Supervised for RedisStore {
     fn restarting(&mut self, _: &mut Self::Context) {
         debug!("restarting redis store");
         self.client.take();
+        self.replica_client.take();
     }
 }
 
 impl Handler<GetAddr> for RedisStore {
-    type Result = Result<MultiplexedConnection, ARError>;
+    type Result = Result<(Connection, Option<Connection>), ARError>;
     fn handle(&mut self, _: GetAddr, ctx: &mut Self::Context) -> Self::Result {
         if let Some(con) = &self.client {
-            Ok(con.clone())
+            Ok((con.clone(), self.replica_client.clone()))
         } else {
             // No connection exists
             if let Some(backoff) = self.backoff.next_backoff() {
@@ -100,11 +464,118 @@ impl Handler<GetAddr> for RedisStore {
     }
 }
 
+// Moves the current count to the previous one when the window changed, see `ActorMessage::Rotate`
+const ROTATE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[3]) == ARGV[1] then
+    return redis.call('GET', KEYS[2])
+end
+local current = redis.call('GET', KEYS[1])
+if current then
+    redis.call('SET', KEYS[2], current, 'EX', ARGV[3])
+else
+    redis.call('DEL', KEYS[2])
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+redis.call('SET', KEYS[3], ARGV[1], 'EX', ARGV[3])
+return current
+";
+
+// Sets the key when it holds the expected value, or does not exist when it is empty, see
+// `ActorMessage::CompareAndSet`
+const COMPARE_AND_SET_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if (current or '') ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+return 1
+";
+
+// Takes from the count without going below zero, like the memory store, since a negative count
+// can not be read back. A missing key is not created, it would have no expiry.
+const UPDATE_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return false
+end
+local new = redis.call('DECRBY', KEYS[1], ARGV[1])
+if new < 0 then
+    redis.call('INCRBY', KEYS[1], -new)
+    new = 0
+end
+return new
+";
+
+// `UPDATE_SCRIPT`, answering with the time to live of the key as well
+const CONSUME_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return false
+end
+local new = redis.call('DECRBY', KEYS[1], ARGV[1])
+if new < 0 then
+    redis.call('INCRBY', KEYS[1], -new)
+    new = 0
+end
+return {new, redis.call('TTL', KEYS[1])}
+";
+
+// Adds to the count when the key exists, up to the cap unless already above, see
+// `ActorMessage::IncrementIfExists`. `INCRBY` keeps the expiry of the key.
+const INCREMENT_IF_EXISTS_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return false
+end
+current = tonumber(current)
+local new = math.min(current + tonumber(ARGV[1]), math.max(tonumber(ARGV[2]), current))
+if new ~= current then
+    redis.call('INCRBY', KEYS[1], new - current)
+end
+return new
+";
+
+// Adds the member to a HyperLogLog, setting its expiry when created, and returns its estimated
+// size up to the cap, see `ActorMessage::AddToSet`. The HyperLogLog keeps its size whatever the
+// members, so they are added past the cap all the same.
+const ADD_TO_SET_SCRIPT: &str = r"
+redis.call('PFADD', KEYS[1], ARGV[1])
+if redis.call('TTL', KEYS[1]) == -1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return math.min(redis.call('PFCOUNT', KEYS[1]), tonumber(ARGV[3]))
+";
+
+// Escapes the glob characters of `prefix` for `SCAN MATCH`
+fn escape_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+// Maps redis failures onto the store-specific error variants
+fn store_error(err: redis::RedisError) -> ARError {
+    if err.is_timeout() {
+        ARError::Timeout
+    } else if err.is_connection_dropped() || err.is_connection_refusal() {
+        debug!("redis unavailable: {}", &err);
+        ARError::StoreUnavailable
+    } else if err.kind() == redis::ErrorKind::TypeError {
+        ARError::Protocol(err.to_string())
+    } else {
+        ARError::ReadWriteError(format!("{:?}", &err))
+    }
+}
+
 /// Actor for redis store
 pub struct RedisStoreActor {
     addr: Addr<RedisStore>,
     backoff: ExponentialBackoff,
-    inner: Option<MultiplexedConnection>,
+    inner: Option<Connection>,
+    replica: Option<Connection>,
 }
 
 impl Actor for RedisStoreActor {
@@ -116,8 +587,9 @@ impl Actor for RedisStoreActor {
             .into_actor(self)
             .map(|res, act, context| match res {
                 Ok(c) => {
-                    if let Ok(conn) = c {
+                    if let Ok((conn, replica)) = c {
                         act.inner = Some(conn);
+                        act.replica = replica;
                     } else {
                         error!("could not get redis store address");
                         if let Some(timeout) = act.backoff.next_backoff() {
@@ -138,12 +610,15 @@ impl Actor for RedisStoreActor {
 
 impl From<Addr<RedisStore>> for RedisStoreActor {
     fn from(addr: Addr<RedisStore>) -> Self {
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_interval = Duration::from_secs(3);
+        let backoff = ExponentialBackoff {
+            max_interval: Duration::from_secs(3),
+            ..Default::default()
+        };
         RedisStoreActor {
             addr,
             backoff,
             inner: None,
+            replica: None,
         }
     }
 }
@@ -160,6 +635,7 @@ impl Supervised for RedisStoreActor {
     fn restarting(&mut self, _: &mut Self::Context) {
         debug!("restarting redis actor!");
         self.inner.take();
+        self.replica.take();
     }
 }
 
@@ -167,54 +643,78 @@ impl Handler<ActorMessage> for RedisStoreActor {
     type Result = ActorResponse;
     fn handle(&mut self, msg: ActorMessage, ctx: &mut Self::Context) -> Self::Result {
         let connection = self.inner.clone();
+        // Reads which change nothing go to the replica, if any
+        let replica = self.replica.clone();
         if let Some(mut con) = connection {
             match msg {
                 ActorMessage::Set { key, value, expiry } => {
                     ActorResponse::Set(Box::pin(async move {
                         let mut cmd = redis::Cmd::new();
                         cmd.arg("SET")
-                            .arg(key)
+                            .arg(con.key(key))
                             .arg(value)
                             .arg("EX")
-                            .arg(expiry.as_secs());
-                        let result = cmd.query_async::<MultiplexedConnection, ()>(&mut con).await;
+                            .arg(expiry.as_secs().max(1));
+                        let result = con.query::<()>(cmd).await;
                         match result {
                             Ok(_) => Ok(()),
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            Err(e) => Err(store_error(e)),
                         }
                     }))
                 }
                 ActorMessage::Update { key, value } => {
                     ActorResponse::Update(Box::pin(async move {
                         let mut cmd = redis::Cmd::new();
-                        cmd.arg("DECRBY").arg(key).arg(value);
-                        let result = cmd
-                            .query_async::<MultiplexedConnection, usize>(&mut con)
-                            .await;
+                        cmd.arg("EVAL")
+                            .arg(UPDATE_SCRIPT)
+                            .arg(1)
+                            .arg(con.key(key))
+                            .arg(value);
+                        let result = con.query::<Option<usize>>(cmd).await;
+                        match result {
+                            Ok(Some(c)) => Ok(c),
+                            Ok(None) => Err(ARError::ReadWriteError(
+                                "redis store: key does not exist".to_string(),
+                            )),
+                            Err(e) => Err(store_error(e)),
+                        }
+                    }))
+                }
+                ActorMessage::Consume { key, value } => {
+                    ActorResponse::Consume(Box::pin(async move {
+                        let mut cmd = redis::Cmd::new();
+                        cmd.arg("EVAL")
+                            .arg(CONSUME_SCRIPT)
+                            .arg(1)
+                            .arg(con.key(key))
+                            .arg(value);
+                        let result = con.query::<Option<(usize, isize)>>(cmd).await;
                         match result {
-                            Ok(c) => Ok(c),
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                            Ok(Some((c, ttl))) if ttl > 0 => Ok((c, Duration::new(ttl as u64, 0))),
+                            Ok(Some(_)) => Err(ARError::ReadWriteError(
+                                "redis store: key does not have an associated ttl".to_string(),
+                            )),
+                            Ok(None) => Err(ARError::ReadWriteError(
+                                "redis store: key does not exist".to_string(),
+                            )),
+                            Err(e) => Err(store_error(e)),
                         }
                     }))
                 }
                 ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
                     let mut cmd = redis::Cmd::new();
-                    cmd.arg("GET").arg(key);
-                    let result = cmd
-                        .query_async::<MultiplexedConnection, Option<usize>>(&mut con)
-                        .await;
+                    cmd.arg("GET").arg(con.key(key));
+                    let result = con.query::<Option<usize>>(cmd).await;
 
                     match result {
                         Ok(c) => Ok(c),
-                        Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        Err(e) => Err(store_error(e)),
                     }
                 })),
                 ActorMessage::Expire(key) => ActorResponse::Expire(Box::pin(async move {
                     let mut cmd = redis::Cmd::new();
-                    cmd.arg("TTL").arg(key);
-                    let result = cmd
-                        .query_async::<MultiplexedConnection, isize>(&mut con)
-                        .await;
+                    cmd.arg("TTL").arg(con.key(key));
+                    let result = con.query::<isize>(cmd).await;
                     match result {
                         Ok(c) => {
                             if c > 0 {
@@ -223,20 +723,229 @@ impl Handler<ActorMessage> for RedisStoreActor {
                                 Err(ARError::ReadWriteError("redis error: key does not exists or does not has a associated ttl.".to_string()))
                             }
                         }
-                        Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        Err(e) => Err(store_error(e)),
                     }
                 })),
                 ActorMessage::Remove(key) => ActorResponse::Remove(Box::pin(async move {
                     let mut cmd = redis::Cmd::new();
-                    cmd.arg("DEL").arg(key);
-                    let result = cmd
-                        .query_async::<MultiplexedConnection, usize>(&mut con)
-                        .await;
+                    cmd.arg("DEL").arg(con.key(key));
+                    let result = con.query::<usize>(cmd).await;
                     match result {
                         Ok(c) => Ok(c),
-                        Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        Err(e) => Err(store_error(e)),
                     }
                 })),
+                ActorMessage::List { prefix, limit } => {
+                    ActorResponse::List(Box::pin(async move {
+                        let mut con = replica.unwrap_or(con);
+                        if con.is_cluster() {
+                            return Err(ARError::Other(
+                                "listing keys is not supported with redis cluster".to_string(),
+                            ));
+                        }
+                        let pattern = format!("{}*", escape_pattern(&prefix));
+                        let mut keys = Vec::new();
+                        let mut cursor = 0u64;
+                        loop {
+                            let mut cmd = redis::Cmd::new();
+                            cmd.arg("SCAN")
+                                .arg(cursor)
+                                .arg("MATCH")
+                                .arg(&pattern)
+                                .arg("COUNT")
+                                .arg(100);
+                            let (next, batch) = con
+                                .query::<(u64, Vec<String>)>(cmd)
+                                .await
+                                .map_err(store_error)?;
+                            for key in batch {
+                                if keys.len() >= limit {
+                                    break;
+                                }
+                                let mut get = redis::Cmd::new();
+                                get.arg("GET").arg(&key);
+                                let mut ttl = redis::Cmd::new();
+                                ttl.arg("TTL").arg(&key);
+                                // Keys which are not counters, or expired in between, are skipped
+                                let remaining = con.query::<Option<usize>>(get).await;
+                                let ttl = con.query::<isize>(ttl).await.map_err(store_error)?;
+                                if let (Ok(Some(remaining)), true) = (remaining, ttl > 0) {
+                                    keys.push((key, remaining, Duration::from_secs(ttl as u64)));
+                                }
+                            }
+                            cursor = next;
+                            if cursor == 0 || keys.len() >= limit {
+                                break;
+                            }
+                        }
+                        Ok(keys)
+                    }))
+                }
+                ActorMessage::Rotate {
+                    key,
+                    new_window_start,
+                    value,
+                    expiry,
+                } => ActorResponse::Rotate(Box::pin(async move {
+                    // Hash tagged in cluster mode, so the three keys share a slot
+                    let previous = con.key(derived_key(&key, ":previous"));
+                    let window = con.key(derived_key(&key, ":window"));
+                    let mut cmd = redis::Cmd::new();
+                    cmd.arg("EVAL")
+                        .arg(ROTATE_SCRIPT)
+                        .arg(3)
+                        .arg(con.key(key))
+                        .arg(previous)
+                        .arg(window)
+                        .arg(new_window_start)
+                        .arg(value)
+                        .arg(expiry.as_secs().max(1));
+                    let result = con.query::<Option<usize>>(cmd).await;
+                    match result {
+                        Ok(c) => Ok(c),
+                        Err(e) => Err(store_error(e)),
+                    }
+                })),
+                ActorMessage::Ping => ActorResponse::Ping(Box::pin(async move {
+                    let mut cmd = redis::Cmd::new();
+                    cmd.arg("PING");
+                    con.query::<()>(cmd).await.map_err(store_error)
+                })),
+                ActorMessage::Time => ActorResponse::Time(Box::pin(async move {
+                    let mut cmd = redis::Cmd::new();
+                    cmd.arg("TIME");
+                    // Seconds and microseconds since the Unix epoch
+                    let (secs, micros) = con.query::<(u64, u64)>(cmd).await.map_err(store_error)?;
+                    Ok(Duration::from_secs(secs) + Duration::from_micros(micros))
+                })),
+                ActorMessage::SetMetadata {
+                    key,
+                    metadata,
+                    expiry,
+                } => ActorResponse::SetMetadata(Box::pin(async move {
+                    let key = con.key(derived_key(&key, ":metadata"));
+                    let mut cmd = redis::Cmd::new();
+                    match metadata {
+                        Some(metadata) => cmd
+                            .arg("SET")
+                            .arg(key)
+                            .arg(metadata)
+                            .arg("EX")
+                            .arg(expiry.as_secs().max(1)),
+                        None => cmd.arg("DEL").arg(key),
+                    };
+                    con.query::<()>(cmd).await.map_err(store_error)
+                })),
+                ActorMessage::MultiGet(keys) => ActorResponse::MultiGet(Box::pin(async move {
+                    let mut con = replica.unwrap_or(con);
+                    if keys.is_empty() {
+                        return Ok(Vec::new());
+                    }
+                    // Keys of different clients live in different slots of a cluster, which
+                    // MGET can not span
+                    if con.is_cluster() {
+                        let mut counts = Vec::with_capacity(keys.len());
+                        for key in keys {
+                            let mut cmd = redis::Cmd::new();
+                            cmd.arg("GET").arg(con.key(key));
+                            counts
+                                .push(con.query::<Option<usize>>(cmd).await.map_err(store_error)?);
+                        }
+                        return Ok(counts);
+                    }
+                    let mut cmd = redis::Cmd::new();
+                    cmd.arg("MGET").arg(keys);
+                    con.query::<Vec<Option<usize>>>(cmd)
+                        .await
+                        .map_err(store_error)
+                })),
+                ActorMessage::GetMetadata(key) => {
+                    ActorResponse::GetMetadata(Box::pin(async move {
+                        let mut con = replica.unwrap_or(con);
+                        let mut cmd = redis::Cmd::new();
+                        cmd.arg("GET").arg(con.key(derived_key(&key, ":metadata")));
+                        con.query::<Option<String>>(cmd).await.map_err(store_error)
+                    }))
+                }
+                ActorMessage::MultiGetMetadata(keys) => {
+                    ActorResponse::MultiGetMetadata(Box::pin(async move {
+                        let mut con = replica.unwrap_or(con);
+                        if keys.is_empty() {
+                            return Ok(Vec::new());
+                        }
+                        let keys: Vec<String> = keys
+                            .iter()
+                            .map(|key| con.key(derived_key(key, ":metadata")))
+                            .collect();
+                        // Like MultiGet, MGET can not span the slots of a cluster
+                        if con.is_cluster() {
+                            let mut metadata = Vec::with_capacity(keys.len());
+                            for key in keys {
+                                let mut cmd = redis::Cmd::new();
+                                cmd.arg("GET").arg(key);
+                                metadata.push(
+                                    con.query::<Option<String>>(cmd)
+                                        .await
+                                        .map_err(store_error)?,
+                                );
+                            }
+                            return Ok(metadata);
+                        }
+                        let mut cmd = redis::Cmd::new();
+                        cmd.arg("MGET").arg(keys);
+                        con.query::<Vec<Option<String>>>(cmd)
+                            .await
+                            .map_err(store_error)
+                    }))
+                }
+                ActorMessage::CompareAndSet {
+                    key,
+                    expected,
+                    new,
+                    expiry,
+                } => ActorResponse::CompareAndSet(Box::pin(async move {
+                    let mut cmd = redis::Cmd::new();
+                    cmd.arg("EVAL")
+                        .arg(COMPARE_AND_SET_SCRIPT)
+                        .arg(1)
+                        .arg(con.key(key))
+                        .arg(expected.map(|e| e.to_string()).unwrap_or_default())
+                        .arg(new)
+                        .arg(expiry.as_secs().max(1));
+                    let result = con.query::<usize>(cmd).await;
+                    match result {
+                        Ok(c) => Ok(c == 1),
+                        Err(e) => Err(store_error(e)),
+                    }
+                })),
+                ActorMessage::IncrementIfExists { key, by, cap } => {
+                    ActorResponse::IncrementIfExists(Box::pin(async move {
+                        let mut cmd = redis::Cmd::new();
+                        cmd.arg("EVAL")
+                            .arg(INCREMENT_IF_EXISTS_SCRIPT)
+                            .arg(1)
+                            .arg(con.key(key))
+                            .arg(by)
+                            .arg(cap);
+                        con.query::<Option<usize>>(cmd).await.map_err(store_error)
+                    }))
+                }
+                ActorMessage::AddToSet {
+                    key,
+                    member,
+                    expiry,
+                    cap,
+                } => ActorResponse::AddToSet(Box::pin(async move {
+                    let mut cmd = redis::Cmd::new();
+                    cmd.arg("EVAL")
+                        .arg(ADD_TO_SET_SCRIPT)
+                        .arg(1)
+                        .arg(con.key(key))
+                        .arg(member)
+                        .arg(expiry.as_secs().max(1))
+                        .arg(cap);
+                    con.query::<usize>(cmd).await.map_err(store_error)
+                })),
             }
         } else {
             ctx.stop();
@@ -253,6 +962,40 @@ mod tests {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    #[test]
+    fn test_hash_tag() {
+        // The keys Rotate writes, and those the middleware reads, such as when peeking
+        for client in &[
+            "127.0.0.1:8080",
+            "/admin/:127.0.0.1:8080",
+            "203.0.113.7:1700000000",
+        ] {
+            let tagged = hash_tag(client);
+            assert_eq!(tagged, format!("{{{}}}", client));
+            for suffix in DERIVED_SUFFIXES {
+                let derived = hash_tag(&derived_key(client, suffix));
+                assert_eq!(derived, format!("{}{}", tagged, suffix));
+            }
+            // Derived from a derived key, in the slot of the client all the same
+            let blocked = derived_key(&derived_key(client, ":errors"), ":blocked");
+            assert_eq!(hash_tag(&blocked), format!("{}:errors:blocked", tagged));
+        }
+        assert_eq!(hash_tag("{tagged}:window"), "{tagged}:window");
+    }
+
+    #[test]
+    fn test_store_error() {
+        use std::io::{Error, ErrorKind};
+        let err = redis::RedisError::from(Error::new(ErrorKind::ConnectionRefused, "refused"));
+        assert!(matches!(store_error(err), ARError::StoreUnavailable));
+        let err = redis::RedisError::from(Error::new(ErrorKind::TimedOut, "timed out"));
+        assert!(matches!(store_error(err), ARError::Timeout));
+        let err = redis::RedisError::from((redis::ErrorKind::TypeError, "not a number"));
+        assert!(matches!(store_error(err), ARError::Protocol(_)));
+        let err = redis::RedisError::from((redis::ErrorKind::ResponseError, "busy"));
+        assert!(matches!(store_error(err), ARError::ReadWriteError(_)));
+    }
+
     #[actix_rt::test]
     async fn test_set() {
         init();
@@ -285,7 +1028,7 @@ mod tests {
             .send(ActorMessage::Set {
                 key: "hello".to_string(),
                 value: 30usize,
-                expiry: expiry,
+                expiry,
             })
             .await;
         let res = res.expect("Failed to send msg");
@@ -320,7 +1063,7 @@ mod tests {
             .send(ActorMessage::Set {
                 key: "hello_test".to_string(),
                 value: 30usize,
-                expiry: expiry,
+                expiry,
             })
             .await;
         let res = res.expect("Failed to send msg");
@@ -331,7 +1074,7 @@ mod tests {
             },
             _ => panic!("Shouldn't happen!"),
         }
-        assert_eq!(addr.connected(), true);
+        assert!(addr.connected());
 
         let res3 = addr
             .send(ActorMessage::Expire("hello_test".to_string()))
@@ -352,4 +1095,47 @@ mod tests {
             _ => panic!("Shouldn't happen!"),
         };
     }
+
+    #[actix_rt::test]
+    async fn test_pool() {
+        use crate::stores::{get, set, update};
+        init();
+        let store = RedisStore::connect_with_pool_size("redis://127.0.0.1/", 3);
+        let addr = RedisStoreActor::from(store).start();
+        set(&addr, "pooled", 10, Duration::from_secs(5))
+            .await
+            .unwrap();
+        // Taken in turn by the connections of the pool, which all see the same count
+        let updates = (0..9).map(|_| update(&addr, "pooled", 1));
+        let mut counts: Vec<_> = futures::future::join_all(updates)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        counts.sort_unstable();
+        assert_eq!(counts, (1..10).collect::<Vec<_>>());
+        assert_eq!(get(&addr, "pooled").await.unwrap(), Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn test_replica() {
+        use crate::stores::{get, multi_get, set};
+        init();
+        // A second database of the same server stands in for a replica which has not caught
+        // up, holding none of the keys written to the primary
+        let store =
+            RedisStore::connect_with_replica("redis://127.0.0.1/", "redis://127.0.0.1/1", 1);
+        let addr = RedisStoreActor::from(store).start();
+        set(&addr, "replicated", 30, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(get(&addr, "replicated").await.unwrap(), Some(30));
+        assert_eq!(multi_get(&addr, &["replicated"]).await.unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn test_escape_pattern() {
+        assert_eq!(escape_pattern("user:1"), "user:1");
+        assert_eq!(escape_pattern("a*b?[c]"), "a\\*b\\?\\[c\\]");
+    }
 }
@@ -0,0 +1,182 @@
+//! Instrumentation of the middleware, compiled out when the matching features are off
+use std::{future::Future, time::Duration};
+
+#[cfg(feature = "tracing")]
+use crate::identifiers;
+
+/// Outcome of a request, the `result` label of `ratelimit_requests_total`
+#[derive(Clone, Copy)]
+pub(crate) enum Outcome {
+    Allowed,
+    Rejected,
+    Error,
+}
+
+impl Outcome {
+    #[cfg_attr(not(any(feature = "metrics", feature = "tracing")), allow(dead_code))]
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Allowed => "allowed",
+            Outcome::Rejected => "rejected",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+pub(crate) fn request(_outcome: Outcome) {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("ratelimit_requests_total", "result" => _outcome.as_str());
+}
+
+pub(crate) fn store_error() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("ratelimit_store_errors_total");
+}
+
+pub(crate) fn store_latency(_latency: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("ratelimit_store_latency", _latency.as_secs_f64());
+}
+
+/// Span covering the store round trips deciding on a request
+pub(crate) struct DecisionSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl DecisionSpan {
+    pub(crate) fn new(_key: &str, _hash_key: bool) -> Self {
+        DecisionSpan {
+            #[cfg(feature = "tracing")]
+            span: if _hash_key {
+                tracing::info_span!(
+                    "ratelimit",
+                    key = %format!("{:016x}", identifiers::fnv1a(_key)),
+                    decision = tracing::field::Empty,
+                    latency_ms = tracing::field::Empty,
+                )
+            } else {
+                tracing::info_span!(
+                    "ratelimit",
+                    key = %_key,
+                    decision = tracing::field::Empty,
+                    latency_ms = tracing::field::Empty,
+                )
+            },
+        }
+    }
+
+    pub(crate) async fn run<F: Future>(&self, fut: F) -> F::Output {
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(fut, self.span.clone());
+        fut.await
+    }
+
+    pub(crate) fn record(&self, _outcome: Outcome, _latency: Duration) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("decision", _outcome.as_str());
+            self.span
+                .record("latency_ms", _latency.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+/// Runs a single store round trip, in a child span of the decision when tracing
+pub(crate) async fn store_call<F: Future>(_op: &'static str, fut: F) -> F::Output {
+    #[cfg(feature = "tracing")]
+    let fut =
+        tracing::Instrument::instrument(fut, tracing::debug_span!("ratelimit.store", op = _op));
+    fut.await
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(feature = "metrics", feature = "memory"))]
+    #[actix_rt::test]
+    async fn test_metrics() {
+        use crate::{MemoryStore, RateLimiter};
+        use actix_web::{test, web, App, HttpResponse};
+        use metrics::{Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Unit};
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // Counts the increments of the counters and the samples of the histograms, by name
+        // and labels
+        #[derive(Clone, Default)]
+        struct Recorded(Arc<Mutex<HashMap<String, u64>>>);
+        struct Handle(Recorded, String);
+
+        impl Recorded {
+            fn get(&self, name: &str) -> u64 {
+                *self.0.lock().unwrap().get(name).unwrap_or(&0)
+            }
+
+            fn handle(&self, key: &Key) -> Arc<Handle> {
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+                let name = if labels.is_empty() {
+                    key.name().to_string()
+                } else {
+                    format!("{}{{{}}}", key.name(), labels.join(","))
+                };
+                Arc::new(Handle(self.clone(), name))
+            }
+        }
+
+        impl CounterFn for Handle {
+            fn increment(&self, value: u64) {
+                *self.0 .0.lock().unwrap().entry(self.1.clone()).or_default() += value;
+            }
+
+            fn absolute(&self, _: u64) {}
+        }
+
+        impl HistogramFn for Handle {
+            fn record(&self, _: f64) {
+                self.increment(1);
+            }
+        }
+
+        impl metrics::Recorder for Recorded {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: metrics::SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: metrics::SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: metrics::SharedString) {}
+
+            fn register_counter(&self, key: &Key) -> Counter {
+                Counter::from_arc(self.handle(key))
+            }
+
+            fn register_gauge(&self, _: &Key) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key) -> Histogram {
+                Histogram::from_arc(self.handle(key))
+            }
+        }
+
+        // The recorder is global, other tests running alongside count into it as well
+        let recorded = Recorded::default();
+        metrics::set_boxed_recorder(Box::new(recorded.clone())).unwrap();
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RateLimiter::memory(&store, 1, Duration::from_secs(60)))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let _ = actix_web::dev::Service::call(&mut app, req).await;
+        }
+        assert!(recorded.get("ratelimit_requests_total{result=allowed}") >= 1);
+        assert!(recorded.get("ratelimit_requests_total{result=rejected}") >= 1);
+        assert!(recorded.get("ratelimit_store_latency") >= 2);
+    }
+}